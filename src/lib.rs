@@ -0,0 +1,11 @@
+//! Places MagicaVoxel models and templates in the persisted terrain of a
+//! Veloren server. The `map_placer` binary is the command line interface of
+//! this library.
+
+pub mod model;
+pub mod persistence;
+pub mod place;
+pub mod resolve;
+pub mod scene;
+pub mod sink;
+pub mod spec;
@@ -1,335 +1,3208 @@
 use std::{
-    collections::HashMap,
-    ops::{Deref, DerefMut},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use dot_vox::{DotVoxData, Model};
-use rand::{thread_rng, Rng};
-use serde::Deserialize;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
 use vek::*;
 use veloren_common::{
-    assets::{Asset, AssetExt, AssetHandle, DotVoxAsset, RonLoader},
-    figure::Cell,
-    lottery::Lottery,
+    assets::{AssetExt, ASSETS_PATH},
     terrain::{Block, BlockKind, SpriteKind},
-    vol::{IntoFullVolIterator, VolSize, WriteVol},
-    volumes::{chunk::Chunk, vol_grid_3d::VolGrid3d},
 };
-use veloren_server::terrain_persistence::TerrainPersistence;
-
-#[derive(Clone, Debug)]
-pub struct SscSize;
-impl VolSize for SscSize {
-    const SIZE: Vec3<u32> = Vec3 {
-        x: 32,
-        y: 32,
-        z: 32,
+
+use map_placer::{
+    model::{is_model_path, spec_dir, Template},
+    persistence::{
+        apply_piece_undo, apply_undo, clear_region, copy_region, load_allowed_chunks,
+        restore_blocks, rollback, terrain_chunk_key, terrain_persistence_dir, verify_written,
+        AllowedArea, Journal, Mismatch, PersistedBlocks, Undo, World, Writer, JOURNAL_FILE,
+    },
+    place::{
+        merge_pieces, place_into, points_of_interest, save_special_sprites, BuiltPiece, Context,
+        PlaceOptions, PlaceReport, PlaceStats, Poi, UnmappedColors, Verbosity,
+    },
+    scene::{PieceBounds, SparseScene},
+    sink::{BlockSink, BoundsFilter, ChunkFilter, CommandScript, ProtectPersisted, TopView},
+    spec::{
+        ColorTransform, Fnv, Merge, PlaceSpec, Placement, PlacementSet, Rotation, Validation,
+        VoxSpec,
+    },
+};
+
+/// Adds an error if the bounds of `pieces` together are larger than
+/// `max_total_volume`, or reach further than `world_bounds` from the origin,
+/// which usually means an offset has a typo.
+fn check_bounds(
+    name: &str,
+    pieces: &[BuiltPiece],
+    max_total_volume: u64,
+    world_bounds: i32,
+    validation: &mut Validation,
+) {
+    let volume = pieces
+        .iter()
+        .flat_map(|piece| &piece.aabbs)
+        .map(|aabb| (aabb.max - aabb.min + 1).map(|e| e as u64).product::<u64>())
+        .sum::<u64>();
+    let outside = pieces.iter().filter_map(|piece| piece.bounds.as_ref()).find(|bounds| {
+        let aabb = bounds.aabb;
+        aabb.min
+            .map2(aabb.max, |min, max| min.abs().max(max.abs()))
+            .reduce_max()
+            > world_bounds
+    });
+    let problem = if let Some(bounds) = outside {
+        format!(
+            "piece {} spans {} to {}, further than the world bounds of {}",
+            bounds.name, bounds.aabb.min, bounds.aabb.max, world_bounds
+        )
+    } else if volume > max_total_volume {
+        format!(
+            "its pieces cover {} blocks, more than the maximum total volume of {}",
+            volume, max_total_volume
+        )
+    } else {
+        return;
     };
+    let mut error = format!(
+        "{}: {}, pass --yes-i-know if this is intended. The bounds of the pieces are:",
+        name, problem
+    );
+    for piece in pieces {
+        if let Some(bounds) = &piece.bounds {
+            error += &format!(
+                "\n  piece {} {}: {} to {}",
+                piece.index, bounds.name, bounds.aabb.min, bounds.aabb.max
+            );
+        }
+    }
+    validation.errors.push(error);
 }
 
-struct SparseScene(VolGrid3d<Chunk<Cell, SscSize, ()>>);
-impl Deref for SparseScene {
-    type Target = VolGrid3d<Chunk<Cell, SscSize, ()>>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-impl DerefMut for SparseScene {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-impl SparseScene {
-    pub fn new_from<'a>(
-        dot_vox_data: impl Iterator<Item = (assets_manager::AssetGuard<'a, DotVoxAsset>, Vec3<i32>)>,
-    ) -> (Self, Vec<Aabb<i32>>) {
-        fn render_model(
-            palette: &Vec<Rgb<u8>>,
-            model: &Model,
-            sparse_scene: &mut SparseScene,
-            aabbs: &mut Vec<Aabb<i32>>,
-            rot: Mat3<i32>,
-            trans: Vec3<i32>,
-        ) {
-            // Get the rotated size of the model
-            let size =
-                rot.map(|e| e.abs() as u32) * Vec3::new(model.size.x, model.size.y, model.size.z);
-            // Position of min corner
-            let pos = trans
-                .map2(size, |m, s| (s, m))
-                .map2(rot * Vec3::<i32>::one(), |(s, m), f| {
-                    m - (s as i32 + f.min(0) * -1) / 2
-                });
-            let model_bounds = Aabb {
-                min: pos,
-                // vek Aabbs are inclusive
-                max: pos + size.map(|e| e as i32) - 1,
+/// The keys of the terrain chunks that aren't `allowed` which the bounds and
+/// skirts of `pieces` reach into.
+fn disallowed_chunks(pieces: &[BuiltPiece], allowed: &HashSet<Vec2<i32>>) -> BTreeSet<[i32; 2]> {
+    let bounds = pieces.iter().flat_map(|piece| &piece.aabbs).flat_map(|aabb| {
+        AllowedArea::Rect {
+            min: (aabb.min.x, aabb.min.y),
+            max: (aabb.max.x, aabb.max.y),
+        }
+        .chunks()
+    });
+    let skirts = pieces
+        .iter()
+        .flat_map(|piece| &piece.skirt)
+        .map(|(wpos, _)| terrain_chunk_key(*wpos));
+    bounds
+        .chain(skirts)
+        .filter(|key| !allowed.contains(key))
+        .map(Vec2::into_array)
+        .collect()
+}
+
+/// Prints the bounds, relative to the group of the piece and in the world,
+/// and the voxel count of every piece, and adds a warning for every pair of
+/// pieces whose bounds overlap, or an error with `fail_on_overlap`.
+fn report_pieces(
+    name: &str,
+    pieces: &[BuiltPiece],
+    fail_on_overlap: bool,
+    verbosity: Verbosity,
+    validation: &mut Validation,
+) {
+    let bounds = pieces
+        .iter()
+        .filter_map(|piece| Some((piece.index, piece.bounds.as_ref()?)))
+        .collect::<Vec<_>>();
+    if verbosity > Verbosity::Quiet && !bounds.is_empty() {
+        println!("Pieces of {}:", name);
+        println!(
+            "{:>6} {:<32} {:<40} {:<40} {:>10}",
+            "Piece", "Name", "Local bounds", "World bounds", "Voxels"
+        );
+        for piece in pieces {
+            let Some(bounds) = &piece.bounds else {
+                continue;
             };
-            if !aabbs.iter_mut().any(|aabb| {
-                (if model_bounds.contains_aabb(*aabb) {
-                    *aabb = model_bounds;
-                    true
+            let local = piece.local_aabb.unwrap_or(bounds.aabb);
+            println!(
+                "{:>6} {:<32} {:<40} {:<40} {:>10}",
+                piece.index,
+                bounds.name,
+                format!("{} to {}", local.min, local.max),
+                format!("{} to {}", bounds.aabb.min, bounds.aabb.max),
+                bounds.voxels
+            );
+        }
+    }
+    for (i, (a_index, a)) in bounds.iter().enumerate() {
+        for (b_index, b) in &bounds[i + 1..] {
+            // Bounds are inclusive.
+            let min = a.aabb.min.map2(b.aabb.min, i32::max);
+            let max = a.aabb.max.map2(b.aabb.max, i32::min);
+            if min.map2(max, |min, max| min <= max).reduce_and() {
+                let message = format!(
+                    "{}: pieces {} {} and {} {} overlap from {} to {}",
+                    name, a_index, a.name, b_index, b.name, min, max
+                );
+                if fail_on_overlap {
+                    validation.errors.push(message);
                 } else {
-                    false
-                }) || aabb.contains_aabb(model_bounds)
-            }) {
-                aabbs.push(model_bounds);
-            }
-            // dbg!(pos);
-            // Insert required chunks
-            let min_key = sparse_scene.pos_key(pos);
-            let max_key = sparse_scene.pos_key(pos + size.map(|e| e as i32 - 1));
-            for x in min_key.x..=max_key.x {
-                for y in min_key.y..=max_key.y {
-                    for z in min_key.z..=max_key.z {
-                        let key = Vec3::new(x, y, z);
-                        if sparse_scene.get_key_arc(key).is_none() {
-                            sparse_scene
-                                .insert(key, std::sync::Arc::new(Chunk::filled(Cell::Empty, ())));
-                        }
-                    }
+                    validation.warnings.push(message);
                 }
             }
-            let offset = (rot
-                * Vec3::new(model.size.x, model.size.y, model.size.z).map(|e| e as i32))
-            .map(|e| if e > 0 { 0 } else { -e - 1 });
-            for voxel in &model.voxels {
-                if let Some(&color) = palette.get(voxel.i as usize) {
-                    sparse_scene
-                        .set(
-                            (rot * Vec3::new(voxel.x, voxel.y, voxel.z).map(|e| i32::from(e)))
-                                + offset
-                                + pos,
-                            Cell::new(color, false, false, voxel.i == 16),
-                        )
-                        .unwrap();
+        }
+    }
+}
+
+/// Warns if the variants of `--variants` overlap, which they do unless the
+/// stride is longer than the bounds of a variant along some axis.
+fn check_variant_stride(
+    specs: &[(&Placement, PlaceSpec)],
+    built: &[Vec<BuiltPiece>],
+    stride: Vec3<i32>,
+    validation: &mut Validation,
+) {
+    let Some(bounds) = specs
+        .iter()
+        .zip(built)
+        .filter(|((_, place_spec), _)| place_spec.variant == 0)
+        .flat_map(|(_, pieces)| pieces.iter().flat_map(|piece| &piece.aabbs))
+        .copied()
+        .reduce(|a, b| a.union(b))
+    else {
+        return;
+    };
+    let size = bounds.max - bounds.min + 1;
+    if stride.map2(size, |stride, size| stride.abs() < size).reduce_and() {
+        validation.warnings.push(format!(
+            "The variants overlap, their stride {} doesn't exceed their size {} along any axis",
+            stride, size
+        ));
+    }
+}
+
+/// Records the blocks of every variant of `--variants` on their own.
+struct VariantStats<'a> {
+    /// The variant being placed, blocks written outside of any aren't
+    /// recorded.
+    current: &'a std::cell::Cell<Option<usize>>,
+    stats: &'a mut Vec<PlaceStats>,
+}
+
+impl BlockSink for VariantStats<'_> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        if let Some(stats) = self.current.get().and_then(|i| self.stats.get_mut(i)) {
+            stats.set_block(wpos, block);
+        }
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        if let Some(stats) = self.current.get().and_then(|i| self.stats.get_mut(i)) {
+            stats.set_blocks_in_chunk(key, blocks);
+        }
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// How many colors of every spec with a `color_transform` the summary shows.
+const COLOR_SAMPLES: usize = 5;
+
+/// The most mismatches `--verify` lists.
+const MAX_MISMATCHES: usize = 20;
+
+/// Reports the blocks that didn't read back as they were written.
+fn print_mismatches(mismatches: &[Mismatch], written: usize) {
+    if mismatches.is_empty() {
+        println!("Verified {} written blocks", written);
+        return;
+    }
+    error!(
+        "{} of {} written blocks read back differently",
+        mismatches.len(),
+        written
+    );
+    for mismatch in mismatches.iter().take(MAX_MISMATCHES) {
+        match mismatch.found {
+            Some(found) => error!(
+                "Wrote {:?} at {}, read back {:?}",
+                mismatch.written, mismatch.wpos, found
+            ),
+            None => error!(
+                "Wrote {:?} at {}, read back nothing",
+                mismatch.written, mismatch.wpos
+            ),
+        }
+    }
+    if mismatches.len() > MAX_MISMATCHES {
+        error!("And {} more", mismatches.len() - MAX_MISMATCHES);
+    }
+}
+
+/// What placing the pieces of `place_spec` one by one with `--streaming` does
+/// differently from placing them together.
+fn streaming_differences(place_spec: &PlaceSpec) -> Vec<&'static str> {
+    let mut differences = Vec::new();
+    let fills = |piece: &VoxSpec| piece.options.fill_empty.unwrap_or(place_spec.fill_empty);
+    if place_spec.pieces.iter().any(fills) {
+        differences.push("the filled bounds of a piece overwrite the pieces placed before it");
+    }
+    if place_spec.pieces.iter().any(|piece| piece.options.merge != Merge::Overwrite) {
+        differences.push("merge modes only apply within a piece, every piece overwrites");
+    }
+    if place_spec.pieces.iter().any(|piece| piece.options.priority != 0) {
+        differences.push("priorities only apply within a piece, later pieces overwrite");
+    }
+    if place_spec.uses_neighbors() {
+        differences.push("neighbor conditions only see the cells of their own piece");
+    }
+    if place_spec.carve_interior {
+        differences.push("interiors are carved in every piece on its own");
+    }
+    if place_spec.max_glow_blocks.is_some() {
+        differences.push("max_glow_blocks limits every piece on its own");
+    }
+    differences
+}
+
+/// The peak resident memory of the process since the last
+/// `reset_peak_memory`, in bytes, where the system reports it.
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Resets the peak of `peak_memory` to the current resident memory, which
+/// only Linux supports.
+fn reset_peak_memory() {
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+fn format_memory(memory: Option<u64>) -> String {
+    match memory {
+        Some(memory) => format!("{:.1} MiB", memory as f64 / (1 << 20) as f64),
+        None => "unknown".to_string(),
+    }
+}
+
+/// The pieces whose keys are in `redo`.
+fn redo_pieces<'a>(
+    pieces: &'a [BuiltPiece],
+    keys: &[String],
+    redo: &HashSet<String>,
+) -> Vec<&'a BuiltPiece> {
+    pieces
+        .iter()
+        .zip(keys)
+        .filter(|(_, key)| redo.contains(*key))
+        .map(|(piece, _)| piece)
+        .collect()
+}
+
+/// What the program does.
+#[derive(Default)]
+enum Command {
+    /// Places the specs.
+    #[default]
+    Place,
+    /// Validates the specs without placing them.
+    Check,
+    /// Reverts the placement of an undo file, or only what one piece of it
+    /// wrote, including what later pieces wrote again if forced.
+    Undo(PathBuf, Option<String>, bool),
+    /// Removes the persisted blocks in a region.
+    Clear(Aabb<i32>),
+    /// Copies the persisted blocks in a region to a template file.
+    Copy(Aabb<i32>, PathBuf),
+    /// Compares the blocks two specs place, optionally writing the
+    /// differences to a file.
+    Diff(String, String, Option<PathBuf>),
+    /// Finishes the placement the journal says was interrupted.
+    Resume,
+    /// Reverts what the placement the journal says was interrupted wrote.
+    Rollback,
+    /// Prints the commands of a script at a rate in blocks per second,
+    /// continuing where the last feed stopped if resuming.
+    Feed(PathBuf, f64, bool),
+}
+
+/// Places MagicaVoxel models in the terrain persistence of a Veloren server.
+#[derive(Parser)]
+#[command(name = "vox_placer", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: CliCommand,
+    #[command(flatten)]
+    global: GlobalArgs,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Places a spec, or every spec of a placement set.
+    Place {
+        #[command(flatten)]
+        spec: SpecArgs,
+        #[command(flatten)]
+        write: WriteArgs,
+    },
+    /// Validates a spec and builds its pieces, without placing them.
+    Check {
+        #[command(flatten)]
+        spec: SpecArgs,
+    },
+    /// Reverts a previous placement with its undo file.
+    Undo {
+        /// The undo file the placement wrote.
+        file: PathBuf,
+        /// Only reverts the blocks written by the piece with this name.
+        #[arg(long)]
+        piece: Option<String>,
+        /// Also reverts the blocks of the piece that later pieces wrote again.
+        #[arg(long, requires = "piece")]
+        force: bool,
+    },
+    /// Removes the persisted blocks in a region, so the terrain generates
+    /// again.
+    Clear {
+        #[command(flatten)]
+        region: RegionArgs,
+        /// Write empty blocks instead of removing persisted blocks.
+        #[arg(long)]
+        fill_air: bool,
+        /// Only report what would be cleared.
+        #[arg(long)]
+        dry_run: bool,
+        /// Where to write the undo file, instead of a new timestamped one.
+        #[arg(long, value_name = "PATH")]
+        undo_file: Option<PathBuf>,
+    },
+    /// Copies the persisted blocks in a region to a template file.
+    Copy {
+        /// Where to write the template.
+        path: PathBuf,
+        #[command(flatten)]
+        region: RegionArgs,
+    },
+    /// Places two specs in memory and compares the blocks they place.
+    Diff {
+        /// The first spec, an asset specifier or the path of a spec file.
+        a: String,
+        /// The second spec.
+        b: String,
+        /// Also write every differing block to this RON file.
+        #[arg(long, value_name = "PATH")]
+        diff_output: Option<PathBuf>,
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+    /// Finishes a placement that was interrupted while writing.
+    Resume,
+    /// Reverts what a placement that was interrupted while writing wrote.
+    Rollback,
+    /// Prints the commands of a script written with --commands-out, paced
+    /// for piping into the console of a running server.
+    Feed {
+        /// The script --commands-out wrote.
+        script: PathBuf,
+        /// How many commands to print per second.
+        #[arg(long, value_name = "BLOCKS", default_value_t = 200.0)]
+        rate: f64,
+        /// Continue after the last batch an interrupted feed printed.
+        #[arg(long)]
+        resume: bool,
+    },
+}
+
+/// The arguments every command takes.
+#[derive(clap::Args)]
+struct GlobalArgs {
+    /// Only print warnings, errors and the final summary.
+    #[arg(long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log timings for every chunk instead of showing a progress bar.
+    #[arg(long, global = true)]
+    verbose: bool,
+    /// The most verbose messages logged: error, warn, info, debug or trace.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// Also append the log to this file as JSON lines.
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// The terrain persistence directory, the one with the chunk files.
+    #[arg(long, global = true, value_name = "PATH")]
+    terrain_dir: Option<PathBuf>,
+    /// Create the terrain directory if it doesn't exist.
+    #[arg(long, global = true)]
+    create: bool,
+    /// Write the chunk files without `TerrainPersistence`.
+    #[arg(long, global = true)]
+    standalone_persistence: bool,
+}
+
+/// The arguments that change the blocks a spec places.
+#[derive(clap::Args)]
+struct BuildArgs {
+    /// Translates the whole placement, in the format x,y,z.
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3, allow_hyphen_values = true)]
+    offset: Option<Vec3<i32>>,
+    /// Overrides the seed of every spec.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Pick random blocks with a thread local RNG instead of from the seed.
+    #[arg(long)]
+    thread_rng: bool,
+    /// Leave out the pieces whose models are missing instead of failing.
+    #[arg(long)]
+    allow_missing: bool,
+    /// Overrides `missing_piece` of every spec, the model placed instead of
+    /// missing ones.
+    #[arg(long, value_name = "MODEL")]
+    missing_piece: Option<String>,
+    /// Fail instead of placing the missing piece for missing models.
+    #[arg(long)]
+    strict: bool,
+    /// Snap scene graph rotations that aren't quarter turns to the closest
+    /// one, instead of failing.
+    #[arg(long)]
+    approximate_rotations: bool,
+    /// Overrides a variable of the `vars` of every spec, or adds it.
+    #[arg(long = "set", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, i32)>,
+}
+
+/// The arguments of the commands that validate a spec.
+#[derive(clap::Args)]
+struct SpecArgs {
+    /// The spec or placement set, an asset specifier or the path of a spec
+    /// file.
+    #[arg(default_value = "place")]
+    spec: String,
+    #[command(flatten)]
+    build: BuildArgs,
+    /// The maximum volume of the bounds of a model, in blocks.
+    #[arg(long, value_name = "BLOCKS")]
+    max_volume: Option<u64>,
+    /// Overrides `max_total_volume` of every spec.
+    #[arg(long, value_name = "BLOCKS")]
+    max_total_volume: Option<u64>,
+    /// Overrides `world_bounds` of every spec.
+    #[arg(long, value_name = "BLOCKS")]
+    world_bounds: Option<i32>,
+    /// Place specs even if they exceed the total volume or world bounds.
+    #[arg(long)]
+    yes_i_know: bool,
+    /// Fail validation if the bounds of pieces of a spec overlap.
+    #[arg(long)]
+    fail_on_overlap: bool,
+    /// Fail if any color isn't replaced.
+    #[arg(long)]
+    strict_colors: bool,
+    /// Only place the pieces with these names.
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    only: Vec<String>,
+    /// Leave out the pieces with these names.
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    skip: Vec<String>,
+    /// Where to write statistics about the run as JSON, `-` for stdout.
+    #[arg(long, value_name = "PATH")]
+    stats_json: Option<PathBuf>,
+    /// Place this many variants side by side, each with the next seed.
+    #[arg(long, default_value_t = 1, requires = "variant_stride")]
+    variants: u32,
+    /// How far each variant is placed from the one before it, x,y,z.
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3, allow_hyphen_values = true)]
+    variant_stride: Option<Vec3<i32>>,
+}
+
+/// The arguments of placing that decide what is written.
+#[derive(clap::Args)]
+struct WriteArgs {
+    /// Only report what would be written.
+    #[arg(long)]
+    dry_run: bool,
+    /// Where to write the undo file, instead of a new timestamped one.
+    #[arg(long, value_name = "PATH")]
+    undo_file: Option<PathBuf>,
+    /// Place again whenever the specs or models change.
+    #[arg(long)]
+    watch: bool,
+    /// Keep the last placement when watching stops, instead of reverting it.
+    #[arg(long, requires = "watch")]
+    keep_on_exit: bool,
+    /// Only write blocks whose x coordinate is within min..max.
+    #[arg(long, value_name = "MIN..MAX", value_parser = parse_range, allow_hyphen_values = true)]
+    x_range: Vec<(i32, i32)>,
+    /// Only write blocks whose y coordinate is within min..max.
+    #[arg(long, value_name = "MIN..MAX", value_parser = parse_range, allow_hyphen_values = true)]
+    y_range: Vec<(i32, i32)>,
+    /// Only write blocks whose z coordinate is within min..max.
+    #[arg(long, value_name = "MIN..MAX", value_parser = parse_range, allow_hyphen_values = true)]
+    z_range: Vec<(i32, i32)>,
+    /// Only write blocks in the terrain chunks listed in this file, as RON
+    /// or CSV.
+    #[arg(long, value_name = "PATH")]
+    allowed_chunks: Option<PathBuf>,
+    /// Fail before writing anything if the bounds of the placement reach
+    /// outside of --allowed-chunks.
+    #[arg(long, requires = "allowed_chunks")]
+    strict_bounds: bool,
+    /// Also write the placed blocks to a MagicaVoxel file.
+    #[arg(long, value_name = "PATH")]
+    export_vox: Option<PathBuf>,
+    /// Also write the placed blocks to a template.
+    #[arg(long, value_name = "PATH")]
+    export_prefab: Option<PathBuf>,
+    /// Also write a PNG map of the placed blocks seen from above.
+    #[arg(long, value_name = "PATH")]
+    export_map: Option<PathBuf>,
+    /// Where to add the points of interest of the placement, instead of
+    /// pois.ron next to the terrain directory.
+    #[arg(long, value_name = "PATH")]
+    poi_out: Option<PathBuf>,
+    /// Write the bounds, anchors and markers of the pieces and groups to this
+    /// context file, for later specs to align to.
+    #[arg(long, value_name = "PATH")]
+    context_out: Option<PathBuf>,
+    /// The world position the blocks of the prefab are relative to.
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3, allow_hyphen_values = true)]
+    prefab_origin: Option<Vec3<i32>>,
+    /// Place every piece, even the ones the manifest says are unchanged.
+    #[arg(long)]
+    force: bool,
+    /// Clear what changed pieces wrote and no longer cover with empty blocks,
+    /// instead of restoring it from their undo files.
+    #[arg(long)]
+    clear_empty: bool,
+    /// Replace the sprite manifest instead of adding to it.
+    #[arg(long)]
+    fresh_manifest: bool,
+    /// Place without showing a preview and asking first.
+    #[arg(long)]
+    no_confirm: bool,
+    /// Build, resolve and place the pieces one at a time, so only the scene of
+    /// one piece is kept in memory. The written blocks are still all kept
+    /// until the end, for the undo file.
+    #[arg(long, conflicts_with = "thread_rng")]
+    streaming: bool,
+    /// Read the written chunks back once they are written, and fail unless
+    /// every block is what was written.
+    #[arg(long, conflicts_with = "dry_run")]
+    verify: bool,
+    /// Leave the positions that already have a persisted block alone, unless
+    /// an earlier placement wrote them.
+    #[arg(long)]
+    no_clobber_persisted: bool,
+    /// Write persisted positions within this region anyway, x,y,z..x,y,z.
+    #[arg(
+        long,
+        value_name = "X,Y,Z..X,Y,Z",
+        value_parser = parse_region,
+        allow_hyphen_values = true,
+        requires = "no_clobber_persisted"
+    )]
+    clobber_within: Vec<Aabb<i32>>,
+    /// Where to write the positions left alone by --no-clobber-persisted.
+    #[arg(long, value_name = "PATH", requires = "no_clobber_persisted")]
+    skipped_positions: Option<PathBuf>,
+    /// The heights of the slices of the preview.
+    #[arg(long, value_name = "Z,Z,...", value_delimiter = ',', allow_hyphen_values = true)]
+    preview_z: Vec<i32>,
+    /// Time building the scenes, resolving their blocks and writing them
+    /// separately, without writing anything unless --bench-with-io is passed.
+    #[arg(long, conflicts_with = "streaming")]
+    bench: bool,
+    /// How often --bench builds and resolves the placement.
+    #[arg(long, value_name = "N", default_value_t = 5, requires = "bench")]
+    bench_iterations: u32,
+    /// Also time writing the blocks with --bench, which writes them to the
+    /// terrain along with an undo file.
+    #[arg(long, requires = "bench", conflicts_with = "dry_run")]
+    bench_with_io: bool,
+    /// Write a script of server console commands to this file instead of
+    /// writing the terrain, to place into a running server with `feed`.
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "command_template",
+        conflicts_with_all = ["dry_run", "verify", "watch", "bench"]
+    )]
+    commands_out: Option<PathBuf>,
+    /// The command that sets a block, with {x}, {y}, {z}, {kind}, {sprite},
+    /// {r}, {g} and {b} in it.
+    #[arg(long, value_name = "COMMAND", requires = "commands_out")]
+    command_template: Option<String>,
+    /// Ask before writing a script of more commands than this, and fail with
+    /// --no-confirm.
+    #[arg(long, value_name = "N", default_value_t = 100_000, requires = "commands_out")]
+    max_commands: usize,
+}
+
+/// The bounds of a region, inclusive.
+#[derive(clap::Args)]
+struct RegionArgs {
+    /// A corner of the region, in the format x,y,z.
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3, allow_hyphen_values = true)]
+    min: Vec3<i32>,
+    /// The opposite corner of the region, in the format x,y,z.
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_vec3, allow_hyphen_values = true)]
+    max: Vec3<i32>,
+}
+
+impl RegionArgs {
+    fn aabb(&self) -> Aabb<i32> {
+        Aabb {
+            min: self.min,
+            max: self.max,
+        }
+        .made_valid()
+    }
+}
+
+/// The arguments of a run, whichever command it is.
+#[derive(Default)]
+struct Args {
+    command: Command,
+    /// The arguments of the run, starting with the command.
+    raw: Vec<String>,
+    /// The spec or placement set to place.
+    spec: String,
+    options: PlaceOptions,
+    dry_run: bool,
+    offset: Vec3<i32>,
+    export_vox: Option<PathBuf>,
+    /// Writes the placed blocks as a template.
+    export_prefab: Option<PathBuf>,
+    /// Writes a map of the topmost placed blocks.
+    export_map: Option<PathBuf>,
+    /// Where to add the points of interest of the placement.
+    poi_out: Option<PathBuf>,
+    /// Where to write the context of the placement.
+    context_out: Option<PathBuf>,
+    /// The world position the blocks of the prefab are relative to.
+    prefab_origin: Option<Vec3<i32>>,
+    /// Leave out the pieces whose models are missing instead of failing.
+    allow_missing: bool,
+    /// Overrides `missing_piece` of every spec.
+    missing_piece: Option<String>,
+    /// Fail instead of placing the missing piece for missing models.
+    strict: bool,
+    /// The maximum volume of the bounds of a model, in blocks.
+    max_volume: Option<u64>,
+    /// Place every piece, even the ones the manifest says are unchanged.
+    force: bool,
+    /// Clear what changed pieces wrote and no longer cover with empty blocks,
+    /// instead of restoring it from their undo files.
+    clear_empty: bool,
+    /// Clear regions by writing empty blocks instead of removing persisted
+    /// blocks.
+    fill_air: bool,
+    /// Fail if any color isn't replaced.
+    strict_colors: bool,
+    /// The terrain persistence directory, the one with the chunk files.
+    terrain_dir: Option<PathBuf>,
+    /// Create the terrain directory if it doesn't exist.
+    create: bool,
+    /// Overrides `max_total_volume` of every spec.
+    max_total_volume: Option<u64>,
+    /// Overrides `world_bounds` of every spec.
+    world_bounds: Option<i32>,
+    /// Place specs even if they exceed the total volume or world bounds.
+    yes_i_know: bool,
+    /// Fail validation if the bounds of pieces of a spec overlap.
+    fail_on_overlap: bool,
+    /// Snap scene graph rotations that aren't quarter turns to the closest
+    /// one, instead of failing.
+    approximate_rotations: bool,
+    /// Replace the sprite manifest instead of adding to it.
+    fresh_manifest: bool,
+    /// The heights of the slices of the preview.
+    preview_z: Vec<i32>,
+    /// Place without showing a preview and asking first.
+    no_confirm: bool,
+    /// Place the pieces one at a time.
+    streaming: bool,
+    /// Read the written chunks back and compare them.
+    verify: bool,
+    /// Time the phases of the placement instead of placing it.
+    bench: bool,
+    /// How often the phases in memory are repeated.
+    bench_iterations: u32,
+    /// Also time writing the blocks.
+    bench_with_io: bool,
+    /// Leave positions with persisted blocks alone.
+    no_clobber_persisted: bool,
+    /// Where persisted positions are written anyway.
+    clobber_within: Vec<Aabb<i32>>,
+    /// Where to write the positions that were left alone.
+    skipped_positions: Option<PathBuf>,
+    /// Where to write the undo file, instead of a new timestamped one.
+    undo_file: Option<PathBuf>,
+    /// Place again whenever the specs or models change.
+    watch: bool,
+    /// Keep the last placement when watching stops, instead of reverting it.
+    keep_on_exit: bool,
+    /// Write the chunk files without `TerrainPersistence`.
+    standalone_persistence: bool,
+    /// Where to write statistics about the run as JSON, `-` for stdout.
+    stats_json: Option<PathBuf>,
+    /// How many variants to place, with consecutive seeds.
+    variants: u32,
+    /// The offset of each variant from the one before it.
+    variant_stride: Vec3<i32>,
+    /// Only write blocks within these bounds, from the coordinate ranges.
+    write_bounds: Option<Aabb<i32>>,
+    /// The file the allowed chunks are loaded from once logging is set up.
+    allowed_chunks_file: Option<PathBuf>,
+    /// Only write blocks in these terrain chunks.
+    allowed_chunks: Option<HashSet<Vec2<i32>>>,
+    /// Fail if the bounds of the placement reach outside of `allowed_chunks`.
+    strict_bounds: bool,
+    /// Only place the pieces with these names.
+    only: Vec<String>,
+    /// Leave out the pieces with these names.
+    skip: Vec<String>,
+    /// The most verbose messages logged, instead of the one of the verbosity.
+    log_level: Option<LevelFilter>,
+    /// Where to append the log as JSON lines.
+    log_file: Option<PathBuf>,
+    /// Overrides the `vars` of every spec.
+    vars: HashMap<String, i32>,
+    /// Where to write a script of console commands instead of the terrain.
+    commands_out: Option<PathBuf>,
+    /// The command of every block in the script.
+    command_template: String,
+    /// The most commands written without asking.
+    max_commands: usize,
+}
+
+/// The default maximum volume of a model, a cube of 1024 blocks.
+const DEFAULT_MAX_VOLUME: u64 = 1 << 30;
+
+/// The default maximum volume of all pieces of a spec, a cube of 256 blocks.
+const DEFAULT_MAX_TOTAL_VOLUME: u64 = 1 << 24;
+
+/// The default maximum coordinate, the size of the default world.
+const DEFAULT_WORLD_BOUNDS: i32 = 1 << 15;
+
+impl Args {
+    fn parse() -> Self { Self::parse_from(std::env::args().skip(1).collect()) }
+
+    /// Parses the arguments, without the name of the executable. Exits with
+    /// the usage on unknown or invalid arguments.
+    fn parse_from(mut raw: Vec<String>) -> Self {
+        // Before there were commands, the program took no arguments and
+        // placed the spec `place`.
+        if raw.is_empty() {
+            eprintln!(
+                "Running without a command is deprecated, use `vox_placer place place` instead"
+            );
+            raw = vec!["place".to_string(), "place".to_string()];
+        }
+        let cli = Cli::try_parse_from(std::iter::once("vox_placer".to_string()).chain(raw.clone()))
+            .unwrap_or_else(|e| e.exit());
+        let GlobalArgs {
+            quiet,
+            verbose,
+            log_level,
+            log_file,
+            terrain_dir,
+            create,
+            standalone_persistence,
+        } = cli.global;
+        let mut args = Args {
+            raw,
+            spec: "place".to_string(),
+            log_level,
+            log_file,
+            terrain_dir,
+            create,
+            standalone_persistence,
+            ..Args::default()
+        };
+        args.options.verbosity = if quiet {
+            Verbosity::Quiet
+        } else if verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        };
+        let (spec, write) = match cli.command {
+            CliCommand::Place { spec, write } => (spec, Some(write)),
+            CliCommand::Check { spec } => {
+                args.command = Command::Check;
+                (spec, None)
+            },
+            CliCommand::Undo { file, piece, force } => {
+                args.command = Command::Undo(file, piece, force);
+                return args;
+            },
+            CliCommand::Clear {
+                region,
+                fill_air,
+                dry_run,
+                undo_file,
+            } => {
+                args.command = Command::Clear(region.aabb());
+                args.fill_air = fill_air;
+                args.dry_run = dry_run;
+                args.undo_file = undo_file;
+                return args;
+            },
+            CliCommand::Copy { path, region } => {
+                args.command = Command::Copy(region.aabb(), path);
+                return args;
+            },
+            CliCommand::Diff {
+                a,
+                b,
+                diff_output,
+                build,
+            } => {
+                args.command = Command::Diff(a, b, diff_output);
+                args.apply_build(build);
+                return args;
+            },
+            CliCommand::Resume => {
+                args.command = Command::Resume;
+                return args;
+            },
+            CliCommand::Rollback => {
+                args.command = Command::Rollback;
+                return args;
+            },
+            CliCommand::Feed {
+                script,
+                rate,
+                resume,
+            } => {
+                args.command = Command::Feed(script, rate, resume);
+                return args;
+            },
+        };
+        args.spec = spec.spec;
+        args.apply_build(spec.build);
+        args.max_volume = spec.max_volume;
+        args.max_total_volume = spec.max_total_volume;
+        args.world_bounds = spec.world_bounds;
+        args.yes_i_know = spec.yes_i_know;
+        args.fail_on_overlap = spec.fail_on_overlap;
+        args.strict_colors = spec.strict_colors;
+        args.only = spec.only;
+        args.skip = spec.skip;
+        args.stats_json = spec.stats_json;
+        args.variants = spec.variants;
+        args.variant_stride = spec.variant_stride.unwrap_or_default();
+        let Some(write) = write else {
+            // Checking never writes.
+            args.dry_run = true;
+            return args;
+        };
+        // A script is written instead of the terrain, which is left alone.
+        args.dry_run = write.dry_run || write.commands_out.is_some();
+        args.commands_out = write.commands_out;
+        args.command_template = write.command_template.unwrap_or_default();
+        args.max_commands = write.max_commands;
+        args.undo_file = write.undo_file;
+        args.watch = write.watch;
+        args.keep_on_exit = write.keep_on_exit;
+        let ranges = [write.x_range, write.y_range, write.z_range];
+        if ranges.iter().any(|ranges| !ranges.is_empty()) {
+            let mut bounds = Aabb {
+                min: Vec3::broadcast(i32::MIN),
+                max: Vec3::broadcast(i32::MAX),
+            };
+            // Ranges of the same axis intersect.
+            for (axis, ranges) in ranges.iter().enumerate() {
+                for (min, max) in ranges {
+                    bounds.min[axis] = bounds.min[axis].max(*min);
+                    bounds.max[axis] = bounds.max[axis].min(*max);
                 }
             }
+            args.write_bounds = Some(bounds);
         }
+        args.allowed_chunks_file = write.allowed_chunks;
+        args.strict_bounds = write.strict_bounds;
+        args.export_vox = write.export_vox;
+        args.export_prefab = write.export_prefab;
+        args.export_map = write.export_map;
+        args.poi_out = write.poi_out;
+        args.context_out = write.context_out;
+        args.prefab_origin = write.prefab_origin;
+        args.force = write.force;
+        args.clear_empty = write.clear_empty;
+        args.fresh_manifest = write.fresh_manifest;
+        args.no_confirm = write.no_confirm;
+        args.streaming = write.streaming;
+        args.bench = write.bench;
+        args.bench_iterations = write.bench_iterations;
+        args.bench_with_io = write.bench_with_io;
+        args.verify = write.verify;
+        args.no_clobber_persisted = write.no_clobber_persisted;
+        args.clobber_within = write.clobber_within;
+        args.skipped_positions = write.skipped_positions;
+        args.preview_z = write.preview_z;
+        args
+    }
 
-        fn insert_scene(
-            dot_vox_data: &DotVoxData,
-            palette: &Vec<Rgb<u8>>,
-            scene: u32,
-            mut rot: Mat3<i32>,
-            mut trans: Vec3<i32>,
-            sparse_scene: &mut SparseScene,
-            aabbs: &mut Vec<Aabb<i32>>,
-        ) {
-            let scene = dot_vox_data.scenes.get(scene as usize).unwrap();
-            match scene {
-                dot_vox::SceneNode::Transform { frames, child, .. } => {
-                    if let Some(frame) = frames.get(0) {
-                        let t = frame
-                            .position()
-                            .and_then(|t| Some(Vec3::new(t.x, t.y, t.z)))
-                            .unwrap_or_default();
-
-                        let r = frame
-                            .orientation()
-                            .map(|r| {
-                                let arr = r.to_cols_array_2d();
-                                Mat3::from_col_arrays(arr).map(|f| f as i32)
-                            })
-                            .unwrap_or(Mat3::identity());
-
-                        trans += rot * t;
-                        rot *= r;
-                    }
+    /// Takes the arguments of `build`.
+    fn apply_build(&mut self, build: BuildArgs) {
+        self.offset = build.offset.unwrap_or_default();
+        self.vars = build.vars.into_iter().collect();
+        self.options.seed = build.seed;
+        self.options.thread_rng = build.thread_rng;
+        self.allow_missing = build.allow_missing;
+        // Paths are relative to where the tool runs, not to the specs.
+        self.missing_piece = build.missing_piece.map(|model| {
+            if is_model_path(&model) {
+                let dir = std::env::current_dir().expect("Failed to get the current directory");
+                dir.join(model).to_string_lossy().into_owned()
+            } else {
+                model
+            }
+        });
+        self.strict = build.strict;
+        self.approximate_rotations = build.approximate_rotations;
+    }
 
-                    insert_scene(
-                        dot_vox_data,
-                        palette,
-                        *child,
-                        rot,
-                        trans,
-                        sparse_scene,
-                        aabbs,
-                    );
-                }
-                dot_vox::SceneNode::Group { children, .. } => {
-                    for child in children {
-                        insert_scene(
-                            dot_vox_data,
-                            palette,
-                            *child,
-                            rot,
-                            trans,
-                            sparse_scene,
-                            aabbs,
-                        );
-                    }
-                }
-                dot_vox::SceneNode::Shape { models, .. } => {
-                    for model in models {
-                        if let Some(model) = dot_vox_data.models.get(model.model_id as usize) {
-                            render_model(palette, model, sparse_scene, aabbs, rot, trans);
-                        }
-                    }
+    /// Whether the piece called `name` is placed with `--only` and `--skip`.
+    fn includes(&self, name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|only| only == name))
+            && !self.skip.iter().any(|skip| skip == name)
+    }
+}
+
+/// Logs to stderr at the log level, by default depending on the verbosity,
+/// and optionally appends JSON lines to the log file.
+fn init_logging(args: &Args) {
+    let level = args.log_level.unwrap_or(match args.options.verbosity {
+        Verbosity::Quiet => LevelFilter::WARN,
+        Verbosity::Normal => LevelFilter::INFO,
+        Verbosity::Verbose => LevelFilter::DEBUG,
+    });
+    let file = args.log_file.as_ref().map(|path| {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|e| panic!("Failed to open log file {:?}: {}", path, e));
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(Mutex::new(file))
+            .with_filter(level)
+    });
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_target(false)
+                .without_time()
+                .with_filter(level),
+        )
+        .with(file)
+        .init();
+}
+
+/// Parses a position in the format `x,y,z`.
+fn parse_vec3(s: &str) -> Result<Vec3<i32>, String> {
+    let parts = s
+        .split(',')
+        .map(|e| e.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid position '{}': {}", s, e))?;
+    match parts.as_slice() {
+        &[x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("Expected a position in the format x,y,z, got '{}'", s)),
+    }
+}
+
+/// Parses a variable in the format `name=value`.
+fn parse_var(s: &str) -> Result<(String, i32), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected a variable in the format name=value, got '{}'", s))?;
+    let name = name.trim().trim_start_matches('$');
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid variable name in '{}'", s));
+    }
+    let value = value
+        .trim()
+        .parse::<i32>()
+        .map_err(|e| format!("Invalid value of the variable '{}': {}", s, e))?;
+    Ok((name.to_string(), value))
+}
+
+/// Parses a region in the format `x,y,z..x,y,z`, between two corners.
+fn parse_region(s: &str) -> Result<Aabb<i32>, String> {
+    let (min, max) = s
+        .split_once("..")
+        .ok_or_else(|| format!("Expected a region in the format x,y,z..x,y,z, got '{}'", s))?;
+    Ok(Aabb {
+        min: parse_vec3(min)?,
+        max: parse_vec3(max)?,
+    }
+    .made_valid())
+}
+
+/// Parses an inclusive range in the format `min..max`.
+fn parse_range(s: &str) -> Result<(i32, i32), String> {
+    let (min, max) = s
+        .split_once("..")
+        .ok_or_else(|| format!("Expected a range in the format min..max, got '{}'", s))?;
+    let parse = |e: &str| {
+        e.trim()
+            .parse::<i32>()
+            .map_err(|e| format!("Invalid range '{}': {}", s, e))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!("The range '{}' is empty", s));
+    }
+    Ok((min, max))
+}
+
+/// A hash of what the pieces in `manifest` place, to check that a resumed run
+/// places the same as the interrupted one.
+fn placement_hash(manifest: &Manifest) -> u64 {
+    use std::fmt::Write;
+    let mut entries = manifest
+        .pieces
+        .iter()
+        .map(|(key, entry)| (key, entry.hash, entry.offset, entry.rotation, entry.seed))
+        .collect::<Vec<_>>();
+    entries.sort();
+    let mut hasher = Fnv::default();
+    write!(hasher, "{:?}", entries).unwrap();
+    hasher.0
+}
+
+/// The path of a new undo file.
+fn undo_path() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    PathBuf::from(format!("undo-{}.bin", timestamp))
+}
+
+/// Loads the spec `name`, an asset specifier or the path of a spec file,
+/// with the specs it includes merged in. The expressions of every spec are
+/// evaluated with its own `vars`, overridden by `vars`.
+fn load_spec(name: &str, vars: &HashMap<String, i32>) -> Result<PlaceSpec, String> {
+    load_spec_including(name, vars, &mut Vec::new())
+}
+
+/// Loads the spec `name` like `load_spec`, within the specs in `including`
+/// that include it.
+fn load_spec_including(
+    name: &str,
+    vars: &HashMap<String, i32>,
+    including: &mut Vec<String>,
+) -> Result<PlaceSpec, String> {
+    if including.iter().any(|other| other == name) {
+        return Err(format!("Specs include each other: {} -> {}", including.join(" -> "), name));
+    }
+    let mut spec = load_spec_file(name)?;
+    spec.evaluate_vars(vars).map_err(|e| format!("{} in {}", e, name))?;
+    if spec.include.is_empty() {
+        return Ok(spec);
+    }
+    including.push(name.to_string());
+    // Included files are relative to the spec including them.
+    let dir = spec_dir(name);
+    let mut included = Vec::new();
+    for include in &spec.include {
+        let path = if is_model_path(include) {
+            dir.join(include).to_string_lossy().into_owned()
+        } else {
+            include.clone()
+        };
+        let spec = load_spec_including(&path, vars, including)
+            .map_err(|e| format!("{}, included by {}", e, name))?;
+        included.push((include.clone(), path, spec));
+    }
+    including.pop();
+    Ok(spec.with_includes(name, included))
+}
+
+/// Loads the spec `name` on its own.
+fn load_spec_file(name: &str) -> Result<PlaceSpec, String> {
+    if is_model_path(name) {
+        let spec = std::fs::read_to_string(name)
+            .map_err(|e| format!("Could not read {}: {}", name, e))?;
+        ron::from_str(&spec).map_err(|e| format!("Could not parse {}: {}", name, e))
+    } else {
+        PlaceSpec::load(name)
+            .map(|spec| spec.read().clone())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The placements of `name`, an asset specifier or the path of a file: the
+/// placements of a placement set, or a spec placed once at no additional
+/// offset.
+fn load_placements(name: &str) -> Vec<Placement> {
+    let set = if is_model_path(name) {
+        std::fs::read_to_string(name)
+            .ok()
+            .and_then(|set| ron::from_str::<PlacementSet>(&set).ok())
+    } else {
+        PlacementSet::load(name).ok().map(|set| set.read().clone())
+    };
+    match set {
+        Some(set) => set.placements,
+        None => vec![Placement {
+            spec: name.to_string(),
+            offset: [0; 3],
+            rotation: Rotation::default(),
+        }],
+    }
+}
+
+/// Expands and validates `place_spec` with the arguments. With `--strict`,
+/// pieces substituted with the missing piece are errors.
+fn expand_spec(
+    name: &str,
+    place_spec: &PlaceSpec,
+    seed: Option<u64>,
+    args: &Args,
+    validation: &mut Validation,
+) -> PlaceSpec {
+    let mut place_spec = place_spec.clone();
+    if args.missing_piece.is_some() {
+        place_spec.missing_piece = args.missing_piece.clone();
+    }
+    let place_spec = place_spec.expanded(name, seed, validation);
+    place_spec.validate(name, args.allow_missing, args.approximate_rotations, validation);
+    if args.strict {
+        for (intended, substitute) in &place_spec.substituted {
+            validation.errors.push(format!(
+                "Piece {} of {} is missing and would be substituted with {}, which --strict \
+                 doesn't allow",
+                intended, name, substitute
+            ));
+        }
+    }
+    place_spec
+}
+
+/// The blocks `place_spec` places, resolved in memory without touching the
+/// terrain. `None` if the spec isn't valid.
+fn resolve_in_memory(
+    name: &str,
+    place_spec: &PlaceSpec,
+    args: &Args,
+    options: PlaceOptions,
+    terrain_dir: &Path,
+    validation: &mut Validation,
+) -> Option<HashMap<Vec3<i32>, Block>> {
+    let seed = options.seed_of(place_spec);
+    let place_spec = expand_spec(name, place_spec, seed, args, validation);
+    if !validation.errors.is_empty() {
+        return None;
+    }
+    let mut world = World::new(terrain_dir.to_path_buf());
+    let pieces =
+        place_spec.build_pieces(Mat3::identity(), args.offset, &mut world, seed, validation);
+    let mut blocks = HashMap::new();
+    place_into(&place_spec, &merge_pieces(pieces.iter()), &mut world, &mut blocks, options);
+    Some(blocks)
+}
+
+/// The blocks that differ between two placements, as written by `diff`.
+#[derive(Serialize, Default)]
+struct BlockDiff {
+    only_in_a: Vec<([i32; 3], Block)>,
+    only_in_b: Vec<([i32; 3], Block)>,
+    /// The block of A, then the one of B.
+    changed: Vec<([i32; 3], Block, Block)>,
+}
+
+/// How many example positions `diff` lists for every terrain chunk.
+const DIFF_EXAMPLES: usize = 3;
+
+/// The times of a phase of `--bench`, one for every iteration.
+struct BenchPhase {
+    name: &'static str,
+    times: Vec<Duration>,
+    /// The voxels or blocks handled by every iteration.
+    voxels: usize,
+}
+
+impl BenchPhase {
+    fn min(&self) -> Duration {
+        self.times.iter().copied().min().unwrap_or_default()
+    }
+
+    fn median(&self) -> Duration {
+        let mut times = self.times.clone();
+        times.sort();
+        times.get(times.len() / 2).copied().unwrap_or_default()
+    }
+
+    /// The voxels per second of the median iteration.
+    fn voxels_per_second(&self) -> f64 {
+        self.voxels as f64 / self.median().as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Times the phases of placing the specs: building their scenes, resolving
+/// the blocks of their cells into memory, and with `--bench-with-io` writing
+/// the blocks to the terrain. The phases in memory are repeated
+/// `--bench-iterations` times, writing only happens once.
+fn bench(args: &Args, terrain_dir: &Path) {
+    let mut run_stats = RunStats::new(args.stats_json.clone(), Instant::now());
+    let mut validation = Validation::default();
+    let mut specs = Vec::new();
+    for placement in load_placements(&args.spec) {
+        match load_spec(&placement.spec, &args.vars) {
+            Ok(place_spec) => {
+                let seed = args.options.seed_of(&place_spec);
+                let place_spec =
+                    expand_spec(&placement.spec, &place_spec, seed, args, &mut validation);
+                specs.push((placement, place_spec));
+            },
+            Err(e) => validation
+                .errors
+                .push(format!("Could not load spec {}: {}", placement.spec, e)),
+        }
+    }
+    run_stats.seeds = specs
+        .iter()
+        .map(|(placement, place_spec)| (placement.spec.clone(), args.options.seed_of(place_spec)))
+        .collect();
+    if validation.report() {
+        run_stats.validation = validation;
+        // Exiting skips destructors.
+        drop(run_stats);
+        std::process::exit(1);
+    }
+    let options = PlaceOptions {
+        verbosity: Verbosity::Quiet,
+        ..args.options
+    };
+    let iterations = args.bench_iterations.max(1);
+    let mut build = BenchPhase {
+        name: "build",
+        times: Vec::new(),
+        voxels: 0,
+    };
+    let mut resolve = BenchPhase {
+        name: "resolve",
+        times: Vec::new(),
+        voxels: 0,
+    };
+    let mut blocks = HashMap::new();
+    for iteration in 0..iterations {
+        info!("Bench iteration {} of {}", iteration + 1, iterations);
+        let mut world = World::new(terrain_dir.to_path_buf());
+        let start = Instant::now();
+        let built = specs
+            .iter()
+            .map(|(placement, place_spec)| {
+                place_spec.build_pieces(
+                    placement.rotation.to_mat(),
+                    args.offset + Vec3::from(placement.offset),
+                    &mut world,
+                    args.options.seed_of(place_spec),
+                    &mut validation,
+                )
+            })
+            .collect::<Vec<_>>();
+        build.times.push(start.elapsed());
+        build.voxels = built
+            .iter()
+            .flatten()
+            .map(|piece| piece.scene.cells().count())
+            .sum();
+        // Every iteration builds the same pieces, the last one is recorded.
+        run_stats.pieces = specs
+            .iter()
+            .zip(&built)
+            .flat_map(|((placement, _), pieces)| {
+                pieces
+                    .iter()
+                    .filter_map(move |piece| {
+                        Some((placement.spec.clone(), piece.bounds.clone()?))
+                    })
+            })
+            .collect();
+        // Later placements overwrite earlier ones, like when placing.
+        blocks.clear();
+        let start = Instant::now();
+        for ((_, place_spec), pieces) in specs.iter().zip(&built) {
+            let merged = merge_pieces(pieces.iter());
+            place_into(place_spec, &merged, &mut world, &mut blocks, options);
+        }
+        resolve.times.push(start.elapsed());
+        resolve.voxels = build.voxels;
+    }
+    let mut phases = vec![build, resolve];
+    if args.bench_with_io {
+        let mut chunks = HashMap::<_, Vec<_>>::new();
+        for (wpos, block) in &blocks {
+            chunks.entry(terrain_chunk_key(*wpos)).or_default().push((*wpos, *block));
+        }
+        let start = Instant::now();
+        let mut writer = Writer::new(terrain_dir.to_path_buf(), args.standalone_persistence);
+        for (key, blocks) in &chunks {
+            writer.set_blocks_in_chunk(*key, blocks);
+        }
+        // Flushing writes the chunks and unloads them.
+        writer.flush();
+        phases.push(BenchPhase {
+            name: "write",
+            times: vec![start.elapsed()],
+            voxels: blocks.len(),
+        });
+        let undo_path = args.undo_file.clone().unwrap_or_else(undo_path);
+        writer.into_undo().save(&undo_path);
+        println!("Wrote {} blocks, the undo file is {}", blocks.len(), undo_path.display());
+    }
+    println!(
+        "{:<10} {:>10} {:>12} {:>12} {:>16}",
+        "Phase", "Iterations", "Min", "Median", "Voxels/s"
+    );
+    for phase in &phases {
+        println!(
+            "{:<10} {:>10} {:>12} {:>12} {:>16.0}",
+            phase.name,
+            phase.times.len(),
+            format!("{:.3?}", phase.min()),
+            format!("{:.3?}", phase.median()),
+            phase.voxels_per_second()
+        );
+    }
+    if !args.bench_with_io {
+        println!("Writing wasn't timed, pass --bench-with-io to write the blocks too.");
+    }
+    run_stats.completed = true;
+    run_stats.bench = phases;
+    run_stats.validation = validation;
+}
+
+/// Places the specs `a` and `b` in memory and reports the blocks only one of
+/// them places and the ones they place differently, by terrain chunk.
+fn diff_specs(a: &str, b: &str, output: Option<&Path>, args: &Args, terrain_dir: &Path) {
+    let mut validation = Validation::default();
+    let specs = [a, b].map(|name| {
+        load_spec(name, &args.vars)
+            .map_err(|e| validation.errors.push(format!("Could not load spec {}: {}", name, e)))
+            .ok()
+    });
+    let [Some(spec_a), Some(spec_b)] = specs else {
+        validation.report();
+        std::process::exit(1);
+    };
+    // Random blocks have to be picked the same way on both sides, or every
+    // lottery shows up as a difference.
+    let mut options = PlaceOptions {
+        verbosity: Verbosity::Quiet,
+        thread_rng: false,
+        ..args.options
+    };
+    if options.seed.is_none() && (spec_a.seed.is_none() || spec_b.seed.is_none()) {
+        warn!("Not both specs have a seed, placing both with the seed 0");
+        options.seed = Some(0);
+    }
+    let blocks = [(a, &spec_a), (b, &spec_b)].map(|(name, spec)| {
+        resolve_in_memory(name, spec, args, options, terrain_dir, &mut validation)
+    });
+    if validation.report() {
+        std::process::exit(1);
+    }
+    let [Some(blocks_a), Some(blocks_b)] = blocks else {
+        unreachable!("Specs that fail to resolve have errors");
+    };
+
+    let mut diff = BlockDiff::default();
+    for (wpos, block) in &blocks_a {
+        match blocks_b.get(wpos) {
+            None => diff.only_in_a.push((wpos.into_array(), *block)),
+            Some(other) if other != block => diff.changed.push((wpos.into_array(), *block, *other)),
+            Some(_) => {},
+        }
+    }
+    for (wpos, block) in &blocks_b {
+        if !blocks_a.contains_key(wpos) {
+            diff.only_in_b.push((wpos.into_array(), *block));
+        }
+    }
+    diff.only_in_a.sort_by_key(|(wpos, _)| *wpos);
+    diff.only_in_b.sort_by_key(|(wpos, _)| *wpos);
+    diff.changed.sort_by_key(|(wpos, _, _)| *wpos);
+
+    // Positions only in A, only in B and changed, for every terrain chunk.
+    let mut chunks = HashMap::<_, [Vec<Vec3<i32>>; 3]>::new();
+    let positions = [
+        diff.only_in_a.iter().map(|(wpos, _)| *wpos).collect::<Vec<_>>(),
+        diff.only_in_b.iter().map(|(wpos, _)| *wpos).collect(),
+        diff.changed.iter().map(|(wpos, _, _)| *wpos).collect(),
+    ];
+    for (kind, positions) in positions.into_iter().enumerate() {
+        for wpos in positions.into_iter().map(Vec3::from) {
+            chunks.entry(terrain_chunk_key(wpos)).or_default()[kind].push(wpos);
+        }
+    }
+    let mut keys = chunks.keys().copied().collect::<Vec<_>>();
+    keys.sort_by_key(|key| (key.x, key.y));
+    for key in keys {
+        let chunk = &chunks[&key];
+        println!(
+            "Chunk {}: {} only in {}, {} only in {}, {} changed",
+            key,
+            chunk[0].len(),
+            a,
+            chunk[1].len(),
+            b,
+            chunk[2].len()
+        );
+        let labels = [format!("Only in {}", a), format!("Only in {}", b), "Changed".into()];
+        for (label, positions) in labels.iter().zip(chunk) {
+            if !positions.is_empty() {
+                let examples = positions
+                    .iter()
+                    .take(DIFF_EXAMPLES)
+                    .map(|wpos| wpos.to_string())
+                    .collect::<Vec<_>>();
+                println!("    {}: {}", label, examples.join(", "));
+            }
+        }
+    }
+    println!(
+        "{} blocks only in {}, {} only in {}, {} changed",
+        diff.only_in_a.len(),
+        a,
+        diff.only_in_b.len(),
+        b,
+        diff.changed.len()
+    );
+    if let Some(path) = output {
+        let ron = ron::ser::to_string_pretty(&diff, ron::ser::PrettyConfig::default())
+            .expect("Failed to serialize diff");
+        std::fs::write(path, ron)
+            .unwrap_or_else(|e| panic!("Failed to write diff {:?}: {}", path, e));
+    }
+}
+
+/// How long the watched files have to stay unchanged before placing again, so
+/// saving several files at once only places once.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The files placing reads, with when they were last modified: the placement
+/// set, the specs and the models of their pieces. Files that don't exist are
+/// included too, so creating one counts as a change.
+fn watched_files(name: &str) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let asset_path = |specifier: &str, extension: &str| {
+        ASSETS_PATH
+            .join(specifier.replace('.', "/"))
+            .with_extension(extension)
+    };
+    let spec_path = |spec: &str| {
+        if is_model_path(spec) {
+            PathBuf::from(spec)
+        } else {
+            asset_path(spec, "ron")
+        }
+    };
+    // The files are read directly, the asset cache wouldn't see changes.
+    let set_path = spec_path(name);
+    let specs = std::fs::read_to_string(&set_path)
+        .ok()
+        .and_then(|set| ron::from_str::<PlacementSet>(&set).ok())
+        .map_or_else(
+            || vec![name.to_string()],
+            |set| set.placements.into_iter().map(|p| p.spec).collect(),
+        );
+    let mut files = vec![set_path];
+    for spec in specs {
+        let path = spec_path(&spec);
+        let place_spec = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|place_spec| ron::from_str::<PlaceSpec>(&place_spec).ok());
+        files.push(path);
+        let Some(place_spec) = place_spec else {
+            continue;
+        };
+        let dir = spec_dir(&spec);
+        let pieces = place_spec
+            .pieces
+            .iter()
+            .chain(place_spec.scatter.iter().flat_map(|s| s.variants.iter().map(|(_, v)| v)));
+        for piece in pieces {
+            for model in std::iter::once(&piece.specifier).chain(&piece.options.corner_piece) {
+                if is_model_path(model) {
+                    files.push(dir.join(model));
+                } else {
+                    files.push(asset_path(model, "vox"));
+                    files.push(asset_path(model, "ron"));
                 }
             }
         }
+    }
+    files.sort();
+    files.dedup();
+    files
+        .into_iter()
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
 
-        let mut sparse_scene = SparseScene(match VolGrid3d::new() {
-            Ok(ok) => ok,
-            Err(_) => panic!(),
-        });
-        let mut aabbs = Vec::new();
-        for (dot_vox_data, offset) in dot_vox_data {
-            let palette = dot_vox_data
-                .0
-                .palette
+/// The persisted blocks at the positions written by the placement of the
+/// undo file at `path`, as they are now.
+fn placed_blocks(path: &Path, terrain_dir: &Path) -> HashMap<Vec3<i32>, Option<Block>> {
+    let Ok(undo) = Undo::load(path) else {
+        return HashMap::new();
+    };
+    let mut persisted = PersistedBlocks::new(terrain_dir.to_path_buf());
+    undo.prior
+        .into_iter()
+        .map(|(wpos, _)| (wpos, persisted.get(wpos)))
+        .collect()
+}
+
+/// Runs this program with `args`, returning whether it succeeded.
+fn run_self(args: &[String]) -> bool {
+    let exe = std::env::current_exe().expect("Failed to find the executable");
+    std::process::Command::new(exe)
+        .args(args)
+        .status()
+        .map_or(false, |status| status.success())
+}
+
+/// Places the specs with the other arguments whenever the specs or their
+/// models change, reverting the previous placement first. Placements are run
+/// as separate processes, which load the assets from scratch.
+fn watch(args: &Args, terrain_dir: &Path) {
+    let (name, keep_on_exit) = (&args.spec, args.keep_on_exit);
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed))
+        .expect("Failed to handle Ctrl-C");
+    let args = args
+        .raw
+        .iter()
+        .filter(|arg| *arg != "--watch" && *arg != "--keep-on-exit")
+        .cloned()
+        .collect::<Vec<_>>();
+    let journal_path = terrain_dir.parent().unwrap_or(terrain_dir).join(JOURNAL_FILE);
+    let undo_args = |undo: &Path| {
+        // What an interrupted placement wrote is rolled back with its journal.
+        let mut undo_args = if journal_path.exists() {
+            vec!["rollback".to_string()]
+        } else {
+            vec!["undo".to_string(), undo.display().to_string()]
+        };
+        undo_args.extend(["--terrain-dir".to_string(), terrain_dir.display().to_string()]);
+        undo_args
+    };
+    let mut last_undo = None::<PathBuf>;
+    let mut seen = None;
+    let mut runs = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let files = watched_files(name);
+        if seen.as_ref() == Some(&files) {
+            std::thread::sleep(WATCH_DEBOUNCE);
+            continue;
+        }
+        std::thread::sleep(WATCH_DEBOUNCE);
+        if watched_files(name) != files || stop.load(Ordering::Relaxed) {
+            continue;
+        }
+        let before = last_undo
+            .as_deref()
+            .map(|undo| placed_blocks(undo, terrain_dir))
+            .unwrap_or_default();
+        if let Some(undo) = last_undo.take() {
+            println!("Reverting the previous placement");
+            run_self(&undo_args(&undo));
+        }
+        runs += 1;
+        let undo = undo_path().with_extension(format!("{}.bin", runs));
+        let mut place_args = args.clone();
+        place_args.extend(["--force", "--no-confirm", "--undo-file"].map(String::from));
+        place_args.push(undo.display().to_string());
+        if !run_self(&place_args) {
+            // A placement interrupted by Ctrl-C, which reaches it too, may
+            // have written chunks already. Its undo file is saved first.
+            if undo.exists() {
+                last_undo = Some(undo);
+            }
+            println!("Placing failed, waiting for the files to change");
+        } else if undo.exists() {
+            let after = placed_blocks(&undo, terrain_dir);
+            let added = after.keys().filter(|wpos| !before.contains_key(wpos)).count();
+            let removed = before.keys().filter(|wpos| !after.contains_key(wpos)).count();
+            let changed = after
                 .iter()
-                .map(|col| Rgb::new(col.r, col.g, col.b))
-                .collect::<Vec<_>>();
-            // Zero is always the root node.
-            insert_scene(
-                &dot_vox_data.0,
-                &palette,
-                0,
-                Mat3::identity(),
-                offset,
-                &mut sparse_scene,
-                &mut aabbs,
+                .filter(|(wpos, block)| before.get(wpos).map_or(false, |old| old != *block))
+                .count();
+            println!(
+                "Compared to the previous placement, {} blocks were added, {} removed and {} \
+                 changed",
+                added, removed, changed
             );
+            last_undo = Some(undo);
         }
+        println!("Watching {} files for changes, stop with Ctrl-C", files.len());
+        seen = Some(files);
+    }
+    match last_undo {
+        Some(_) if keep_on_exit && journal_path.exists() => {
+            println!(
+                "Keeping the interrupted last placement, finish it with `resume` or revert it \
+                 with `rollback`"
+            );
+        },
+        Some(undo) if keep_on_exit => {
+            println!("Keeping the last placement, revert it with `undo {}`", undo.display());
+        },
+        Some(undo) => {
+            println!("Reverting the last placement");
+            run_self(&undo_args(&undo));
+        },
+        None => {},
+    }
+}
+
+/// The name of the manifest in the data directory.
+const MANIFEST_FILE: &str = ".vox_placer_manifest.ron";
+
+/// What previous runs placed, so pieces that didn't change can be skipped.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// The pieces by `<placement index>/<spec>#<piece index>`.
+    pieces: HashMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ManifestEntry {
+    /// See `PlaceSpec::piece_hash`.
+    hash: u64,
+    offset: [i32; 3],
+    rotation: [[i32; 3]; 3],
+    /// `None` when placed with a thread local RNG.
+    seed: Option<u64>,
+    bounds: Option<Aabb<i32>>,
+    /// The undo file of the run that placed the piece.
+    undo: Option<PathBuf>,
+}
 
-        (sparse_scene, aabbs)
+impl ManifestEntry {
+    /// Whether placing `other` would give the same blocks as placing this.
+    fn same_placement(&self, other: &Self) -> bool {
+        // Random blocks differ between runs with a thread local RNG.
+        self.seed.is_some()
+            && (self.hash, self.offset, self.rotation, self.seed, self.bounds)
+                == (other.hash, other.offset, other.rotation, other.seed, other.bounds)
     }
 }
 
-#[derive(Deserialize, Default, Clone, Copy)]
-enum Medium {
-    #[default]
-    Air,
-    Water,
+impl Manifest {
+    fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(manifest) => ron::from_str(&manifest).unwrap_or_else(|e| {
+                warn!("Ignoring invalid manifest {:?}: {}", path, e);
+                Manifest::default()
+            }),
+            Err(_) => Manifest::default(),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let manifest = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        std::fs::write(path, manifest)
+    }
 }
 
-#[derive(Deserialize, Clone)]
-enum BlockSpec {
-    Sprite {
-        kind: SpriteKind,
-        #[serde(default)]
-        medium: Medium,
-    },
-    Block {
-        kind: BlockKind,
-        #[serde(default)]
-        color: [u8; 3],
-    },
-    Random(Lottery<BlockSpec>),
+/// The name of the manifest of sprites with loot in the data directory.
+const SPRITE_MANIFEST_FILE: &str = ".vox_placer_sprites.ron";
+
+/// The version of the document `--stats-json` writes, increased whenever a
+/// field changes meaning or is removed.
+const STATS_SCHEMA_VERSION: u32 = 1;
+
+/// What `--stats-json` writes about a run. The document is written when this
+/// is dropped, so runs that fail partway still write what they got to.
+struct RunStats {
+    /// Where to write the document, `-` for stdout, none to not write it.
+    path: Option<PathBuf>,
+    start: Instant,
+    completed: bool,
+    seeds: Vec<(String, Option<u64>)>,
+    /// The timings of `--bench`.
+    bench: Vec<BenchPhase>,
+    /// The spec and bounds of every built piece.
+    pieces: Vec<(String, PieceBounds)>,
+    blocks: usize,
+    kinds: HashMap<BlockKind, usize>,
+    report: PlaceReport,
+    validation: Validation,
+}
+
+impl BlockSink for RunStats {
+    fn set_block(&mut self, _wpos: Vec3<i32>, block: Block) {
+        self.blocks += 1;
+        *self.kinds.entry(block.kind()).or_default() += 1;
+    }
+
+    fn flush(&mut self) {}
+}
+
+impl RunStats {
+    fn new(path: Option<PathBuf>, start: Instant) -> Self {
+        Self {
+            path,
+            start,
+            completed: false,
+            seeds: Vec::new(),
+            bench: Vec::new(),
+            pieces: Vec::new(),
+            blocks: 0,
+            kinds: HashMap::new(),
+            report: PlaceReport::default(),
+            validation: Validation::default(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut kinds = self
+            .kinds
+            .iter()
+            .map(|(kind, count)| (format!("{:?}", kind), count))
+            .collect::<Vec<_>>();
+        kinds.sort();
+        let mut unmapped = self.report.unmapped.0.iter().collect::<Vec<_>>();
+        unmapped.sort_by_key(|((color, fallback), _)| (color.into_array(), *fallback as u8));
+        serde_json::json!({
+            "schema_version": STATS_SCHEMA_VERSION,
+            "completed": self.completed,
+            "elapsed_seconds": self.start.elapsed().as_secs_f64(),
+            "seeds": self.seeds.iter().map(|(spec, seed)| serde_json::json!({
+                "spec": spec,
+                "seed": seed,
+            })).collect::<Vec<_>>(),
+            "bench": self.bench.iter().map(|phase| serde_json::json!({
+                "phase": phase.name,
+                "iterations": phase.times.len(),
+                "min_seconds": phase.min().as_secs_f64(),
+                "median_seconds": phase.median().as_secs_f64(),
+                "voxels": phase.voxels,
+                "voxels_per_second": phase.voxels_per_second(),
+            })).collect::<Vec<_>>(),
+            "pieces": self.pieces.iter().map(|(spec, bounds)| serde_json::json!({
+                "spec": spec,
+                "name": bounds.name,
+                "min": bounds.aabb.min.into_array(),
+                "max": bounds.aabb.max.into_array(),
+                "voxels": bounds.voxels,
+                "markers": bounds.markers.iter().map(|(marker, positions)| {
+                    let positions = positions.iter().map(|wpos| wpos.into_array());
+                    (marker.clone(), serde_json::json!(positions.collect::<Vec<_>>()))
+                }).collect::<serde_json::Map<_, _>>(),
+            })).collect::<Vec<_>>(),
+            "blocks_written": self.blocks,
+            "blocks_by_kind": kinds.into_iter().collect::<serde_json::Map<_, _>>(),
+            "unmapped_colors": unmapped.into_iter().map(|((color, fallback), (count, wpos))| {
+                serde_json::json!({
+                    "color": color.into_array(),
+                    "fallback": format!("{:?}", fallback),
+                    "voxels": count,
+                    "example": wpos.into_array(),
+                })
+            }).collect::<Vec<_>>(),
+            "skipped_voxels": self.report.skipped_voxels,
+            "decayed_voxels": self.report.decayed_voxels,
+            "overlaid_blocks": self.report.overlaid,
+            "blocks_by_piece": self.report.writes.iter().map(
+                |(piece, positions)| serde_json::json!({
+                    "piece": piece,
+                    "blocks": positions.len(),
+                }),
+            ).collect::<Vec<_>>(),
+            "suppressed_by_priority": self.report.suppressed.iter().map(
+                |(kept, later, count)| serde_json::json!({
+                    "kept": kept,
+                    "later": later,
+                    "cells": count,
+                }),
+            ).collect::<Vec<_>>(),
+            "sprite_chances": self.report.sorted_sprite_chances().into_iter().map(
+                |((color, kind), (placed, skipped))| serde_json::json!({
+                    "color": color.into_array(),
+                    "sprite": format!("{:?}", kind),
+                    "placed": placed,
+                    "left_out": skipped,
+                }),
+            ).collect::<Vec<_>>(),
+            "warnings": self.validation.warnings,
+            "errors": self.validation.errors,
+        })
+    }
+}
+
+impl Drop for RunStats {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let json =
+            serde_json::to_string_pretty(&self.to_json()).expect("Failed to serialize stats");
+        if path == Path::new("-") {
+            println!("{}", json);
+        } else if let Err(e) = std::fs::write(path, json) {
+            error!("Failed to write stats {:?}: {}", path, e);
+        }
+    }
 }
 
-impl BlockSpec {
-    fn get_block(&self, rng: &mut impl Rng) -> Block {
-        match self {
-            BlockSpec::Sprite { kind, medium } => match medium {
-                Medium::Air => Block::air(*kind),
-                Medium::Water => Block::water(*kind),
+// Palette colors for blocks without a color of their own in exported vox
+// files.
+const SPRITE_MARKER: Rgb<u8> = Rgb {
+    r: 255,
+    g: 0,
+    b: 255,
+};
+
+const WATER_MARKER: Rgb<u8> = Rgb { r: 0, g: 64, b: 255 };
+
+const AIR_MARKER: Rgb<u8> = Rgb {
+    r: 192,
+    g: 255,
+    b: 255,
+};
+
+/// The largest model size the vox format supports.
+const VOX_MODEL_SIZE: i32 = 256;
+
+/// Prints horizontal slices of `blocks` at the heights in `levels`, or in the
+/// middle of their bounds without any, with a character for every block kind
+/// colored like the blocks. Slices wider than the terminal are downsampled.
+fn print_preview(blocks: &HashMap<Vec3<i32>, Block>, levels: &[i32]) {
+    const KEYS: &[char] = &[
+        '#', '%', '@', '&', '$', '=', '+', 'O', 'X', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H',
+    ];
+    let Some(bounds) = blocks.keys().fold(None::<Aabb<i32>>, |bounds, wpos| {
+        Some(bounds.map_or(
+            Aabb {
+                min: *wpos,
+                max: *wpos,
             },
-            BlockSpec::Block { kind, color } => Block::new(*kind, Rgb::from(*color)),
-            BlockSpec::Random(lottery) => lottery.choose_seeded(rng.gen()).get_block(rng),
+            |bounds| bounds.expanded_to_contain_point(*wpos),
+        ))
+    }) else {
+        println!("Nothing to preview.");
+        return;
+    };
+    let levels = if levels.is_empty() {
+        vec![(bounds.min.z + bounds.max.z).div_euclid(2)]
+    } else {
+        levels.to_vec()
+    };
+    let width = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse::<i32>().ok())
+        .unwrap_or(80)
+        .max(1);
+    // Rows are downsampled like columns to keep the proportions.
+    let step = ((bounds.max.x - bounds.min.x + 1 + width - 1) / width).max(1) as usize;
+    let mut legend = Vec::<BlockKind>::new();
+    for z in levels {
+        if step > 1 {
+            println!("Slice at z = {}, one character per {}x{} blocks:", z, step, step);
+        } else {
+            println!("Slice at z = {}:", z);
+        }
+        for y in (bounds.min.y..=bounds.max.y).rev().step_by(step) {
+            let mut line = String::new();
+            for x in (bounds.min.x..=bounds.max.x).step_by(step) {
+                // The first block within the area of the character is shown.
+                let block = (0..step as i32)
+                    .flat_map(|dx| (0..step as i32).map(move |dy| Vec3::new(x + dx, y - dy, z)))
+                    .find_map(|wpos| blocks.get(&wpos));
+                let Some(block) = block else {
+                    line.push(' ');
+                    continue;
+                };
+                if block.get_sprite().map_or(false, |sprite| sprite != SpriteKind::Empty) {
+                    line.push('*');
+                } else if block.kind() == BlockKind::Water {
+                    line.push('~');
+                } else if block.kind() == BlockKind::Air {
+                    line.push('.');
+                } else {
+                    let index = match legend.iter().position(|kind| *kind == block.kind()) {
+                        Some(index) => index,
+                        None => {
+                            legend.push(block.kind());
+                            legend.len() - 1
+                        },
+                    };
+                    let key = KEYS.get(index).copied().unwrap_or('?');
+                    match block.get_color() {
+                        Some(color) => line.push_str(&format!(
+                            "\x1b[38;2;{};{};{}m{}\x1b[0m",
+                            color.r, color.g, color.b, key
+                        )),
+                        None => line.push(key),
+                    }
+                }
+            }
+            println!("{}", line.trim_end());
         }
     }
+    let mut keys = vec!["* sprite".to_string(), "~ water".to_string(), ". air".to_string()];
+    keys.extend(
+        legend
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| format!("{} {:?}", KEYS.get(i).copied().unwrap_or('?'), kind)),
+    );
+    println!("{}", keys.join(", "));
 }
 
-#[derive(Deserialize)]
-struct VoxSpec(String, [i32; 3]);
+/// The file points of interest are added to by default, next to the terrain
+/// directory.
+const POI_FILE: &str = "pois.ron";
 
-#[derive(Deserialize)]
-struct PlaceSpec {
-    pieces: Vec<VoxSpec>,
-    #[serde(default)]
-    replace: Vec<([u8; 3], BlockSpec)>,
-    #[serde(default)]
-    fill_empty: bool,
+/// Adds the entries of `pois` that aren't in the POI file at `path` yet to it,
+/// creating it if it doesn't exist. Returns how many were added.
+fn add_pois(path: &Path, pois: Vec<Poi>) -> Result<usize, String> {
+    let mut all = match std::fs::read_to_string(path) {
+        Ok(ron) => ron::from_str::<Vec<Poi>>(&ron).map_err(|e| e.to_string())?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let before = all.len();
+    for poi in pois {
+        if !all.contains(&poi) {
+            all.push(poi);
+        }
+    }
+    let ron = ron::ser::to_string_pretty(&all, ron::ser::PrettyConfig::new())
+        .map_err(|e| e.to_string())?;
+    std::fs::write(path, ron).map_err(|e| e.to_string())?;
+    Ok(all.len() - before)
 }
 
-impl PlaceSpec {
-    // pub fn load_watched() -> std::sync::Arc<Self> {
-    //     PlaceSpec::load("place")
-    // }
+/// Writes a PNG map of `top_view` to `path`, one pixel per column with north
+/// up and columns without blocks transparent, and the world coordinates of
+/// its corners to a text file next to it.
+fn export_map(top_view: &TopView, path: &Path) -> io::Result<()> {
+    const GLOW_HIGHLIGHT: Rgb<u8> = Rgb {
+        r: 255,
+        g: 255,
+        b: 128,
+    };
+    let Some(bounds) = top_view.columns.keys().fold(None::<Aabr<i32>>, |bounds, pos| {
+        Some(bounds.map_or(
+            Aabr {
+                min: *pos,
+                max: *pos,
+            },
+            |bounds| bounds.expanded_to_contain_point(*pos),
+        ))
+    }) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to export"));
+    };
+    let size = (bounds.max - bounds.min + 1).map(|e| e as u32);
+    let mut image = image::RgbaImage::new(size.x, size.y);
+    for (pos, (_, block)) in &top_view.columns {
+        let color = if block.get_sprite().map_or(false, |sprite| sprite != SpriteKind::Empty) {
+            SPRITE_MARKER
+        } else if block.kind() == BlockKind::Water {
+            WATER_MARKER
+        } else {
+            let color = block.get_color().unwrap_or(AIR_MARKER);
+            match block.get_glow() {
+                // Glowing blocks are halfway to a bright yellow.
+                Some(_) => color.map2(GLOW_HIGHLIGHT, |a, b| ((a as u16 + b as u16) / 2) as u8),
+                None => color,
+            }
+        };
+        let pixel = Vec2::new(pos.x - bounds.min.x, bounds.max.y - pos.y).map(|e| e as u32);
+        image.put_pixel(pixel.x, pixel.y, image::Rgba([color.r, color.g, color.b, 255]));
+    }
+    image
+        .save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let sidecar = path.with_extension("txt");
+    std::fs::write(
+        &sidecar,
+        format!(
+            "Map of the placement seen from above, one pixel per block with north up.\n\
+             Min corner: x = {}, y = {}\n\
+             Max corner: x = {}, y = {}\n\
+             The top left pixel is at x = {}, y = {}.\n",
+            bounds.min.x, bounds.min.y, bounds.max.x, bounds.max.y, bounds.min.x, bounds.max.y
+        ),
+    )?;
+    println!(
+        "Exported a map of {} by {} blocks to {}, its coordinates are in {}",
+        size.x,
+        size.y,
+        path.display(),
+        sidecar.display()
+    );
+    Ok(())
+}
 
-    pub fn build_place(&self) -> ((SparseScene, Vec<Aabb<i32>>), Vec3<i32>) {
-        // TODO add sparse scene combination
-        //use common::figure::{DynaUnionizer, Segment};
-        fn graceful_load_vox(name: &str) -> AssetHandle<DotVoxAsset> {
-            match DotVoxAsset::load(name) {
-                Ok(dot_vox) => dot_vox,
-                Err(_) => {
-                    println!("Could not load vox file for placement: {}", name);
-                    DotVoxAsset::load_expect("voxygen.voxel.not_found")
-                }
+/// Prints the commands of `script` to stdout, `rate` per second, for piping
+/// into the console of a running server. After every batch the number of
+/// commands printed is kept in a progress file next to the script, so an
+/// interrupted feed can be resumed. The progress file is removed once the
+/// whole script is printed.
+fn feed_script(script: &Path, rate: f64, resume: bool) -> io::Result<()> {
+    use std::io::Write;
+    if rate <= 0.0 || !rate.is_finite() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the rate has to be positive"));
+    }
+    let mut progress_path = script.as_os_str().to_owned();
+    progress_path.push(".progress");
+    let progress_path = PathBuf::from(progress_path);
+    let skip = if resume {
+        std::fs::read_to_string(&progress_path)?.trim().parse::<usize>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("invalid progress: {}", e))
+        })?
+    } else {
+        0
+    };
+    let text = std::fs::read_to_string(script)?;
+    let total = text.lines().filter(|line| !line.starts_with('#') && !line.is_empty()).count();
+    if skip > 0 {
+        info!("Resuming after {} of {} commands", skip, total);
+    }
+    let mut stdout = io::stdout().lock();
+    let mut fed = 0;
+    let start = Instant::now();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        if let Some(batch) = line.strip_prefix('#') {
+            stdout.flush()?;
+            std::fs::write(&progress_path, fed.to_string())?;
+            if fed >= skip {
+                info!("Feeding {} ({} of {} commands fed)", batch.trim(), fed, total);
             }
+            continue;
         }
-        //let mut unionizer = DynaUnionizer::new();
-        //for VoxSpec(specifier, offset) in &self.pieces {
-        //    let seg = Segment::from(graceful_load_vox(&specifier,
-        // indicator).as_ref());    unionizer = unionizer.add(seg,
-        // (*offset).into());
-        //}
-
-        //unionizer.unify()
-        (
-            SparseScene::new_from(self.pieces.iter().map(|spec| {
-                let vox = graceful_load_vox(&spec.0).read();
-                let offset = Vec3::<i32>::from(spec.1);
-                (vox, offset)
-            })),
-            Vec3::zero(),
-        )
+        fed += 1;
+        if fed <= skip {
+            continue;
+        }
+        // Pacing by the time since the start keeps slow writes from adding
+        // up.
+        let due = Duration::from_secs_f64((fed - skip - 1) as f64 / rate);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        writeln!(stdout, "{}", line)?;
     }
+    stdout.flush()?;
+    std::fs::remove_file(&progress_path).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+    info!("Fed {} commands", total - skip.min(total));
+    Ok(())
 }
 
-impl Asset for PlaceSpec {
-    type Loader = RonLoader;
+/// Asks the user to confirm with `prompt`, anything but yes counts as no.
+fn confirm(prompt: &str) -> bool {
+    use std::io::Write;
+    print!("{}", prompt);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Writes `blocks` to a MagicaVoxel file at `path`, split into as many models
+/// as needed.
+fn export_vox(blocks: &HashMap<Vec3<i32>, Block>, path: &Path) -> io::Result<()> {
+    fn marker_color(block: &Block) -> Rgb<u8> {
+        if let Some(color) = block.get_color() {
+            color
+        } else if block.get_sprite().map_or(false, |sprite| sprite != SpriteKind::Empty) {
+            SPRITE_MARKER
+        } else if block.kind() == BlockKind::Water {
+            WATER_MARKER
+        } else {
+            AIR_MARKER
+        }
+    }
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((content.len() as u32).to_le_bytes());
+        bytes.extend((children.len() as u32).to_le_bytes());
+        bytes.extend(content);
+        bytes.extend(children);
+        bytes
+    }
+
+    fn dict(bytes: &mut Vec<u8>, entries: &[(&str, String)]) {
+        bytes.extend((entries.len() as u32).to_le_bytes());
+        for (key, value) in entries {
+            for s in [*key, value.as_str()] {
+                bytes.extend((s.len() as u32).to_le_bytes());
+                bytes.extend(s.as_bytes());
+            }
+        }
+    }
+
+    let Some(bounds) = blocks.keys().fold(None, |bounds: Option<Aabb<i32>>, wpos| {
+        Some(bounds.map_or(
+            Aabb {
+                min: *wpos,
+                max: *wpos,
+            },
+            |b| b.expanded_to_contain_point(*wpos),
+        ))
+    }) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to export"));
+    };
+
+    // The vox format only has 255 colors, the most common ones are kept and
+    // the others use the closest of those.
+    let mut color_counts = HashMap::<Rgb<u8>, usize>::new();
+    for block in blocks.values() {
+        *color_counts.entry(marker_color(block)).or_default() += 1;
+    }
+    let mut colors = color_counts.into_iter().collect::<Vec<_>>();
+    colors.sort_by(|a, b| b.1.cmp(&a.1));
+    let palette = colors.iter().take(255).map(|(color, _)| *color).collect::<Vec<_>>();
+    let mut indices = palette
+        .iter()
+        .enumerate()
+        .map(|(i, color)| (*color, i as u8 + 1))
+        .collect::<HashMap<_, _>>();
+    for (color, _) in colors.iter().skip(255) {
+        let closest = palette
+            .iter()
+            .min_by_key(|p| p.map2(*color, |a, b| (a as i32 - b as i32).pow(2)).sum())
+            .copied()
+            .unwrap_or(*color);
+        let index = indices[&closest];
+        indices.insert(*color, index);
+    }
+
+    // Split the blocks into models of at most 256³.
+    let mut models = HashMap::<Vec3<i32>, Vec<[u8; 4]>>::new();
+    for (wpos, block) in blocks {
+        let rpos = wpos - bounds.min;
+        let tile = rpos.map(|e| e.div_euclid(VOX_MODEL_SIZE));
+        let pos = rpos.map(|e| e.rem_euclid(VOX_MODEL_SIZE) as u8);
+        models
+            .entry(tile)
+            .or_default()
+            .push([pos.x, pos.y, pos.z, indices[&marker_color(block)]]);
+    }
+    let mut models = models.into_iter().collect::<Vec<_>>();
+    models.sort_by_key(|(tile, _)| (tile.z, tile.y, tile.x));
+
+    let extent = bounds.max - bounds.min + 1;
+    let mut children = Vec::new();
+    for (tile, voxels) in &models {
+        let size = (extent - *tile * VOX_MODEL_SIZE).map(|e| e.min(VOX_MODEL_SIZE) as u32);
+        let mut content = Vec::new();
+        for e in size {
+            content.extend(e.to_le_bytes());
+        }
+        children.extend(chunk(b"SIZE", &content, &[]));
+        let mut content = (voxels.len() as u32).to_le_bytes().to_vec();
+        for voxel in voxels {
+            content.extend(voxel);
+        }
+        children.extend(chunk(b"XYZI", &content, &[]));
+    }
+
+    // Root transform, with a group containing a transform and shape node for
+    // each model.
+    let mut root = Vec::new();
+    root.extend(0i32.to_le_bytes());
+    dict(&mut root, &[]);
+    root.extend(1i32.to_le_bytes());
+    root.extend((-1i32).to_le_bytes());
+    root.extend((-1i32).to_le_bytes());
+    root.extend(1i32.to_le_bytes());
+    dict(&mut root, &[]);
+    children.extend(chunk(b"nTRN", &root, &[]));
+
+    let mut group = Vec::new();
+    group.extend(1i32.to_le_bytes());
+    dict(&mut group, &[]);
+    group.extend((models.len() as u32).to_le_bytes());
+    for i in 0..models.len() {
+        group.extend((2 + 2 * i as i32).to_le_bytes());
+    }
+    children.extend(chunk(b"nGRP", &group, &[]));
+
+    for (i, (tile, _)) in models.iter().enumerate() {
+        let size = (extent - *tile * VOX_MODEL_SIZE).map(|e| e.min(VOX_MODEL_SIZE));
+        // MagicaVoxel positions models by their center.
+        let t = *tile * VOX_MODEL_SIZE + size / 2;
+        let mut transform = Vec::new();
+        transform.extend((2 + 2 * i as i32).to_le_bytes());
+        dict(&mut transform, &[]);
+        transform.extend((3 + 2 * i as i32).to_le_bytes());
+        transform.extend((-1i32).to_le_bytes());
+        transform.extend(0i32.to_le_bytes());
+        transform.extend(1i32.to_le_bytes());
+        dict(&mut transform, &[("_t", format!("{} {} {}", t.x, t.y, t.z))]);
+        children.extend(chunk(b"nTRN", &transform, &[]));
+
+        let mut shape = Vec::new();
+        shape.extend((3 + 2 * i as i32).to_le_bytes());
+        dict(&mut shape, &[]);
+        shape.extend(1i32.to_le_bytes());
+        shape.extend((i as i32).to_le_bytes());
+        dict(&mut shape, &[]);
+        children.extend(chunk(b"nSHP", &shape, &[]));
+    }
 
-    const EXTENSION: &'static str = "ron";
+    // Palette entry `i` is color index `i + 1`.
+    let mut rgba = Vec::new();
+    for i in 0..256 {
+        let color = palette.get(i).copied().unwrap_or_default();
+        rgba.extend([color.r, color.g, color.b, 255]);
+    }
+    children.extend(chunk(b"RGBA", &rgba, &[]));
+
+    let mut bytes = b"VOX ".to_vec();
+    bytes.extend(150u32.to_le_bytes());
+    bytes.extend(chunk(b"MAIN", &[], &children));
+    std::fs::write(path, bytes)?;
+    println!(
+        "Exported {} blocks in {} models to {:?}, its origin is at {}",
+        blocks.len(),
+        models.len(),
+        path,
+        bounds.min
+    );
+    Ok(())
 }
 
 fn main() {
-    let mut persistance = TerrainPersistence::new("./terrain/".into());
-    let mut rng = thread_rng();
-    let place_spec = PlaceSpec::load_expect("place").read();
-    let ((vox, aabbs), _) = place_spec.build_place();
-    let replace_map = place_spec
-        .replace
+    let mut args = Args::parse();
+    init_logging(&args);
+    let start = Instant::now();
+    // Feeding a script doesn't need the terrain.
+    if let Command::Feed(script, rate, resume) = &args.command {
+        if let Err(e) = feed_script(script, *rate, *resume) {
+            error!("Failed to feed {:?}: {}", script, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = &args.allowed_chunks_file {
+        match load_allowed_chunks(path) {
+            Ok(allowed) => args.allowed_chunks = Some(allowed),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+    let terrain_dir = args
+        .terrain_dir
+        .clone()
+        .unwrap_or_else(|| terrain_persistence_dir(Path::new("./terrain/")));
+    if !terrain_dir.is_dir() {
+        if args.create {
+            std::fs::create_dir_all(&terrain_dir).unwrap_or_else(|e| {
+                panic!("Failed to create terrain directory {:?}: {}", terrain_dir, e)
+            });
+        } else {
+            error!(
+                "Terrain directory {:?} doesn't exist, pass --create to create it",
+                terrain_dir
+            );
+            std::process::exit(1);
+        }
+    }
+    info!(
+        "Using terrain directory {}",
+        std::fs::canonicalize(&terrain_dir)
+            .unwrap_or_else(|_| terrain_dir.clone())
+            .display()
+    );
+    // `TerrainPersistence` only takes other directories from the environment.
+    std::env::set_var("VELOREN_TERRAIN", &terrain_dir);
+    let journal_path = terrain_dir
+        .parent()
+        .unwrap_or(&terrain_dir)
+        .join(JOURNAL_FILE);
+    let mut resumed = None;
+    if journal_path.exists() {
+        let (journal, written) = Journal::load(&journal_path).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        if matches!(args.command, Command::Rollback) {
+            rollback(&journal_path, journal, written.len(), &terrain_dir);
+            return;
+        } else if matches!(args.command, Command::Resume) {
+            // Random blocks differ between runs with a thread local RNG.
+            if journal.args.iter().any(|arg| arg == "--thread-rng") {
+                error!("The interrupted placement used --thread-rng, it can only be rolled back");
+                std::process::exit(1);
+            }
+            let mut journal_args = journal.args.clone();
+            // Journals from before there were commands only have the flags.
+            if journal_args.first().map_or(true, |arg| arg.starts_with('-')) {
+                journal_args.splice(0..0, ["place", "place"].map(String::from));
+            }
+            args = Args::parse_from(journal_args);
+            args.undo_file = Some(journal.undo.clone());
+            args.no_confirm = true;
+            println!(
+                "Resuming the interrupted placement, {} of {} terrain chunks are already written",
+                written.len(),
+                journal.chunks.len()
+            );
+            resumed = Some((journal, written));
+        } else {
+            error!(
+                "A placement was interrupted after writing {} of {} terrain chunks, run \
+                 `resume` to finish it or `rollback` to revert it, see {:?}",
+                written.len(),
+                journal.chunks.len(),
+                journal_path
+            );
+            std::process::exit(1);
+        }
+    } else if matches!(args.command, Command::Resume | Command::Rollback) {
+        error!("There is no interrupted placement to resume or roll back");
+        std::process::exit(1);
+    }
+    if args.watch {
+        watch(&args, &terrain_dir);
+        return;
+    }
+    let undo_path = || args.undo_file.clone().unwrap_or_else(undo_path);
+    if let Command::Undo(undo, piece, force) = &args.command {
+        let Some(piece) = piece else {
+            apply_undo(undo, &terrain_dir);
+            return;
+        };
+        match apply_piece_undo(undo, &terrain_dir, piece, *force) {
+            Ok((restored, skipped)) => {
+                println!("Reverted {} blocks of {}", restored, piece);
+                for (writer, count) in skipped {
+                    warn!(
+                        "{} blocks of {} were written again by {} later, they were left alone, \
+                         revert them too with --force",
+                        count, piece, writer
+                    );
+                }
+            },
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+    if let Command::Diff(a, b, output) = &args.command {
+        diff_specs(a, b, output.as_deref(), &args, &terrain_dir);
+        return;
+    }
+    if let Command::Copy(bounds, path) = &args.command {
+        let template = copy_region(*bounds, &terrain_dir);
+        let ron = ron::to_string(&template).expect("Failed to serialize template");
+        std::fs::write(path, ron)
+            .unwrap_or_else(|e| panic!("Failed to write template {:?}: {}", path, e));
+        println!("Copied {} blocks to {}", template.blocks.len(), path.display());
+        return;
+    }
+    if let Command::Clear(bounds) = args.command {
+        if args.dry_run {
+            println!("Dry run, nothing was cleared.");
+        } else if args.fill_air {
+            let mut writer = Writer::new(terrain_dir, args.standalone_persistence);
+            for x in bounds.min.x..=bounds.max.x {
+                for y in bounds.min.y..=bounds.max.y {
+                    for z in bounds.min.z..=bounds.max.z {
+                        writer.set_block(Vec3::new(x, y, z), Block::empty());
+                    }
+                }
+            }
+            writer.flush();
+            writer.into_undo().save(&undo_path());
+        } else {
+            clear_region(bounds, &terrain_dir, args.options.verbosity).save(&undo_path());
+        }
+        return;
+    }
+    if args.bench {
+        bench(&args, &terrain_dir);
+        return;
+    }
+    // By default the manifest is next to the terrain directory, in the data
+    // directory of the server.
+    let manifest_path = terrain_dir
+        .parent()
+        .unwrap_or(&terrain_dir)
+        .join(MANIFEST_FILE);
+    let undo_path = undo_path();
+    let mut world = World::new(terrain_dir.clone());
+    let mut writer = if args.dry_run {
+        None
+    } else {
+        Some(Writer::new(terrain_dir.clone(), args.standalone_persistence))
+    };
+    if let (Some(writer), Some((journal, written))) = (&mut writer, &resumed) {
+        let undo = Undo::load(&journal.undo).unwrap_or_else(|e| panic!("{}", e));
+        writer.resume(written.clone(), undo);
+    }
+    // Every variant places all placements again, further along the stride.
+    let placements = (0..args.variants.max(1))
+        .flat_map(|variant| {
+            load_placements(&args.spec).into_iter().map(move |mut placement| {
+                let offset = Vec3::from(placement.offset) + args.variant_stride * variant as i32;
+                placement.offset = offset.into_array();
+                (placement, variant)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut run_stats = RunStats::new(args.stats_json.clone(), start);
+    // Every spec is validated and built before any terrain is touched, so
+    // pieces snapping to the surface only see the terrain from before the run.
+    let mut validation = Validation::default();
+    let mut specs = Vec::new();
+    for (placement, variant) in &placements {
+        match load_spec(&placement.spec, &args.vars) {
+            Ok(mut place_spec) => {
+                place_spec.variant = *variant;
+                let seed = args.options.seed_of(&place_spec);
+                let place_spec =
+                    expand_spec(&placement.spec, &place_spec, seed, args, &mut validation);
+                specs.push((placement, place_spec));
+            },
+            Err(e) => validation
+                .errors
+                .push(format!("Could not load spec {}: {}", placement.spec, e)),
+        }
+    }
+    let names = specs
         .iter()
-        .map(|(color, block)| (Rgb::from(*color), block.clone()))
-        .collect::<HashMap<_, _>>();
-    for (key, chunk) in vox.iter() {
-        println!("Filling chunk {}", key);
-        for (pos, cell) in chunk.full_vol_iter() {
-            let wpos = vox.key_pos(key) + pos;
-            if place_spec.fill_empty {
-                if !aabbs.iter().any(|aabb| aabb.contains_point(wpos)) {
-                    continue;
+        .flat_map(|(_, place_spec)| place_spec.pieces.iter().map(VoxSpec::name))
+        .collect::<BTreeSet<_>>();
+    for (filter, unknown) in [("--only", &args.only), ("--skip", &args.skip)] {
+        for unknown in unknown.iter().filter(|name| !names.contains(name.as_str())) {
+            validation.errors.push(format!(
+                "{} names {}, which isn't a piece of the specs, the pieces are: {}",
+                filter,
+                unknown,
+                names.iter().copied().collect::<Vec<_>>().join(", ")
+            ));
+        }
+    }
+    run_stats.seeds = specs
+        .iter()
+        .map(|(placement, place_spec)| (placement.spec.clone(), args.options.seed_of(place_spec)))
+        .collect();
+    let mut built = Vec::new();
+    if validation.errors.is_empty() {
+        let max_volume = args.max_volume.unwrap_or(DEFAULT_MAX_VOLUME);
+        for (placement, place_spec) in &specs {
+            let replace_maps = if args.strict_colors {
+                place_spec.replace_maps()
+            } else {
+                Vec::new()
+            };
+            let mut unmapped = UnmappedColors::default();
+            let mut pieces = Vec::new();
+            place_spec.build_pieces_each(
+                placement.rotation.to_mat(),
+                args.offset + Vec3::from(placement.offset),
+                &mut world,
+                args.options.seed_of(place_spec),
+                &mut validation,
+                |mut piece, _| {
+                    if args.strict_colors {
+                        for (wpos, cell, origin) in piece.scene.cells() {
+                            let replace_map = &replace_maps[origin.piece as usize];
+                            unmapped.record(&cell, origin, wpos, replace_map);
+                        }
+                    }
+                    // Streaming builds the pieces again to place them, until
+                    // then only their bounds are kept.
+                    if args.streaming {
+                        piece.scene = SparseScene::empty();
+                    }
+                    pieces.push(piece);
+                },
+            );
+            if args.streaming && pieces.len() > 1 {
+                for difference in streaming_differences(place_spec) {
+                    validation.warnings.push(format!(
+                        "{} is placed with --streaming, so {}",
+                        placement.spec, difference
+                    ));
+                }
+            }
+            for aabb in pieces.iter().flat_map(|piece| &piece.aabbs) {
+                let volume = (aabb.max - aabb.min + 1).map(|e| e as u64).product::<u64>();
+                if volume > max_volume {
+                    validation.errors.push(format!(
+                        "A model of {} spans {} to {}, {} blocks, more than the maximum of {}",
+                        placement.spec, aabb.min, aabb.max, volume, max_volume
+                    ));
+                }
+            }
+            report_pieces(
+                &placement.spec,
+                &pieces,
+                args.fail_on_overlap,
+                args.options.verbosity,
+                &mut validation,
+            );
+            if !args.yes_i_know {
+                check_bounds(
+                    &placement.spec,
+                    &pieces,
+                    args
+                        .max_total_volume
+                        .or(place_spec.max_total_volume)
+                        .unwrap_or(DEFAULT_MAX_TOTAL_VOLUME),
+                    args
+                        .world_bounds
+                        .or(place_spec.world_bounds)
+                        .unwrap_or(DEFAULT_WORLD_BOUNDS),
+                    &mut validation,
+                );
+            }
+            if let (Some(allowed), true) = (&args.allowed_chunks, args.strict_bounds) {
+                let outside = disallowed_chunks(&pieces, allowed);
+                if !outside.is_empty() {
+                    validation.errors.push(format!(
+                        "{} reaches into terrain chunks that aren't allowed, so nothing is \
+                         written with --strict-bounds: {:?}",
+                        placement.spec, outside
+                    ));
+                }
+            }
+            if args.strict_colors {
+                let mut colors = unmapped
+                    .0
+                    .keys()
+                    .map(|(color, _)| color.into_array())
+                    .collect::<Vec<_>>();
+                colors.sort();
+                colors.dedup();
+                if !colors.is_empty() {
+                    validation.errors.push(format!(
+                        "{} uses colors that aren't replaced: {:?}",
+                        placement.spec, colors
+                    ));
+                }
+            }
+            run_stats.pieces.extend(
+                pieces
+                    .iter()
+                    .filter_map(|piece| Some((placement.spec.clone(), piece.bounds.clone()?))),
+            );
+            built.push(pieces);
+        }
+    }
+    if args.variants > 1 && !built.is_empty() {
+        check_variant_stride(&specs, &built, args.variant_stride, &mut validation);
+    }
+    let failed = validation.report();
+    run_stats.validation = validation;
+    if failed {
+        // Exiting skips destructors.
+        drop(run_stats);
+        std::process::exit(1);
+    }
+    if matches!(args.command, Command::Check) {
+        println!("All specs are valid.");
+        run_stats.completed = true;
+        return;
+    }
+
+    // Compare what would be placed with what previous runs placed.
+    let old_manifest = if args.force {
+        Manifest::default()
+    } else {
+        Manifest::load(&manifest_path)
+    };
+    let mut manifest = Manifest::default();
+    let mut keys = Vec::new();
+    // Pieces left out by `--only` and `--skip` keep what they placed before.
+    let mut excluded = HashSet::new();
+    for (i, ((placement, place_spec), pieces)) in specs.iter().zip(&built).enumerate() {
+        let seed = args.options.seed_of(place_spec);
+        let piece_keys = pieces
+            .iter()
+            .map(|piece| format!("{}/{}#{}", i, placement.spec, piece.index))
+            .collect::<Vec<_>>();
+        for (piece, key) in pieces.iter().zip(&piece_keys) {
+            if !args.includes(place_spec.pieces[piece.index as usize].name()) {
+                if let Some(old) = old_manifest.pieces.get(key) {
+                    manifest.pieces.insert(key.clone(), old.clone());
                 }
-            } else if matches!(cell, Cell::Empty) {
+                excluded.insert(key.clone());
                 continue;
             }
-            let block = match cell.get_color() {
-                Some(color) => replace_map
-                    .get(&color)
-                    .map(|spec| spec.get_block(&mut rng))
-                    .unwrap_or_else(|| {
-                        if cell.is_hollow() {
-                            Block::air(SpriteKind::Empty)
-                        } else if cell.is_glowy() {
-                            Block::new(BlockKind::GlowingRock, color)
-                        } else if cell.is_shiny() {
-                            Block::water(SpriteKind::Empty)
-                        } else {
-                            Block::new(BlockKind::Misc, color)
-                        }
-                    }),
-                None => Block::empty(),
-            };
-            persistance.set_block(wpos, block);
+            manifest.pieces.insert(key.clone(), ManifestEntry {
+                hash: place_spec.piece_hash(piece.index),
+                offset: piece.offset.into_array(),
+                rotation: piece.rot.into_col_arrays(),
+                seed,
+                bounds: piece.aabbs.iter().copied().reduce(|a, b| a.union(b)),
+                undo: None,
+            });
+        }
+        keys.push(piece_keys);
+    }
+    if let Some((journal, _)) = &resumed {
+        if placement_hash(&manifest) != journal.hash {
+            error!(
+                "The specs or models changed since the placement was interrupted, so it can't \
+                 be resumed, run `rollback` to revert it instead"
+            );
+            drop(run_stats);
+            std::process::exit(1);
+        }
+    }
+    let changed = manifest
+        .pieces
+        .iter()
+        .filter(|(key, entry)| {
+            !excluded.contains(*key)
+                && old_manifest
+                    .pieces
+                    .get(*key)
+                    .map_or(true, |old| !old.same_placement(entry))
+        })
+        .map(|(key, _)| key.clone())
+        .collect::<HashSet<_>>();
+    // Pieces that changed or were removed leave behind what they placed before.
+    let stale = old_manifest
+        .pieces
+        .iter()
+        .filter(|(key, _)| !manifest.pieces.contains_key(*key) || changed.contains(*key))
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    // Unchanged pieces are placed again if a changed piece may have overwritten
+    // them.
+    let dirty = stale
+        .iter()
+        .filter_map(|entry| entry.bounds)
+        .chain(changed.iter().filter_map(|key| manifest.pieces[key].bounds))
+        .collect::<Vec<_>>();
+    let redo = manifest
+        .pieces
+        .iter()
+        .filter(|(key, entry)| {
+            changed.contains(*key)
+                || (!excluded.contains(*key)
+                    && entry.bounds.map_or(false, |bounds| {
+                        dirty.iter().any(|dirty| dirty.collides_with_aabb(bounds))
+                    }))
+        })
+        .map(|(key, _)| key.clone())
+        .collect::<HashSet<_>>();
+    for (key, entry) in &mut manifest.pieces {
+        entry.undo = if redo.contains(key) {
+            Some(undo_path.clone())
+        } else {
+            old_manifest
+                .pieces
+                .get(key)
+                .and_then(|old| old.undo.clone())
+        };
+    }
+    if args.options.verbosity > Verbosity::Quiet && !old_manifest.pieces.is_empty() {
+        println!(
+            "Skipping {} of {} unchanged pieces",
+            manifest.pieces.len() - redo.len(),
+            manifest.pieces.len()
+        );
+    }
+
+    // Clear what stale pieces placed outside of the pieces placed now.
+    let placed_bounds = manifest
+        .pieces
+        .values()
+        .filter_map(|entry| entry.bounds)
+        .collect::<Vec<_>>();
+    let covered = |wpos: Vec3<i32>| {
+        placed_bounds
+            .iter()
+            .any(|bounds| bounds.contains_point(wpos))
+    };
+    let mut restored = Vec::new();
+    for entry in &stale {
+        let (Some(bounds), Some(path)) = (entry.bounds, &entry.undo) else {
+            continue;
+        };
+        match Undo::load(path) {
+            Ok(undo) => restored.extend(
+                undo.prior
+                    .into_iter()
+                    .filter(|(wpos, _)| bounds.contains_point(*wpos) && !covered(*wpos)),
+            ),
+            Err(e) => warn!("Can't clear a removed piece: {}", e),
+        }
+    }
+    if let Some(bounds) = args.write_bounds {
+        restored.retain(|(wpos, _)| bounds.contains_point(*wpos));
+    }
+    let mut disallowed = BTreeMap::<[i32; 2], usize>::new();
+    if let Some(allowed) = &args.allowed_chunks {
+        restored.retain(|(wpos, _)| {
+            let key = terrain_chunk_key(*wpos);
+            let allowed = allowed.contains(&key);
+            if !allowed {
+                *disallowed.entry(key.into_array()).or_default() += 1;
+            }
+            allowed
+        });
+    }
+    // Only what the stale pieces wrote is cleared, pieces of the same run
+    // share their undo file.
+    let cleared = if args.clear_empty {
+        let mut cleared = std::mem::take(&mut restored)
+            .into_iter()
+            .map(|(wpos, _)| wpos)
+            .collect::<Vec<_>>();
+        cleared.sort_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+        cleared.dedup();
+        cleared
+    } else {
+        Vec::new()
+    };
+
+    if args.streaming && !args.dry_run && !redo.is_empty() {
+        // The undo file has to be complete before the first chunk is written,
+        // so the writes can't be flushed piece by piece.
+        warn!(
+            "Streaming only keeps the scene of one piece, every written block is still kept \
+             until the end: the written chunks, the blocks they replace for the undo file and \
+             the positions every piece writes{}",
+            if args.verify { ", and what was written to verify it" } else { "" }
+        );
+    }
+    if !args.dry_run && !args.no_confirm && !redo.is_empty() && args.streaming {
+        println!("Streaming placements aren't previewed, they'd need every piece in memory.");
+        if !confirm("Apply? [y/N] ") {
+            println!("Nothing was written.");
+            return;
         }
+    } else if !args.dry_run && !args.no_confirm && !redo.is_empty() {
+        // The preview resolves blocks like placing does, without writing them.
+        let mut preview_world = World::new(terrain_dir.clone());
+        let mut stats = PlaceStats::default();
+        let mut blocks = cleared
+            .iter()
+            .map(|wpos| (*wpos, Block::empty()))
+            .collect::<HashMap<_, _>>();
+        let options = PlaceOptions {
+            verbosity: Verbosity::Quiet,
+            ..args.options
+        };
+        for (((_, place_spec), pieces), keys) in specs.iter().zip(&built).zip(&keys) {
+            let pieces = redo_pieces(pieces, keys, &redo);
+            if !pieces.is_empty() {
+                place_into(
+                    place_spec,
+                    &merge_pieces(pieces.into_iter()),
+                    &mut preview_world,
+                    &mut BoundsFilter {
+                        bounds: args.write_bounds,
+                        filtered: 0,
+                        sink: (&mut stats, &mut blocks),
+                    },
+                    options,
+                );
+            }
+        }
+        print_preview(&blocks, &args.preview_z);
+        stats.print_summary(start.elapsed());
+        if !cleared.is_empty() {
+            println!("Blocks cleared: {}", cleared.len());
+        }
+        if !confirm("Apply? [y/N] ") {
+            println!("Nothing was written.");
+            return;
+        }
+    }
+
+    // Streaming builds the pieces again with the world they were built with
+    // before, not the one they are placed into.
+    let mut build_world = args
+        .streaming
+        .then(|| std::mem::replace(&mut world, World::new(terrain_dir.clone())));
+    if !restored.is_empty() && !args.dry_run {
+        restore_blocks(restored, &terrain_dir);
+        // The world has to see the restored blocks.
+        world = World::new(terrain_dir.clone());
+    }
+
+    let mut stats = PlaceStats::default();
+    let mut export = (args.export_vox.is_some() || args.export_prefab.is_some())
+        .then(HashMap::new);
+    // The stats of every variant on its own, for comparing them.
+    let current_variant = std::cell::Cell::new(None);
+    let mut variant_stats = vec![PlaceStats::default(); args.variants as usize];
+    let mut variants = VariantStats {
+        current: &current_variant,
+        stats: &mut variant_stats,
+    };
+    // Every block written, to read them back with `--verify`.
+    let mut verify = args.verify.then(HashMap::new);
+    let mut commands = args
+        .commands_out
+        .as_ref()
+        .map(|_| CommandScript::new(args.command_template.clone()));
+    let mut top_view = args.export_map.is_some().then(TopView::default);
+    let mut report = PlaceReport::default();
+    // The peak memory of every piece placed with `--streaming`.
+    let mut piece_memory = Vec::new();
+    // What earlier placements wrote is theirs to overwrite.
+    let own = if args.no_clobber_persisted {
+        let mut undo_files = Manifest::load(&manifest_path)
+            .pieces
+            .into_values()
+            .filter_map(|entry| entry.undo)
+            .collect::<HashSet<_>>();
+        undo_files.extend(resumed.iter().map(|(journal, _)| journal.undo.clone()));
+        undo_files
+            .iter()
+            .filter_map(|path| Undo::load(path).ok())
+            .flat_map(|undo| undo.prior.into_iter().map(|(wpos, _)| wpos))
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let mut sink = ChunkFilter {
+        allowed: args.allowed_chunks.clone(),
+        disallowed,
+        sink: BoundsFilter {
+            bounds: args.write_bounds,
+            filtered: 0,
+            sink: ProtectPersisted {
+                persisted: args
+                    .no_clobber_persisted
+                    .then(|| PersistedBlocks::new(terrain_dir.clone())),
+                own,
+                clobber_within: args.clobber_within.clone(),
+                skipped: Vec::new(),
+                sink: (
+                    Some((&mut stats, (&mut run_stats, &mut variants))),
+                    (
+                        (&mut writer, &mut commands),
+                        (&mut export, (&mut verify, &mut top_view)),
+                    ),
+                ),
+            },
+        },
+    };
+    // Cleared blocks aren't counted as placed.
+    let counted = sink.sink.sink.sink.0.take();
+    for wpos in &cleared {
+        sink.set_block(*wpos, Block::empty());
+    }
+    sink.sink.sink.sink.0 = counted;
+    // Later placements overwrite earlier ones.
+    for (((placement, place_spec), pieces), keys) in specs.iter().zip(&built).zip(&keys) {
+        let pieces = redo_pieces(pieces, keys, &redo);
+        if pieces.is_empty() {
+            continue;
+        }
+        info!("Placing {}", placement.spec);
+        current_variant.set(Some(place_spec.variant as usize));
+        report.decayed_voxels += pieces.iter().map(|piece| piece.decayed).sum::<usize>();
+        for piece in &pieces {
+            let spec = &place_spec.pieces[piece.index as usize];
+            info!(
+                spec = %placement.spec,
+                piece = spec.name(),
+                bounds = ?piece.aabbs.iter().copied().reduce(|a, b| a.union(b)),
+                "Placing piece {} of {}",
+                spec.name(),
+                placement.spec
+            );
+            let mut markers = piece
+                .bounds
+                .iter()
+                .flat_map(|bounds| &bounds.markers)
+                .collect::<Vec<_>>();
+            markers.sort_by_key(|(marker, _)| *marker);
+            for (marker, positions) in markers {
+                debug!(
+                    piece = spec.name(),
+                    marker = %marker,
+                    positions = ?positions,
+                    "Piece {} has {} voxels of marker {}",
+                    spec.name(),
+                    positions.len(),
+                    marker
+                );
+            }
+            let exact = place_spec.exact_replace_map(spec);
+            for (color, replaced) in place_spec.tolerant_matches(spec, &exact) {
+                report
+                    .approximate_colors
+                    .push((spec.specifier.clone(), color, replaced));
+            }
+        }
+        if let Some(build_world) = &mut build_world {
+            let indices = pieces.iter().map(|piece| piece.index).collect::<HashSet<_>>();
+            reset_peak_memory();
+            place_spec.build_pieces_each(
+                placement.rotation.to_mat(),
+                args.offset + Vec3::from(placement.offset),
+                build_world,
+                args.options.seed_of(place_spec),
+                // The pieces were validated when they were first built.
+                &mut Validation::default(),
+                |piece, _| {
+                    if !indices.contains(&piece.index) {
+                        return;
+                    }
+                    report.extend(place_into(
+                        place_spec,
+                        &merge_pieces(std::iter::once(&piece)),
+                        &mut world,
+                        &mut sink,
+                        args.options,
+                    ));
+                    let name = place_spec.pieces[piece.index as usize].name().to_string();
+                    drop(piece);
+                    piece_memory.push((placement.spec.clone(), name, peak_memory()));
+                    reset_peak_memory();
+                },
+            );
+        } else {
+            report.extend(place_into(
+                place_spec,
+                &merge_pieces(pieces.into_iter()),
+                &mut world,
+                &mut sink,
+                args.options,
+            ));
+        }
+        if place_spec.color_transform != ColorTransform::None {
+            let samples = place_spec.color_samples(COLOR_SAMPLES).into_iter();
+            report.color_samples.extend(
+                samples.map(|(color, transformed)| (placement.spec.clone(), color, transformed)),
+            );
+        }
+        info!(spec = %placement.spec, "Placed {}", placement.spec);
+    }
+
+    let filtered = sink.sink.filtered;
+    let protected = std::mem::take(&mut sink.sink.sink.skipped);
+    let disallowed = std::mem::take(&mut sink.disallowed);
+
+    if let (Some(path), Some(blocks)) = (&args.export_vox, &export) {
+        export_vox(blocks, path)
+            .unwrap_or_else(|e| panic!("Failed to export vox file {:?}: {}", path, e));
+    }
+    if let (Some(path), Some(top_view)) = (&args.export_map, &top_view) {
+        export_map(top_view, path)
+            .unwrap_or_else(|e| panic!("Failed to export map {:?}: {}", path, e));
+    }
+    let pois = specs
+        .iter()
+        .zip(&built)
+        .flat_map(|((_, place_spec), pieces)| points_of_interest(place_spec, pieces))
+        .collect::<Vec<_>>();
+    if !pois.is_empty() && !args.dry_run {
+        let path = args.poi_out.clone().unwrap_or_else(|| {
+            terrain_dir.parent().unwrap_or(&terrain_dir).join(POI_FILE)
+        });
+        match add_pois(&path, pois) {
+            Ok(added) => println!("Points of interest added to {}: {}", path.display(), added),
+            Err(e) => error!("Failed to write points of interest to {:?}: {}", path, e),
+        }
+    }
+    // The context doesn't touch the terrain, so dry runs write it too.
+    if let Some(path) = &args.context_out {
+        let mut context = Context::default();
+        for ((_, place_spec), pieces) in specs.iter().zip(&built) {
+            context.add(place_spec, pieces);
+        }
+        context
+            .save(path)
+            .unwrap_or_else(|e| panic!("Failed to write context {:?}: {}", path, e));
+        println!(
+            "Wrote {} pieces and groups to the context {}",
+            context.entries.len(),
+            path.display()
+        );
+    }
+    if let (Some(path), Some(blocks)) = (&args.export_prefab, &export) {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned());
+        let prefab = Template::from_blocks(name, blocks, args.prefab_origin);
+        let ron = ron::ser::to_string_pretty(&prefab, ron::ser::PrettyConfig::default())
+            .expect("Failed to serialize prefab");
+        std::fs::write(path, ron)
+            .unwrap_or_else(|e| panic!("Failed to write prefab {:?}: {}", path, e));
+        println!("Exported {} blocks to {}", prefab.blocks.len(), path.display());
     }
 
-    persistance.unload_all();
+    let mut mismatches = Vec::new();
+    if let Some(mut writer) = writer {
+        // The undo file is complete before any chunk is written, so an
+        // interrupted run can be rolled back.
+        let mut undo = writer.undo();
+        undo.attribute(&report.writes);
+        undo.save(&undo_path);
+        writer.journal = Some((journal_path.clone(), Journal {
+            args: match &resumed {
+                Some((journal, _)) => journal.args.clone(),
+                None => args.raw.clone(),
+            },
+            hash: placement_hash(&manifest),
+            seeds: run_stats.seeds.clone(),
+            undo: undo_path.clone(),
+            chunks: Vec::new(),
+        }));
+        writer.flush();
+        manifest
+            .save(&manifest_path)
+            .unwrap_or_else(|e| panic!("Failed to write manifest {:?}: {}", manifest_path, e));
+        let sprites = std::mem::take(&mut report.special_sprites);
+        if !sprites.is_empty() || args.fresh_manifest {
+            let path = manifest_path.with_file_name(SPRITE_MANIFEST_FILE);
+            if args.options.verbosity > Verbosity::Quiet {
+                println!("Recording {} sprites with loot in {:?}", sprites.len(), path);
+            }
+            save_special_sprites(sprites, &path, args.fresh_manifest)
+                .unwrap_or_else(|e| panic!("Failed to write sprite manifest {:?}: {}", path, e));
+        }
+        std::fs::remove_file(&journal_path)
+            .unwrap_or_else(|e| panic!("Failed to remove journal {:?}: {}", journal_path, e));
+        // Dropping the writer unloads the chunks of `TerrainPersistence`.
+        drop(writer);
+        if let Some(written) = &verify {
+            mismatches = verify_written(written, &terrain_dir).unwrap_or_else(|e| {
+                error!("Failed to read back the written chunks: {}", e);
+                std::process::exit(1);
+            });
+            print_mismatches(&mismatches, written.len());
+        }
+    } else if let (Some(path), Some(commands)) = (&args.commands_out, &commands) {
+        if commands.len() > args.max_commands {
+            let prompt = format!(
+                "The script has {} commands, more than --max-commands {}. Write it? [y/N] ",
+                commands.len(),
+                args.max_commands
+            );
+            if args.no_confirm || !confirm(&prompt) {
+                error!(
+                    "Not writing {} commands, more than --max-commands {}",
+                    commands.len(),
+                    args.max_commands
+                );
+                std::process::exit(1);
+            }
+        }
+        let written = std::fs::File::create(path)
+            .map(io::BufWriter::new)
+            .and_then(|mut file| commands.write(&mut file));
+        if let Err(e) = written {
+            panic!("Failed to write commands {:?}: {}", path, e);
+        }
+        println!(
+            "Wrote {} commands in {} batches to {}, nothing was written to the terrain.",
+            commands.len(),
+            commands.chunks.len(),
+            path.display()
+        );
+    } else {
+        println!("Dry run, nothing was written.");
+    }
+    stats.print_summary(start.elapsed());
+    if !cleared.is_empty() {
+        println!("Blocks cleared: {}", cleared.len());
+    }
+    if args.variants > 1 {
+        println!("Variants:");
+        println!(
+            "{:<8} {:>8} {:<24} {:>12} {:>12} {:>12}",
+            "Variant", "Seed", "Offset", "Blocks", "Glowing", "Chunks"
+        );
+        for (variant, stats) in variant_stats.iter().enumerate() {
+            println!(
+                "{:<8} {:>8} {:<24} {:>12} {:>12} {:>12}",
+                variant,
+                format!("+{}", variant),
+                (args.variant_stride * variant as i32).to_string(),
+                stats.blocks,
+                stats.glowing,
+                stats.terrain_chunks.len()
+            );
+        }
+    }
+    if !piece_memory.is_empty() {
+        println!("Peak memory by piece:");
+        println!("{:<32} {:<32} {:>12}", "Spec", "Piece", "Memory");
+        for (spec, piece, memory) in &piece_memory {
+            println!("{:<32} {:<32} {:>12}", spec, piece, format_memory(*memory));
+        }
+    } else if let Some(memory) = peak_memory() {
+        println!("Peak memory: {}", format_memory(Some(memory)));
+    }
+    if args.write_bounds.is_some() {
+        println!("Blocks outside of the ranges, not placed: {}", filtered);
+    }
+    if args.no_clobber_persisted {
+        println!("Blocks already persisted, not placed: {}", protected.len());
+    }
+    if !disallowed.is_empty() {
+        println!("Blocks in terrain chunks that aren't allowed, not placed:");
+        println!("{:<16} {:>12}", "Chunk", "Blocks");
+        for (key, count) in &disallowed {
+            println!("{:<16} {:>12}", format!("{:?}", key), count);
+        }
+    }
+    let substituted = specs
+        .iter()
+        .flat_map(|(placement, place_spec)| {
+            place_spec.substituted.iter().map(move |substituted| (&placement.spec, substituted))
+        })
+        .collect::<Vec<_>>();
+    if !substituted.is_empty() {
+        println!("Pieces substituted with the missing piece:");
+        println!("{:<32} {:<40} {}", "Spec", "Intended piece", "Placed instead");
+        for (spec, (intended, substitute)) in substituted {
+            println!("{:<32} {:<40} {}", spec, intended, substitute);
+        }
+    }
+    if let Some(path) = &args.skipped_positions {
+        let positions = protected.iter().map(|wpos| wpos.into_array()).collect::<Vec<_>>();
+        let ron = ron::to_string(&positions).expect("Failed to serialize positions");
+        std::fs::write(path, ron)
+            .unwrap_or_else(|e| panic!("Failed to write skipped positions {:?}: {}", path, e));
+    }
+    report.print();
+    let failed = !report.sprite_errors.is_empty()
+        || !report.glow_errors.is_empty()
+        || !mismatches.is_empty();
+    run_stats.report = report;
+    run_stats.completed = !failed;
+    if failed {
+        drop(run_stats);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,225 @@
+//! Loading the models of pieces, vox files and templates.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use vek::*;
+use veloren_common::{
+    assets::{Asset, AssetExt, AssetHandle, DotVoxAsset, RonLoader, ASSETS_PATH},
+    terrain::Block,
+};
+
+use crate::spec::VoxSpec;
+
+/// The model of a piece, a vox file or a template.
+#[derive(Clone)]
+pub(crate) enum PieceModel {
+    Vox(Loaded<DotVoxAsset>),
+    Template(Loaded<Template>),
+}
+
+/// A model loaded through the asset system, or read from a file.
+pub(crate) enum Loaded<T: Asset> {
+    Asset(AssetHandle<T>),
+    File(Arc<T>),
+}
+
+impl<T: Asset> Clone for Loaded<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Loaded::Asset(handle) => Loaded::Asset(*handle),
+            Loaded::File(model) => Loaded::File(Arc::clone(model)),
+        }
+    }
+}
+
+impl<T: Asset> Loaded<T> {
+    pub(crate) fn read(&self) -> LoadedGuard<'_, T> {
+        match self {
+            Loaded::Asset(handle) => LoadedGuard::Asset(handle.read()),
+            Loaded::File(model) => LoadedGuard::File(model),
+        }
+    }
+}
+
+pub(crate) enum LoadedGuard<'a, T> {
+    Asset(assets_manager::AssetGuard<'a, T>),
+    File(&'a T),
+}
+
+impl<T> Deref for LoadedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            LoadedGuard::Asset(guard) => &**guard,
+            LoadedGuard::File(model) => model,
+        }
+    }
+}
+
+/// Whether a piece is a file rather than an asset specifier.
+pub fn is_model_path(name: &str) -> bool {
+    name.contains(['/', '\\'])
+        || [".vox", ".ron", ".gz"]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+}
+
+/// The directory the file of the spec `name` is in.
+pub fn spec_dir(name: &str) -> PathBuf {
+    let path = if is_model_path(name) {
+        PathBuf::from(name)
+    } else {
+        ASSETS_PATH.join(name.replace('.', "/"))
+    };
+    path.parent().map_or_else(|| ASSETS_PATH.clone(), Path::to_path_buf)
+}
+
+/// Makes the model files of `piece` relative to `dir`, the directory of its
+/// spec, so specs can be moved along with their models.
+pub(crate) fn resolve_model_path(dir: &Path, piece: &mut VoxSpec) {
+    if is_model_path(&piece.specifier) {
+        // The piece can still be aligned to by the path in the spec.
+        piece.options.name.get_or_insert_with(|| piece.specifier.clone());
+        piece.specifier = dir.join(&piece.specifier).to_string_lossy().into_owned();
+    }
+    if let Some(corner) = &mut piece.options.corner_piece {
+        if is_model_path(corner) {
+            *corner = dir.join(&*corner).to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Reads the model file at `path`, decompressing it if it is gzipped.
+pub(crate) fn read_model_file(path: &Path) -> Result<Vec<u8>, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(bytes);
+    }
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&bytes[..])
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("Could not decompress {}: {}", path.display(), e))?;
+    Ok(decompressed)
+}
+
+/// The models read from files so far, by their path.
+fn model_files() -> &'static Mutex<HashMap<PathBuf, PieceModel>> {
+    static MODEL_FILES: OnceLock<Mutex<HashMap<PathBuf, PieceModel>>> = OnceLock::new();
+    MODEL_FILES.get_or_init(Default::default)
+}
+
+/// Loads a vox file or, for `.ron` files, a template from `path`. Files are
+/// only read once.
+pub(crate) fn load_model_file(path: &Path) -> Result<PieceModel, String> {
+    let files = model_files();
+    if let Some(model) = files.lock().unwrap().get(path) {
+        return Ok(model.clone());
+    }
+    let bytes = read_model_file(path)?;
+    let model = parse_model(path, &bytes)?;
+    files.lock().unwrap().insert(path.to_owned(), model.clone());
+    Ok(model)
+}
+
+/// Parses `bytes` as the model file `name`, and uses it for the pieces named
+/// `name` instead of reading the file, so models can be placed without any
+/// files or assets.
+pub fn load_model_bytes(name: &str, bytes: &[u8]) -> Result<(), String> {
+    let path = Path::new(name);
+    let model = parse_model(path, bytes)?;
+    model_files().lock().unwrap().insert(path.to_owned(), model);
+    Ok(())
+}
+
+/// Parses the contents of the model file at `path`.
+fn parse_model(path: &Path, bytes: &[u8]) -> Result<PieceModel, String> {
+    let is_template = path.to_string_lossy().trim_end_matches(".gz").ends_with(".ron");
+    if is_template {
+        let template = ron::de::from_bytes(bytes)
+            .map_err(|e| format!("Could not parse template {}: {}", path.display(), e))?;
+        Ok(PieceModel::Template(Loaded::File(Arc::new(template))))
+    } else {
+        let vox = dot_vox::load_bytes(bytes)
+            .map_err(|e| format!("Could not parse vox file {}: {}", path.display(), e))?;
+        Ok(PieceModel::Vox(Loaded::File(Arc::new(DotVoxAsset(vox)))))
+    }
+}
+
+/// Loads the model of a piece, from a file or from the assets. Missing models
+/// are errors, callers decide whether to leave them out or substitute them.
+pub(crate) fn load_piece_model(name: &str) -> Result<PieceModel, String> {
+    if is_model_path(name) {
+        return load_model_file(Path::new(name));
+    }
+    match DotVoxAsset::load(name) {
+        Ok(dot_vox) => Ok(PieceModel::Vox(Loaded::Asset(dot_vox))),
+        Err(e) => match Template::load(name) {
+            Ok(template) => Ok(PieceModel::Template(Loaded::Asset(template))),
+            Err(_) => Err(e.to_string()),
+        },
+    }
+}
+
+/// Blocks copied from the terrain or exported from a placement, relative to
+/// an origin, the minimum corner of the copied region for copies.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Template {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The bounds of the blocks, relative to the origin.
+    #[serde(default)]
+    pub bounds: Option<Aabb<i32>>,
+    pub blocks: Vec<([i32; 3], Block)>,
+}
+
+impl Template {
+    /// A template of placed blocks, relative to `origin`, by default the
+    /// minimum corner of their bounds.
+    pub fn from_blocks(
+        name: Option<String>,
+        blocks: &HashMap<Vec3<i32>, Block>,
+        origin: Option<Vec3<i32>>,
+    ) -> Self {
+        let bounds = blocks.keys().fold(None::<Aabb<i32>>, |bounds, wpos| {
+            Some(bounds.map_or(
+                Aabb {
+                    min: *wpos,
+                    max: *wpos,
+                },
+                |bounds| bounds.expanded_to_contain_point(*wpos),
+            ))
+        });
+        let origin = origin
+            .or(bounds.map(|bounds| bounds.min))
+            .unwrap_or_default();
+        let mut blocks = blocks
+            .iter()
+            .map(|(wpos, block)| ((*wpos - origin).into_array(), *block))
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|(pos, _)| *pos);
+        Template {
+            name,
+            bounds: bounds.map(|bounds| Aabb {
+                min: bounds.min - origin,
+                max: bounds.max - origin,
+            }),
+            blocks,
+        }
+    }
+}
+
+impl Asset for Template {
+    type Loader = RonLoader;
+
+    const EXTENSION: &'static str = "ron";
+}
@@ -0,0 +1,863 @@
+//! Reading and writing the persisted terrain chunks of a server.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use vek::*;
+use veloren_common::{
+    terrain::{Block, TerrainChunkSize},
+    vol::RectVolSize,
+};
+#[cfg(feature = "server")]
+use veloren_server::terrain_persistence::TerrainPersistence;
+
+use crate::{model::Template, place::Verbosity, sink::BlockSink};
+
+/// The key of the terrain chunk containing `wpos`.
+///
+/// ```
+/// use map_placer::persistence::terrain_chunk_key;
+/// use vek::{Vec2, Vec3};
+///
+/// assert_eq!(terrain_chunk_key(Vec3::new(33, 70, -10)), Vec2::new(1, 2));
+/// assert_eq!(terrain_chunk_key(Vec3::new(-1, 0, 0)), Vec2::new(-1, 0));
+/// ```
+pub fn terrain_chunk_key(wpos: Vec3<i32>) -> Vec2<i32> {
+    wpos.xy()
+        .map2(TerrainChunkSize::RECT_SIZE, |e, sz| e.div_euclid(sz as i32))
+}
+
+/// The position of `wpos` relative to the terrain chunk `key`, as used in the
+/// persistence files. Only x and y are relative, z stays absolute.
+pub(crate) fn terrain_chunk_pos(wpos: Vec3<i32>, key: Vec2<i32>) -> Vec3<i32> {
+    wpos - Vec3::from(key * TerrainChunkSize::RECT_SIZE.map(|e| e as i32))
+}
+
+/// The inverse of `terrain_chunk_pos`.
+pub(crate) fn terrain_world_pos(pos: Vec3<i32>, key: Vec2<i32>) -> Vec3<i32> {
+    pos + Vec3::from(key * TerrainChunkSize::RECT_SIZE.map(|e| e as i32))
+}
+
+/// The directory `TerrainPersistence::new` stores chunks in for `data_dir`.
+pub fn terrain_persistence_dir(data_dir: &Path) -> PathBuf {
+    std::env::var("VELOREN_TERRAIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir.join("terrain"))
+}
+
+/// An entry of a list of the terrain chunks a placement may write to.
+#[derive(Deserialize, Clone, Debug)]
+pub enum AllowedArea {
+    /// A terrain chunk by its key, the world position divided by the terrain
+    /// chunk size and rounded down.
+    Chunk(i32, i32),
+    /// Every terrain chunk that a rectangle of world columns touches, with
+    /// inclusive corners.
+    Rect { min: (i32, i32), max: (i32, i32) },
+}
+
+impl AllowedArea {
+    /// The keys of the terrain chunks in the area.
+    pub fn chunks(&self) -> Vec<Vec2<i32>> {
+        match *self {
+            AllowedArea::Chunk(x, y) => vec![Vec2::new(x, y)],
+            AllowedArea::Rect { min, max } => {
+                let (a, b) = (Vec2::<i32>::from(min), Vec2::<i32>::from(max));
+                let min = terrain_chunk_key(Vec3::from(Vec2::partial_min(a, b)));
+                let max = terrain_chunk_key(Vec3::from(Vec2::partial_max(a, b)));
+                (min.y..=max.y)
+                    .flat_map(|y| (min.x..=max.x).map(move |x| Vec2::new(x, y)))
+                    .collect()
+            },
+        }
+    }
+}
+
+/// Parses a list of the terrain chunks a placement may write to, either a RON
+/// list of `AllowedArea`s or CSV with a chunk key `x,y` or the corners of a
+/// rectangle of world columns `x,y,x,y` on every line. Terrain chunks are 32
+/// by 32 columns, unlike the chunks of scenes they have no height.
+///
+/// ```
+/// use map_placer::persistence::parse_allowed_chunks;
+/// use vek::Vec2;
+///
+/// let chunks = parse_allowed_chunks("# owned by ada\n-1,0\n0,0,40,31\n", false).unwrap();
+/// assert_eq!(chunks.len(), 3);
+/// assert!(chunks.contains(&Vec2::new(-1, 0)) && chunks.contains(&Vec2::new(1, 0)));
+/// let ron = "[Chunk(-1, 0), Rect(min: (0, 0), max: (40, 31))]";
+/// assert_eq!(parse_allowed_chunks(ron, true).unwrap(), chunks);
+/// ```
+pub fn parse_allowed_chunks(text: &str, ron: bool) -> Result<HashSet<Vec2<i32>>, String> {
+    let areas = if ron {
+        ron::from_str::<Vec<AllowedArea>>(text).map_err(|e| e.to_string())?
+    } else {
+        let mut areas = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let numbers = line
+                .split(',')
+                .map(|n| n.trim().parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            areas.push(match numbers[..] {
+                [x, y] => AllowedArea::Chunk(x, y),
+                [x0, y0, x1, y1] => AllowedArea::Rect {
+                    min: (x0, y0),
+                    max: (x1, y1),
+                },
+                _ => {
+                    return Err(format!(
+                        "line {}: expected a chunk x,y or a rectangle x,y,x,y",
+                        i + 1
+                    ));
+                },
+            });
+        }
+        areas
+    };
+    Ok(areas.iter().flat_map(AllowedArea::chunks).collect())
+}
+
+/// Reads the allowed terrain chunks from `path`, as RON if it ends with
+/// `.ron` and as CSV otherwise.
+pub fn load_allowed_chunks(path: &Path) -> Result<HashSet<Vec2<i32>>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read allowed chunks {:?}: {}", path, e))?;
+    let ron = path.extension().map_or(false, |extension| extension == "ron");
+    parse_allowed_chunks(&text, ron)
+        .map_err(|e| format!("Could not parse allowed chunks {:?}: {}", path, e))
+}
+
+// The on disk chunk formats of `veloren_server::terrain_persistence`, which
+// doesn't expose reading or removing persisted blocks.
+
+pub(crate) fn version_magic(n: u16) -> u64 {
+    (n as u64) | (0x3352ACEEA789 << 16)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ChunkV1 {
+    pub blocks: HashMap<Vec3<i32>, Block>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ChunkV2 {
+    pub version: u64,
+    pub blocks: Vec<BlockV2>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BlockV2 {
+    pub pos: Vec3<i16>,
+    pub block: Block,
+}
+
+/// The persisted blocks of a single terrain chunk, keyed by chunk position.
+#[derive(Default, Clone, Debug)]
+pub struct PersistedChunk {
+    pub blocks: HashMap<Vec3<i32>, Block>,
+}
+
+impl PersistedChunk {
+    /// The file of the chunk `key` in `dir`.
+    pub fn path(dir: &Path, key: Vec2<i32>) -> PathBuf {
+        dir.join(format!("chunk_{}_{}.dat", key.x, key.y))
+    }
+
+    /// Loads the chunk `key` from `dir`, returns `None` if it isn't persisted.
+    pub fn load(dir: &Path, key: Vec2<i32>) -> io::Result<Option<Self>> {
+        let bytes = match std::fs::read(Self::path(dir, key)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let blocks = match bincode::deserialize::<ChunkV2>(&bytes) {
+            Ok(chunk) if chunk.version == version_magic(2) => chunk
+                .blocks
+                .into_iter()
+                .map(|b| (b.pos.map(i32::from), b.block))
+                .collect(),
+            _ => bincode::deserialize::<ChunkV1>(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .blocks,
+        };
+        Ok(Some(Self { blocks }))
+    }
+
+    /// Writes the chunk `key` to `dir`, removing the file if the chunk has no
+    /// blocks left.
+    pub fn save(&self, dir: &Path, key: Vec2<i32>) -> io::Result<()> {
+        let path = Self::path(dir, key);
+        if self.blocks.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let chunk = ChunkV2 {
+            version: version_magic(2),
+            blocks: self
+                .blocks
+                .iter()
+                .map(|(pos, block)| BlockV2 {
+                    pos: pos.map(|e| e as i16),
+                    block: *block,
+                })
+                .collect(),
+        };
+        let bytes =
+            bincode::serialize(&chunk).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let tmp = path.with_extension("dat.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// Lazily loaded persisted blocks of a terrain persistence directory.
+#[derive(Debug)]
+pub struct PersistedBlocks {
+    pub dir: PathBuf,
+    pub chunks: HashMap<Vec2<i32>, PersistedChunk>,
+}
+
+impl PersistedBlocks {
+    /// The persisted blocks in `dir`, none of which are loaded yet.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Loads the persisted chunk `key` if it isn't loaded yet.
+    pub fn load(&mut self, key: Vec2<i32>) -> &PersistedChunk {
+        self.load_mut(key)
+    }
+
+    pub(crate) fn load_mut(&mut self, key: Vec2<i32>) -> &mut PersistedChunk {
+        let dir = &self.dir;
+        self.chunks.entry(key).or_insert_with(|| {
+            PersistedChunk::load(dir, key)
+                .unwrap_or_else(|e| panic!("Failed to read persisted chunk {}: {}", key, e))
+                .unwrap_or_default()
+        })
+    }
+
+    /// The persisted block at `wpos`, if there is one.
+    pub fn get(&mut self, wpos: Vec3<i32>) -> Option<Block> {
+        let key = terrain_chunk_key(wpos);
+        self.load(key)
+            .blocks
+            .get(&terrain_chunk_pos(wpos, key))
+            .copied()
+    }
+
+    /// Like `get`, but treats chunks that aren't loaded yet as having no
+    /// persisted blocks.
+    pub(crate) fn get_loaded(&self, wpos: Vec3<i32>) -> Option<Block> {
+        let key = terrain_chunk_key(wpos);
+        self.chunks
+            .get(&key)?
+            .blocks
+            .get(&terrain_chunk_pos(wpos, key))
+            .copied()
+    }
+}
+
+/// The blocks already in the world, as far as this tool can tell: the
+/// persisted blocks, with the blocks written by earlier placements of this run
+/// on top. Generated terrain isn't available, so anything else counts as air.
+#[derive(Debug)]
+pub struct World {
+    pub persisted: PersistedBlocks,
+    pub written: HashMap<Vec3<i32>, Block>,
+}
+
+impl World {
+    /// The world persisted in `dir`, before anything is placed.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            persisted: PersistedBlocks::new(dir),
+            written: HashMap::new(),
+        }
+    }
+
+    /// Loads the persisted chunks intersecting `bounds`, which has to be done
+    /// before looking up blocks in them.
+    pub(crate) fn load_region(&mut self, bounds: Aabb<i32>) {
+        let min = terrain_chunk_key(bounds.min);
+        let max = terrain_chunk_key(bounds.max);
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                self.persisted.load(Vec2::new(x, y));
+            }
+        }
+    }
+
+    /// The height of the topmost solid block of the column at `pos` within
+    /// `range`, inclusive.
+    pub(crate) fn surface_height(&mut self, pos: Vec2<i32>, range: (i32, i32)) -> Option<i32> {
+        self.persisted.load(terrain_chunk_key(pos.with_z(0)));
+        (range.0..=range.1)
+            .rev()
+            .find(|z| self.get(pos.with_z(*z)).kind().is_filled())
+    }
+
+    /// The positions from `wpos` downwards until the first solid block, at
+    /// most `max_depth` of them.
+    pub(crate) fn gap_below(&mut self, wpos: Vec3<i32>, max_depth: u32) -> Vec<Vec3<i32>> {
+        self.persisted.load(terrain_chunk_key(wpos));
+        (0..max_depth as i32)
+            .map(|depth| wpos - Vec3::unit_z() * depth)
+            .take_while(|pos| !self.get(*pos).kind().is_filled())
+            .collect()
+    }
+
+    /// The block at `wpos`, with chunks that aren't loaded counting as air.
+    pub fn get(&self, wpos: Vec3<i32>) -> Block {
+        self.written
+            .get(&wpos)
+            .copied()
+            .or_else(|| self.persisted.get_loaded(wpos))
+            .unwrap_or_else(Block::empty)
+    }
+}
+
+/// The name of the journal of an unfinished placement in the data directory.
+pub const JOURNAL_FILE: &str = ".vox_placer_journal.bin";
+
+/// The plan of a placement that is writing its chunks, so it can be resumed
+/// or rolled back when it's interrupted. The file holds the plan, followed by
+/// the key of every chunk once it's written, and is removed once the run
+/// completes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Journal {
+    /// The arguments of the run, to place the same again when resuming.
+    pub args: Vec<String>,
+    /// See `placement_hash`.
+    pub hash: u64,
+    /// The seed of every spec.
+    pub seeds: Vec<(String, Option<u64>)>,
+    /// The undo file of the run, written before any chunk.
+    pub undo: PathBuf,
+    /// The terrain chunks to write, in order.
+    pub chunks: Vec<Vec2<i32>>,
+}
+
+impl Journal {
+    /// Writes the journal to `path`, along with the chunks that are already
+    /// `written`, returning the file to record the other chunks in.
+    pub fn start(&self, path: &Path, written: &HashSet<Vec2<i32>>) -> io::Result<std::fs::File> {
+        // The previous journal of a resumed run stays until the new one is
+        // complete.
+        let temp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        bincode::serialize_into(&mut file, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        for key in self.chunks.iter().filter(|key| written.contains(key)) {
+            Self::mark_written(&mut file, *key)?;
+        }
+        std::fs::rename(&temp, path)?;
+        Ok(file)
+    }
+
+    /// Records in the journal `file` that the chunk `key` is written.
+    pub fn mark_written(file: &mut std::fs::File, key: Vec2<i32>) -> io::Result<()> {
+        use std::io::Write;
+        file.write_all(&key.x.to_le_bytes())?;
+        file.write_all(&key.y.to_le_bytes())?;
+        file.sync_data()
+    }
+
+    /// Loads the journal at `path`, along with the chunks it wrote.
+    pub fn load(path: &Path) -> Result<(Self, HashSet<Vec2<i32>>), String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let mut reader = bytes.as_slice();
+        let journal: Self = bincode::deserialize_from(&mut reader)
+            .map_err(|e| format!("Invalid journal {:?}: {}", path, e))?;
+        // A partial key is a chunk that was interrupted while being recorded.
+        let coord = |bytes: &[u8]| i32::from_le_bytes(bytes.try_into().unwrap());
+        let written = reader
+            .chunks_exact(8)
+            .map(|key| Vec2::new(coord(&key[..4]), coord(&key[4..])))
+            .collect();
+        Ok((journal, written))
+    }
+}
+
+/// What a placement overwrote, so it can be reverted.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Undo {
+    /// The persisted block at each written position before the placement, or
+    /// `None` where nothing was persisted.
+    pub prior: Vec<(Vec3<i32>, Option<Block>)>,
+    /// The positions of `prior` written by each piece, by piece name.
+    pub pieces: Vec<PieceWrites>,
+}
+
+/// The positions a piece wrote in a placement.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PieceWrites {
+    pub name: String,
+    /// The positions the piece wrote last.
+    pub positions: Vec<Vec3<i32>>,
+    /// The positions the piece wrote that a later piece wrote again, along
+    /// with the index of that piece in `Undo::pieces`.
+    pub overwritten: Vec<(Vec3<i32>, usize)>,
+}
+
+/// How a `Writer` persists blocks.
+pub enum Persistence {
+    #[cfg(feature = "server")]
+    Server(TerrainPersistence),
+    /// Writes the chunk files itself, from the loaded chunks of the writer.
+    Standalone,
+}
+
+/// Writes blocks to terrain persistence, remembering what they replaced.
+pub struct Writer {
+    pub persistance: Persistence,
+    pub persisted: PersistedBlocks,
+    pub prior: HashMap<Vec3<i32>, Option<Block>>,
+    /// The keys of the modified terrain chunks.
+    pub modified: HashSet<Vec2<i32>>,
+    /// Where to record which chunks are written, along with the plan.
+    pub journal: Option<(PathBuf, Journal)>,
+    /// The chunks an interrupted run already wrote, which are skipped.
+    pub written: HashSet<Vec2<i32>>,
+}
+
+impl Writer {
+    /// Writes to `terrain_dir`, which has to be `VELOREN_TERRAIN` since that
+    /// is the only way to give `TerrainPersistence` its directory. With
+    /// `standalone`, or without the `server` feature, the chunk files are
+    /// written without `TerrainPersistence`.
+    pub fn new(terrain_dir: PathBuf, standalone: bool) -> Self {
+        #[cfg(feature = "server")]
+        let persistance = if standalone {
+            Persistence::Standalone
+        } else {
+            Persistence::Server(TerrainPersistence::new(terrain_dir.clone()))
+        };
+        #[cfg(not(feature = "server"))]
+        let persistance = {
+            let _ = standalone;
+            Persistence::Standalone
+        };
+        Self {
+            persistance,
+            persisted: PersistedBlocks::new(terrain_dir),
+            prior: HashMap::new(),
+            modified: HashSet::new(),
+            journal: None,
+            written: HashSet::new(),
+        }
+    }
+
+    /// Continues the interrupted run that wrote the chunks in `written` and
+    /// recorded `undo` before writing any. Placing the same blocks again
+    /// skips those chunks, and what was there before is taken from `undo`,
+    /// since the chunks on disk may already have the new blocks.
+    pub fn resume(&mut self, written: HashSet<Vec2<i32>>, undo: Undo) {
+        self.prior.extend(undo.prior);
+        self.written = written;
+    }
+
+    pub(crate) fn persist(&mut self, wpos: Vec3<i32>, block: Block) {
+        let key = terrain_chunk_key(wpos);
+        self.modified.insert(key);
+        if self.written.contains(&key) {
+            return;
+        }
+        match &mut self.persistance {
+            #[cfg(feature = "server")]
+            Persistence::Server(persistance) => persistance.set_block(wpos, block),
+            Persistence::Standalone => {
+                self.persisted
+                    .load_mut(key)
+                    .blocks
+                    .insert(terrain_chunk_pos(wpos, key), block);
+            },
+        }
+    }
+
+    /// What the written blocks replaced, so far.
+    pub fn undo(&self) -> Undo {
+        Undo {
+            prior: self.prior.iter().map(|(wpos, block)| (*wpos, *block)).collect(),
+            ..Undo::default()
+        }
+    }
+
+    /// What the written blocks replaced.
+    pub fn into_undo(self) -> Undo {
+        Undo {
+            prior: self.prior.into_iter().collect(),
+            ..Undo::default()
+        }
+    }
+}
+
+impl BlockSink for Writer {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        // Only the first write to a position sees what was there before the
+        // placement.
+        if let Entry::Vacant(entry) = self.prior.entry(wpos) {
+            entry.insert(self.persisted.get(wpos));
+        }
+        self.persist(wpos, block);
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        let chunk = self.persisted.load(key);
+        for (wpos, _) in blocks {
+            if let Entry::Vacant(entry) = self.prior.entry(*wpos) {
+                entry.insert(chunk.blocks.get(&terrain_chunk_pos(*wpos, key)).copied());
+            }
+        }
+        for (wpos, block) in blocks {
+            self.persist(*wpos, *block);
+        }
+    }
+
+    /// Writes the modified chunks one by one, recording each in the journal.
+    fn flush(&mut self) {
+        let mut keys = self.modified.drain().collect::<Vec<_>>();
+        keys.sort_by_key(|key| (key.x, key.y));
+        let mut journal = self.journal.take().map(|(path, mut journal)| {
+            journal.chunks = keys.clone();
+            journal
+                .start(&path, &self.written)
+                .unwrap_or_else(|e| panic!("Failed to write journal {:?}: {}", path, e))
+        });
+        for key in keys {
+            if self.written.contains(&key) {
+                continue;
+            }
+            match &mut self.persistance {
+                #[cfg(feature = "server")]
+                Persistence::Server(persistance) => persistance.unload_chunk(key),
+                Persistence::Standalone => self.persisted.chunks[&key]
+                    .save(&self.persisted.dir, key)
+                    .unwrap_or_else(|e| panic!("Failed to write persisted chunk {}: {}", key, e)),
+            }
+            if let Some(file) = &mut journal {
+                Journal::mark_written(file, key)
+                    .unwrap_or_else(|e| panic!("Failed to write journal: {}", e));
+            }
+        }
+    }
+}
+
+impl Undo {
+    /// Reads the undo file at `path`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        bincode::deserialize(&bytes).or_else(|e| {
+            // Older undo files end after `prior`, without the pieces.
+            let prior = bincode::deserialize(&bytes)
+                .map_err(|_| format!("Invalid undo file {:?}: {}", path, e))?;
+            Ok(Undo {
+                prior,
+                ..Undo::default()
+            })
+        })
+    }
+
+    /// Records which piece wrote each position of `prior`, from the positions
+    /// every piece wrote in the order they were written. Positions written by
+    /// several pieces belong to the last one.
+    ///
+    /// ```
+    /// use map_placer::persistence::Undo;
+    /// use vek::Vec3;
+    ///
+    /// let (a, b) = (Vec3::new(0, 0, 0), Vec3::new(1, 0, 0));
+    /// let mut undo = Undo {
+    ///     prior: vec![(a, None), (b, None)],
+    ///     ..Undo::default()
+    /// };
+    /// undo.attribute(&[
+    ///     ("road".to_string(), vec![a, b]),
+    ///     ("house".to_string(), vec![b]),
+    /// ]);
+    /// assert_eq!(undo.pieces[0].positions, vec![a]);
+    /// assert_eq!(undo.pieces[0].overwritten, vec![(b, 1)]);
+    /// assert_eq!(undo.pieces[1].positions, vec![b]);
+    /// ```
+    pub fn attribute(&mut self, writes: &[(String, Vec<Vec3<i32>>)]) {
+        let written = self.prior.iter().map(|(wpos, _)| *wpos).collect::<HashSet<_>>();
+        let mut pieces = Vec::<PieceWrites>::new();
+        let mut index_of = |name: &str| match pieces.iter().position(|p| p.name == name) {
+            Some(index) => index,
+            None => {
+                pieces.push(PieceWrites {
+                    name: name.to_string(),
+                    ..PieceWrites::default()
+                });
+                pieces.len() - 1
+            },
+        };
+        let writes = writes
+            .iter()
+            .map(|(name, positions)| (index_of(name), positions))
+            .collect::<Vec<_>>();
+        // The last writer of every position is found first, then the writes
+        // of every piece are split into what it kept and what was overwritten.
+        let mut last = HashMap::new();
+        for (index, positions) in &writes {
+            for wpos in positions.iter().filter(|wpos| written.contains(wpos)) {
+                last.insert(*wpos, *index);
+            }
+        }
+        for (index, positions) in writes {
+            for wpos in positions.iter().filter(|wpos| written.contains(wpos)) {
+                match last[wpos] {
+                    writer if writer == index => pieces[index].positions.push(*wpos),
+                    writer => pieces[index].overwritten.push((*wpos, writer)),
+                }
+            }
+        }
+        for piece in &mut pieces {
+            piece.positions.sort_unstable_by_key(|wpos| wpos.into_array());
+            piece.positions.dedup();
+            piece.overwritten.sort_unstable_by_key(|(wpos, _)| wpos.into_array());
+            piece.overwritten.dedup();
+        }
+        self.pieces = pieces;
+    }
+
+    /// Writes the undo file to `path`, panicking if that fails.
+    pub fn save(&self, path: &Path) {
+        std::fs::write(
+            path,
+            bincode::serialize(self).expect("Failed to serialize undo data"),
+        )
+        .unwrap_or_else(|e| panic!("Failed to write undo file {:?}: {}", path, e));
+        println!(
+            "Wrote undo file {}, revert with `undo {}`",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+/// The persisted blocks within `bounds`, relative to its minimum corner.
+pub fn copy_region(bounds: Aabb<i32>, terrain_dir: &Path) -> Template {
+    let mut blocks = Vec::new();
+    let (min_key, max_key) = (terrain_chunk_key(bounds.min), terrain_chunk_key(bounds.max));
+    for x in min_key.x..=max_key.x {
+        for y in min_key.y..=max_key.y {
+            let key = Vec2::new(x, y);
+            let Some(chunk) = PersistedChunk::load(terrain_dir, key)
+                .unwrap_or_else(|e| panic!("Failed to read persisted chunk {}: {}", key, e))
+            else {
+                continue;
+            };
+            for (pos, block) in chunk.blocks {
+                let wpos = terrain_world_pos(pos, key);
+                if bounds.contains_point(wpos) {
+                    blocks.push(((wpos - bounds.min).into_array(), block));
+                }
+            }
+        }
+    }
+    // Sorted, so copying the same region twice gives the same file.
+    blocks.sort_by_key(|(pos, _)| *pos);
+    Template {
+        name: None,
+        bounds: Some(Aabb {
+            min: Vec3::zero(),
+            max: bounds.max - bounds.min,
+        }),
+        blocks,
+    }
+}
+
+/// Removes the persisted blocks within `bounds`, so the terrain generates
+/// naturally again. Only the chunks intersecting `bounds` are loaded.
+pub fn clear_region(bounds: Aabb<i32>, terrain_dir: &Path, verbosity: Verbosity) -> Undo {
+    let mut prior = Vec::new();
+    let (min_key, max_key) = (terrain_chunk_key(bounds.min), terrain_chunk_key(bounds.max));
+    for x in min_key.x..=max_key.x {
+        for y in min_key.y..=max_key.y {
+            let key = Vec2::new(x, y);
+            let Some(mut chunk) = PersistedChunk::load(terrain_dir, key)
+                .unwrap_or_else(|e| panic!("Failed to read persisted chunk {}: {}", key, e))
+            else {
+                continue;
+            };
+            let before = prior.len();
+            chunk.blocks.retain(|pos, block| {
+                let wpos = terrain_world_pos(*pos, key);
+                let cleared = bounds.contains_point(wpos);
+                if cleared {
+                    prior.push((wpos, Some(*block)));
+                }
+                !cleared
+            });
+            let removed = prior.len() - before;
+            if removed > 0 {
+                debug!("Removed {} persisted blocks in chunk {}", removed, key);
+                chunk
+                    .save(terrain_dir, key)
+                    .unwrap_or_else(|e| panic!("Failed to write persisted chunk {}: {}", key, e));
+            }
+        }
+    }
+    if verbosity > Verbosity::Quiet {
+        println!("Removed {} persisted blocks in total", prior.len());
+    }
+    Undo {
+        prior,
+        ..Undo::default()
+    }
+}
+
+/// Restores the persisted blocks recorded in the undo file at `path`.
+pub fn apply_undo(path: &Path, terrain_dir: &Path) {
+    let undo = Undo::load(path).unwrap_or_else(|e| panic!("{}", e));
+    restore_blocks(undo.prior, terrain_dir);
+}
+
+/// Restores the persisted blocks written by the piece `name` in the undo file
+/// at `path`. Positions a later piece wrote again are only restored with
+/// `force`, otherwise they are counted by the piece that wrote them.
+pub fn apply_piece_undo(
+    path: &Path,
+    terrain_dir: &Path,
+    name: &str,
+    force: bool,
+) -> Result<(usize, Vec<(String, usize)>), String> {
+    let undo = Undo::load(path)?;
+    let Some(piece) = undo.pieces.iter().find(|piece| piece.name == name) else {
+        let mut names = undo.pieces.iter().map(|piece| piece.name.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        return Err(format!(
+            "No piece called {} wrote blocks in {:?}, the pieces are {:?}",
+            name, path, names
+        ));
+    };
+    let mut positions = piece.positions.iter().copied().collect::<HashSet<_>>();
+    let mut skipped = BTreeMap::<usize, usize>::new();
+    for (wpos, writer) in &piece.overwritten {
+        if force {
+            positions.insert(*wpos);
+        } else {
+            *skipped.entry(*writer).or_default() += 1;
+        }
+    }
+    let blocks = undo
+        .prior
+        .into_iter()
+        .filter(|(wpos, _)| positions.contains(wpos))
+        .collect::<Vec<_>>();
+    let restored = blocks.len();
+    restore_blocks(blocks, terrain_dir);
+    let skipped = skipped
+        .into_iter()
+        .map(|(writer, count)| (undo.pieces[writer].name.clone(), count))
+        .collect();
+    Ok((restored, skipped))
+}
+
+/// Writes the given blocks to the persisted chunks, removing the ones that are
+/// `None`.
+pub fn restore_blocks(blocks: Vec<(Vec3<i32>, Option<Block>)>, terrain_dir: &Path) {
+    let mut chunks = HashMap::<_, Vec<_>>::new();
+    for (wpos, block) in blocks {
+        chunks
+            .entry(terrain_chunk_key(wpos))
+            .or_default()
+            .push((wpos, block));
+    }
+    for (key, blocks) in chunks {
+        let mut chunk = PersistedChunk::load(terrain_dir, key)
+            .unwrap_or_else(|e| panic!("Failed to read persisted chunk {}: {}", key, e))
+            .unwrap_or_default();
+        debug!("Restoring {} blocks in chunk {}", blocks.len(), key);
+        for (wpos, block) in blocks {
+            let pos = terrain_chunk_pos(wpos, key);
+            match block {
+                Some(block) => {
+                    chunk.blocks.insert(pos, block);
+                },
+                None => {
+                    chunk.blocks.remove(&pos);
+                },
+            }
+        }
+        chunk
+            .save(terrain_dir, key)
+            .unwrap_or_else(|e| panic!("Failed to write persisted chunk {}: {}", key, e));
+    }
+}
+
+/// Reverts what the interrupted run of `journal` wrote, from its undo file.
+pub fn rollback(journal_path: &Path, journal: Journal, written: usize, terrain_dir: &Path) {
+    let undo = Undo::load(&journal.undo).unwrap_or_else(|e| panic!("{}", e));
+    // Chunks the run didn't write are restored to what they already have,
+    // which also covers a chunk that was interrupted while being written.
+    restore_blocks(undo.prior, terrain_dir);
+    std::fs::remove_file(&journal.undo)
+        .unwrap_or_else(|e| panic!("Failed to remove undo file {:?}: {}", journal.undo, e));
+    std::fs::remove_file(journal_path)
+        .unwrap_or_else(|e| panic!("Failed to remove journal {:?}: {}", journal_path, e));
+    println!(
+        "Rolled back the interrupted placement, which wrote {} of {} terrain chunks",
+        written,
+        journal.chunks.len()
+    );
+}
+
+/// A block that reads back differently from what was written.
+#[derive(Clone, Copy, Debug)]
+pub struct Mismatch {
+    pub wpos: Vec3<i32>,
+    pub written: Block,
+    /// `None` where nothing is persisted.
+    pub found: Option<Block>,
+}
+
+/// Reads the chunks of the `written` blocks back from `terrain_dir`, and
+/// returns the positions that don't have the block written there.
+pub fn verify_written(
+    written: &HashMap<Vec3<i32>, Block>,
+    terrain_dir: &Path,
+) -> io::Result<Vec<Mismatch>> {
+    let mut chunks = HashMap::<_, Vec<_>>::new();
+    for (wpos, block) in written {
+        chunks
+            .entry(terrain_chunk_key(*wpos))
+            .or_default()
+            .push((*wpos, *block));
+    }
+    let mut mismatches = Vec::new();
+    for (key, blocks) in chunks {
+        let chunk = PersistedChunk::load(terrain_dir, key)?.unwrap_or_default();
+        for (wpos, block) in blocks {
+            let found = chunk.blocks.get(&terrain_chunk_pos(wpos, key)).copied();
+            if found != Some(block) {
+                mismatches.push(Mismatch {
+                    wpos,
+                    written: block,
+                    found,
+                });
+            }
+        }
+    }
+    mismatches.sort_by_key(|mismatch| {
+        let wpos = mismatch.wpos;
+        (wpos.z, wpos.y, wpos.x)
+    });
+    Ok(mismatches)
+}
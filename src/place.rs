@@ -0,0 +1,1361 @@
+//! Placing built pieces into the world.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::Path,
+    sync::{atomic::AtomicUsize, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use vek::*;
+use veloren_common::{
+    figure::Cell,
+    terrain::{Block, BlockKind, SpriteKind},
+    vol::{IntoFullVolIterator, ReadVol, VolSize},
+};
+
+use crate::{
+    persistence::{terrain_chunk_key, World},
+    resolve::{
+        resolve_block, splitmix64, vacant_block, Fallback, PosRng, ReplaceMap, ResolveCtx,
+        Resolved, SpriteChances,
+    },
+    scene::{CellOrigin, PieceBounds, SparseScene, SscSize},
+    sink::BlockSink,
+    spec::{GlowLimit, Merge, PlaceSpec, SpriteSupport},
+};
+
+/// Keeps `max` of the glowing blocks at `glowing` in `blocks`, evenly spread
+/// over them in position order from an offset picked with `seed`, and turns the
+/// others into blocks that don't glow. Returns which blocks were kept.
+pub(crate) fn thin_glow(
+    blocks: &mut HashMap<Vec3<i32>, Block>,
+    mut glowing: Vec<Vec3<i32>>,
+    max: usize,
+    seed: u64,
+) -> Vec<(Vec3<i32>, bool)> {
+    glowing.sort_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+    let count = glowing.len();
+    let start = (splitmix64(seed) % count.max(1) as u64) as usize;
+    let kept = (0..max)
+        .map(|i| (start + i * count / max) % count)
+        .collect::<HashSet<_>>();
+    glowing
+        .into_iter()
+        .enumerate()
+        .map(|(i, wpos)| {
+            let keep = kept.contains(&i);
+            if !keep {
+                blocks.insert(wpos, unlit(blocks[&wpos]));
+            }
+            (wpos, keep)
+        })
+        .collect()
+}
+
+/// A block like `block` that doesn't glow: rock of the same color, or empty if
+/// it isn't solid.
+fn unlit(block: Block) -> Block {
+    if block.kind().is_filled() {
+        Block::new(BlockKind::Rock, block.get_color().unwrap_or_default())
+    } else {
+        block.into_vacant()
+    }
+}
+
+/// Turns the glowing blocks in `blocks` without an empty position within
+/// `radius` steps along the axes into blocks that don't glow, and returns
+/// where they are. Positions outside of `blocks` count as empty.
+pub(crate) fn strip_hidden_glow(
+    blocks: &mut HashMap<Vec3<i32>, Block>,
+    radius: u32,
+) -> Vec<Vec3<i32>> {
+    let radius = radius as i32;
+    let mut offsets = Vec::new();
+    for z in -radius..=radius {
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                let offset = Vec3::new(x, y, z);
+                if offset != Vec3::zero() && offset.map(i32::abs).sum() <= radius {
+                    offsets.push(offset);
+                }
+            }
+        }
+    }
+    let solid = |wpos: Vec3<i32>| blocks.get(&wpos).map_or(false, |block| block.kind().is_filled());
+    let mut hidden = blocks
+        .iter()
+        .filter(|(_, block)| block.get_glow().is_some())
+        .map(|(wpos, _)| *wpos)
+        .filter(|wpos| offsets.iter().all(|offset| solid(*wpos + *offset)))
+        .collect::<Vec<_>>();
+    hidden.sort_unstable_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+    for wpos in &hidden {
+        blocks.insert(*wpos, unlit(blocks[wpos]));
+    }
+    hidden
+}
+
+/// A piece or group of a placement listed in the POI file, for map markers.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Poi {
+    pub name: String,
+    pub kind: String,
+    /// The mean world position of the voxels, or the middle of the bounds if
+    /// there are none.
+    pub center: [f32; 3],
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+}
+
+/// The points of interest of `pieces` built from `place_spec`: the pieces and
+/// groups with a `poi` kind, groups with the pieces of the groups in them.
+pub fn points_of_interest(place_spec: &PlaceSpec, pieces: &[BuiltPiece]) -> Vec<Poi> {
+    let poi = |name: &str, kind: &str, members: Vec<&PieceBounds>| {
+        let aabb = members.iter().map(|bounds| bounds.aabb).reduce(Aabb::union)?;
+        let (voxels, sum) = members
+            .iter()
+            .filter_map(|bounds| Some((bounds.voxels as f32, bounds.centroid?)))
+            .fold((0.0, Vec3::<f32>::zero()), |(voxels, sum), (count, centroid)| {
+                (voxels + count, sum + centroid * count)
+            });
+        let center = if voxels > 0.0 {
+            sum / voxels
+        } else {
+            (aabb.min + aabb.max).map(|e| e as f32) / 2.0
+        };
+        Some(Poi {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            center: center.into_array(),
+            min: aabb.min.into_array(),
+            max: aabb.max.into_array(),
+        })
+    };
+    let mut pois = Vec::new();
+    for piece in pieces {
+        let spec = &place_spec.pieces[usize::from(piece.index)];
+        if let (Some(kind), Some(bounds)) = (&spec.options.poi, &piece.bounds) {
+            pois.extend(poi(spec.name(), kind, vec![bounds]));
+        }
+    }
+    for group in &place_spec.groups {
+        let Some(kind) = &group.poi else {
+            continue;
+        };
+        let members = pieces
+            .iter()
+            .filter(|piece| {
+                let frame = place_spec.pieces[usize::from(piece.index)].options.group.as_ref();
+                frame.map_or(false, |frame| {
+                    frame.name == group.name || frame.outer.as_ref() == Some(&group.name)
+                })
+            })
+            .filter_map(|piece| piece.bounds.as_ref())
+            .collect();
+        pois.extend(poi(&group.name, kind, members));
+    }
+    pois
+}
+
+/// The version of the context files this version writes and reads.
+pub const CONTEXT_VERSION: u32 = 1;
+
+/// What a placement built, for later placements to align to: the bounds,
+/// anchors and markers of its pieces and groups by name, in world
+/// coordinates.
+///
+/// ```
+/// use map_placer::place::{Context, CONTEXT_VERSION};
+///
+/// let context: Context = ron::from_str(
+///     r#"(
+///         version: 1,
+///         entries: {"platform": (min: (0, 0, 0), max: (31, 31, 4), anchors: [], markers: {})},
+///     )"#,
+/// )
+/// .unwrap();
+/// assert_eq!(context.version, CONTEXT_VERSION);
+/// assert_eq!(context.entries["platform"].max, [31, 31, 4]);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Context {
+    pub version: u32,
+    pub entries: BTreeMap<String, ContextEntry>,
+}
+
+/// A piece or group of a context. Several pieces with the same name, like
+/// the instances of a repeated piece, are one entry covering all of them.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ContextEntry {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+    pub anchors: Vec<[i32; 3]>,
+    pub markers: BTreeMap<String, Vec<[i32; 3]>>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            version: CONTEXT_VERSION,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Context {
+    /// Adds the pieces built from `place_spec` by their name, and its groups
+    /// with the pieces of the groups in them.
+    pub fn add(&mut self, place_spec: &PlaceSpec, pieces: &[BuiltPiece]) {
+        for piece in pieces {
+            let spec = &place_spec.pieces[usize::from(piece.index)];
+            if let Some(bounds) = &piece.bounds {
+                self.insert(spec.name(), bounds);
+            }
+        }
+        for group in &place_spec.groups {
+            for piece in pieces {
+                let frame = place_spec.pieces[usize::from(piece.index)].options.group.as_ref();
+                let member = frame.map_or(false, |frame| {
+                    frame.name == group.name || frame.outer.as_ref() == Some(&group.name)
+                });
+                if let (true, Some(bounds)) = (member, &piece.bounds) {
+                    self.insert(&group.name, bounds);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, name: &str, bounds: &PieceBounds) {
+        let entry = self.entries.entry(name.to_string()).or_insert_with(|| ContextEntry {
+            min: bounds.aabb.min.into_array(),
+            max: bounds.aabb.max.into_array(),
+            anchors: Vec::new(),
+            markers: BTreeMap::new(),
+        });
+        let aabb = Aabb {
+            min: Vec3::from(entry.min),
+            max: Vec3::from(entry.max),
+        }
+        .union(bounds.aabb);
+        entry.min = aabb.min.into_array();
+        entry.max = aabb.max.into_array();
+        entry.anchors.extend(bounds.anchors.iter().map(|anchor| anchor.into_array()));
+        for (marker, positions) in &bounds.markers {
+            let entry = entry.markers.entry(marker.clone()).or_default();
+            entry.extend(positions.iter().map(|wpos| wpos.into_array()));
+        }
+    }
+
+    /// Loads the context at `path`, failing unless this version can read it.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        let ron = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read context {:?}: {}", path, e))?;
+        let version = ron::from_str::<Version>(&ron)
+            .map_err(|e| format!("Could not parse context {:?}: {}", path, e))?
+            .version;
+        if version != CONTEXT_VERSION {
+            return Err(format!(
+                "The context {:?} has version {}, this version only reads version {}",
+                path, version, CONTEXT_VERSION
+            ));
+        }
+        ron::from_str(&ron).map_err(|e| format!("Could not parse context {:?}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let ron = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())
+            .expect("Failed to serialize context");
+        std::fs::write(path, ron)
+    }
+}
+
+/// Sprites mounted on the wall behind them rather than on the block below.
+pub const WALL_SPRITES: &[SpriteKind] =
+    &[SpriteKind::WallLamp, SpriteKind::WallLampSmall, SpriteKind::WallSconce];
+
+/// The direction of the block a sprite rests on: below it, or behind it for
+/// sprites on walls.
+pub(crate) fn support_dir(block: Block) -> Vec3<i32> {
+    match block.get_sprite() {
+        Some(kind) if WALL_SPRITES.contains(&kind) => {
+            // Orientations are in eighths of a turn, diagonal ones are
+            // rounded down.
+            match block.get_ori().unwrap_or(0) / 2 % 4 {
+                0 => Vec3::new(0, -1, 0),
+                1 => Vec3::new(1, 0, 0),
+                2 => Vec3::new(0, 1, 0),
+                _ => Vec3::new(-1, 0, 0),
+            }
+        },
+        _ => -Vec3::unit_z(),
+    }
+}
+
+/// Checks that every sprite in `blocks` rests on a solid block, in `blocks`
+/// or already in `world`, and handles those that don't like `support` says.
+pub(crate) fn support_sprites(
+    blocks: &mut HashMap<Vec3<i32>, Block>,
+    world: &World,
+    support: SpriteSupport,
+    report: &mut PlaceReport,
+) {
+    let solid = |blocks: &HashMap<Vec3<i32>, Block>, wpos: Vec3<i32>| {
+        blocks
+            .get(&wpos)
+            .copied()
+            .unwrap_or_else(|| world.get(wpos))
+            .kind()
+            .is_filled()
+    };
+    let mut sprites = blocks
+        .iter()
+        .filter(|(_, block)| block.get_sprite().map_or(false, |s| s != SpriteKind::Empty))
+        .map(|(wpos, block)| (*wpos, *block))
+        .collect::<Vec<_>>();
+    // Lower sprites settle first.
+    sprites.sort_by_key(|(wpos, _)| (wpos.z, wpos.y, wpos.x));
+    for (wpos, block) in sprites {
+        let dir = support_dir(block);
+        if solid(blocks, wpos + dir) {
+            continue;
+        }
+        blocks.insert(wpos, block.into_vacant());
+        let settled = match support {
+            SpriteSupport::Settle(max) if dir == -Vec3::unit_z() => (1..=i32::from(max))
+                .map(|depth| wpos - Vec3::unit_z() * depth)
+                .take_while(|pos| !solid(blocks, *pos))
+                .find(|pos| solid(blocks, *pos - Vec3::unit_z())),
+            _ => None,
+        };
+        let kind = block.get_sprite().unwrap_or(SpriteKind::Empty);
+        match (settled, support) {
+            (Some(target), _) => {
+                blocks.insert(target, block);
+                report.settled_sprites += 1;
+                for sprite in report.special_sprites.iter_mut().filter(|s| s.pos == wpos) {
+                    sprite.pos = target;
+                }
+                continue;
+            },
+            (None, SpriteSupport::Error) => report.sprite_errors.push((wpos, kind)),
+            (None, _) => report.dropped_sprites += 1,
+        }
+        report.special_sprites.retain(|sprite| sprite.pos != wpos);
+    }
+}
+
+/// A piece of a spec, built on its own.
+#[derive(Clone, Debug)]
+pub struct BuiltPiece {
+    pub index: u16,
+    pub scene: SparseScene,
+    pub aabbs: Vec<Aabb<i32>>,
+    /// `None` for pieces without any cells.
+    pub bounds: Option<PieceBounds>,
+    /// The bounds of the piece relative to the origin of its group, or of the
+    /// spec, before they are turned.
+    pub local_aabb: Option<Aabb<i32>>,
+    pub merge: Merge,
+    /// The priority of the cells of the piece over the ones of other pieces.
+    pub priority: i32,
+    /// The transform of the piece, before it was moved to the surface.
+    pub rot: Mat3<i32>,
+    pub offset: Vec3<i32>,
+    /// The number of voxels dropped by the decay of the piece.
+    pub decayed: usize,
+    /// Whether the empty cells within the bounds of the piece are filled.
+    pub fill_empty: bool,
+    /// The lowest and highest z filled in every column of the piece, when it
+    /// fills its footprint rather than its bounds.
+    pub fill_footprint: Option<HashMap<Vec2<i32>, (i32, i32)>>,
+    /// The regions cut out of the piece, in world space.
+    pub cutouts: Vec<Aabb<i32>>,
+    /// The positions of the skirt of the piece, and whether each is part of
+    /// its berm.
+    pub skirt: Vec<(Vec3<i32>, bool)>,
+}
+
+/// The merged scene of several pieces.
+#[derive(Clone, Debug)]
+pub struct MergedScene {
+    pub scene: SparseScene,
+    pub aabbs: Vec<Aabb<i32>>,
+    /// The bounds of each piece by index.
+    pub piece_bounds: HashMap<u16, Aabb<i32>>,
+    /// Where the pieces that fill their empty cells fill them.
+    pub fill: FillRegion,
+    /// The regions cut out of each piece, by the index of the piece.
+    pub cutouts: Vec<(u16, Aabb<i32>)>,
+    /// The skirts of the pieces, by the index of the piece.
+    pub skirts: Vec<(u16, Vec3<i32>, bool)>,
+    /// The cells of pieces that were kept over the ones of later pieces with
+    /// a lower priority, by the kept and the later piece.
+    pub suppressed: BTreeMap<(u16, u16), usize>,
+}
+
+/// The positions of a merged scene whose empty cells are filled.
+#[derive(Clone, Debug, Default)]
+pub struct FillRegion {
+    /// The bounds of the pieces that fill their bounds.
+    pub aabbs: Vec<Aabb<i32>>,
+    /// The ranges of z filled in every column of the pieces that fill their
+    /// footprint.
+    pub columns: HashMap<Vec2<i32>, Vec<(i32, i32)>>,
+}
+
+impl FillRegion {
+    /// Whether the empty cell at `wpos` is filled.
+    pub fn contains(&self, wpos: Vec3<i32>) -> bool {
+        self.aabbs.iter().any(|aabb| aabb.contains_point(wpos))
+            || self.columns.get(&wpos.xy()).map_or(false, |ranges| {
+                ranges.iter().any(|(low, high)| (*low..=*high).contains(&wpos.z))
+            })
+    }
+
+    /// Bounds covering every filled position.
+    pub fn bounds(&self) -> impl Iterator<Item = Aabb<i32>> + '_ {
+        let columns = self.columns.iter().flat_map(|(column, ranges)| {
+            ranges.iter().map(move |(low, high)| Aabb {
+                min: Vec3::from((*column, *low)),
+                max: Vec3::from((*column, *high)),
+            })
+        });
+        self.aabbs.iter().copied().chain(columns)
+    }
+}
+
+/// Merges the scenes of built pieces, later pieces overwrite earlier ones.
+pub fn merge_pieces<'a>(pieces: impl Iterator<Item = &'a BuiltPiece>) -> MergedScene {
+    let mut scene = SparseScene::empty();
+    let mut aabbs = Vec::new();
+    let mut piece_bounds = HashMap::new();
+    let mut fill = FillRegion::default();
+    let mut cutouts = Vec::new();
+    let mut skirts = Vec::new();
+    let mut priorities = HashMap::new();
+    let mut suppressed = BTreeMap::new();
+    for piece in pieces {
+        if let (Merge::CutOut, Some(bounds)) = (piece.merge, &piece.bounds) {
+            scene.clear(bounds.aabb);
+        }
+        scene.merge(&piece.scene, piece.merge, |existing| {
+            let outranks = priorities.get(&existing).map_or(false, |p| *p > piece.priority);
+            if outranks {
+                *suppressed.entry((existing, piece.index)).or_default() += 1;
+            }
+            outranks
+        });
+        priorities.insert(piece.index, piece.priority);
+        aabbs.extend(&piece.aabbs);
+        if piece.fill_empty {
+            match &piece.fill_footprint {
+                Some(columns) => {
+                    for (column, range) in columns {
+                        fill.columns.entry(*column).or_default().push(*range);
+                    }
+                },
+                None => fill.aabbs.extend(&piece.aabbs),
+            }
+        }
+        cutouts.extend(piece.cutouts.iter().map(|cutout| (piece.index, *cutout)));
+        skirts.extend(piece.skirt.iter().map(|(wpos, berm)| (piece.index, *wpos, *berm)));
+        if let Some(bounds) = piece.aabbs.iter().copied().reduce(|a, b| a.union(b)) {
+            piece_bounds.insert(piece.index, bounds);
+        }
+    }
+    MergedScene {
+        scene,
+        aabbs,
+        piece_bounds,
+        fill,
+        cutouts,
+        skirts,
+        suppressed,
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Verbosity {
+    /// Only warnings, errors and the final summary.
+    Quiet,
+    /// A progress bar and info messages.
+    #[default]
+    Normal,
+    /// A debug message with timings for every chunk.
+    Verbose,
+}
+
+/// Options for how specs are placed.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PlaceOptions {
+    pub verbosity: Verbosity,
+    /// Use a thread local RNG instead of seeding it from the position, which
+    /// makes placements differ between runs.
+    pub thread_rng: bool,
+    /// Overrides the seed of every spec.
+    pub seed: Option<u64>,
+}
+
+impl PlaceOptions {
+    /// The seed to place `place_spec` with, `None` with a thread local RNG.
+    /// Every variant uses the seed after the one of the variant before it.
+    pub fn seed_of(&self, place_spec: &PlaceSpec) -> Option<u64> {
+        (!self.thread_rng).then(|| {
+            let seed = self.seed.or(place_spec.seed).unwrap_or(0);
+            seed.wrapping_add(u64::from(place_spec.variant))
+        })
+    }
+}
+
+/// A sprite that needs more than its block, like a chest with loot, for a
+/// server plugin or a manual step to set up.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpecialSprite {
+    /// The world position of the sprite.
+    pub pos: Vec3<i32>,
+    pub kind: SpriteKind,
+    pub loot: String,
+}
+
+/// Adds `sprites` to the sprite manifest at `path`, replacing what it had with
+/// `fresh`.
+pub fn save_special_sprites(
+    sprites: Vec<SpecialSprite>,
+    path: &Path,
+    fresh: bool,
+) -> io::Result<()> {
+    let mut all = Vec::<SpecialSprite>::new();
+    if !fresh {
+        if let Ok(existing) = std::fs::read_to_string(path) {
+            all = ron::from_str(&existing).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid manifest: {}", e))
+            })?;
+        }
+    }
+    all.extend(sprites);
+    let manifest = ron::ser::to_string_pretty(&all, ron::ser::PrettyConfig::default())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    std::fs::write(path, manifest)
+}
+
+/// Statistics about the blocks a placement writes.
+#[derive(Default, Clone, Debug)]
+pub struct PlaceStats {
+    pub terrain_chunks: HashSet<Vec2<i32>>,
+    pub blocks: usize,
+    pub kinds: HashMap<BlockKind, usize>,
+    pub bounds: Option<Aabb<i32>>,
+    pub glowing: usize,
+}
+
+impl BlockSink for PlaceStats {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        self.terrain_chunks.insert(terrain_chunk_key(wpos));
+        self.record(wpos, &block);
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        self.terrain_chunks.insert(key);
+        for (wpos, block) in blocks {
+            self.record(*wpos, block);
+        }
+    }
+
+    fn flush(&mut self) {}
+}
+
+impl PlaceStats {
+    /// Records a block, without its terrain chunk.
+    pub fn record(&mut self, wpos: Vec3<i32>, block: &Block) {
+        self.blocks += 1;
+        *self.kinds.entry(block.kind()).or_default() += 1;
+        if block.get_glow().is_some() {
+            self.glowing += 1;
+        }
+        self.bounds = Some(match self.bounds {
+            Some(bounds) => bounds.expanded_to_contain_point(wpos),
+            None => Aabb {
+                min: wpos,
+                max: wpos,
+            },
+        });
+    }
+
+    /// Prints the statistics, along with how fast the blocks were placed.
+    pub fn print_summary(&self, elapsed: Duration) {
+        println!("Terrain chunks touched: {}", self.terrain_chunks.len());
+        println!("Blocks placed: {}", self.blocks);
+        println!("Glowing blocks: {}", self.glowing);
+        println!(
+            "Took {:.2?}, {:.0} blocks per second",
+            elapsed,
+            self.blocks as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+        if let Some(bounds) = self.bounds {
+            println!("Bounds: {} to {}", bounds.min, bounds.max);
+        }
+        let mut kinds = self.kinds.iter().collect::<Vec<_>>();
+        kinds.sort_by(|a, b| b.1.cmp(a.1));
+        println!("{:<20} {:>12}", "Block kind", "Count");
+        for (kind, count) in kinds {
+            println!("{:<20} {:>12}", format!("{:?}", kind), count);
+        }
+    }
+}
+
+/// The colors that weren't replaced, with the number of voxels and an example
+/// position for each.
+#[derive(Default, Clone, Debug)]
+pub struct UnmappedColors(pub HashMap<(Rgb<u8>, Fallback), (usize, Vec3<i32>)>);
+
+impl UnmappedColors {
+    /// Records `cell` if neither its color or palette index are in
+    /// `replace_map`.
+    pub fn record(
+        &mut self,
+        cell: &Cell,
+        origin: CellOrigin,
+        wpos: Vec3<i32>,
+        replace_map: &ReplaceMap,
+    ) {
+        if let Some(color) = cell
+            .get_color()
+            .filter(|color| replace_map.get(*color, origin.index).is_none())
+        {
+            self.0
+                .entry((color, Fallback::of(cell)))
+                .or_insert((0, wpos))
+                .0 += 1;
+        }
+    }
+
+    /// Adds the colors of `other`.
+    pub fn extend(&mut self, other: Self) {
+        for (color, (count, wpos)) in other.0 {
+            self.0.entry(color).or_insert((0, wpos)).0 += count;
+        }
+    }
+
+    /// Warns about every unmapped color and prints them as a table.
+    pub fn print(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut colors = self.0.iter().collect::<Vec<_>>();
+        colors.sort_by(|a, b| b.1 .0.cmp(&a.1 .0));
+        for ((color, fallback), (count, wpos)) in &colors {
+            warn!(
+                color = ?color.into_array(),
+                fallback = ?fallback,
+                voxels = count,
+                example = %wpos,
+                "Unmapped color placed as a fallback block"
+            );
+        }
+        println!("Unmapped colors, placed as fallback blocks:");
+        println!("{:<16} {:<8} {:>12}  Example", "Color", "Fallback", "Voxels");
+        for ((color, fallback), (count, wpos)) in colors {
+            println!(
+                "{:<16} {:<8} {:>12}  {}",
+                format!("{:?}", color.into_array()),
+                format!("{:?}", fallback),
+                count,
+                wpos
+            );
+        }
+    }
+}
+
+/// What placing scenes reports besides the blocks it writes.
+#[derive(Default, Clone, Debug)]
+pub struct PlaceReport {
+    pub unmapped: UnmappedColors,
+    /// Sprites below the water level that were explicitly placed in air.
+    pub kept_air: usize,
+    /// Colors of pieces that were replaced like a close color, along with
+    /// that color.
+    pub approximate_colors: Vec<(String, Rgb<u8>, Rgb<u8>)>,
+    /// Sample colors of specs with a `color_transform`, along with the color
+    /// they are placed with.
+    pub color_samples: Vec<(String, Rgb<u8>, Rgb<u8>)>,
+    pub special_sprites: Vec<SpecialSprite>,
+    pub sprite_chances: SpriteChances,
+    /// Sprites without support that were left out.
+    pub dropped_sprites: usize,
+    /// Sprites without support that were moved down onto one.
+    pub settled_sprites: usize,
+    /// Sprites without support that were left out and are errors.
+    pub sprite_errors: Vec<(Vec3<i32>, SpriteKind)>,
+    /// Cells that didn't place a block.
+    pub skipped_voxels: usize,
+    /// Voxels dropped by the decay of their piece.
+    pub decayed_voxels: usize,
+    /// Blocks deposited by the overlay of the spec.
+    pub overlaid: usize,
+    /// The glowing blocks kept and removed of every piece by `GlowLimit::Thin`.
+    pub thinned_glow: Vec<(String, usize, usize)>,
+    /// The glowing blocks without an empty neighbor of every piece that were
+    /// turned into blocks that don't glow.
+    pub stripped_glow: Vec<(String, usize)>,
+    /// The glowing blocks of specs that placed more than their maximum, along
+    /// with the maximum.
+    pub glow_errors: Vec<(usize, usize)>,
+    /// The cells of pieces kept over the ones of later pieces with a lower
+    /// priority, by the kept and the later piece.
+    pub suppressed: Vec<(String, String, usize)>,
+    /// The positions written by every piece, by the name of the piece, in
+    /// the order they were written.
+    pub writes: Vec<(String, Vec<Vec3<i32>>)>,
+}
+
+impl PlaceReport {
+    /// Adds the report of another placement.
+    pub fn extend(&mut self, other: Self) {
+        self.unmapped.extend(other.unmapped);
+        self.kept_air += other.kept_air;
+        self.approximate_colors.extend(other.approximate_colors);
+        self.color_samples.extend(other.color_samples);
+        self.special_sprites.extend(other.special_sprites);
+        for (key, (placed, skipped)) in other.sprite_chances {
+            let counts = self.sprite_chances.entry(key).or_default();
+            counts.0 += placed;
+            counts.1 += skipped;
+        }
+        self.dropped_sprites += other.dropped_sprites;
+        self.settled_sprites += other.settled_sprites;
+        self.sprite_errors.extend(other.sprite_errors);
+        self.skipped_voxels += other.skipped_voxels;
+        self.decayed_voxels += other.decayed_voxels;
+        self.overlaid += other.overlaid;
+        self.thinned_glow.extend(other.thinned_glow);
+        self.stripped_glow.extend(other.stripped_glow);
+        self.glow_errors.extend(other.glow_errors);
+        self.suppressed.extend(other.suppressed);
+        self.writes.extend(other.writes);
+    }
+
+    /// The counts of `sprite_chances`, by color and then sprite.
+    pub fn sorted_sprite_chances(&self) -> Vec<((Rgb<u8>, SpriteKind), (usize, usize))> {
+        let mut chances = self.sprite_chances.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        chances.sort_by_key(|((color, kind), _)| (color.into_array(), format!("{:?}", kind)));
+        chances
+    }
+
+    /// Prints or logs everything the report found.
+    pub fn print(&self) {
+        if self.kept_air > 0 {
+            warn!(
+                "{} sprites below the water level were placed in air, because their medium is \
+                 Air",
+                self.kept_air
+            );
+        }
+        if !self.approximate_colors.is_empty() {
+            println!("Colors replaced within the color tolerance:");
+            println!("{:<32} {:<16} {:<16}", "Piece", "Color", "Replaced as");
+            for (piece, color, replaced) in &self.approximate_colors {
+                println!(
+                    "{:<32} {:<16} {:<16}",
+                    piece,
+                    format!("{:?}", color.into_array()),
+                    format!("{:?}", replaced.into_array())
+                );
+            }
+        }
+        if !self.color_samples.is_empty() {
+            println!("Colors changed by color_transform:");
+            println!("{:<32} {:<16} {:<16}", "Spec", "Color", "Placed as");
+            for (spec, color, transformed) in &self.color_samples {
+                println!(
+                    "{:<32} {:<16} {:<16}",
+                    spec,
+                    format!("{:?}", color.into_array()),
+                    format!("{:?}", transformed.into_array())
+                );
+            }
+        }
+        if !self.sprite_chances.is_empty() {
+            println!("Sprites placed by chance:");
+            println!("{:<16} {:<24} {:>12} {:>12}", "Color", "Sprite", "Placed", "Left out");
+            for ((color, kind), (placed, skipped)) in self.sorted_sprite_chances() {
+                println!(
+                    "{:<16} {:<24} {:>12} {:>12}",
+                    format!("{:?}", color.into_array()),
+                    format!("{:?}", kind),
+                    placed,
+                    skipped
+                );
+            }
+        }
+        if self.dropped_sprites > 0 {
+            warn!(
+                "{} sprites without a solid block to rest on were left out",
+                self.dropped_sprites
+            );
+        }
+        if self.decayed_voxels > 0 {
+            println!("{} voxels were dropped by decay", self.decayed_voxels);
+        }
+        if self.overlaid > 0 {
+            println!("{} blocks were deposited by the overlay", self.overlaid);
+        }
+        if self.settled_sprites > 0 {
+            println!(
+                "{} sprites without a solid block to rest on were moved down onto one",
+                self.settled_sprites
+            );
+        }
+        for (wpos, kind) in &self.sprite_errors {
+            error!("Sprite {:?} at {} has no solid block to rest on", kind, wpos);
+        }
+        if !self.suppressed.is_empty() {
+            println!("Cells kept over later pieces with a lower priority:");
+            println!("{:<32} {:<32} {:>12}", "Kept piece", "Later piece", "Cells");
+            for (kept, later, count) in &self.suppressed {
+                println!("{:<32} {:<32} {:>12}", kept, later, count);
+            }
+        }
+        if !self.stripped_glow.is_empty() {
+            println!("Hidden glowing blocks that no longer glow:");
+            println!("{:<32} {:>12}", "Piece", "Blocks");
+            for (piece, count) in &self.stripped_glow {
+                println!("{:<32} {:>12}", piece, count);
+            }
+        }
+        if !self.thinned_glow.is_empty() {
+            println!("Glowing blocks thinned to max_glow_blocks:");
+            println!("{:<32} {:>12} {:>12}", "Piece", "Kept", "Removed");
+            for (piece, kept, removed) in &self.thinned_glow {
+                println!("{:<32} {:>12} {:>12}", piece, kept, removed);
+            }
+        }
+        for (count, max) in &self.glow_errors {
+            error!(
+                "A spec would place {} glowing blocks, more than its max_glow_blocks of {}, so \
+                 it wasn't placed",
+                count, max
+            );
+        }
+        self.unmapped.print();
+    }
+}
+
+/// Reports how far along placing the chunks of a scene is.
+#[derive(Debug)]
+pub struct Progress {
+    pub verbosity: Verbosity,
+    pub start: Instant,
+    pub total_chunks: usize,
+    pub chunks: usize,
+    pub blocks: usize,
+}
+
+impl Progress {
+    const BAR_WIDTH: usize = 30;
+
+    /// Starts reporting the progress of placing `total_chunks` chunks.
+    pub fn new(verbosity: Verbosity, total_chunks: usize) -> Self {
+        Self {
+            verbosity,
+            start: Instant::now(),
+            total_chunks,
+            chunks: 0,
+            blocks: 0,
+        }
+    }
+
+    pub(crate) fn chunk_done(&mut self, key: Vec3<i32>, blocks: usize, took: Duration) {
+        self.chunks += 1;
+        self.blocks += blocks;
+        debug!(
+            "Filled chunk {} ({}/{}) with {} blocks in {:.2?}",
+            key, self.chunks, self.total_chunks, blocks, took
+        );
+        match self.verbosity {
+            Verbosity::Quiet | Verbosity::Verbose => {},
+            Verbosity::Normal => {
+                let elapsed = self.start.elapsed();
+                let eta = elapsed.mul_f64(
+                    (self.total_chunks - self.chunks) as f64 / self.chunks as f64,
+                );
+                let filled = Self::BAR_WIDTH * self.chunks / self.total_chunks.max(1);
+                eprint!(
+                    "\r[{}{}] {}/{} chunks, {} blocks, {:.0?} elapsed, ETA {:.0?}   ",
+                    "#".repeat(filled),
+                    " ".repeat(Self::BAR_WIDTH - filled),
+                    self.chunks,
+                    self.total_chunks,
+                    self.blocks,
+                    elapsed,
+                    eta,
+                );
+                if self.chunks == self.total_chunks {
+                    eprintln!();
+                }
+            },
+        }
+    }
+}
+
+/// How many scene chunks are resolved at once before their blocks are
+/// written, which bounds how many resolved blocks are kept in memory.
+pub const RESOLVE_BATCH: usize = 256;
+
+/// Resolves the blocks of every cell in `merged`, and of the filled bounds
+/// where there are no cells, and writes them to `sink`.
+///
+/// Chunks are resolved in parallel, but written in order.
+pub(crate) fn place_scene(
+    merged: &MergedScene,
+    place_spec: &PlaceSpec,
+    world: &World,
+    sink: &mut impl BlockSink,
+    options: PlaceOptions,
+) -> PlaceReport {
+    let MergedScene {
+        scene: vox,
+        aabbs,
+        piece_bounds,
+        fill,
+        cutouts,
+        skirts,
+        suppressed,
+    } = merged;
+    let replace_maps = place_spec.replace_maps();
+    let seed = options.seed.or(place_spec.seed).unwrap_or(0);
+    let seed = seed.wrapping_add(u64::from(place_spec.variant));
+    if !options.thread_rng {
+        info!("Using seed {}", seed);
+    }
+    let interior = if place_spec.carve_interior {
+        vox.interior(aabbs, place_spec.carve_depth)
+    } else {
+        HashSet::new()
+    };
+    // The cutouts of later pieces come first, so they take precedence.
+    let cutout_blocks = cutouts
+        .iter()
+        .rev()
+        .filter_map(|(piece, cutout)| {
+            let spec = place_spec.pieces.get(usize::from(*piece))?;
+            Some((*cutout, spec.options.cutout_block.as_ref()?))
+        })
+        .collect::<Vec<_>>();
+    let cutout_block = |wpos: Vec3<i32>| {
+        cutout_blocks
+            .iter()
+            .find(|(cutout, _)| cutout.contains_point(wpos))
+            .map(|(_, block)| *block)
+    };
+    // Chunks are only allocated where there are cells, but filled bounds and
+    // cutouts can cover chunks without any.
+    let mut keys = vox.iter().map(|(key, _)| key).collect::<HashSet<_>>();
+    for aabb in fill.bounds().chain(cutout_blocks.iter().map(|(cutout, _)| *cutout)) {
+        let (min_key, max_key) = (vox.pos_key(aabb.min), vox.pos_key(aabb.max));
+        for x in min_key.x..=max_key.x {
+            for y in min_key.y..=max_key.y {
+                for z in min_key.z..=max_key.z {
+                    keys.insert(Vec3::new(x, y, z));
+                }
+            }
+        }
+    }
+    let mut chunks = keys.iter().copied().collect::<Vec<_>>();
+    chunks.sort_unstable_by_key(|key| (key.z, key.y, key.x));
+    let size = SscSize::SIZE.map(|e| e as i32);
+    let mut progress = Progress::new(options.verbosity, chunks.len());
+    let mut unmapped = UnmappedColors::default();
+    let kept_air = AtomicUsize::new(0);
+    let special_sprites = Mutex::new(Vec::new());
+    let sprite_chances = Mutex::new(HashMap::new());
+    let mut skipped_voxels = 0;
+    // Sprites are validated and overlays deposited once all blocks around
+    // them are resolved.
+    let mut buffer = (place_spec.validate_sprites
+        || place_spec.max_glow_blocks.is_some()
+        || place_spec.strip_hidden_glow
+        || place_spec.overlay.is_some())
+    .then(HashMap::new);
+    for batch in chunks.chunks(RESOLVE_BATCH) {
+        let resolved = batch
+            .par_iter()
+            .map(|key| {
+                let start = Instant::now();
+                let mut local_rng = options.thread_rng.then(thread_rng);
+                let chunk = vox.get_key_arc(*key);
+                let pieces = vox.1.get_key_arc(*key);
+                let min = vox.key_pos(*key);
+                let mut blocks = Vec::new();
+                let mut unmapped = UnmappedColors::default();
+                let mut skipped = 0;
+                let cells: Box<dyn Iterator<Item = (Vec3<i32>, &Cell)>> = match chunk {
+                    Some(chunk) => Box::new(chunk.full_vol_iter()),
+                    // Only the positions that are placed anyway are visited in
+                    // chunks without cells.
+                    None => Box::new(
+                        (0..size.z)
+                            .flat_map(move |z| {
+                                (0..size.y)
+                                    .flat_map(move |y| (0..size.x).map(move |x| Vec3::new(x, y, z)))
+                            })
+                            .filter(|pos| {
+                                let wpos = min + *pos;
+                                interior.contains(&wpos)
+                                    || fill.contains(wpos)
+                                    || cutout_block(wpos).is_some()
+                            })
+                            .map(|pos| (pos, &Cell::Empty)),
+                    ),
+                };
+                for (pos, cell) in cells {
+                    // `pos` is relative to the chunk, the bounds are in world space.
+                    let wpos = min + pos;
+                    let origin = pieces.and_then(|p| p.get(pos).ok()).copied().unwrap_or_default();
+                    let replace_map = &replace_maps[origin.piece as usize];
+                    let ctx = ResolveCtx {
+                        wpos,
+                        color: cell.get_color().unwrap_or_default(),
+                        world,
+                        scene: vox,
+                        seed,
+                        piece_bounds: piece_bounds.get(&origin.piece).copied(),
+                        water_level: place_spec.water_level,
+                        kept_air: &kept_air,
+                        special_sprites: &special_sprites,
+                        sprite_chances: &sprite_chances,
+                    };
+                    let cutout = cell.get_color().is_none().then(|| cutout_block(wpos)).flatten();
+                    let block = if let Some(block) = vox.2.get(&wpos) {
+                        Some(*block)
+                    } else if interior.contains(&wpos) {
+                        Some(vacant_block(SpriteKind::Empty, wpos, place_spec.water_level))
+                    } else if let Some(spec) = cutout {
+                        let resolved = match &mut local_rng {
+                            Some(rng) => spec.get_block(&ctx, rng),
+                            None => spec.get_block(&ctx, &mut PosRng::new(seed, wpos)),
+                        };
+                        match resolved {
+                            Resolved::Block(block) => Some(block),
+                            Resolved::Empty | Resolved::Keep => None,
+                        }
+                    } else {
+                        unmapped.record(cell, origin, wpos, replace_map);
+                        match &mut local_rng {
+                            Some(rng) => resolve_block(
+                                cell,
+                                &ctx,
+                                place_spec,
+                                fill,
+                                origin.index,
+                                replace_map,
+                                rng,
+                            ),
+                            None => resolve_block(
+                                cell,
+                                &ctx,
+                                place_spec,
+                                fill,
+                                origin.index,
+                                replace_map,
+                                &mut PosRng::new(seed, wpos),
+                            ),
+                        }
+                    };
+                    match block {
+                        Some(block) => blocks.push((wpos, block)),
+                        None if cell.get_color().is_some() => skipped += 1,
+                        None => {},
+                    }
+                }
+                // Scene chunks line up with terrain chunks, but don't have to.
+                let mut terrain_chunks = HashMap::<_, Vec<_>>::new();
+                for (wpos, block) in blocks {
+                    terrain_chunks
+                        .entry(terrain_chunk_key(wpos))
+                        .or_default()
+                        .push((wpos, block));
+                }
+                (*key, terrain_chunks, unmapped, skipped, start.elapsed())
+            })
+            .collect::<Vec<_>>();
+        for (key, terrain_chunks, chunk_unmapped, skipped, took) in resolved {
+            unmapped.extend(chunk_unmapped);
+            skipped_voxels += skipped;
+            let mut count = 0;
+            for (terrain_key, blocks) in terrain_chunks {
+                count += blocks.len();
+                match &mut buffer {
+                    Some(buffer) => buffer.extend(blocks),
+                    None => sink.set_blocks_in_chunk(terrain_key, &blocks),
+                }
+            }
+            progress.chunk_done(key, count, took);
+        }
+    }
+    // Large enclosed spaces can cover chunks without any cells.
+    for wpos in &interior {
+        if !keys.contains(&vox.pos_key(*wpos)) {
+            let block = vacant_block(SpriteKind::Empty, *wpos, place_spec.water_level);
+            match &mut buffer {
+                Some(buffer) => {
+                    buffer.insert(*wpos, block);
+                },
+                None => sink.set_block(*wpos, block),
+            }
+        }
+    }
+    // Skirts go where nothing else is placed, the ones of later pieces first.
+    let mut rng = options.thread_rng.then(thread_rng);
+    let mut skirted = HashSet::new();
+    for (piece, wpos, in_berm) in skirts.iter().rev().copied() {
+        let placed = vox.is_occupied(wpos)
+            || interior.contains(&wpos)
+            || fill.contains(wpos)
+            || cutout_block(wpos).is_some();
+        if placed || !skirted.insert(wpos) {
+            continue;
+        }
+        let Some(skirt) = place_spec
+            .pieces
+            .get(usize::from(piece))
+            .and_then(|spec| spec.options.skirt.as_ref())
+        else {
+            continue;
+        };
+        let spec = match &skirt.berm {
+            Some(berm) if in_berm => berm.block.as_ref().unwrap_or(&skirt.block),
+            _ => &skirt.block,
+        };
+        let ctx = ResolveCtx {
+            wpos,
+            color: Rgb::zero(),
+            world,
+            scene: vox,
+            seed,
+            piece_bounds: piece_bounds.get(&piece).copied(),
+            water_level: place_spec.water_level,
+            kept_air: &kept_air,
+            special_sprites: &special_sprites,
+            sprite_chances: &sprite_chances,
+        };
+        let resolved = match &mut rng {
+            Some(rng) => spec.get_block(&ctx, rng),
+            None => spec.get_block(&ctx, &mut PosRng::new(seed, wpos)),
+        };
+        if let Resolved::Block(block) = resolved {
+            match &mut buffer {
+                Some(buffer) => {
+                    buffer.insert(wpos, block);
+                },
+                None => sink.set_block(wpos, block),
+            }
+        }
+    }
+    let mut overlaid = 0;
+    if let (Some(overlay), Some(buffer)) = (&place_spec.overlay, &mut buffer) {
+        // Cells that kept the terrain aren't empty, positions in scene chunks
+        // that were never allocated are.
+        let vacant = |buffer: &HashMap<Vec3<i32>, Block>, wpos: Vec3<i32>| {
+            let empty = |block: &Block| {
+                !block.kind().is_filled()
+                    && block.get_sprite().map_or(true, |sprite| sprite == SpriteKind::Empty)
+            };
+            buffer.get(&wpos).map_or(!vox.is_occupied(wpos), empty)
+                && (!overlay.check_terrain || empty(&world.get(wpos)))
+        };
+        let mut faces = buffer
+            .iter()
+            .filter(|(_, block)| block.kind().is_filled())
+            .map(|(wpos, _)| *wpos + Vec3::unit_z())
+            .filter(|above| aabbs.iter().any(|aabb| aabb.contains_point(*above)))
+            .filter(|above| {
+                (0..i32::from(overlay.min_exposure))
+                    .all(|z| vacant(buffer, *above + Vec3::unit_z() * z))
+            })
+            .collect::<Vec<_>>();
+        faces.sort_unstable_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+        let mut rng = options.thread_rng.then(thread_rng);
+        for wpos in faces {
+            let covered = match &mut rng {
+                Some(rng) => rng.gen::<f32>() < overlay.coverage,
+                None => PosRng::new(splitmix64(seed), wpos).gen::<f32>() < overlay.coverage,
+            };
+            if !covered {
+                continue;
+            }
+            let piece = vox.1.get(wpos - Vec3::unit_z()).map_or(0, |origin| origin.piece);
+            let ctx = ResolveCtx {
+                wpos,
+                color: Rgb::zero(),
+                world,
+                scene: vox,
+                seed,
+                piece_bounds: piece_bounds.get(&piece).copied(),
+                water_level: place_spec.water_level,
+                kept_air: &kept_air,
+                special_sprites: &special_sprites,
+                sprite_chances: &sprite_chances,
+            };
+            let resolved = match &mut rng {
+                Some(rng) => overlay.block.get_block(&ctx, rng),
+                None => overlay.block.get_block(&ctx, &mut PosRng::new(seed, wpos)),
+            };
+            if let Resolved::Block(block) = resolved {
+                buffer.insert(wpos, block);
+                overlaid += 1;
+            }
+        }
+    }
+    let piece_name = |piece: u16| {
+        place_spec.pieces.get(usize::from(piece)).map_or("", |piece| piece.name()).to_string()
+    };
+    let mut report = PlaceReport {
+        unmapped,
+        kept_air: kept_air.into_inner(),
+        special_sprites: special_sprites.into_inner().unwrap(),
+        sprite_chances: sprite_chances.into_inner().unwrap(),
+        skipped_voxels,
+        overlaid,
+        suppressed: suppressed
+            .iter()
+            .map(|((kept, later), count)| (piece_name(*kept), piece_name(*later), *count))
+            .collect(),
+        ..PlaceReport::default()
+    };
+    if let Some(mut buffer) = buffer {
+        if place_spec.validate_sprites {
+            support_sprites(&mut buffer, world, place_spec.sprite_support, &mut report);
+        }
+        if place_spec.strip_hidden_glow {
+            let mut pieces = HashMap::<u16, usize>::new();
+            for wpos in strip_hidden_glow(&mut buffer, place_spec.hidden_glow_radius) {
+                let piece = vox.1.get(wpos).map_or(0, |origin| origin.piece);
+                *pieces.entry(piece).or_default() += 1;
+            }
+            let mut pieces = pieces.into_iter().collect::<Vec<_>>();
+            pieces.sort_by_key(|(piece, _)| *piece);
+            report.stripped_glow.extend(pieces.into_iter().map(|(piece, count)| {
+                let name = place_spec
+                    .pieces
+                    .get(usize::from(piece))
+                    .map_or("", |piece| &piece.specifier);
+                (name.to_string(), count)
+            }));
+        }
+        // Replacements decide what glows, so the limit applies to the
+        // resolved blocks.
+        let glowing = buffer
+            .iter()
+            .filter(|(_, block)| block.get_glow().is_some())
+            .map(|(wpos, _)| *wpos)
+            .collect::<Vec<_>>();
+        match place_spec.max_glow_blocks {
+            Some(max) if glowing.len() > max => match place_spec.glow_limit {
+                GlowLimit::Error => {
+                    report.glow_errors.push((glowing.len(), max));
+                    return report;
+                },
+                GlowLimit::Thin => {
+                    let mut pieces = HashMap::<u16, (usize, usize)>::new();
+                    for (wpos, kept) in thin_glow(&mut buffer, glowing, max, seed) {
+                        let piece = vox.1.get(wpos).map_or(0, |origin| origin.piece);
+                        let counts = pieces.entry(piece).or_default();
+                        if kept {
+                            counts.0 += 1;
+                        } else {
+                            counts.1 += 1;
+                        }
+                    }
+                    let mut pieces = pieces.into_iter().collect::<Vec<_>>();
+                    pieces.sort_by_key(|(piece, _)| *piece);
+                    report.thinned_glow.extend(pieces.into_iter().map(|(piece, (kept, removed))| {
+                        let name = place_spec
+                            .pieces
+                            .get(usize::from(piece))
+                            .map_or("", |piece| &piece.specifier);
+                        (name.to_string(), kept, removed)
+                    }));
+                },
+            },
+            _ => {},
+        }
+        let mut terrain_chunks = HashMap::<_, Vec<_>>::new();
+        for (wpos, block) in buffer {
+            terrain_chunks
+                .entry(terrain_chunk_key(wpos))
+                .or_default()
+                .push((wpos, block));
+        }
+        for (terrain_key, blocks) in terrain_chunks {
+            sink.set_blocks_in_chunk(terrain_key, &blocks);
+        }
+    }
+    report
+}
+
+/// Places a built scene of `place_spec` into `sink`. The written blocks are
+/// also added to `world`.
+pub fn place_into(
+    place_spec: &PlaceSpec,
+    merged: &MergedScene,
+    world: &mut World,
+    sink: &mut impl BlockSink,
+    options: PlaceOptions,
+) -> PlaceReport {
+    if let Some(bounds) = merged.aabbs.iter().copied().reduce(|a, b| a.union(b)) {
+        info!("Placing within world bounds {} to {}", bounds.min, bounds.max);
+        world.load_region(bounds);
+    }
+    // Conditions see the world as it was before this placement.
+    let mut written = Vec::new();
+    let mut report = place_scene(merged, place_spec, world, &mut (sink, &mut written), options);
+    // Where skirts overlap, the one of the later piece is placed.
+    let skirts = merged
+        .skirts
+        .iter()
+        .map(|(piece, wpos, _)| (*wpos, *piece))
+        .collect::<HashMap<_, _>>();
+    let mut writes = BTreeMap::<u16, Vec<Vec3<i32>>>::new();
+    for (wpos, _) in &written {
+        let piece = writer_of(merged, &skirts, *wpos);
+        writes.entry(piece).or_default().push(*wpos);
+    }
+    report.writes = writes
+        .into_iter()
+        .map(|(piece, positions)| {
+            let name = place_spec.pieces.get(usize::from(piece)).map_or("", |piece| piece.name());
+            (name.to_string(), positions)
+        })
+        .collect();
+    world.written.extend(written);
+    report
+}
+
+/// The piece of `merged` that wrote `wpos`: the piece of its cell, of its
+/// skirt in `skirts`, or else the last piece whose bounds it is in, for
+/// filled and carved positions.
+fn writer_of(merged: &MergedScene, skirts: &HashMap<Vec3<i32>, u16>, wpos: Vec3<i32>) -> u16 {
+    if merged.scene.is_occupied(wpos) {
+        return merged.scene.1.get(wpos).map_or(0, |origin| origin.piece);
+    }
+    skirts.get(&wpos).copied().unwrap_or_else(|| {
+        merged
+            .piece_bounds
+            .iter()
+            .filter(|(_, bounds)| bounds.contains_point(wpos))
+            .map(|(piece, _)| *piece)
+            .max()
+            .unwrap_or(0)
+    })
+}
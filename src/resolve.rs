@@ -0,0 +1,520 @@
+//! Resolving the cells of a scene into blocks.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use vek::*;
+use veloren_common::{
+    figure::Cell,
+    terrain::{Block, BlockKind, SpriteKind},
+};
+
+use crate::{
+    persistence::World,
+    place::{FillRegion, SpecialSprite},
+    scene::SparseScene,
+    spec::{BlockSpec, ColorRange, ColorTransform, Medium, PlaceSpec},
+};
+
+/// A condition on the block already in the world.
+#[derive(Deserialize, Clone, Debug)]
+pub enum ExistingBlock {
+    /// Water or another liquid.
+    Fluid,
+    Solid,
+    Kind(BlockKind),
+}
+
+impl ExistingBlock {
+    pub(crate) fn matches(&self, block: Block) -> bool {
+        match self {
+            ExistingBlock::Fluid => block.kind().is_liquid(),
+            ExistingBlock::Solid => block.kind().is_filled(),
+            ExistingBlock::Kind(kind) => block.kind() == *kind,
+        }
+    }
+}
+
+/// A direction in world space, north being positive y and east positive x.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Direction {
+    /// The offset of the neighbouring position in this direction.
+    pub fn offset(self) -> Vec3<i32> {
+        match self {
+            Direction::Up => Vec3::unit_z(),
+            Direction::Down => -Vec3::unit_z(),
+            Direction::North => Vec3::unit_y(),
+            Direction::South => -Vec3::unit_y(),
+            Direction::East => Vec3::unit_x(),
+            Direction::West => -Vec3::unit_x(),
+        }
+    }
+}
+
+/// A condition on a cell of the scene.
+#[derive(Deserialize, Clone, Debug)]
+pub enum NeighborCell {
+    /// No voxel, including positions outside of the scene.
+    Empty,
+    /// Any voxel or block of a template.
+    Solid,
+    Color([u8; 3]),
+}
+
+impl NeighborCell {
+    pub(crate) fn matches(&self, scene: &SparseScene, wpos: Vec3<i32>) -> bool {
+        match self {
+            NeighborCell::Empty => !scene.is_occupied(wpos),
+            NeighborCell::Solid => scene.is_occupied(wpos),
+            NeighborCell::Color(color) => scene.cell(wpos).get_color() == Some(Rgb::from(*color)),
+        }
+    }
+}
+
+/// What a block spec is resolved for.
+#[derive(Clone, Copy, Debug)]
+pub struct ResolveCtx<'a> {
+    pub wpos: Vec3<i32>,
+    /// Color of the cell.
+    pub color: Rgb<u8>,
+    pub world: &'a World,
+    /// The scene the cell is in, for conditions on neighbouring cells.
+    pub scene: &'a SparseScene,
+    /// The seed of the spec.
+    pub seed: u64,
+    /// Bounds of the piece the cell belongs to.
+    pub piece_bounds: Option<Aabb<i32>>,
+    pub water_level: Option<i32>,
+    /// Counts sprites explicitly kept in air below the water level.
+    pub kept_air: &'a AtomicUsize,
+    /// Collects the sprites with loot.
+    pub special_sprites: &'a Mutex<Vec<SpecialSprite>>,
+    /// Counts the sprites placed and left out by their chance.
+    pub sprite_chances: &'a Mutex<SpriteChances>,
+}
+
+/// The sprites placed and left out by their chance, by the color of their
+/// cells and their kind.
+pub type SpriteChances = HashMap<(Rgb<u8>, SpriteKind), (usize, usize)>;
+
+/// A sprite in water below `water_level`, or in air above it.
+pub fn vacant_block(sprite: SpriteKind, wpos: Vec3<i32>, water_level: Option<i32>) -> Block {
+    match water_level {
+        Some(level) if wpos.z < level => Block::water(sprite),
+        _ => Block::air(sprite),
+    }
+}
+
+/// What is placed for a cell.
+#[derive(Clone, Copy, Debug)]
+pub enum Resolved {
+    Block(Block),
+    /// Treated as an empty cell.
+    Empty,
+    /// Nothing is written.
+    Keep,
+}
+
+impl BlockSpec {
+    /// What to place for a cell.
+    ///
+    /// ```
+    /// use std::{
+    ///     collections::HashMap,
+    ///     path::PathBuf,
+    ///     sync::{atomic::AtomicUsize, Mutex},
+    /// };
+    ///
+    /// use map_placer::{
+    ///     persistence::World,
+    ///     resolve::{PosRng, ResolveCtx, Resolved},
+    ///     scene::SparseScene,
+    ///     spec::BlockSpec,
+    /// };
+    /// use vek::{Rgb, Vec3};
+    /// use veloren_common::terrain::BlockKind;
+    ///
+    /// let world = World::new(PathBuf::from("terrain"));
+    /// let scene = SparseScene::empty();
+    /// let (kept_air, special_sprites) = (AtomicUsize::new(0), Mutex::new(Vec::new()));
+    /// let sprite_chances = Mutex::new(HashMap::new());
+    /// let ctx = ResolveCtx {
+    ///     wpos: Vec3::new(0, 0, 10),
+    ///     color: Rgb::new(200, 40, 40),
+    ///     world: &world,
+    ///     scene: &scene,
+    ///     seed: 0,
+    ///     piece_bounds: None,
+    ///     water_level: None,
+    ///     kept_air: &kept_air,
+    ///     special_sprites: &special_sprites,
+    ///     sprite_chances: &sprite_chances,
+    /// };
+    /// let mut rng = PosRng::new(ctx.seed, ctx.wpos);
+    /// let Resolved::Block(block) = BlockSpec::Glowy.get_block(&ctx, &mut rng) else {
+    ///     unreachable!();
+    /// };
+    /// assert_eq!(block.kind(), BlockKind::GlowingRock);
+    /// assert_eq!(block.get_color(), Some(ctx.color));
+    /// ```
+    pub fn get_block(&self, ctx: &ResolveCtx, rng: &mut impl Rng) -> Resolved {
+        Resolved::Block(match self {
+            BlockSpec::Sprite {
+                kind,
+                medium,
+                ori,
+                random_ori,
+                loot,
+                chance,
+                fallback,
+            } => {
+                // Sprites that are always placed don't roll, so they don't
+                // change the randomness of the other blocks.
+                if *chance < 1.0 {
+                    let placed = rng.gen::<f32>() < *chance;
+                    {
+                        let mut chances = ctx.sprite_chances.lock().unwrap();
+                        let counts = chances.entry((ctx.color, *kind)).or_default();
+                        if placed {
+                            counts.0 += 1;
+                        } else {
+                            counts.1 += 1;
+                        }
+                    }
+                    if !placed {
+                        return fallback
+                            .as_ref()
+                            .map_or(Resolved::Keep, |fallback| fallback.get_block(ctx, rng));
+                    }
+                }
+                let block = match medium {
+                    Medium::Auto => vacant_block(*kind, ctx.wpos, ctx.water_level),
+                    Medium::Air => {
+                        if ctx.water_level.map_or(false, |level| ctx.wpos.z < level) {
+                            ctx.kept_air.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Block::air(*kind)
+                    },
+                    Medium::Water => Block::water(*kind),
+                };
+                let ori = if *random_ori {
+                    Some(rng.gen_range(0..8))
+                } else {
+                    ori.filter(|ori| *ori < 8)
+                };
+                if let Some(loot) = loot {
+                    ctx.special_sprites.lock().unwrap().push(SpecialSprite {
+                        pos: ctx.wpos,
+                        kind: *kind,
+                        loot: loot.clone(),
+                    });
+                }
+                // Sprites without an orientation keep the default one, this is
+                // warned about in `check`.
+                ori.and_then(|ori| block.with_ori(ori)).unwrap_or(block)
+            },
+            BlockSpec::Block {
+                kind,
+                color,
+                keep_color,
+            } => Block::new(
+                *kind,
+                if *keep_color {
+                    ctx.color
+                } else {
+                    color.apply(ctx.color)
+                },
+            ),
+            BlockSpec::Random(lottery) => {
+                return lottery.choose_seeded(rng.gen()).get_block(ctx, rng);
+            },
+            BlockSpec::Glowy => Block::new(BlockKind::GlowingRock, ctx.color),
+            BlockSpec::Hollow => vacant_block(SpriteKind::Empty, ctx.wpos, ctx.water_level),
+            BlockSpec::Water => Block::water(SpriteKind::Empty),
+            BlockSpec::Skip => return Resolved::Empty,
+            BlockSpec::Keep => return Resolved::Keep,
+            BlockSpec::If {
+                when,
+                then,
+                otherwise,
+            } => {
+                return if when.matches(ctx.world.get(ctx.wpos)) {
+                    then.get_block(ctx, rng)
+                } else {
+                    otherwise.get_block(ctx, rng)
+                };
+            },
+            BlockSpec::Neighbor {
+                dir,
+                is,
+                then,
+                otherwise,
+            } => {
+                return if is.matches(ctx.scene, ctx.wpos + dir.offset()) {
+                    then.get_block(ctx, rng)
+                } else {
+                    otherwise.get_block(ctx, rng)
+                };
+            },
+            BlockSpec::Noise {
+                scale,
+                threshold,
+                below,
+                above,
+            } => {
+                let noise = value_noise(ctx.seed, ctx.wpos.map(|e| e as f32) / *scale);
+                return if noise > *threshold {
+                    above.get_block(ctx, rng)
+                } else {
+                    below.get_block(ctx, rng)
+                };
+            },
+            BlockSpec::HeightGradient(breakpoints) => {
+                let height = ctx.piece_bounds.map_or(0, |bounds| ctx.wpos.z - bounds.min.z);
+                // Below the first breakpoint the first spec is used.
+                return match breakpoints
+                    .iter()
+                    .take_while(|(threshold, _)| *threshold <= height)
+                    .last()
+                    .or_else(|| breakpoints.first())
+                {
+                    Some((_, spec)) => spec.get_block(ctx, rng),
+                    None => Resolved::Empty,
+                };
+            },
+        })
+    }
+}
+
+/// What the cells of a piece are replaced with.
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceMap {
+    pub colors: HashMap<Rgb<u8>, BlockSpec>,
+    /// Replacements by palette index, taking precedence over `colors`.
+    pub indices: HashMap<u8, BlockSpec>,
+    /// Replacements of the colors within a range, used when `colors` doesn't
+    /// match. The last range containing a color wins.
+    pub ranges: Vec<(ColorRange, BlockSpec)>,
+    /// Blocks picked from the material of palette indices, used when neither
+    /// of the others match.
+    pub materials: HashMap<u8, BlockSpec>,
+    /// The transform applied to colors before looking them up in `colors`.
+    pub lookup: ColorTransform,
+}
+
+impl ReplaceMap {
+    /// The replacement of a cell with `color` and palette `index`.
+    ///
+    /// ```
+    /// use map_placer::{
+    ///     resolve::ReplaceMap,
+    ///     spec::{BlockSpec, ColorRange},
+    /// };
+    /// use vek::Rgb;
+    ///
+    /// let mut map = ReplaceMap::default();
+    /// map.colors.insert(Rgb::new(255, 0, 0), BlockSpec::Glowy);
+    /// map.indices.insert(3, BlockSpec::Water);
+    /// let blue = ColorRange {
+    ///     min: [0, 0, 200],
+    ///     max: [50, 50, 255],
+    /// };
+    /// map.ranges.push((blue, BlockSpec::Hollow));
+    /// assert!(matches!(map.get(Rgb::new(255, 0, 0), 3), Some(BlockSpec::Water)));
+    /// assert!(matches!(map.get(Rgb::new(255, 0, 0), 4), Some(BlockSpec::Glowy)));
+    /// assert!(matches!(map.get(Rgb::new(10, 0, 220), 4), Some(BlockSpec::Hollow)));
+    /// assert!(map.get(Rgb::new(0, 255, 0), 4).is_none());
+    /// ```
+    pub fn get(&self, color: Rgb<u8>, index: u8) -> Option<&BlockSpec> {
+        let color = self.lookup.apply(color);
+        self.indices
+            .get(&index)
+            .or_else(|| self.colors.get(&color))
+            .or_else(|| {
+                self.ranges
+                    .iter()
+                    .rev()
+                    .find(|(range, _)| range.contains(color))
+                    .map(|(_, block)| block)
+            })
+            .or_else(|| self.materials.get(&index))
+    }
+}
+
+/// Computes the block that should be written for `cell`, or `None` if the
+/// cell shouldn't be written at all. `index` is the palette index of the cell
+/// and `fill` is where empty cells are filled.
+pub fn resolve_block(
+    cell: &Cell,
+    ctx: &ResolveCtx,
+    place_spec: &PlaceSpec,
+    fill: &FillRegion,
+    index: u8,
+    replace_map: &ReplaceMap,
+    rng: &mut impl Rng,
+) -> Option<Block> {
+    let resolved = match cell.get_color() {
+        Some(painted) => {
+            // Replacements are looked up by the painted color, blocks get the
+            // transformed one.
+            let color = place_spec.color_transform.apply(painted);
+            let ctx = ResolveCtx { color, ..*ctx };
+            match replace_map.get(painted, index) {
+                Some(spec) => spec.get_block(&ctx, rng),
+                None => {
+                    let fallback = Fallback::of(cell);
+                    match place_spec.fallback_block(fallback) {
+                        Some(spec) => spec.get_block(&ctx, rng),
+                        None => Resolved::Block(match fallback {
+                            Fallback::Hollow => {
+                                vacant_block(SpriteKind::Empty, ctx.wpos, ctx.water_level)
+                            },
+                            Fallback::Glowy => Block::new(BlockKind::GlowingRock, color),
+                            Fallback::Shiny => Block::water(SpriteKind::Empty),
+                            Fallback::Misc => Block::new(BlockKind::Misc, color),
+                        }),
+                    }
+                },
+            }
+        },
+        None => Resolved::Empty,
+    };
+    // Empty cells are only written when filling.
+    let resolved = match resolved {
+        Resolved::Empty if fill.contains(ctx.wpos) => match &place_spec.fill_block {
+            Some(spec) => spec.get_block(ctx, rng),
+            None => Resolved::Block(vacant_block(SpriteKind::Empty, ctx.wpos, ctx.water_level)),
+        },
+        resolved => resolved,
+    };
+    match resolved {
+        Resolved::Block(block) => Some(block),
+        Resolved::Empty | Resolved::Keep => None,
+    }
+}
+
+/// What a cell whose color isn't replaced is placed as.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Fallback {
+    Hollow,
+    Glowy,
+    Shiny,
+    Misc,
+}
+
+impl Fallback {
+    /// The fallback of `cell` by its flags.
+    pub fn of(cell: &Cell) -> Self {
+        if cell.is_hollow() {
+            Fallback::Hollow
+        } else if cell.is_glowy() {
+            Fallback::Glowy
+        } else if cell.is_shiny() {
+            Fallback::Shiny
+        } else {
+            Fallback::Misc
+        }
+    }
+}
+
+/// A cheap RNG seeded from a seed and a world position, so the randomness of
+/// a block doesn't depend on the order blocks are resolved in.
+///
+/// ```
+/// use map_placer::resolve::PosRng;
+/// use rand::Rng;
+/// use vek::Vec3;
+///
+/// let roll = PosRng::new(7, Vec3::new(1, 2, 3)).gen_range(0..100);
+/// assert!(roll < 100);
+/// assert_eq!(PosRng::new(7, Vec3::new(1, 2, 3)).gen_range(0..100), roll);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PosRng(pub(crate) u64);
+
+impl PosRng {
+    /// The RNG of the block at `wpos`, for a spec with `seed`.
+    pub fn new(seed: u64, wpos: Vec3<i32>) -> Self {
+        let mut state = splitmix64(seed);
+        for e in wpos {
+            state = splitmix64(state ^ e as u32 as u64);
+        }
+        Self(state)
+    }
+}
+
+/// Value noise from 0 to 1 with features about one unit apart. Only uses
+/// operations that give the same results on every platform.
+pub(crate) fn value_noise(seed: u64, pos: Vec3<f32>) -> f32 {
+    let cell = pos.map(f32::floor);
+    // Smoothstep, so the noise has no visible grid.
+    let t = (pos - cell).map(|t| t * t * (3.0 - 2.0 * t));
+    let cell = cell.map(|e| e as i32);
+    let corner = |x, y, z| {
+        let hash = PosRng::new(seed, cell + Vec3::new(x, y, z)).next_u32();
+        (hash >> 8) as f32 / (1 << 24) as f32
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let plane = |z| {
+        lerp(
+            lerp(corner(0, 0, z), corner(1, 0, z), t.x),
+            lerp(corner(0, 1, z), corner(1, 1, z), t.x),
+            t.y,
+        )
+    };
+    lerp(plane(0), plane(1), t.z)
+}
+
+/// The SplitMix64 mixing function, a cheap hash with good avalanche.
+///
+/// ```
+/// use map_placer::resolve::splitmix64;
+///
+/// assert_eq!(splitmix64(0), 0xE220A8397B1DCDAF);
+/// assert_ne!(splitmix64(1), splitmix64(2));
+/// ```
+pub fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+impl RngCore for PosRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = splitmix64(self.0);
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
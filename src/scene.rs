@@ -0,0 +1,1142 @@
+//! Building vox files into sparse scenes of cells.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use dot_vox::{DotVoxData, Model};
+use rand::RngCore;
+use vek::*;
+use veloren_common::{
+    figure::Cell,
+    terrain::{Block, BlockKind},
+    vol::{IntoFullVolIterator, ReadVol, VolSize, WriteVol},
+    volumes::{chunk::Chunk, vol_grid_3d::VolGrid3d},
+};
+
+use crate::{
+    model::Template,
+    persistence::World,
+    resolve::PosRng,
+    spec::{Merge, Pivot, Validation},
+};
+
+#[derive(Clone, Debug)]
+pub struct SscSize;
+
+impl VolSize for SscSize {
+    const SIZE: Vec3<u32> = Vec3 {
+        x: 32,
+        y: 32,
+        z: 32,
+    };
+}
+
+/// The piece a cell of a scene belongs to, and the palette index it had in
+/// the vox file of that piece.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CellOrigin {
+    pub piece: u16,
+    pub index: u8,
+}
+
+/// The origin of each cell of a scene.
+pub type PieceGrid = VolGrid3d<Chunk<CellOrigin, SscSize, ()>>;
+
+/// The cells of a scene, along with where they come from. Both grids have
+/// the same chunks. Blocks of templates are kept as they are, their cells are
+/// empty.
+#[derive(Clone)]
+pub struct SparseScene(
+    pub(crate) VolGrid3d<Chunk<Cell, SscSize, ()>>,
+    pub(crate) PieceGrid,
+    pub(crate) HashMap<Vec3<i32>, Block>,
+);
+
+impl std::fmt::Debug for SparseScene {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SparseScene")
+            .field("cells", &self.cells().count())
+            .field("blocks", &self.2.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Deref for SparseScene {
+    type Target = VolGrid3d<Chunk<Cell, SscSize, ()>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SparseScene {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A vox file to build into a scene, and how.
+#[derive(Clone, Debug)]
+pub struct SceneSource<'a> {
+    /// The piece the cells belong to.
+    pub piece: u16,
+    /// The name of the piece, for reports.
+    pub name: &'a str,
+    pub vox: &'a DotVoxData,
+    /// The animation frame to use.
+    pub frame: u32,
+    /// Layers whose transform nodes are left out, along with their children.
+    pub skipped_layers: HashSet<u32>,
+    /// The scene node to start at.
+    pub root: u32,
+    pub pivot: Pivot,
+    pub rot: Mat3<i32>,
+    pub offset: Vec3<i32>,
+}
+
+/// The world space bounds of `local`, given relative to the minimum corner of a
+/// piece spanning `bounds` before it was turned by `rot`.
+pub fn local_to_world(local: Aabb<i32>, rot: Mat3<i32>, bounds: Aabb<i32>) -> Aabb<i32> {
+    // Rotations only swap and flip axes, so their transpose turns them back.
+    let extent = (rot.transposed() * (bounds.max - bounds.min)).map(i32::abs);
+    // Turning the piece around its minimum corner moves it below that corner
+    // along the flipped axes.
+    let origin = bounds.min - (rot * extent).map(|e| e.min(0));
+    // The turned corners of `local` are corners of its new bounds, though not
+    // necessarily the lowest and highest ones.
+    let (a, b) = (rot * local.min, rot * local.max);
+    Aabb {
+        min: origin + a.map2(b, i32::min),
+        max: origin + a.map2(b, i32::max),
+    }
+}
+
+/// The extent of a single piece, before it is merged with other pieces.
+#[derive(Clone, Debug)]
+pub struct PieceBounds {
+    pub name: String,
+    pub aabb: Aabb<i32>,
+    /// The number of cells the piece sets.
+    pub voxels: usize,
+    /// The mean world position of the voxels of the piece.
+    pub centroid: Option<Vec3<f32>>,
+    /// The world positions of the anchors of the piece, in anchor order.
+    pub anchors: Vec<Vec3<i32>>,
+    /// The world positions of the marker voxels of the piece by marker name,
+    /// in position order.
+    pub markers: HashMap<String, Vec<Vec3<i32>>>,
+}
+
+/// How deep scene graphs may nest, in case a broken file nests them without
+/// end.
+pub const MAX_SCENE_DEPTH: usize = 256;
+
+/// Why a scene graph of a vox file can't be built.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SceneProblem {
+    /// A node refers to a node that doesn't exist.
+    MissingNode(u32),
+    /// A shape refers to a model that doesn't exist.
+    MissingModel(u32),
+    /// A transform node has no frames.
+    MissingFrame,
+    /// A node is its own ancestor.
+    Cycle,
+    TooDeep,
+}
+
+/// A scene graph of a vox file that can't be built.
+#[derive(Clone, Debug)]
+pub struct SceneError {
+    pub piece: String,
+    /// The node with the problem.
+    pub node: u32,
+    pub problem: SceneProblem,
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The scene graph of {} is broken, node {} ", self.piece, self.node)?;
+        match self.problem {
+            SceneProblem::MissingNode(child) => write!(f, "refers to the missing node {}", child),
+            SceneProblem::MissingModel(model) => {
+                write!(f, "refers to the missing model {}", model)
+            },
+            SceneProblem::MissingFrame => write!(f, "has no frames"),
+            SceneProblem::Cycle => write!(f, "contains itself"),
+            SceneProblem::TooDeep => {
+                write!(f, "is nested more than {} nodes deep", MAX_SCENE_DEPTH)
+            },
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+/// A palette color, along with the cell flags voxels of that color get.
+#[derive(Clone, Copy, Debug)]
+pub struct PaletteEntry {
+    pub color: Rgb<u8>,
+    pub glowy: bool,
+    /// Translucent colors become shiny, which is placed as water.
+    pub shiny: bool,
+    pub hollow: bool,
+    /// Fully transparent voxels aren't placed.
+    pub transparent: bool,
+}
+
+impl SparseScene {
+    /// Builds a scene from vox files. Voxels using a palette index in
+    /// `glow_indices` or an emissive material become glowy, voxels using a
+    /// palette index in `hollow_indices` become hollow. Voxels with a color in
+    /// `anchor_colors` are left out, and recorded as anchors of their piece,
+    /// and so are voxels with the color of one of `markers`, recorded by the
+    /// name of the marker. Voxels with a fully transparent color are left out,
+    /// ones with an alpha below `water_alpha_threshold` become shiny.
+    pub fn new_from<'a>(
+        sources: impl Iterator<Item = SceneSource<'a>>,
+        glow_indices: &[u8],
+        hollow_indices: &[u8],
+        anchor_colors: &[Rgb<u8>],
+        markers: &[(String, Rgb<u8>)],
+        water_alpha_threshold: Option<u8>,
+    ) -> Result<(Self, Vec<Aabb<i32>>, Vec<PieceBounds>), SceneError> {
+        fn render_model(
+            palette: &Vec<PaletteEntry>,
+            source: &SceneSource,
+            model: &Model,
+            sparse_scene: &mut SparseScene,
+            aabbs: &mut Vec<Aabb<i32>>,
+            rot: Mat3<i32>,
+            trans: Vec3<i32>,
+        ) {
+            let size = Vec3::new(model.size.x, model.size.y, model.size.z).map(|e| e as i32);
+            // MagicaVoxel rotates a model around the voxel at half its size,
+            // rounded down, and places that voxel at the translation.
+            let pivot = match source.pivot {
+                Pivot::Center => size / 2,
+                Pivot::MinCorner => Vec3::zero(),
+            };
+            let first = rot * -pivot;
+            let last = rot * (size - 1 - pivot);
+            let shift = match source.pivot {
+                Pivot::Center => Vec3::zero(),
+                Pivot::MinCorner => -first.map2(last, i32::min),
+            };
+            let model_bounds = Aabb {
+                min: trans + shift + first.map2(last, i32::min),
+                // vek Aabbs are inclusive
+                max: trans + shift + first.map2(last, i32::max),
+            };
+            if !aabbs.iter_mut().any(|aabb| {
+                (if model_bounds.contains_aabb(*aabb) {
+                    *aabb = model_bounds;
+                    true
+                } else {
+                    false
+                }) || aabb.contains_aabb(model_bounds)
+            }) {
+                aabbs.push(model_bounds);
+            }
+            // Chunks are only inserted where the model has voxels, the empty
+            // cells within its bounds are placed from `aabbs` alone.
+            for voxel in &model.voxels {
+                if let Some(entry) = palette
+                    .get(voxel.i as usize)
+                    .filter(|entry| !entry.transparent)
+                {
+                    let wpos = trans
+                        + shift
+                        + rot * (Vec3::new(voxel.x, voxel.y, voxel.z).map(i32::from) - pivot);
+                    sparse_scene.set_cell(
+                        wpos,
+                        Cell::new(entry.color, entry.glowy, entry.shiny, entry.hollow),
+                        CellOrigin {
+                            piece: source.piece,
+                            index: voxel.i,
+                        },
+                    );
+                }
+            }
+        }
+
+        /// The animation frame of a transform frame or shape model.
+        fn frame_index(attributes: &dot_vox::Dict) -> u32 {
+            attributes
+                .get("_f")
+                .and_then(|f| f.parse().ok())
+                .unwrap_or(0)
+        }
+
+        /// Inserts the node `node` and its children, `ancestors` are the
+        /// nodes it is nested in.
+        fn insert_scene(
+            source: &SceneSource,
+            palette: &Vec<PaletteEntry>,
+            node: u32,
+            ancestors: &mut Vec<u32>,
+            mut rot: Mat3<i32>,
+            mut trans: Vec3<i32>,
+            sparse_scene: &mut SparseScene,
+            aabbs: &mut Vec<Aabb<i32>>,
+        ) -> Result<(), SceneError> {
+            let dot_vox_data = source.vox;
+            let frame = source.frame;
+            let error = |node, problem| SceneError {
+                piece: source.name.to_string(),
+                node,
+                problem,
+            };
+            let parent = ancestors.last().copied().unwrap_or(node);
+            let Some(scene) = dot_vox_data.scenes.get(node as usize) else {
+                return Err(error(parent, SceneProblem::MissingNode(node)));
+            };
+            if ancestors.contains(&node) {
+                return Err(error(node, SceneProblem::Cycle));
+            }
+            if ancestors.len() >= MAX_SCENE_DEPTH {
+                return Err(error(node, SceneProblem::TooDeep));
+            }
+            ancestors.push(node);
+            match scene {
+                dot_vox::SceneNode::Transform {
+                    frames,
+                    child,
+                    layer_id,
+                    ..
+                } => {
+                    if source.skipped_layers.contains(layer_id) {
+                        ancestors.pop();
+                        return Ok(());
+                    }
+                    // Nodes without the requested frame use their first one.
+                    let Some(node_frame) = frames
+                        .iter()
+                        .find(|f| frame_index(&f.attributes) == frame)
+                        .or_else(|| frames.get(0))
+                    else {
+                        return Err(error(node, SceneProblem::MissingFrame));
+                    };
+                    let t = node_frame
+                        .position()
+                        .and_then(|t| Some(Vec3::new(t.x, t.y, t.z)))
+                        .unwrap_or_default();
+
+                    // Other rotations are reported during validation.
+                    let r = frame_rotation(node_frame).unwrap_or_else(snap_rotation);
+
+                    trans += rot * t;
+                    rot *= r;
+
+                    insert_scene(
+                        source,
+                        palette,
+                        *child,
+                        ancestors,
+                        rot,
+                        trans,
+                        sparse_scene,
+                        aabbs,
+                    )?;
+                }
+                dot_vox::SceneNode::Group { children, .. } => {
+                    for child in children {
+                        insert_scene(
+                            source,
+                            palette,
+                            *child,
+                            ancestors,
+                            rot,
+                            trans,
+                            sparse_scene,
+                            aabbs,
+                        )?;
+                    }
+                }
+                dot_vox::SceneNode::Shape { models, .. } => {
+                    // Shapes without the requested frame use their first one.
+                    let frame = if models.iter().any(|m| frame_index(&m.attributes) == frame) {
+                        frame
+                    } else {
+                        models.first().map_or(0, |m| frame_index(&m.attributes))
+                    };
+                    for model in models
+                        .iter()
+                        .filter(|m| frame_index(&m.attributes) == frame)
+                    {
+                        let Some(model) = dot_vox_data.models.get(model.model_id as usize) else {
+                            return Err(error(node, SceneProblem::MissingModel(model.model_id)));
+                        };
+                        render_model(palette, source, model, sparse_scene, aabbs, rot, trans);
+                    }
+                }
+            }
+            ancestors.pop();
+            Ok(())
+        }
+
+        let mut sparse_scene = SparseScene::empty();
+        let mut aabbs = Vec::new();
+        let mut bounds = Vec::new();
+        for source in sources {
+            let dot_vox_data = source.vox;
+            // Material ids are one more than the palette index they belong to.
+            let emissive = dot_vox_data
+                .materials
+                .iter()
+                .filter(|material| {
+                    material.properties.get("_type").map(String::as_str) == Some("_emit")
+                })
+                .filter_map(|material| usize::try_from(material.id).ok()?.checked_sub(1))
+                .collect::<HashSet<_>>();
+            let palette = dot_vox_data
+                .palette
+                .iter()
+                .enumerate()
+                .map(|(i, col)| PaletteEntry {
+                    color: Rgb::new(col.r, col.g, col.b),
+                    glowy: emissive.contains(&i)
+                        || u8::try_from(i).map_or(false, |i| glow_indices.contains(&i)),
+                    shiny: water_alpha_threshold.map_or(false, |threshold| col.a < threshold),
+                    hollow: u8::try_from(i).map_or(false, |i| hollow_indices.contains(&i)),
+                    transparent: col.a == 0,
+                })
+                .collect::<Vec<_>>();
+            // Boxes are only folded within a source, so the bounds of every
+            // source are kept.
+            let mut source_aabbs = Vec::new();
+            insert_scene(
+                &source,
+                &palette,
+                source.root,
+                &mut Vec::new(),
+                source.rot,
+                source.offset,
+                &mut sparse_scene,
+                &mut source_aabbs,
+            )?;
+            if let Some(aabb) = source_aabbs.iter().copied().reduce(|a, b| a.union(b)) {
+                let name = source.name.to_string();
+                bounds.push((source.piece, name, aabb, source.rot, source.offset));
+            }
+            aabbs.extend(source_aabbs);
+        }
+
+        // Anchors are taken out of the scene, along with the index of their color.
+        let anchor_cells = sparse_scene
+            .cells()
+            .filter_map(|(wpos, cell, origin)| {
+                let color = cell.get_color()?;
+                let index = anchor_colors.iter().position(|anchor| *anchor == color)?;
+                Some((wpos, origin.piece, index))
+            })
+            .collect::<Vec<_>>();
+        let mut anchors = HashMap::<u16, Vec<(usize, Vec3<i32>)>>::new();
+        for (wpos, piece, index) in anchor_cells {
+            sparse_scene.set(wpos, Cell::Empty).unwrap();
+            anchors.entry(piece).or_default().push((index, wpos));
+        }
+        let marker_cells = sparse_scene
+            .cells()
+            .filter_map(|(wpos, cell, origin)| {
+                let color = cell.get_color()?;
+                let (name, _) = markers.iter().find(|(_, marker)| *marker == color)?;
+                Some((wpos, origin.piece, name))
+            })
+            .collect::<Vec<_>>();
+        let mut piece_markers = HashMap::<u16, HashMap<String, Vec<Vec3<i32>>>>::new();
+        for (wpos, piece, name) in marker_cells {
+            sparse_scene.set(wpos, Cell::Empty).unwrap();
+            piece_markers
+                .entry(piece)
+                .or_default()
+                .entry(name.clone())
+                .or_default()
+                .push(wpos);
+        }
+        let mut voxels = HashMap::<u16, (usize, Vec3<i64>)>::new();
+        for (wpos, _, origin) in sparse_scene.cells() {
+            let (count, sum) = voxels.entry(origin.piece).or_default();
+            *count += 1;
+            *sum += wpos.map(i64::from);
+        }
+        let bounds = bounds
+            .into_iter()
+            .map(|(piece, name, aabb, rot, offset)| {
+                let mut anchors = anchors.remove(&piece).unwrap_or_default();
+                // Anchors are ordered by color, then by their position in the
+                // vox file, so rotating the piece doesn't change the order.
+                anchors.sort_by_key(|(index, wpos)| {
+                    let pos = rot.transposed() * (*wpos - offset);
+                    (*index, pos.z, pos.y, pos.x)
+                });
+                let mut markers = piece_markers.remove(&piece).unwrap_or_default();
+                for positions in markers.values_mut() {
+                    positions.sort_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+                }
+                let (count, sum) = voxels.get(&piece).copied().unwrap_or_default();
+                PieceBounds {
+                    name,
+                    aabb,
+                    voxels: count,
+                    centroid: (count > 0).then(|| sum.map(|e| e as f32) / count as f32),
+                    anchors: anchors.into_iter().map(|(_, wpos)| wpos).collect(),
+                    markers,
+                }
+            })
+            .collect();
+        Ok((sparse_scene, aabbs, bounds))
+    }
+
+    /// A scene without any cells.
+    ///
+    /// ```
+    /// use map_placer::scene::SparseScene;
+    /// use vek::{Rgb, Vec3};
+    /// use veloren_common::terrain::{Block, BlockKind};
+    ///
+    /// let mut scene = SparseScene::empty();
+    /// assert_eq!(scene.cells().count(), 0);
+    /// // Blocks are placed as they are, their cells stay empty.
+    /// let block = Block::new(BlockKind::Rock, Rgb::new(90, 90, 90));
+    /// scene.set_block(Vec3::new(1, 2, 3), block, 0);
+    /// assert_eq!(scene.cells().count(), 0);
+    /// ```
+    pub fn empty() -> Self {
+        SparseScene(
+            match VolGrid3d::new() {
+                Ok(ok) => ok,
+                Err(_) => panic!(),
+            },
+            match VolGrid3d::new() {
+                Ok(ok) => ok,
+                Err(_) => panic!(),
+            },
+            HashMap::new(),
+        )
+    }
+
+    /// Sets the cell at `wpos`, inserting its chunk if needed.
+    pub(crate) fn set_cell(&mut self, wpos: Vec3<i32>, cell: Cell, origin: CellOrigin) {
+        let key = self.pos_key(wpos);
+        if self.get_key_arc(key).is_none() {
+            self.insert(key, std::sync::Arc::new(Chunk::filled(Cell::Empty, ())));
+            self.1
+                .insert(key, std::sync::Arc::new(Chunk::filled(CellOrigin::default(), ())));
+        }
+        // The chunk of every position is inserted on demand, so setting a cell
+        // can't be out of bounds.
+        self.set(wpos, cell).expect("The chunk of the cell was just inserted");
+        self.1.set(wpos, origin).expect("The chunk of the cell was just inserted");
+        self.2.remove(&wpos);
+    }
+
+    /// The cell at `wpos`, empty outside of the chunks of the scene.
+    pub fn cell(&self, wpos: Vec3<i32>) -> Cell {
+        self.get(wpos).copied().unwrap_or(Cell::Empty)
+    }
+
+    /// Whether there is a cell or a block of a template at `wpos`.
+    pub fn is_occupied(&self, wpos: Vec3<i32>) -> bool {
+        self.2.contains_key(&wpos) || !matches!(self.cell(wpos), Cell::Empty)
+    }
+
+    /// Sets a block that is placed as it is.
+    pub fn set_block(&mut self, wpos: Vec3<i32>, block: Block, piece: u16) {
+        self.set_cell(wpos, Cell::Empty, CellOrigin { piece, index: 0 });
+        self.2.insert(wpos, block);
+    }
+
+    /// Builds a scene from the blocks of a template.
+    pub(crate) fn from_template(
+        template: &Template,
+        piece: u16,
+        rot: Mat3<i32>,
+        offset: Vec3<i32>,
+    ) -> (Self, Vec<Aabb<i32>>) {
+        let mut scene = Self::empty();
+        let mut bounds = None::<Aabb<i32>>;
+        for (pos, block) in &template.blocks {
+            let wpos = offset + rot * Vec3::from(*pos);
+            scene.set_block(wpos, *block, piece);
+            bounds = Some(bounds.map_or(
+                Aabb {
+                    min: wpos,
+                    max: wpos,
+                },
+                |bounds| bounds.expanded_to_contain_point(wpos),
+            ));
+        }
+        (scene, bounds.into_iter().collect())
+    }
+
+    /// Every non-empty cell, along with its position and origin.
+    pub fn cells(&self) -> impl Iterator<Item = (Vec3<i32>, Cell, CellOrigin)> + '_ {
+        self.iter().flat_map(move |(key, chunk)| {
+            let min = self.key_pos(key);
+            chunk
+                .full_vol_iter()
+                .filter(|(_, cell)| !matches!(cell, Cell::Empty))
+                .map(move |(pos, cell)| {
+                    let wpos = min + pos;
+                    (wpos, *cell, *self.1.get(wpos).unwrap())
+                })
+        })
+    }
+
+    /// Copies the cells and blocks of `other` into this scene, over the ones
+    /// already there unless `mode` is `Underwrite` or `keep` returns true for
+    /// the piece of the one already there.
+    pub(crate) fn merge(&mut self, other: &Self, mode: Merge, mut keep: impl FnMut(u16) -> bool) {
+        for (key, chunk) in other.iter() {
+            let origins = other.1.get_key_arc(key).expect("Both grids have the same chunks");
+            // Chunks this scene doesn't have yet are shared rather than copied
+            // cell by cell.
+            if self.get_key_arc(key).is_none() {
+                self.insert(key, chunk.clone());
+                self.1.insert(key, origins.clone());
+                continue;
+            }
+            let min = self.key_pos(key);
+            for (pos, cell) in chunk.full_vol_iter() {
+                let wpos = min + pos;
+                if matches!(cell, Cell::Empty) || !self.overwrites(wpos, mode, &mut keep) {
+                    continue;
+                }
+                let origin = *origins.get(pos).expect("The position is within the chunk");
+                self.set_cell(wpos, *cell, origin);
+            }
+        }
+        for (wpos, block) in &other.2 {
+            if self.overwrites(*wpos, mode, &mut keep) {
+                self.set_block(*wpos, *block, other.1.get(*wpos).unwrap().piece);
+            }
+        }
+    }
+
+    /// Whether merging with `mode` writes over the cell or block at `wpos`.
+    fn overwrites(&self, wpos: Vec3<i32>, mode: Merge, keep: impl FnOnce(u16) -> bool) -> bool {
+        if !self.is_occupied(wpos) {
+            return true;
+        }
+        mode != Merge::Underwrite && !self.1.get(wpos).map_or(false, |origin| keep(origin.piece))
+    }
+
+    /// Empties every cell within `aabb`.
+    pub(crate) fn clear(&mut self, aabb: Aabb<i32>) {
+        let (min_key, max_key) = (self.pos_key(aabb.min), self.pos_key(aabb.max));
+        for x in min_key.x..=max_key.x {
+            for y in min_key.y..=max_key.y {
+                for z in min_key.z..=max_key.z {
+                    let key = Vec3::new(x, y, z);
+                    if self.get_key_arc(key).is_none() {
+                        continue;
+                    }
+                    let min = self.key_pos(key).map2(aabb.min, i32::max);
+                    let max = (self.key_pos(key) + SscSize::SIZE.map(|e| e as i32) - 1)
+                        .map2(aabb.max, i32::min);
+                    for x in min.x..=max.x {
+                        for y in min.y..=max.y {
+                            for z in min.z..=max.z {
+                                self.set(Vec3::new(x, y, z), Cell::Empty).unwrap();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.2.retain(|wpos, _| !aabb.contains_point(*wpos));
+    }
+
+    /// Empties the cells within `cutouts`, given relative to the minimum
+    /// corner of a piece spanning `bounds` before it was turned by `rot`.
+    /// Returns the cutouts in world space, clipped to `bounds`.
+    pub fn cut_out(
+        &mut self,
+        cutouts: &[Aabb<i32>],
+        rot: Mat3<i32>,
+        bounds: Aabb<i32>,
+    ) -> Vec<Aabb<i32>> {
+        let cutouts = cutouts
+            .iter()
+            .filter_map(|cutout| {
+                let cutout = local_to_world(*cutout, rot, bounds);
+                let min = cutout.min.map2(bounds.min, i32::max);
+                let max = cutout.max.map2(bounds.max, i32::min);
+                min.map2(max, |min, max| min <= max)
+                    .reduce_and()
+                    .then_some(Aabb { min, max })
+            })
+            .collect::<Vec<_>>();
+        for cutout in &cutouts {
+            self.clear(*cutout);
+        }
+        cutouts
+    }
+
+    /// Empties `fraction` of the solid cells, preferring cells with more
+    /// neighbours that aren't solid so edges crumble first, and leaving the
+    /// enclosed cells to last. Which cells are emptied only depends on `seed`
+    /// and the cells. Returns how many cells were emptied.
+    pub(crate) fn decay(&mut self, fraction: f32, seed: u64) -> usize {
+        let solid = |cell: &Cell| {
+            !matches!(cell, Cell::Empty) && !cell.is_hollow() && !cell.is_shiny()
+        };
+        let mut cells = self
+            .cells()
+            .filter(|(_, cell, _)| solid(cell))
+            .map(|(wpos, _, _)| {
+                let exposure = [
+                    Vec3::unit_x(),
+                    -Vec3::unit_x(),
+                    Vec3::unit_y(),
+                    -Vec3::unit_y(),
+                    Vec3::unit_z(),
+                    -Vec3::unit_z(),
+                ]
+                .into_iter()
+                .filter(|dir| !self.get(wpos + *dir).map_or(false, solid))
+                .count();
+                // The largest of `exposure` rolls, which weights the cells by
+                // their exposure like the keys of weighted sampling without
+                // replacement, without floating point operations that differ
+                // between platforms.
+                let mut rng = PosRng::new(seed, wpos);
+                let roll = (0..exposure.max(1)).map(|_| rng.next_u64()).max().unwrap();
+                ((exposure > 0, roll), wpos)
+            })
+            .collect::<Vec<_>>();
+        let count = (cells.len() as f64 * f64::from(fraction.clamp(0.0, 1.0))).round() as usize;
+        cells.sort_unstable_by_key(|(key, wpos)| (std::cmp::Reverse(*key), wpos.into_array()));
+        for (_, wpos) in &cells[..count] {
+            self.set(*wpos, Cell::Empty).unwrap();
+        }
+        count
+    }
+
+    /// The mean position of the cells and the blocks other than air of the
+    /// scene.
+    pub(crate) fn centroid(&self) -> Option<Vec3<f32>> {
+        let blocks = self.2.iter().filter(|(_, block)| block.kind() != BlockKind::Air);
+        let positions = self.cells().map(|(wpos, _, _)| wpos).chain(blocks.map(|(wpos, _)| *wpos));
+        let (count, sum) = positions.fold((0usize, Vec3::<i64>::zero()), |(count, sum), wpos| {
+            (count + 1, sum + wpos.map(i64::from))
+        });
+        (count > 0).then(|| sum.map(|e| e as f32) / count as f32)
+    }
+
+    /// The z coordinate of the lowest non-empty cell.
+    pub(crate) fn lowest_cell(&self) -> Option<i32> {
+        self.cells().map(|(wpos, _, _)| wpos.z).min()
+    }
+
+    /// The lowest and highest z of the cells and blocks in every column of the
+    /// scene that has any, with the lowest moved `margin` further down.
+    ///
+    /// ```
+    /// use map_placer::scene::SparseScene;
+    /// use vek::*;
+    /// use veloren_common::terrain::{Block, BlockKind};
+    ///
+    /// let mut scene = SparseScene::empty();
+    /// let block = Block::new(BlockKind::Rock, Rgb::new(90, 90, 90));
+    /// scene.set_block(Vec3::new(1, 2, 3), block, 0);
+    /// scene.set_block(Vec3::new(1, 2, 40), block, 0);
+    /// let columns = scene.column_extents(2);
+    /// assert_eq!(columns.len(), 1);
+    /// assert_eq!(columns[&Vec2::new(1, 2)], (1, 40));
+    /// ```
+    pub fn column_extents(&self, margin: u32) -> HashMap<Vec2<i32>, (i32, i32)> {
+        let mut columns = HashMap::<Vec2<i32>, (i32, i32)>::new();
+        let mut add = |column: Vec2<i32>, (low, high): (i32, i32)| {
+            let extent = columns.entry(column).or_insert((low, high));
+            *extent = (extent.0.min(low), extent.1.max(high));
+        };
+        // The columns of every chunk are gathered in an array first, so each
+        // column of a chunk is only looked up once.
+        let size = SscSize::SIZE.map(|e| e as i32);
+        for (key, chunk) in self.iter() {
+            let mut chunk_columns = vec![None::<(i32, i32)>; (size.x * size.y) as usize];
+            for (pos, cell) in chunk.full_vol_iter() {
+                if matches!(cell, Cell::Empty) {
+                    continue;
+                }
+                let extent = &mut chunk_columns[(pos.y * size.x + pos.x) as usize];
+                *extent = Some(extent.map_or((pos.z, pos.z), |(low, high)| {
+                    (low.min(pos.z), high.max(pos.z))
+                }));
+            }
+            let min = self.key_pos(key);
+            for (i, extent) in (0..).zip(chunk_columns) {
+                if let Some((low, high)) = extent {
+                    add(min.xy() + Vec2::new(i % size.x, i / size.x), (min.z + low, min.z + high));
+                }
+            }
+        }
+        for wpos in self.2.keys() {
+            add(wpos.xy(), (wpos.z, wpos.z));
+        }
+        let margin = i32::try_from(margin).unwrap_or(i32::MAX);
+        for (low, _) in columns.values_mut() {
+            *low = low.saturating_sub(margin);
+        }
+        columns
+    }
+
+    /// The columns of the cells in the lowest layer of the scene, and the z of
+    /// that layer.
+    fn footprint(&self) -> Option<(i32, HashSet<Vec2<i32>>)> {
+        let bottom = self.lowest_cell()?;
+        let columns = self
+            .cells()
+            .filter(|(wpos, _, _)| wpos.z == bottom)
+            .map(|(wpos, _, _)| wpos.xy())
+            .collect();
+        Some((bottom, columns))
+    }
+
+    /// The z of the lowest layer of the scene, with the columns of that layer
+    /// that are within `width` cells of its edge.
+    pub fn skirt_columns(&self, width: u32) -> Option<(i32, Vec<Vec2<i32>>)> {
+        let (bottom, footprint) = self.footprint()?;
+        let width = width as i32;
+        let mut columns = footprint
+            .iter()
+            .copied()
+            .filter(|column| {
+                (-width..=width).any(|x| {
+                    (-width..=width).any(|y| !footprint.contains(&(*column + Vec2::new(x, y))))
+                })
+            })
+            .collect::<Vec<_>>();
+        columns.sort_unstable_by_key(|column| (column.y, column.x));
+        Some((bottom, columns))
+    }
+
+    /// The columns around the lowest layer of the scene within `width` cells
+    /// of it, with how many cells away from it they are.
+    pub fn berm_columns(&self, width: u32) -> Vec<(Vec2<i32>, u32)> {
+        let Some((_, footprint)) = self.footprint() else {
+            return Vec::new();
+        };
+        let width = width as i32;
+        let mut distances = HashMap::<Vec2<i32>, u32>::new();
+        for column in &footprint {
+            for x in -width..=width {
+                for y in -width..=width {
+                    let around = *column + Vec2::new(x, y);
+                    if footprint.contains(&around) {
+                        continue;
+                    }
+                    let distance = x.unsigned_abs().max(y.unsigned_abs());
+                    let entry = distances.entry(around).or_insert(distance);
+                    *entry = (*entry).min(distance);
+                }
+            }
+        }
+        let mut columns = distances.into_iter().collect::<Vec<_>>();
+        columns.sort_unstable_by_key(|(column, _)| (column.y, column.x));
+        columns
+    }
+
+    /// The positions within `aabbs` that can't be reached from outside of them
+    /// through empty cells, and with `carve_depth` the solid cells more than
+    /// that many solid cells away from the outside.
+    pub(crate) fn interior(
+        &self,
+        aabbs: &[Aabb<i32>],
+        carve_depth: Option<u32>,
+    ) -> HashSet<Vec3<i32>> {
+        let is_empty = |wpos| {
+            self.get(wpos)
+                .map_or(true, |cell| matches!(cell, Cell::Empty))
+        };
+        let mut interior = HashSet::new();
+        for aabb in aabbs {
+            // The bounds grown by one, so the outside surrounds the model.
+            let min = aabb.min - 1;
+            let size = (aabb.max + 1 - min + 1).map(|e| e as usize);
+            let index = |pos: Vec3<i32>| {
+                let pos = (pos - min).map(|e| e as usize);
+                (pos.z * size.y + pos.y) * size.x + pos.x
+            };
+            // The number of solid cells passed through to reach each cell from
+            // the outside, found breadth first with empty cells costing nothing.
+            let mut depth = vec![u16::MAX; size.product()];
+            let mut queue = VecDeque::new();
+            for x in 0..size.x as i32 {
+                for y in 0..size.y as i32 {
+                    for z in 0..size.z as i32 {
+                        let border = [x, y, z]
+                            .iter()
+                            .zip(size.iter())
+                            .any(|(e, s)| *e == 0 || *e == *s as i32 - 1);
+                        if border {
+                            let pos = min + Vec3::new(x, y, z);
+                            depth[index(pos)] = 0;
+                            queue.push_back(pos);
+                        }
+                    }
+                }
+            }
+            while let Some(pos) = queue.pop_front() {
+                let pos_depth = depth[index(pos)];
+                for dir in [
+                    Vec3::unit_x(),
+                    -Vec3::unit_x(),
+                    Vec3::unit_y(),
+                    -Vec3::unit_y(),
+                    Vec3::unit_z(),
+                    -Vec3::unit_z(),
+                ] {
+                    let next = pos + dir;
+                    if !aabb.contains_point(next) {
+                        continue;
+                    }
+                    let empty = is_empty(next);
+                    let next_depth = if empty {
+                        pos_depth
+                    } else {
+                        pos_depth.saturating_add(1)
+                    };
+                    if next_depth < depth[index(next)] {
+                        depth[index(next)] = next_depth;
+                        if empty {
+                            queue.push_front(next);
+                        } else {
+                            queue.push_back(next);
+                        }
+                    }
+                }
+            }
+            for x in aabb.min.x..=aabb.max.x {
+                for y in aabb.min.y..=aabb.max.y {
+                    for z in aabb.min.z..=aabb.max.z {
+                        let pos = Vec3::new(x, y, z);
+                        let pos_depth = u32::from(depth[index(pos)]);
+                        let carved = if is_empty(pos) {
+                            pos_depth > 0
+                        } else {
+                            carve_depth.map_or(false, |carve_depth| pos_depth > carve_depth)
+                        };
+                        if carved {
+                            interior.insert(pos);
+                        }
+                    }
+                }
+            }
+        }
+        interior
+    }
+
+    /// Shifts every column up or down so its lowest cell rests on the surface
+    /// of `world`. Columns without a surface within `range`, or whose shift
+    /// differs from the one of a neighbouring column by more than `max_slope`,
+    /// are dropped. Returns the shifted scene along with its bounds.
+    pub(crate) fn conform(
+        &self,
+        world: &mut World,
+        range: (i32, i32),
+        max_slope: Option<u32>,
+    ) -> (Self, Option<Aabb<i32>>) {
+        let mut lowest = HashMap::<Vec2<i32>, i32>::new();
+        for (wpos, _, _) in self.cells() {
+            lowest
+                .entry(wpos.xy())
+                .and_modify(|z| *z = (*z).min(wpos.z))
+                .or_insert(wpos.z);
+        }
+        let shifts = lowest
+            .iter()
+            .filter_map(|(pos, lowest)| {
+                Some((*pos, world.surface_height(*pos, range)? + 1 - lowest))
+            })
+            .collect::<HashMap<_, _>>();
+        let steep = shifts
+            .iter()
+            .filter(|(pos, shift)| {
+                max_slope.map_or(false, |max_slope| {
+                    [Vec2::unit_x(), -Vec2::unit_x(), Vec2::unit_y(), -Vec2::unit_y()]
+                        .iter()
+                        .filter_map(|dir| shifts.get(&(**pos + *dir)))
+                        .any(|other| other.abs_diff(**shift) > max_slope)
+                })
+            })
+            .map(|(pos, _)| *pos)
+            .collect::<HashSet<_>>();
+
+        let mut scene = Self::empty();
+        let mut bounds = None::<Aabb<i32>>;
+        for (wpos, cell, origin) in self.cells() {
+            let Some(shift) = shifts.get(&wpos.xy()) else {
+                continue;
+            };
+            if steep.contains(&wpos.xy()) {
+                continue;
+            }
+            let wpos = wpos + Vec3::unit_z() * *shift;
+            scene.set_cell(wpos, cell, origin);
+            bounds = Some(bounds.map_or(
+                Aabb {
+                    min: wpos,
+                    max: wpos,
+                },
+                |bounds| bounds.expanded_to_contain_point(wpos),
+            ));
+        }
+        (scene, bounds)
+    }
+}
+
+/// The rotation of a transform frame, or its columns if it isn't a signed
+/// permutation matrix, made of quarter turns and mirrorings.
+pub(crate) fn frame_rotation(frame: &dot_vox::Frame) -> Result<Mat3<i32>, [[f32; 3]; 3]> {
+    let Some(cols) = frame.orientation().map(|r| r.to_cols_array_2d()) else {
+        return Ok(Mat3::identity());
+    };
+    let rot = Mat3::from_col_arrays(cols).map(|f| f as i32);
+    let unit = |v: Vec3<i32>| v.map(i32::abs).sum() == 1 && v.map(i32::abs).reduce_max() == 1;
+    let exact = cols.iter().flatten().all(|f| [-1.0, 0.0, 1.0].contains(f));
+    let rows = rot.transposed();
+    if exact && (0..3).all(|i| unit(rot.cols[i]) && unit(rows.cols[i])) {
+        Ok(rot)
+    } else {
+        Err(cols)
+    }
+}
+
+/// The signed permutation matrix closest to the matrix with columns `cols`.
+pub(crate) fn snap_rotation(cols: [[f32; 3]; 3]) -> Mat3<i32> {
+    const PERMUTATIONS: [[usize; 3]; 6] =
+        [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+    let mut best = (f32::NEG_INFINITY, Mat3::identity());
+    for rows in PERMUTATIONS {
+        for signs in 0..8 {
+            let mut rot = Mat3::zero();
+            let mut score = 0.0;
+            for (col, row) in rows.into_iter().enumerate() {
+                let sign = if signs & (1 << col) == 0 { 1 } else { -1 };
+                rot.cols[col][row] = sign;
+                score += cols[col][row] * sign as f32;
+            }
+            if score > best.0 {
+                best = (score, rot);
+            }
+        }
+    }
+    best.1
+}
+
+/// Adds errors for transform frames of `dot_vox_data` whose rotation isn't
+/// made of quarter turns and mirrorings, or warnings with
+/// `approximate_rotations`, and for translations that could overflow once
+/// they are added up through the scene graph.
+pub(crate) fn check_transforms(
+    dot_vox_data: &DotVoxData,
+    owner: &str,
+    approximate_rotations: bool,
+    validation: &mut Validation,
+) {
+    let scenes = &dot_vox_data.scenes;
+    let node_name = |i: usize, attributes: &dot_vox::Dict| match attributes.get("_name") {
+        Some(name) => format!("{:?}", name),
+        None => i.to_string(),
+    };
+    for (i, node) in scenes.iter().enumerate() {
+        if let dot_vox::SceneNode::Transform {
+            frames, attributes, ..
+        } = node
+        {
+            for frame in frames {
+                if let Err(cols) = frame_rotation(frame) {
+                    if approximate_rotations {
+                        validation.warnings.push(format!(
+                            "The node {} of {} has the rotation {:?}, which isn't made of quarter \
+                             turns, it is snapped to {:?}",
+                            node_name(i, attributes),
+                            owner,
+                            cols,
+                            snap_rotation(cols).into_col_arrays()
+                        ));
+                    } else {
+                        validation.errors.push(format!(
+                            "The node {} of {} has the rotation {:?}, which isn't made of quarter \
+                             turns, pass --approximate-rotations to snap it to the closest one",
+                            node_name(i, attributes),
+                            owner,
+                            cols
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    // An upper bound of how far each node can be translated along any axis.
+    let mut stack = vec![(0, 0i64)];
+    let mut visited = HashSet::new();
+    while let Some((i, reach)) = stack.pop() {
+        if !visited.insert(i) {
+            continue;
+        }
+        match scenes.get(i) {
+            Some(dot_vox::SceneNode::Transform {
+                frames,
+                child,
+                attributes,
+                ..
+            }) => {
+                let reach = reach
+                    + frames
+                        .iter()
+                        .filter_map(|frame| frame.position())
+                        .map(|t| i64::from(t.x.abs().max(t.y.abs()).max(t.z.abs())))
+                        .max()
+                        .unwrap_or(0);
+                if reach > i64::from(i32::MAX) {
+                    validation.errors.push(format!(
+                        "The translations up to node {} of {} add up to more than fits in the \
+                         world",
+                        node_name(i, attributes),
+                        owner
+                    ));
+                    continue;
+                }
+                stack.push((*child as usize, reach));
+            },
+            Some(dot_vox::SceneNode::Group { children, .. }) => {
+                stack.extend(children.iter().map(|child| (*child as usize, reach)));
+            },
+            Some(dot_vox::SceneNode::Shape { .. }) | None => {},
+        }
+    }
+}
+
+/// The names of the named transform nodes of a vox file, along with their
+/// indices.
+pub(crate) fn named_nodes(dot_vox_data: &DotVoxData) -> impl Iterator<Item = (u32, &str)> {
+    (0..).zip(&dot_vox_data.scenes).filter_map(|(i, node)| match node {
+        dot_vox::SceneNode::Transform { attributes, .. } => {
+            Some((i, attributes.get("_name")?.as_str()))
+        },
+        _ => None,
+    })
+}
+
+/// The squared Euclidean distance between two colors.
+pub(crate) fn color_distance(a: Rgb<u8>, b: Rgb<u8>) -> u32 {
+    a.map2(b, |a, b| u32::from(a.abs_diff(b)).pow(2)).sum()
+}
+
+/// The colors of every voxel in the models of a vox file.
+pub(crate) fn used_colors(dot_vox_data: &DotVoxData) -> HashSet<Rgb<u8>> {
+    dot_vox_data
+        .models
+        .iter()
+        .flat_map(|model| &model.voxels)
+        .filter_map(|voxel| dot_vox_data.palette.get(voxel.i as usize))
+        .map(|col| Rgb::new(col.r, col.g, col.b))
+        .collect()
+}
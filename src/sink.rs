@@ -0,0 +1,378 @@
+//! Where resolved blocks are written to.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
+
+use vek::*;
+use veloren_common::terrain::{Block, BlockKind, SpriteKind};
+#[cfg(feature = "server")]
+use veloren_server::terrain_persistence::TerrainPersistence;
+
+use crate::persistence::{terrain_chunk_key, PersistedBlocks};
+
+/// Somewhere placed blocks are written to.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use map_placer::sink::{BlockSink, BoundsFilter};
+/// use vek::{Aabb, Rgb, Vec3};
+/// use veloren_common::terrain::{Block, BlockKind};
+///
+/// let mut filter = BoundsFilter {
+///     bounds: Some(Aabb {
+///         min: Vec3::zero(),
+///         max: Vec3::broadcast(15),
+///     }),
+///     filtered: 0,
+///     sink: HashMap::<Vec3<i32>, Block>::new(),
+/// };
+/// let block = Block::new(BlockKind::Rock, Rgb::new(90, 90, 90));
+/// filter.set_block(Vec3::new(1, 2, 3), block);
+/// filter.set_block(Vec3::new(1, 2, 30), block);
+/// filter.flush();
+/// assert_eq!(filter.sink.len(), 1);
+/// assert_eq!(filter.filtered, 1);
+/// ```
+pub trait BlockSink {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block);
+
+    /// Sets blocks that are all in the terrain chunk `key`, so sinks can look
+    /// the chunk up once.
+    fn set_blocks_in_chunk(&mut self, _key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        for (wpos, block) in blocks {
+            self.set_block(*wpos, *block);
+        }
+    }
+
+    /// Called once all blocks are written.
+    fn flush(&mut self);
+}
+
+#[cfg(feature = "server")]
+impl BlockSink for TerrainPersistence {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        TerrainPersistence::set_block(self, wpos, block);
+    }
+
+    fn flush(&mut self) {
+        self.unload_all();
+    }
+}
+
+/// Keeps the blocks in memory.
+impl BlockSink for HashMap<Vec3<i32>, Block> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        self.insert(wpos, block);
+    }
+
+    fn flush(&mut self) {}
+}
+
+impl<S: BlockSink + ?Sized> BlockSink for &mut S {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        (**self).set_block(wpos, block);
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        (**self).set_blocks_in_chunk(key, blocks);
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+}
+
+/// Discards the blocks when `None`.
+impl<S: BlockSink> BlockSink for Option<S> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        if let Some(sink) = self {
+            sink.set_block(wpos, block);
+        }
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        if let Some(sink) = self {
+            sink.set_blocks_in_chunk(key, blocks);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(sink) = self {
+            sink.flush();
+        }
+    }
+}
+
+impl BlockSink for Vec<(Vec3<i32>, Block)> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        self.push((wpos, block));
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// Writes to both sinks.
+impl<A: BlockSink, B: BlockSink> BlockSink for (A, B) {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        self.0.set_block(wpos, block);
+        self.1.set_block(wpos, block);
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        self.0.set_blocks_in_chunk(key, blocks);
+        self.1.set_blocks_in_chunk(key, blocks);
+    }
+
+    fn flush(&mut self) {
+        self.0.flush();
+        self.1.flush();
+    }
+}
+
+/// Only passes the blocks within `bounds` on to `sink`, counting the others.
+#[derive(Debug)]
+pub struct BoundsFilter<S> {
+    pub bounds: Option<Aabb<i32>>,
+    pub filtered: usize,
+    pub sink: S,
+}
+
+impl<S: BlockSink> BlockSink for BoundsFilter<S> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        if self.bounds.map_or(true, |bounds| bounds.contains_point(wpos)) {
+            self.sink.set_block(wpos, block);
+        } else {
+            self.filtered += 1;
+        }
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        let Some(bounds) = self.bounds else {
+            self.sink.set_blocks_in_chunk(key, blocks);
+            return;
+        };
+        let inside = blocks
+            .iter()
+            .filter(|(wpos, _)| bounds.contains_point(*wpos))
+            .copied()
+            .collect::<Vec<_>>();
+        self.filtered += blocks.len() - inside.len();
+        self.sink.set_blocks_in_chunk(key, &inside);
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+/// Only passes the blocks in the terrain chunks of `allowed` on to `sink`,
+/// counting the others by their chunk. Without `allowed` every block is passed
+/// on.
+#[derive(Debug)]
+pub struct ChunkFilter<S> {
+    pub allowed: Option<HashSet<Vec2<i32>>>,
+    /// The blocks that weren't passed on, by the key of their terrain chunk.
+    pub disallowed: BTreeMap<[i32; 2], usize>,
+    pub sink: S,
+}
+
+impl<S: BlockSink> BlockSink for ChunkFilter<S> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        let key = terrain_chunk_key(wpos);
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(&key) => {
+                *self.disallowed.entry(key.into_array()).or_default() += 1;
+            },
+            _ => self.sink.set_block(wpos, block),
+        }
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(&key) => {
+                *self.disallowed.entry(key.into_array()).or_default() += blocks.len();
+            },
+            _ => self.sink.set_blocks_in_chunk(key, blocks),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+/// Only passes blocks on to `sink` where nothing is persisted, so that what
+/// players built survives, counting the others. Positions in `own`, which
+/// earlier placements wrote, and positions within `clobber_within` are always
+/// passed on. Without `persisted` every block is passed on.
+#[derive(Debug)]
+pub struct ProtectPersisted<S> {
+    /// The persisted blocks from before the placement.
+    pub persisted: Option<PersistedBlocks>,
+    pub own: HashSet<Vec3<i32>>,
+    pub clobber_within: Vec<Aabb<i32>>,
+    /// The positions that weren't passed on.
+    pub skipped: Vec<Vec3<i32>>,
+    pub sink: S,
+}
+
+impl<S> ProtectPersisted<S> {
+    fn protects(&mut self, wpos: Vec3<i32>) -> bool {
+        let Some(persisted) = &mut self.persisted else {
+            return false;
+        };
+        let protected = !self.own.contains(&wpos)
+            && !self.clobber_within.iter().any(|aabb| aabb.contains_point(wpos))
+            && persisted.get(wpos).is_some();
+        if protected {
+            self.skipped.push(wpos);
+        }
+        protected
+    }
+}
+
+impl<S: BlockSink> BlockSink for ProtectPersisted<S> {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        if !self.protects(wpos) {
+            self.sink.set_block(wpos, block);
+        }
+    }
+
+    fn set_blocks_in_chunk(&mut self, key: Vec2<i32>, blocks: &[(Vec3<i32>, Block)]) {
+        if self.persisted.is_none() {
+            self.sink.set_blocks_in_chunk(key, blocks);
+            return;
+        }
+        let unprotected = blocks
+            .iter()
+            .filter(|(wpos, _)| !self.protects(*wpos))
+            .copied()
+            .collect::<Vec<_>>();
+        self.sink.set_blocks_in_chunk(key, &unprotected);
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+/// Keeps the topmost block of every column that isn't empty air, for a map
+/// of the placement seen from above. Only one block per column is kept, so
+/// large placements fit in memory.
+#[derive(Default, Debug)]
+pub struct TopView {
+    /// The height and block at the top of each column.
+    pub columns: HashMap<Vec2<i32>, (i32, Block)>,
+}
+
+impl BlockSink for TopView {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        let sprite = block.get_sprite().map_or(false, |sprite| sprite != SpriteKind::Empty);
+        if block.kind() == BlockKind::Air && !sprite {
+            return;
+        }
+        let top = self.columns.entry(wpos.xy()).or_insert((wpos.z, block));
+        if wpos.z >= top.0 {
+            *top = (wpos.z, block);
+        }
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// Collects blocks into a script of server console commands, one per block,
+/// batched by terrain chunk so a running server can be fed one region at a
+/// time. Later blocks at the same position replace earlier ones.
+///
+/// In `template`, `{x}`, `{y}` and `{z}` are replaced with the position,
+/// `{kind}` with the kind of the block, `{sprite}` with its sprite and `{r}`,
+/// `{g}` and `{b}` with its color, 0 for blocks without one.
+///
+/// ```
+/// use map_placer::sink::{BlockSink, CommandScript};
+/// use vek::{Rgb, Vec3};
+/// use veloren_common::terrain::{Block, BlockKind};
+///
+/// let mut script = CommandScript::new("/set_block {x} {y} {z} {kind} {r} {g} {b}");
+/// let block = Block::new(BlockKind::Rock, Rgb::new(90, 80, 70));
+/// script.set_block(Vec3::new(1, 2, 3), block);
+/// script.set_block(Vec3::new(40, 2, 3), block);
+/// script.set_block(Vec3::new(1, 2, 3), Block::new(BlockKind::Wood, Rgb::new(1, 2, 3)));
+/// assert_eq!(script.len(), 2);
+/// let mut out = Vec::new();
+/// script.write(&mut out).unwrap();
+/// assert_eq!(
+///     String::from_utf8(out).unwrap(),
+///     "# Terrain chunk 0, 0: 1 blocks\n\
+///      /set_block 1 2 3 Wood 1 2 3\n\
+///      # Terrain chunk 1, 0: 1 blocks\n\
+///      /set_block 40 2 3 Rock 90 80 70\n"
+/// );
+/// ```
+#[derive(Debug)]
+pub struct CommandScript {
+    pub template: String,
+    /// The blocks of every terrain chunk, by their z, y and x.
+    pub chunks: BTreeMap<(i32, i32), BTreeMap<(i32, i32, i32), Block>>,
+}
+
+impl CommandScript {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// The number of commands, one for every block.
+    pub fn len(&self) -> usize { self.chunks.values().map(BTreeMap::len).sum() }
+
+    pub fn is_empty(&self) -> bool { self.chunks.is_empty() }
+
+    /// The command that sets `block` at `wpos`.
+    pub fn command(&self, wpos: Vec3<i32>, block: Block) -> String {
+        let color = block.get_color().unwrap_or_default();
+        let sprite = block
+            .get_sprite()
+            .filter(|sprite| *sprite != SpriteKind::Empty)
+            .map_or(String::new(), |sprite| format!("{:?}", sprite));
+        self.template
+            .replace("{x}", &wpos.x.to_string())
+            .replace("{y}", &wpos.y.to_string())
+            .replace("{z}", &wpos.z.to_string())
+            .replace("{kind}", &format!("{:?}", block.kind()))
+            .replace("{sprite}", &sprite)
+            .replace("{r}", &color.r.to_string())
+            .replace("{g}", &color.g.to_string())
+            .replace("{b}", &color.b.to_string())
+    }
+
+    /// Writes the script, a comment line starting with `#` before the
+    /// commands of every terrain chunk, bottom to top within the chunk.
+    pub fn write(&self, out: &mut impl io::Write) -> io::Result<()> {
+        for ((x, y), blocks) in &self.chunks {
+            writeln!(out, "# Terrain chunk {}, {}: {} blocks", x, y, blocks.len())?;
+            for ((z, y, x), block) in blocks {
+                writeln!(out, "{}", self.command(Vec3::new(*x, *y, *z), *block))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockSink for CommandScript {
+    fn set_block(&mut self, wpos: Vec3<i32>, block: Block) {
+        let key = terrain_chunk_key(wpos);
+        self.chunks
+            .entry((key.x, key.y))
+            .or_default()
+            .insert((wpos.z, wpos.y, wpos.x), block);
+    }
+
+    /// The script is only written with `write`, once the placement is
+    /// confirmed.
+    fn flush(&mut self) {}
+}
@@ -0,0 +1,3292 @@
+//! Specs, which say which models to place where and how their colors are
+//! replaced.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use dot_vox::DotVoxData;
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+use vek::*;
+use veloren_common::{
+    assets::{Asset, RonLoader},
+    lottery::Lottery,
+    terrain::{Block, BlockKind, SpriteKind},
+};
+
+use crate::{
+    model::{
+        is_model_path, load_piece_model, resolve_model_path, spec_dir,
+        PieceModel, Template,
+    },
+    persistence::World,
+    place::{BuiltPiece, Context},
+    resolve::{splitmix64, Direction, ExistingBlock, Fallback, NeighborCell, PosRng, ReplaceMap},
+    scene::{
+        check_transforms, color_distance, named_nodes, used_colors, PieceBounds, SceneError,
+        SceneSource, SparseScene,
+    },
+};
+
+#[derive(Deserialize, Default, Clone, Debug, Copy)]
+pub enum Medium {
+    /// Water below the water level of the spec, air elsewhere.
+    #[default]
+    Auto,
+    Air,
+    Water,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum BlockSpec {
+    Sprite {
+        kind: SpriteKind,
+        #[serde(default)]
+        medium: Medium,
+        /// The orientation of the sprite, from 0 to 7.
+        #[serde(default)]
+        ori: Option<u8>,
+        /// Gives every sprite a random orientation, overriding `ori`.
+        #[serde(default)]
+        random_ori: bool,
+        /// A loot table specifier, recorded in the sprite manifest.
+        #[serde(default)]
+        loot: Option<String>,
+        /// The chance of every voxel to become the sprite, from 0 to 1.
+        #[serde(default = "default_chance")]
+        chance: f32,
+        /// What the voxels that don't become the sprite are placed as. Without
+        /// one the block already in the world is kept.
+        #[serde(default)]
+        fallback: Option<Box<BlockSpec>>,
+    },
+    Block {
+        kind: BlockKind,
+        #[serde(default)]
+        color: ColorSpec,
+        /// Uses the color of the cell instead of `color`, like `Inherit`.
+        #[serde(default)]
+        keep_color: bool,
+    },
+    Random(Lottery<BlockSpec>),
+    /// Glowing rock with the color of the cell.
+    Glowy,
+    /// Carves out air.
+    Hollow,
+    Water,
+    /// Drops the voxel, as if it was never part of the model.
+    Skip,
+    /// Keeps the block already in the world, even when filling empty cells.
+    Keep,
+    /// Picks `above` where smooth noise with features about `scale` blocks
+    /// apart is above `threshold`, from 0 to 1, otherwise `below`.
+    Noise {
+        scale: f32,
+        threshold: f32,
+        below: Box<BlockSpec>,
+        above: Box<BlockSpec>,
+    },
+    /// Picks `then` if the block already in the world matches `when`,
+    /// otherwise `else`.
+    If {
+        when: ExistingBlock,
+        then: Box<BlockSpec>,
+        #[serde(rename = "else")]
+        otherwise: Box<BlockSpec>,
+    },
+    /// Picks `then` if the cell next to the voxel in the direction `dir`
+    /// matches `is`, otherwise `else`.
+    Neighbor {
+        dir: Direction,
+        is: NeighborCell,
+        then: Box<BlockSpec>,
+        #[serde(rename = "else")]
+        otherwise: Box<BlockSpec>,
+    },
+    /// Breakpoints of `(height, spec)`, picking the spec with the highest
+    /// height at or below the voxel, counted from the bottom of the piece.
+    HeightGradient(Vec<(i32, BlockSpec)>),
+}
+
+pub(crate) fn default_chance() -> f32 {
+    1.0
+}
+
+impl BlockSpec {
+    /// Whether this spec or any spec within it is a `Neighbor` condition.
+    pub fn uses_neighbors(&self) -> bool {
+        match self {
+            BlockSpec::Neighbor { .. } => true,
+            BlockSpec::Sprite { fallback, .. } => {
+                fallback.as_ref().map_or(false, |fallback| fallback.uses_neighbors())
+            },
+            BlockSpec::Random(lottery) => lottery.iter().any(|(_, spec)| spec.uses_neighbors()),
+            BlockSpec::Noise { below, above, .. } => {
+                below.uses_neighbors() || above.uses_neighbors()
+            },
+            BlockSpec::If {
+                then, otherwise, ..
+            } => then.uses_neighbors() || otherwise.uses_neighbors(),
+            BlockSpec::HeightGradient(breakpoints) => {
+                breakpoints.iter().any(|(_, spec)| spec.uses_neighbors())
+            },
+            BlockSpec::Block { .. }
+            | BlockSpec::Glowy
+            | BlockSpec::Hollow
+            | BlockSpec::Water
+            | BlockSpec::Skip
+            | BlockSpec::Keep => false,
+        }
+    }
+
+    /// Adds errors about blocks that can't be placed, and warnings about
+    /// settings of this spec that are ignored.
+    pub fn check(&self, validation: &mut Validation) {
+        match self {
+            BlockSpec::Sprite {
+                kind,
+                ori,
+                random_ori,
+                chance,
+                fallback,
+                ..
+            } => {
+                if !(0.0..=1.0).contains(chance) {
+                    validation.errors.push(format!(
+                        "Invalid chance {} for {:?} sprites, it has to be from 0 to 1",
+                        chance, kind
+                    ));
+                }
+                if let Some(fallback) = fallback {
+                    fallback.check(validation);
+                }
+                if (ori.is_some() || *random_ori) && !kind.has_ori() {
+                    validation.warnings.push(format!(
+                        "{:?} sprites don't have an orientation, the orientation is ignored",
+                        kind
+                    ));
+                } else if let Some(ori) = ori.filter(|ori| *ori >= 8) {
+                    validation.errors.push(format!(
+                        "Invalid orientation {} for {:?} sprites, orientations go from 0 to 7",
+                        ori, kind
+                    ));
+                }
+            },
+            BlockSpec::Random(lottery) => {
+                for (_, spec) in lottery.iter() {
+                    spec.check(validation);
+                }
+            },
+            BlockSpec::If {
+                then, otherwise, ..
+            }
+            | BlockSpec::Neighbor {
+                then, otherwise, ..
+            } => {
+                then.check(validation);
+                otherwise.check(validation);
+            },
+            BlockSpec::Noise {
+                scale,
+                below,
+                above,
+                ..
+            } => {
+                if scale.is_nan() || *scale <= 0.0 {
+                    validation
+                        .errors
+                        .push(format!("Invalid noise scale {}, it has to be positive", scale));
+                }
+                below.check(validation);
+                above.check(validation);
+            },
+            BlockSpec::HeightGradient(breakpoints) => {
+                if breakpoints.is_empty() {
+                    validation.errors.push("HeightGradient without any breakpoints".to_string());
+                }
+                if let Some(pair) = breakpoints.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+                    validation.errors.push(format!(
+                        "HeightGradient breakpoints out of order, {} comes before {}",
+                        pair[0].0, pair[1].0
+                    ));
+                }
+                for (_, spec) in breakpoints {
+                    spec.check(validation);
+                }
+            },
+            BlockSpec::Block { kind, color, .. } => {
+                // Air and liquids have no color, they are placed as sprites.
+                if !kind.is_filled() {
+                    validation.errors.push(format!(
+                        "{:?} isn't a solid block kind, use Hollow, Water or a sprite instead",
+                        kind
+                    ));
+                }
+                if let ColorSpec::Multiply { rgb } = color {
+                    if rgb.iter().any(|e| !e.is_finite() || *e < 0.0) {
+                        validation.errors.push(format!(
+                            "Invalid color factors {:?} for {:?} blocks, they can't be negative",
+                            rgb, kind
+                        ));
+                    }
+                }
+            },
+            BlockSpec::Glowy
+            | BlockSpec::Hollow
+            | BlockSpec::Water
+            | BlockSpec::Skip
+            | BlockSpec::Keep => {},
+        }
+    }
+}
+
+/// The color of a placed block: a fixed color, or the color of the cell,
+/// optionally adjusted. Written as `(r, g, b)`, `Inherit`,
+/// `Tint(add: (r, g, b))` or `Multiply(rgb: (r, g, b))`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpec {
+    Fixed([u8; 3]),
+    /// The color of the cell.
+    Inherit,
+    /// The color of the cell with `add` added to each channel.
+    Tint { add: [i8; 3] },
+    /// The color of the cell with each channel multiplied by `rgb`.
+    Multiply { rgb: [f32; 3] },
+}
+
+impl Default for ColorSpec {
+    fn default() -> Self {
+        ColorSpec::Fixed([0; 3])
+    }
+}
+
+impl ColorSpec {
+    /// The color of a block placed for a cell with the color `cell`, with
+    /// every channel clamped from 0 to 255.
+    ///
+    /// ```
+    /// use map_placer::spec::ColorSpec;
+    /// use vek::Rgb;
+    ///
+    /// let cell = Rgb::new(100, 200, 250);
+    /// assert_eq!(ColorSpec::Fixed([1, 2, 3]).apply(cell), Rgb::new(1, 2, 3));
+    /// assert_eq!(ColorSpec::Inherit.apply(cell), cell);
+    /// let tint = ColorSpec::Tint { add: [-120, 20, 10] };
+    /// assert_eq!(tint.apply(cell), Rgb::new(0, 220, 255));
+    /// let multiply = ColorSpec::Multiply { rgb: [0.5, 1.0, 2.0] };
+    /// assert_eq!(multiply.apply(cell), Rgb::new(50, 200, 255));
+    /// ```
+    pub fn apply(self, cell: Rgb<u8>) -> Rgb<u8> {
+        match self {
+            ColorSpec::Fixed(color) => Rgb::from(color),
+            ColorSpec::Inherit => cell,
+            ColorSpec::Tint { add } => cell.map2(Rgb::from(add), |e, add| {
+                (i16::from(e) + i16::from(add)).clamp(0, 255) as u8
+            }),
+            ColorSpec::Multiply { rgb } => cell.map2(Rgb::from(rgb), |e, factor| {
+                (f32::from(e) * factor).round().clamp(0.0, 255.0) as u8
+            }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ColorSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ColorSpecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ColorSpecVisitor {
+            type Value = ColorSpec;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a color, Inherit, Tint(add: (r, g, b)) or Multiply(rgb: (r, g, b))")
+            }
+
+            // RON skips the names of the variants, so `Inherit` is a unit and
+            // the others are told apart by their field.
+            fn visit_unit<E: serde::de::Error>(self) -> Result<ColorSpec, E> {
+                Ok(ColorSpec::Inherit)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ColorSpec, A::Error> {
+                use serde::de::Error;
+                let mut color = [0; 3];
+                for (i, e) in color.iter_mut().enumerate() {
+                    *e = seq.next_element()?.ok_or_else(|| Error::invalid_length(i, &self))?;
+                }
+                Ok(ColorSpec::Fixed(color))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<ColorSpec, A::Error> {
+                use serde::de::Error;
+                match map.next_key::<String>()?.as_deref() {
+                    Some("add") => Ok(ColorSpec::Tint {
+                        add: map.next_value()?,
+                    }),
+                    Some("rgb") => Ok(ColorSpec::Multiply {
+                        rgb: map.next_value()?,
+                    }),
+                    Some(field) => Err(Error::unknown_field(field, &["add", "rgb"])),
+                    None => Err(Error::invalid_length(0, &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(ColorSpecVisitor)
+    }
+}
+
+/// A box of colors, from `min` to `max` in every channel, both included.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorRange {
+    pub min: [u8; 3],
+    pub max: [u8; 3],
+}
+
+impl ColorRange {
+    pub fn contains(&self, color: Rgb<u8>) -> bool {
+        (0..3).all(|i| (self.min[i]..=self.max[i]).contains(&color[i]))
+    }
+}
+
+/// Problems found while validating specs, before any terrain is touched.
+#[derive(Clone, Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Validation {
+    /// Logs every problem, returning whether there were any errors.
+    pub fn report(&self) -> bool {
+        for warning in &self.warnings {
+            warn!("{}", warning);
+        }
+        for error in &self.errors {
+            error!("{}", error);
+        }
+        !self.errors.is_empty()
+    }
+}
+
+/// Optional settings of a piece, the third element of a `VoxSpec`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PieceOptions {
+    pub rotation: Rotation,
+    /// Whether to flip the piece along the x, y and z axes. Mirroring is
+    /// applied before the rotation.
+    pub mirror: (bool, bool, bool),
+    /// Replacements for this piece only, taking precedence over the ones of the
+    /// spec.
+    pub replace: Vec<([u8; 3], BlockSpec)>,
+    /// Replacements by palette index for this piece only, taking precedence
+    /// over all color replacements.
+    pub replace_indices: Vec<(u8, BlockSpec)>,
+    /// Replacements of colors within a range for this piece only, taking
+    /// precedence over the ranges of the spec.
+    pub replace_ranges: Vec<(ColorRange, BlockSpec)>,
+    /// Moves the piece up or down so its lowest voxel rests on the topmost
+    /// solid block below its offset.
+    pub snap_to_surface: bool,
+    /// The range of heights searched for the surface.
+    pub surface_range: (i32, i32),
+    /// Moves every column of the piece up or down on its own, so its lowest
+    /// voxel rests on the topmost solid block below it.
+    pub conform: bool,
+    /// When conforming, drops columns whose shift differs from the one of a
+    /// neighbouring column by more than this many blocks.
+    pub max_slope: Option<u32>,
+    /// The animation frame to place.
+    pub frame: FrameSpec,
+    /// Only places the layers in this list.
+    pub include_layers: Option<Vec<LayerRef>>,
+    /// Leaves out the layers in this list.
+    pub exclude_layers: Vec<LayerRef>,
+    /// Places hidden layers too.
+    pub include_hidden: bool,
+    /// Only places the transform node with this name, and its children.
+    pub node: Option<String>,
+    /// The point of every model that is placed at its translation.
+    pub pivot: Pivot,
+    /// How the piece is merged with the pieces before it.
+    pub merge: Merge,
+    /// Pieces can't overwrite the cells of pieces with a higher priority,
+    /// whatever their order.
+    pub priority: i32,
+    /// The name other pieces align to, instead of the specifier.
+    pub name: Option<String>,
+    /// Moves the piece so one of its anchors is at an anchor of an earlier
+    /// piece.
+    pub align: Option<Align>,
+    /// Places the piece several times in a row.
+    pub repeat: Option<Repeat>,
+    /// Repeats the piece as often as it fits before a position, instead of
+    /// `count` times.
+    pub repeat_between: Option<RepeatBetween>,
+    /// Points to place the piece along, as segments turned to follow the legs
+    /// between the points.
+    pub path: Vec<[i32; 3]>,
+    /// A piece placed at every point of the path.
+    pub corner_piece: Option<String>,
+    /// The fraction of the solid voxels of the piece that are dropped, the
+    /// most exposed ones first, to make ruins.
+    pub decay: f32,
+    /// Overrides `fill_empty` of the spec for this piece.
+    pub fill_empty: Option<bool>,
+    /// Overrides `fill_mode` of the spec for this piece.
+    pub fill_mode: Option<FillMode>,
+    /// Regions of the piece that are emptied, relative to its minimum corner
+    /// before it is rotated. Inclusive like all bounds.
+    pub cutouts: Vec<Aabb<i32>>,
+    /// What the cutouts are placed as instead of empty cells.
+    pub cutout_block: Option<BlockSpec>,
+    /// A foundation below the edge of the piece, down to the terrain.
+    pub skirt: Option<Skirt>,
+    /// Leaves the piece out unless the terrain under it is flat and above
+    /// water.
+    pub require_flat: Option<RequireFlat>,
+    /// Makes the offset of the piece relative to a position in the context
+    /// of the spec.
+    pub align_to_context: Option<ContextAlign>,
+    /// The kind of point of interest the piece is listed as in the POI file.
+    pub poi: Option<String>,
+    /// The group the piece was moved with, set when the spec is expanded.
+    #[serde(skip)]
+    pub group: Option<GroupFrame>,
+    /// The offsets tried one after another when the terrain at the offset
+    /// isn't flat, set for scattered instances when the spec is expanded.
+    #[serde(skip)]
+    pub(crate) retry_offsets: Vec<[i32; 3]>,
+}
+
+/// Pieces and groups of a spec moved by `offset` and turned by `rotation`
+/// about the origin of the group together, so the offsets of the members are
+/// relative to that origin.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    #[serde(default)]
+    pub offset: [i32; 3],
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// The names of the pieces and groups in the group, pieces by their name
+    /// or their specifier.
+    pub members: Vec<String>,
+    /// The kind of point of interest the group is listed as in the POI file.
+    #[serde(default)]
+    pub poi: Option<String>,
+    /// Makes the offset of the group relative to a position in the context
+    /// of the spec.
+    #[serde(default)]
+    pub align_to_context: Option<ContextAlign>,
+}
+
+/// Where the origin of a group ends up within its spec and how it is turned,
+/// with the group containing it applied.
+#[derive(Clone, Debug)]
+pub struct GroupFrame {
+    pub name: String,
+    /// The name of the group containing the group.
+    pub outer: Option<String>,
+    pub offset: Vec3<i32>,
+    pub rotation: Rotation,
+}
+
+impl GroupFrame {
+    /// `frame` within this group.
+    fn then(&self, frame: &GroupFrame) -> GroupFrame {
+        GroupFrame {
+            name: frame.name.clone(),
+            outer: Some(self.name.clone()),
+            offset: self.offset + self.rotation.to_mat() * frame.offset,
+            rotation: Rotation((self.rotation.0 + frame.rotation.0) % 4),
+        }
+    }
+
+    /// Moves `piece` from the group into the spec.
+    fn apply(&self, piece: &mut VoxSpec) {
+        let offset = self.offset + self.rotation.to_mat() * Vec3::from(piece.offset);
+        piece.offset = offset.into_array();
+        piece.options.rotation = Rotation((piece.options.rotation.0 + self.rotation.0) % 4);
+        piece.options.group = Some(self.clone());
+    }
+}
+
+/// Places `block` above the solid placed blocks whose upward face is exposed,
+/// within the bounds of the pieces.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OverlaySpec {
+    pub block: BlockSpec,
+    /// The chance of every exposed face to get the block, from 0 to 1.
+    #[serde(default = "default_overlay_coverage")]
+    pub coverage: f32,
+    /// How many empty blocks there have to be above a face for it to count as
+    /// exposed.
+    #[serde(default = "default_min_exposure")]
+    pub min_exposure: u8,
+    /// Also requires the blocks above a face to be empty in the terrain.
+    #[serde(default)]
+    pub check_terrain: bool,
+}
+
+pub(crate) fn default_hidden_glow_radius() -> u32 {
+    1
+}
+
+pub(crate) fn default_overlay_coverage() -> f32 {
+    1.0
+}
+
+pub(crate) fn default_min_exposure() -> u8 {
+    1
+}
+
+/// Extends the outermost `width` cells of the lowest layer of a piece down
+/// until they meet solid terrain, so it doesn't float on uneven ground.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Skirt {
+    #[serde(default = "default_skirt_width")]
+    pub width: u32,
+    pub block: BlockSpec,
+    /// How far down the foundation reaches at most.
+    #[serde(default = "default_skirt_depth")]
+    pub max_depth: u32,
+    /// Ramps the terrain up to the piece around it.
+    #[serde(default)]
+    pub berm: Option<Berm>,
+}
+
+pub(crate) fn default_skirt_width() -> u32 {
+    1
+}
+
+pub(crate) fn default_skirt_depth() -> u32 {
+    16
+}
+
+/// Fills the terrain in the `width` columns around a piece up to a slope that
+/// drops `slope` blocks for every column away from the piece.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Berm {
+    pub width: u32,
+    #[serde(default = "default_berm_slope")]
+    pub slope: f32,
+    /// Defaults to the block of the skirt.
+    #[serde(default)]
+    pub block: Option<BlockSpec>,
+}
+
+pub(crate) fn default_berm_slope() -> f32 {
+    1.0
+}
+
+impl Skirt {
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = &BlockSpec> {
+        std::iter::once(&self.block).chain(self.berm.iter().flat_map(|berm| &berm.block))
+    }
+
+    /// The positions of the skirt below `scene` and of its berm around it,
+    /// along with whether they are part of the berm.
+    pub(crate) fn positions(
+        &self,
+        scene: &SparseScene,
+        world: &mut World,
+    ) -> Vec<(Vec3<i32>, bool)> {
+        let Some((bottom, columns)) = scene.skirt_columns(self.width) else {
+            return Vec::new();
+        };
+        let mut positions = Vec::new();
+        for column in columns {
+            let gap = world.gap_below(column.with_z(bottom - 1), self.max_depth);
+            positions.extend(gap.into_iter().map(|wpos| (wpos, false)));
+        }
+        if let Some(berm) = &self.berm {
+            for (column, distance) in scene.berm_columns(berm.width) {
+                let drop = ((distance - 1) as f32 * berm.slope).round() as i32;
+                let gap = world.gap_below(column.with_z(bottom - 1 - drop), self.max_depth);
+                positions.extend(gap.into_iter().map(|wpos| (wpos, true)));
+            }
+        }
+        positions
+    }
+}
+
+/// Places a piece `count` times, each instance `stride` further than the one
+/// before it.
+#[derive(Clone)]
+pub struct Repeat {
+    pub count: u32,
+    pub stride: [i32; 3],
+    /// The expressions of `count` and of the components of `stride`, by their
+    /// index, until they are evaluated.
+    pub(crate) count_expr: Option<String>,
+    pub(crate) stride_exprs: Vec<(usize, String)>,
+}
+
+impl<'de> Deserialize<'de> for Repeat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Repeat")]
+        struct RawRepeat {
+            #[serde(default = "default_repeat_count")]
+            count: SpecInt,
+            stride: SpecInts<3>,
+        }
+
+        let raw = RawRepeat::deserialize(deserializer)?;
+        let (count, count_expr) = match raw.count {
+            SpecInt::Literal(count) => (
+                u32::try_from(count).map_err(|_| {
+                    serde::de::Error::custom(format!("invalid repeat count {}", count))
+                })?,
+                None,
+            ),
+            SpecInt::Expr(expr) => (1, Some(expr)),
+        };
+        let (stride, stride_exprs) = raw.stride.split();
+        Ok(Repeat {
+            count,
+            stride,
+            count_expr,
+            stride_exprs,
+        })
+    }
+}
+
+// The expressions are left out, since pieces are hashed with the debug output
+// of their options.
+impl std::fmt::Debug for Repeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Repeat")
+            .field("count", &self.count)
+            .field("stride", &self.stride)
+            .finish()
+    }
+}
+
+fn default_repeat_count() -> SpecInt {
+    SpecInt::Literal(1)
+}
+
+/// An integer of a spec, or an expression for it.
+enum SpecInt {
+    Literal(i32),
+    Expr(String),
+}
+
+impl<'de> Deserialize<'de> for SpecInt {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SpecIntVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for SpecIntVisitor {
+            type Value = SpecInt;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an integer or an expression like \"$grid * 2\"")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<SpecInt, E> {
+                i32::try_from(v)
+                    .map(SpecInt::Literal)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<SpecInt, E> {
+                i32::try_from(v)
+                    .map(SpecInt::Literal)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<SpecInt, E> {
+                Ok(SpecInt::Expr(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(SpecIntVisitor)
+    }
+}
+
+/// `N` integers of a spec that may be expressions, as a tuple or a list.
+struct SpecInts<const N: usize>(Vec<SpecInt>);
+
+impl<const N: usize> SpecInts<N> {
+    /// The literals, 0 in place of expressions, and the expressions by their
+    /// index.
+    fn split(self) -> ([i32; N], Vec<(usize, String)>) {
+        let mut values = [0; N];
+        let mut exprs = Vec::new();
+        for (i, int) in self.0.into_iter().enumerate() {
+            match int {
+                SpecInt::Literal(value) => values[i] = value,
+                SpecInt::Expr(expr) => exprs.push((i, expr)),
+            }
+        }
+        (values, exprs)
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for SpecInts<N> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SpecIntsVisitor<const N: usize>;
+
+        impl<'de, const N: usize> serde::de::Visitor<'de> for SpecIntsVisitor<N> {
+            type Value = SpecInts<N>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{} integers or expressions", N)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<SpecInts<N>, A::Error> {
+                let mut ints = Vec::with_capacity(N);
+                while let Some(int) = seq.next_element()? {
+                    if ints.len() == N {
+                        return Err(serde::de::Error::invalid_length(N + 1, &self));
+                    }
+                    ints.push(int);
+                }
+                if ints.len() < N {
+                    return Err(serde::de::Error::invalid_length(ints.len(), &self));
+                }
+                Ok(SpecInts(ints))
+            }
+        }
+
+        deserializer.deserialize_any(SpecIntsVisitor::<N>)
+    }
+}
+
+/// Evaluates `expr`: integers and the `vars` written as `$name`, combined
+/// with `+`, `-`, `*` and parentheses.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use map_placer::spec::evaluate;
+///
+/// let vars = HashMap::from([("grid".to_string(), 24)]);
+/// assert_eq!(evaluate("$grid * 2 - (3 + 1)", &vars), Ok(44));
+/// assert_eq!(evaluate("-$grid", &vars), Ok(-24));
+/// assert!(evaluate("$street", &vars).unwrap_err().contains("street"));
+/// assert!(evaluate("2 * (3", &vars).is_err());
+/// ```
+pub fn evaluate(expr: &str, vars: &HashMap<String, i32>) -> Result<i32, String> {
+    struct Parser<'a> {
+        rest: &'a str,
+        vars: &'a HashMap<String, i32>,
+    }
+
+    impl Parser<'_> {
+        /// Takes `c` if it is next, after any whitespace.
+        fn eat(&mut self, c: char) -> bool {
+            self.rest = self.rest.trim_start();
+            match self.rest.strip_prefix(c) {
+                Some(rest) => {
+                    self.rest = rest;
+                    true
+                },
+                None => false,
+            }
+        }
+
+        fn sum(&mut self) -> Result<i32, String> {
+            let mut value = self.product()?;
+            loop {
+                value = if self.eat('+') {
+                    value.checked_add(self.product()?)
+                } else if self.eat('-') {
+                    value.checked_sub(self.product()?)
+                } else {
+                    return Ok(value);
+                }
+                .ok_or("the value is too large")?;
+            }
+        }
+
+        fn product(&mut self) -> Result<i32, String> {
+            let mut value = self.factor()?;
+            while self.eat('*') {
+                value = value.checked_mul(self.factor()?).ok_or("the value is too large")?;
+            }
+            Ok(value)
+        }
+
+        fn factor(&mut self) -> Result<i32, String> {
+            if self.eat('-') {
+                return self.factor()?.checked_neg().ok_or_else(|| "the value is too large".into());
+            }
+            if self.eat('(') {
+                let value = self.sum()?;
+                if !self.eat(')') {
+                    return Err("a parenthesis isn't closed".into());
+                }
+                return Ok(value);
+            }
+            let var = self.eat('$');
+            let len = self
+                .rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(self.rest.len());
+            let (token, rest) = self.rest.split_at(len);
+            self.rest = rest;
+            if var && token.is_empty() {
+                Err("expected the name of a variable after $".into())
+            } else if var {
+                self.vars
+                    .get(token)
+                    .copied()
+                    .ok_or_else(|| format!("there is no variable {}", token))
+            } else if token.is_empty() {
+                Err(match self.rest.chars().next() {
+                    Some(c) => format!("expected an integer or a variable at {}", c),
+                    None => "expected an integer or a variable at the end".into(),
+                })
+            } else {
+                token.parse().map_err(|_| format!("invalid integer {}", token))
+            }
+        }
+    }
+
+    let mut parser = Parser { rest: expr, vars };
+    let value = parser.sum()?;
+    match parser.rest.trim_start().chars().next() {
+        Some(c) => Err(format!("unexpected {}", c)),
+        None => Ok(value),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RepeatBetween {
+    pub to: [i32; 3],
+}
+
+/// A position in the context of a spec: a corner of the bounds of the piece
+/// or group `piece` of the context, or one of its anchors or the first
+/// position of one of its markers instead.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ContextAlign {
+    pub piece: String,
+    #[serde(default)]
+    pub corner: Corner,
+    #[serde(default)]
+    pub anchor: Option<usize>,
+    #[serde(default)]
+    pub marker: Option<String>,
+}
+
+impl ContextAlign {
+    /// The position in `context`, or why it isn't in there.
+    pub(crate) fn position(&self, context: &Context) -> Result<Vec3<i32>, String> {
+        let Some(entry) = context.entries.get(&self.piece) else {
+            let names = context.entries.keys().cloned().collect::<Vec<_>>();
+            return Err(format!(
+                "there is no {} in the context, which has {}",
+                self.piece,
+                if names.is_empty() { "nothing".to_string() } else { names.join(", ") }
+            ));
+        };
+        match (self.anchor, &self.marker) {
+            (Some(_), Some(_)) => Err("it can align to an anchor or a marker, not both".into()),
+            (Some(anchor), None) => {
+                entry.anchors.get(anchor).map(|a| Vec3::from(*a)).ok_or_else(|| {
+                    format!(
+                        "{} has {} anchors in the context, so there is no anchor {}",
+                        self.piece,
+                        entry.anchors.len(),
+                        anchor
+                    )
+                })
+            },
+            (None, Some(marker)) => entry
+                .markers
+                .get(marker)
+                .and_then(|positions| positions.first())
+                .map(|wpos| Vec3::from(*wpos))
+                .ok_or_else(|| format!("{} has no marker {} in the context", self.piece, marker)),
+            (None, None) => Ok(self.corner.of(Aabb {
+                min: Vec3::from(entry.min),
+                max: Vec3::from(entry.max),
+            })),
+        }
+    }
+}
+
+/// A corner of bounds, named by the side of every axis in any order, like
+/// `MinXMinYMaxZ` or `MaxZMinXMinY`. The minimum corner by default.
+///
+/// ```
+/// use map_placer::spec::Corner;
+/// use vek::{Aabb, Vec3};
+///
+/// let corner: Corner = ron::from_str("MaxZMinXMaxY").unwrap();
+/// let aabb = Aabb {
+///     min: Vec3::new(0, 0, 0),
+///     max: Vec3::new(7, 8, 9),
+/// };
+/// assert_eq!(corner.of(aabb), Vec3::new(0, 8, 9));
+/// assert!(ron::from_str::<Corner>("MaxZMinX").is_err());
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Corner {
+    /// Whether the corner is on the maximum side, for every axis.
+    pub max: Vec3<bool>,
+}
+
+impl Corner {
+    pub fn of(self, aabb: Aabb<i32>) -> Vec3<i32> {
+        Vec3::new(0, 1, 2).map(|axis| {
+            if self.max[axis] {
+                aabb.max[axis]
+            } else {
+                aabb.min[axis]
+            }
+        })
+    }
+}
+
+impl std::str::FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid corner {}, expected Min or Max for each of X, Y and Z, like MinXMinYMaxZ",
+                s
+            )
+        };
+        let mut seen = Vec3::broadcast(false);
+        let mut corner = Corner::default();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (side, axis) = (rest.get(..3).ok_or_else(invalid)?, rest.get(3..4));
+            let axis = match axis {
+                Some("X") => 0,
+                Some("Y") => 1,
+                Some("Z") => 2,
+                _ => return Err(invalid()),
+            };
+            corner.max[axis] = match side {
+                "Min" => false,
+                "Max" => true,
+                _ => return Err(invalid()),
+            };
+            if std::mem::replace(&mut seen[axis], true) {
+                return Err(invalid());
+            }
+            rest = &rest[4..];
+        }
+        if seen.reduce_and() {
+            Ok(corner)
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Corner {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CornerVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for CornerVisitor {
+            type Value = Corner;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a corner like MinXMinYMaxZ")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Corner, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        // Corners are written like unit variants, without quotes.
+        deserializer.deserialize_identifier(CornerVisitor)
+    }
+}
+
+/// Aligns anchor `to_anchor` of a piece with anchor `anchor` of `piece`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Align {
+    pub piece: String,
+    pub anchor: usize,
+    pub to_anchor: usize,
+}
+
+/// How a piece is merged with the pieces before it where they overlap.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Merge {
+    /// The cells of the piece replace the ones before it.
+    #[default]
+    Overwrite,
+    /// The piece only fills the cells that are still empty.
+    Underwrite,
+    /// Empties the bounds of the piece before placing it, so it can carve
+    /// into earlier pieces.
+    CutOut,
+}
+
+/// Which empty cells of a piece are filled.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FillMode {
+    /// Every empty cell within the bounds of the piece.
+    #[default]
+    Aabb,
+    /// The empty cells of every column between the lowest and the highest
+    /// voxel of the piece in it, columns without voxels aren't filled.
+    Footprint,
+}
+
+/// The point of a model that its translation refers to.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub enum Pivot {
+    /// The voxel at half the size of the model, rounded down, like MagicaVoxel.
+    #[default]
+    Center,
+    /// The corner with the lowest coordinates, after rotating the model.
+    MinCorner,
+}
+
+/// A layer of a vox file, by name or index.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum LayerRef {
+    Index(u32),
+    Name(String),
+}
+
+impl LayerRef {
+    pub(crate) fn matches(&self, index: u32, layer: &dot_vox::Layer) -> bool {
+        match self {
+            LayerRef::Index(i) => *i == index,
+            LayerRef::Name(name) => layer.attributes.get("_name") == Some(name),
+        }
+    }
+}
+
+/// An animation frame of a vox file, or a range to pick one from.
+#[derive(Clone, Copy, Debug)]
+pub enum FrameSpec {
+    Fixed(u32),
+    /// Picks a frame from `start..end` for every piece.
+    Random(u32, u32),
+}
+
+impl Default for FrameSpec {
+    fn default() -> Self {
+        FrameSpec::Fixed(0)
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FrameSpecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FrameSpecVisitor {
+            type Value = FrameSpec;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a frame index or Random(start, end)")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, frame: u64) -> Result<FrameSpec, E> {
+                u32::try_from(frame)
+                    .map(FrameSpec::Fixed)
+                    .map_err(|_| E::custom(format!("invalid frame {}", frame)))
+            }
+
+            // RON skips the name of the variant here, leaving its fields.
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<FrameSpec, A::Error> {
+                use serde::de::Error;
+                let start = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::invalid_length(0, &self))?;
+                let end = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::invalid_length(1, &self))?;
+                Ok(FrameSpec::Random(start, end))
+            }
+        }
+
+        deserializer.deserialize_any(FrameSpecVisitor)
+    }
+}
+
+impl Default for PieceOptions {
+    fn default() -> Self {
+        Self {
+            rotation: Rotation::default(),
+            mirror: (false, false, false),
+            replace: Vec::new(),
+            replace_indices: Vec::new(),
+            replace_ranges: Vec::new(),
+            snap_to_surface: false,
+            surface_range: (-512, 4096),
+            conform: false,
+            max_slope: None,
+            frame: FrameSpec::default(),
+            include_layers: None,
+            exclude_layers: Vec::new(),
+            include_hidden: false,
+            node: None,
+            pivot: Pivot::default(),
+            merge: Merge::default(),
+            priority: 0,
+            name: None,
+            align: None,
+            repeat: None,
+            repeat_between: None,
+            path: Vec::new(),
+            corner_piece: None,
+            decay: 0.0,
+            fill_empty: None,
+            fill_mode: None,
+            cutouts: Vec::new(),
+            cutout_block: None,
+            skirt: None,
+            require_flat: None,
+            align_to_context: None,
+            poi: None,
+            group: None,
+            retry_offsets: Vec::new(),
+        }
+    }
+}
+
+impl PieceOptions {
+    /// The animation frame the piece `piece` is built with, picked from the
+    /// seed for random frames.
+    pub(crate) fn frame_of(&self, piece: u16, seed: Option<u64>) -> u32 {
+        match self.frame {
+            FrameSpec::Fixed(frame) => frame,
+            FrameSpec::Random(start, end) => match seed {
+                Some(seed) => PosRng(splitmix64(seed ^ u64::from(piece))).gen_range(start..end),
+                None => thread_rng().gen_range(start..end),
+            },
+        }
+    }
+
+    /// How many instances of the piece at `offset` are placed.
+    pub(crate) fn repeat_count(&self, offset: [i32; 3]) -> u32 {
+        let Some(repeat) = self.repeat.as_ref().filter(|r| r.stride != [0; 3]) else {
+            return 1;
+        };
+        match &self.repeat_between {
+            // Only whole instances that end before `to` are placed.
+            Some(between) => {
+                let stride = Vec3::from(repeat.stride).map(i64::from);
+                let span = (Vec3::from(between.to) - Vec3::from(offset)).map(i64::from);
+                let count = span.dot(stride).div_euclid(stride.dot(stride));
+                u32::try_from(count).unwrap_or(0)
+            },
+            None => repeat.count,
+        }
+    }
+
+    /// The layers of `dot_vox_data` that aren't placed.
+    pub(crate) fn skipped_layers(&self, dot_vox_data: &DotVoxData) -> HashSet<u32> {
+        (0..)
+            .zip(&dot_vox_data.layers)
+            .filter(|(i, layer)| {
+                let hidden = layer.attributes.get("_hidden").map_or(false, |h| h == "1");
+                (hidden && !self.include_hidden)
+                    || self.include_layers.as_ref().map_or(false, |layers| {
+                        !layers.iter().any(|l| l.matches(*i, layer))
+                    })
+                    || self.exclude_layers.iter().any(|l| l.matches(*i, layer))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The scene node of `dot_vox_data` to place from. Zero is always the
+    /// root node.
+    pub(crate) fn root_node(&self, dot_vox_data: &DotVoxData) -> Option<u32> {
+        match &self.node {
+            Some(node) => named_nodes(dot_vox_data)
+                .find(|(_, name)| name == node)
+                .map(|(i, _)| i),
+            None => Some(0),
+        }
+    }
+
+    /// `aabb` relative to the origin of the group of the piece, or of the spec
+    /// for pieces outside of groups, before they are turned. The spec is
+    /// placed at `offset` turned by `rot`.
+    pub(crate) fn local_bounds(
+        &self,
+        aabb: Aabb<i32>,
+        rot: Mat3<i32>,
+        offset: Vec3<i32>,
+    ) -> Aabb<i32> {
+        let (rot, origin) = match &self.group {
+            Some(group) => (rot * group.rotation.to_mat(), offset + rot * group.offset),
+            None => (rot, offset),
+        };
+        // Rotations and mirrors are inverted by their transpose.
+        let inverse = rot.transposed();
+        let (a, b) = (inverse * (aabb.min - origin), inverse * (aabb.max - origin));
+        Aabb {
+            min: a.map2(b, i32::min),
+            max: a.map2(b, i32::max),
+        }
+    }
+
+    /// The transform of the piece, mirroring first and then rotating.
+    pub(crate) fn transform(&self) -> Mat3<i32> {
+        let (x, y, z) = self.mirror;
+        let flip = |b| if b { -1 } else { 1 };
+        self.rotation.to_mat() * Mat3::with_diagonal(Vec3::new(flip(x), flip(y), flip(z)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VoxSpec {
+    pub specifier: String,
+    pub offset: [i32; 3],
+    pub options: PieceOptions,
+    /// The expressions of the components of `offset`, by their index, until
+    /// they are evaluated.
+    pub(crate) offset_exprs: Vec<(usize, String)>,
+}
+
+impl<'de> Deserialize<'de> for VoxSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VoxSpecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for VoxSpecVisitor {
+            type Value = VoxSpec;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a tuple of an asset specifier, an offset and optional piece options")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<VoxSpec, A::Error> {
+                use serde::de::Error;
+                let specifier: String = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::invalid_length(0, &self))?;
+                let (offset, offset_exprs) = seq
+                    .next_element::<SpecInts<3>>()?
+                    .ok_or_else(|| Error::invalid_length(1, &self))?
+                    .split();
+                // Name the piece, since errors in the options are otherwise hard to find.
+                let options = seq
+                    .next_element()
+                    .map_err(|e| {
+                        Error::custom(format!("invalid options for piece {}: {}", specifier, e))
+                    })?
+                    .unwrap_or_default();
+                Ok(VoxSpec {
+                    specifier,
+                    offset,
+                    options,
+                    offset_exprs,
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple_struct("VoxSpec", 3, VoxSpecVisitor)
+    }
+}
+
+impl VoxSpec {
+    /// The name of the piece, its specifier unless it has a `name`.
+    pub fn name(&self) -> &str { self.options.name.as_deref().unwrap_or(&self.specifier) }
+
+    /// Evaluates the expressions of the offset and the repeat of the piece
+    /// with `vars`.
+    fn evaluate_vars(&mut self, vars: &HashMap<String, i32>) -> Result<(), String> {
+        let name = self.name().to_string();
+        let eval = |field: &str, expr: &str| {
+            evaluate(expr, vars)
+                .map_err(|e| format!("Invalid {} of piece {}, {:?}: {}", field, name, expr, e))
+        };
+        for (i, expr) in std::mem::take(&mut self.offset_exprs) {
+            self.offset[i] = eval("offset", &expr)?;
+        }
+        if let Some(repeat) = &mut self.options.repeat {
+            for (i, expr) in std::mem::take(&mut repeat.stride_exprs) {
+                repeat.stride[i] = eval("repeat stride", &expr)?;
+            }
+            if let Some(expr) = repeat.count_expr.take() {
+                let count = eval("repeat count", &expr)?;
+                repeat.count = u32::try_from(count).map_err(|_| {
+                    format!(
+                        "Invalid repeat count of piece {}, {:?} is {}, which is negative",
+                        name, expr, count
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which pieces to place and how their cells become blocks.
+///
+/// ```
+/// use map_placer::spec::{BlockSpec, PlaceSpec};
+///
+/// let spec: PlaceSpec = ron::from_str(
+///     r#"(
+///         pieces: [("world.structure.example", (0, 0, 0), (name: Some("tower")))],
+///         replace: [((255, 0, 0), Glowy)],
+///     )"#,
+/// )
+/// .unwrap();
+/// assert_eq!(spec.pieces[0].specifier, "world.structure.example");
+/// assert_eq!(spec.pieces[0].name(), "tower");
+/// assert!(matches!(spec.replace[0], ([255, 0, 0], BlockSpec::Glowy)));
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+pub struct PlaceSpec {
+    pub pieces: Vec<VoxSpec>,
+    /// Integers the offsets and repeats of the pieces can refer to in
+    /// expressions, like `"$grid * 2"`.
+    #[serde(default)]
+    pub vars: HashMap<String, i32>,
+    #[serde(default)]
+    pub replace: Vec<([u8; 3], BlockSpec)>,
+    /// Replacements by palette index, taking precedence over `replace`.
+    #[serde(default)]
+    pub replace_indices: Vec<(u8, BlockSpec)>,
+    /// Replacements of every color within a range, used for the colors
+    /// `replace` doesn't match. The last range containing a color wins.
+    #[serde(default)]
+    pub replace_ranges: Vec<(ColorRange, BlockSpec)>,
+    /// Fills the empty cells within the bounds of the pieces, unless a piece
+    /// overrides it.
+    #[serde(default)]
+    pub fill_empty: bool,
+    /// What empty cells are filled with, instead of air, or water below
+    /// `water_level`.
+    #[serde(default)]
+    pub fill_block: Option<BlockSpec>,
+    /// Which empty cells of the pieces are filled.
+    #[serde(default)]
+    pub fill_mode: FillMode,
+    /// How many blocks below their lowest voxel the columns of pieces filled
+    /// by their footprint are filled.
+    #[serde(default)]
+    pub fill_margin: u32,
+    /// Palette indices of glowy voxels.
+    #[serde(default)]
+    pub glow_indices: Vec<u8>,
+    /// Palette indices of hollow voxels, which are carved out as air.
+    #[serde(default = "default_hollow_indices")]
+    pub hollow_indices: Vec<u8>,
+    /// Seed for random blocks, defaults to 0.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Empties the cells enclosed by the model.
+    #[serde(default)]
+    pub carve_interior: bool,
+    /// When carving the interior, also empties the solid cells more than this
+    /// many cells away from the outside.
+    #[serde(default)]
+    pub carve_depth: Option<u32>,
+    /// A block deposited on top of the placed blocks, snow for example.
+    #[serde(default)]
+    pub overlay: Option<OverlaySpec>,
+    /// Areas to scatter models over, each instance becomes a piece.
+    #[serde(default)]
+    pub scatter: Vec<ScatterSpec>,
+    /// Sets of pieces moved and turned together.
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    /// What colors that aren't replaced become, instead of `BlockKind::Misc`
+    /// blocks with the color of the cell.
+    #[serde(default)]
+    pub default_block: Option<BlockSpec>,
+    /// Uses `default_block` for hollow, glowy and shiny cells too.
+    #[serde(default)]
+    pub default_overrides_special: bool,
+    /// What glowy cells become, instead of glowing rock.
+    #[serde(default)]
+    pub glowy_block: Option<BlockSpec>,
+    /// What shiny cells become, instead of water.
+    #[serde(default)]
+    pub shiny_block: Option<BlockSpec>,
+    /// Air below this height becomes water, including filled empty cells.
+    #[serde(default)]
+    pub water_level: Option<i32>,
+    /// Colors without a replacement use the closest replaced color within
+    /// this Euclidean distance in RGB.
+    #[serde(default)]
+    pub color_tolerance: u8,
+    /// Colors of voxels that aren't placed, but mark anchors to align pieces
+    /// with.
+    #[serde(default)]
+    pub anchor_colors: Vec<[u8; 3]>,
+    /// Names of colors of voxels that aren't placed, but mark positions for
+    /// later steps, which are recorded for every piece.
+    #[serde(default)]
+    pub markers: Vec<(String, [u8; 3])>,
+    /// The maximum volume of the bounds of all pieces together, in blocks.
+    #[serde(default)]
+    pub max_total_volume: Option<u64>,
+    /// The maximum distance of any placed block from the origin along each
+    /// axis.
+    #[serde(default)]
+    pub world_bounds: Option<i32>,
+    /// Checks that placed sprites rest on a solid block.
+    #[serde(default)]
+    pub validate_sprites: bool,
+    /// What happens to sprites without support when validating them.
+    #[serde(default)]
+    pub sprite_support: SpriteSupport,
+    /// The most glowing blocks the spec may place.
+    #[serde(default)]
+    pub max_glow_blocks: Option<usize>,
+    /// What happens when the spec places more than `max_glow_blocks`.
+    #[serde(default)]
+    pub glow_limit: GlowLimit,
+    /// Turns glowing blocks without an empty position within
+    /// `hidden_glow_radius` into blocks that don't glow, since they can't be
+    /// seen.
+    #[serde(default)]
+    pub strip_hidden_glow: bool,
+    #[serde(default = "default_hidden_glow_radius")]
+    pub hidden_glow_radius: u32,
+    /// Voxels with a palette alpha below this are placed as water, unless
+    /// their color is replaced.
+    #[serde(default)]
+    pub water_alpha_threshold: Option<u8>,
+    /// Picks blocks for palette entries from their MagicaVoxel material,
+    /// glass becomes water and emissive materials glowy.
+    #[serde(default)]
+    pub use_materials: bool,
+    /// Blocks for material types like `glass`, `metal` or `emit`, overriding
+    /// the defaults of `use_materials`.
+    #[serde(default)]
+    pub materials: Vec<(String, BlockSpec)>,
+    /// How the colors of voxels are changed for the blocks placed with them.
+    #[serde(default)]
+    pub color_transform: ColorTransform,
+    /// Looks replacements up by the transformed colors instead of the painted
+    /// ones.
+    #[serde(default)]
+    pub transform_before_lookup: bool,
+    /// The variant of `--variants` this spec is placed as, which is added to
+    /// its seed.
+    #[serde(skip)]
+    pub variant: u32,
+    /// The model placed instead of pieces whose models fail to load, a path
+    /// relative to the spec or an asset specifier. Missing models are errors
+    /// without it.
+    #[serde(default)]
+    pub missing_piece: Option<String>,
+    /// The pieces whose models failed to load, by their specifier and the
+    /// model placed instead.
+    #[serde(skip)]
+    pub substituted: Vec<(String, String)>,
+    /// Specs whose replacements, markers and default blocks are merged into
+    /// this one, later ones overriding earlier ones.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Also places the pieces of the included specs.
+    #[serde(default)]
+    pub include_pieces: bool,
+    /// A context file an earlier placement wrote, relative to the spec, that
+    /// pieces and groups can align to.
+    #[serde(default)]
+    pub context: Option<String>,
+    /// Where the merged entries of included specs come from.
+    #[serde(skip)]
+    pub provenance: Provenance,
+}
+
+/// Where the entries of a spec merged from included specs come from.
+#[derive(Clone, Default, Debug)]
+pub struct Provenance {
+    /// The included spec each replaced color comes from, for the colors that
+    /// aren't replaced by the spec itself.
+    pub colors: HashMap<[u8; 3], String>,
+    /// Replacements of a color overridden with a different block, by the
+    /// spec replacing it first and the one overriding it.
+    pub overridden: Vec<([u8; 3], String, String)>,
+}
+
+/// How the colors of voxels are changed for the blocks placed with them,
+/// since the game shows colors differently from MagicaVoxel.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+pub enum ColorTransform {
+    #[default]
+    None,
+    /// Treats colors as sRGB, like MagicaVoxel does, and converts them to
+    /// linear ones.
+    SrgbToLinear,
+    /// Treats colors as linear and converts them to sRGB, which brightens
+    /// them.
+    LinearToSrgb,
+    /// Raises every channel, from 0 to 1, to this power. Below 1 brightens.
+    Gamma(f32),
+}
+
+impl ColorTransform {
+    /// `color` with this transform applied.
+    ///
+    /// ```
+    /// use map_placer::spec::ColorTransform;
+    /// use vek::Rgb;
+    ///
+    /// let color = Rgb::new(0, 128, 255);
+    /// assert_eq!(ColorTransform::None.apply(color), color);
+    /// assert_eq!(ColorTransform::SrgbToLinear.apply(color), Rgb::new(0, 55, 255));
+    /// assert_eq!(ColorTransform::LinearToSrgb.apply(Rgb::new(0, 55, 255)), color);
+    /// assert_eq!(ColorTransform::Gamma(2.0).apply(color), Rgb::new(0, 64, 255));
+    /// ```
+    pub fn apply(self, color: Rgb<u8>) -> Rgb<u8> {
+        if self == ColorTransform::None {
+            return color;
+        }
+        color.map(|channel| {
+            let c = f32::from(channel) / 255.0;
+            let c = match self {
+                ColorTransform::None => c,
+                ColorTransform::SrgbToLinear if c <= 0.04045 => c / 12.92,
+                ColorTransform::SrgbToLinear => ((c + 0.055) / 1.055).powf(2.4),
+                ColorTransform::LinearToSrgb if c <= 0.0031308 => c * 12.92,
+                ColorTransform::LinearToSrgb => 1.055 * c.powf(1.0 / 2.4) - 0.055,
+                ColorTransform::Gamma(gamma) => c.powf(gamma),
+            };
+            (c * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+    }
+}
+
+/// What happens to specs placing more glowing blocks than their maximum.
+#[derive(Deserialize, Default, Clone, Debug, Copy)]
+pub enum GlowLimit {
+    /// The spec isn't placed and is reported as an error.
+    #[default]
+    Error,
+    /// Only as many glowing blocks as allowed are kept, the others turn into
+    /// blocks that don't glow.
+    Thin,
+}
+
+/// What happens to placed sprites without a solid block to rest on.
+#[derive(Deserialize, Default, Clone, Debug, Copy)]
+pub enum SpriteSupport {
+    /// They aren't placed.
+    #[default]
+    Drop,
+    /// They move down to the first support within this many blocks, and
+    /// aren't placed without one. Sprites on walls can't move.
+    Settle(u8),
+    /// They aren't placed and are reported as errors.
+    Error,
+}
+
+/// The block `use_materials` picks for the MagicaVoxel material type `kind`,
+/// if it differs from the usual handling of a voxel.
+pub(crate) fn default_material_block(kind: &str) -> Option<BlockSpec> {
+    match kind {
+        "glass" => Some(BlockSpec::Water),
+        "emit" => Some(BlockSpec::Glowy),
+        _ => None,
+    }
+}
+
+/// The material types MagicaVoxel writes.
+pub const MATERIAL_TYPES: [&str; 6] = ["diffuse", "metal", "plastic", "glass", "emit", "media"];
+
+/// Only places a piece where the terrain under it is flat: the heights of
+/// the surface at the corners and the center of its footprint, grown by
+/// `footprint_margin`, differ by at most `max_height_diff`, and none of them
+/// is under water.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RequireFlat {
+    pub max_height_diff: u32,
+    #[serde(default)]
+    pub footprint_margin: u32,
+}
+
+impl RequireFlat {
+    /// Why the terrain under `footprint` isn't flat, looking for the surface
+    /// within `range`. Surfaces below `water_level` or covered by water are
+    /// under water.
+    pub(crate) fn check(
+        &self,
+        world: &mut World,
+        footprint: Aabr<i32>,
+        range: (i32, i32),
+        water_level: Option<i32>,
+    ) -> Result<(), String> {
+        let margin = self.footprint_margin as i32;
+        let (min, max) = (footprint.min - margin, footprint.max + margin);
+        let samples = [
+            min,
+            Vec2::new(max.x, min.y),
+            Vec2::new(min.x, max.y),
+            max,
+            (min + max).map(|e| e.div_euclid(2)),
+        ];
+        let mut heights = Vec::new();
+        for pos in samples {
+            let Some(height) = world.surface_height(pos, range) else {
+                return Err(format!("there is no surface at {}", pos));
+            };
+            let flooded = world.get(pos.with_z(height + 1)).kind() == BlockKind::Water;
+            if flooded || water_level.map_or(false, |level| height < level) {
+                return Err(format!("the surface at {} is under water", pos));
+            }
+            heights.push(height);
+        }
+        let (lowest, highest) = (heights.iter().min(), heights.iter().max());
+        match (lowest, highest) {
+            (Some(lowest), Some(highest)) if highest - lowest > self.max_height_diff as i32 => {
+                Err(format!(
+                    "the surface is between {} and {} high, more than {} apart",
+                    lowest, highest, self.max_height_diff
+                ))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Randomly scatters instances of models over an area.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ScatterSpec {
+    /// The minimum and maximum corner of the area, inclusive, relative to the
+    /// offset of the placement like piece offsets.
+    pub area: ([i32; 2], [i32; 2]),
+    /// The height of the instances, before snapping them to the surface.
+    #[serde(default)]
+    pub z: i32,
+    pub density: Density,
+    /// The models to pick from, the offsets of the pieces are relative to the
+    /// position of the instance.
+    pub variants: Lottery<VoxSpec>,
+    /// Rotates every instance by a random quarter turn.
+    #[serde(default)]
+    pub random_rotation: bool,
+    /// The minimum distance between the positions of instances.
+    #[serde(default)]
+    pub min_spacing: f32,
+    /// Overrides `require_flat` of the variants.
+    #[serde(default)]
+    pub require_flat: Option<RequireFlat>,
+    /// How many other positions are tried for instances whose terrain isn't
+    /// flat, before they are left out.
+    #[serde(default = "default_flat_retries")]
+    pub flat_retries: u32,
+}
+
+pub(crate) fn default_flat_retries() -> u32 {
+    10
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum Density {
+    /// Instances per 100 by 100 blocks.
+    PerArea(f32),
+    Count(u32),
+}
+
+/// The number of positions tried for every instance before giving up.
+pub const SCATTER_ATTEMPTS: u32 = 30;
+
+impl ScatterSpec {
+    /// Picks the instances with `rng`, along with the number of positions
+    /// rejected for being too close to another instance.
+    pub(crate) fn instances(&self, rng: &mut impl Rng) -> (Vec<VoxSpec>, u32) {
+        let (min, max) = (Vec2::from(self.area.0), Vec2::from(self.area.1));
+        let (min, max) = (min.map2(max, i32::min), min.map2(max, i32::max));
+        let size = (max - min + 1).map(|e| e as f32);
+        let count = match self.density {
+            Density::PerArea(density) => (density * size.product() / 10000.0).round() as u32,
+            Density::Count(count) => count,
+        };
+        let mut positions = Vec::<Vec2<i32>>::new();
+        let mut rejected = 0;
+        for _ in 0..count * SCATTER_ATTEMPTS {
+            if positions.len() as u32 == count {
+                break;
+            }
+            let pos = Vec2::new(rng.gen_range(min.x..=max.x), rng.gen_range(min.y..=max.y));
+            if positions
+                .iter()
+                .any(|other| other.as_::<f32>().distance(pos.as_()) < self.min_spacing)
+            {
+                rejected += 1;
+            } else {
+                positions.push(pos);
+            }
+        }
+        let mut instances = positions
+            .iter()
+            .map(|pos| {
+                let variant = self.variants.choose_seeded(rng.gen());
+                let mut options = variant.options.clone();
+                if self.random_rotation {
+                    options.rotation = Rotation((options.rotation.0 + rng.gen_range(0..4)) % 4);
+                }
+                if self.require_flat.is_some() {
+                    options.require_flat = self.require_flat;
+                }
+                VoxSpec {
+                    specifier: variant.specifier.clone(),
+                    offset: (pos.with_z(self.z) + Vec3::from(variant.offset)).into_array(),
+                    options,
+                    offset_exprs: Vec::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+        // The positions to retry are picked once the instances are, so
+        // scattering without them picks the same instances as before. They
+        // keep their distance to every other position, so any of them can be
+        // placed.
+        for (instance, pos) in instances.iter_mut().zip(positions.clone()) {
+            if instance.options.require_flat.is_none() {
+                continue;
+            }
+            let variant_offset = Vec3::from(instance.offset) - pos.with_z(self.z);
+            for _ in 0..self.flat_retries * SCATTER_ATTEMPTS {
+                if instance.options.retry_offsets.len() as u32 == self.flat_retries {
+                    break;
+                }
+                let pos = Vec2::new(rng.gen_range(min.x..=max.x), rng.gen_range(min.y..=max.y));
+                if positions
+                    .iter()
+                    .all(|other| other.as_::<f32>().distance(pos.as_()) >= self.min_spacing)
+                {
+                    positions.push(pos);
+                    let offset = pos.with_z(self.z) + variant_offset;
+                    instance.options.retry_offsets.push(offset.into_array());
+                }
+            }
+        }
+        (instances, rejected)
+    }
+}
+
+pub(crate) fn default_hollow_indices() -> Vec<u8> {
+    vec![16]
+}
+
+impl PlaceSpec {
+    /// Evaluates the expressions of the offsets and repeats of the pieces
+    /// with `vars`, overridden by `overrides`. Expressions are evaluated
+    /// once, so this is called right after loading the spec.
+    pub fn evaluate_vars(&mut self, overrides: &HashMap<String, i32>) -> Result<(), String> {
+        let mut vars = self.vars.clone();
+        vars.extend(overrides.iter().map(|(name, value)| (name.clone(), *value)));
+        for piece in &mut self.pieces {
+            piece.evaluate_vars(&vars)?;
+        }
+        let scattered = self.scatter.iter().flat_map(|scatter| scatter.variants.iter());
+        for (_, variant) in scattered {
+            let repeat = variant.options.repeat.as_ref();
+            if !variant.offset_exprs.is_empty()
+                || repeat.map_or(false, |r| r.count_expr.is_some() || !r.stride_exprs.is_empty())
+            {
+                return Err(format!(
+                    "The scatter variant {} uses an expression, which only pieces can",
+                    variant.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// What cells taking `fallback` become instead of the default fallback
+    /// blocks.
+    pub(crate) fn fallback_block(&self, fallback: Fallback) -> Option<&BlockSpec> {
+        match fallback {
+            Fallback::Glowy => self.glowy_block.as_ref(),
+            Fallback::Shiny => self.shiny_block.as_ref(),
+            Fallback::Hollow | Fallback::Misc => None,
+        }
+        .or_else(|| {
+            self.default_block
+                .as_ref()
+                .filter(|_| fallback == Fallback::Misc || self.default_overrides_special)
+        })
+    }
+
+    /// The instances of a piece with a path, segments along every leg of the
+    /// path and corner pieces at its points.
+    pub(crate) fn path_instances(
+        &self,
+        piece: &VoxSpec,
+        name: &str,
+        validation: &mut Validation,
+    ) -> Vec<VoxSpec> {
+        let bounds = match self.model_bounds(piece) {
+            Ok(bounds) => bounds,
+            Err(e) => {
+                validation.errors.push(format!(
+                    "Piece {} of {} can't be placed along its path: {}",
+                    piece.specifier, name, e
+                ));
+                return Vec::new();
+            },
+        };
+        // Segments are placed end to end along the x axis of the model.
+        let length = bounds.max.x - bounds.min.x + 1;
+        let points = piece.options.path.iter().copied().map(Vec3::from).collect::<Vec<_>>();
+        let mut instances = Vec::new();
+        let mut rotations = Vec::new();
+        for (leg, pair) in points.windows(2).enumerate() {
+            let delta = pair[1] - pair[0];
+            let (rotation, distance) = match delta.into_tuple() {
+                (x, 0, 0) if x > 0 => (Rotation(0), x),
+                (0, y, 0) if y > 0 => (Rotation(1), y),
+                (x, 0, 0) if x < 0 => (Rotation(2), -x),
+                (0, y, 0) if y < 0 => (Rotation(3), -y),
+                _ => {
+                    validation.errors.push(format!(
+                        "Leg {} of the path of piece {} of {} goes from {} to {}, paths can only \
+                         go along the x or y axis",
+                        leg, piece.specifier, name, pair[0], pair[1]
+                    ));
+                    continue;
+                },
+            };
+            let dir = delta / distance;
+            let count = distance / length;
+            if count * length < distance {
+                validation.warnings.push(format!(
+                    "Leg {} of the path of piece {} of {} leaves a gap of {} blocks before {}",
+                    leg,
+                    piece.specifier,
+                    name,
+                    distance - count * length,
+                    pair[1]
+                ));
+            }
+            for i in 0..count {
+                let mut options = piece.options.clone();
+                options.path = Vec::new();
+                options.rotation = rotation;
+                instances.push(VoxSpec {
+                    specifier: piece.specifier.clone(),
+                    offset: (pair[0] + dir * (i * length - bounds.min.x)).into_array(),
+                    options,
+                    offset_exprs: Vec::new(),
+                });
+            }
+            rotations.push(rotation);
+        }
+        if let Some(corner) = &piece.options.corner_piece {
+            for (i, point) in points.iter().enumerate() {
+                // Corners face along the leg leaving them, the last one along
+                // the leg reaching it.
+                let rotation = rotations.get(i).or(rotations.last()).copied().unwrap_or_default();
+                instances.push(VoxSpec {
+                    specifier: corner.clone(),
+                    offset: point.into_array(),
+                    options: PieceOptions {
+                        rotation,
+                        ..PieceOptions::default()
+                    },
+                    offset_exprs: Vec::new(),
+                });
+            }
+        }
+        instances
+    }
+
+    /// The bounds of the model of `piece`, mirrored but not rotated or moved.
+    pub(crate) fn model_bounds(&self, piece: &VoxSpec) -> Result<Aabb<i32>, String> {
+        let options = PieceOptions {
+            rotation: Rotation::default(),
+            ..piece.options.clone()
+        };
+        let bounds = match load_piece_model(&piece.specifier)? {
+            PieceModel::Vox(vox) => {
+                let vox = vox.read();
+                let skipped_layers = options.skipped_layers(&vox.0);
+                let node = options.node.as_deref().unwrap_or_default();
+                let root = options
+                    .root_node(&vox.0)
+                    .ok_or_else(|| format!("it has no node named {:?}", node))?;
+                let frame = match options.frame {
+                    FrameSpec::Fixed(frame) | FrameSpec::Random(frame, _) => frame,
+                };
+                let (_, aabbs, _) = SparseScene::new_from(
+                    std::iter::once(SceneSource {
+                        piece: 0,
+                        name: &piece.specifier,
+                        vox: &vox.0,
+                        frame,
+                        skipped_layers,
+                        root,
+                        pivot: options.pivot,
+                        rot: options.transform(),
+                        offset: Vec3::zero(),
+                    }),
+                    &self.glow_indices,
+                    &self.hollow_indices,
+                    &[],
+                    &[],
+                    self.water_alpha_threshold,
+                )
+                .map_err(|e| e.to_string())?;
+                aabbs.into_iter().reduce(|a, b| a.union(b))
+            },
+            PieceModel::Template(template) => {
+                let template = template.read();
+                let (_, aabbs) =
+                    SparseScene::from_template(&template, 0, options.transform(), Vec3::zero());
+                aabbs.into_iter().next()
+            },
+        };
+        bounds.ok_or_else(|| "it is empty".to_string())
+    }
+
+    /// The pieces called `name`, or the pieces with `name` as their specifier
+    /// if none are.
+    fn pieces_named(&self, name: &str) -> Vec<usize> {
+        let named = (0..)
+            .zip(&self.pieces)
+            .filter(|(_, piece)| piece.options.name.as_deref() == Some(name))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !named.is_empty() {
+            return named;
+        }
+        (0..)
+            .zip(&self.pieces)
+            .filter(|(_, piece)| piece.specifier == name)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The frame of the group of every piece in a group, by the index of the
+    /// piece.
+    fn group_frames(
+        &self,
+        name: &str,
+        validation: &mut Validation,
+    ) -> HashMap<usize, GroupFrame> {
+        let frame = |group: &Group| GroupFrame {
+            name: group.name.clone(),
+            outer: None,
+            offset: Vec3::from(group.offset),
+            rotation: group.rotation,
+        };
+        let find_group = |member: &str| self.groups.iter().find(|group| group.name == member);
+        let mut names = HashSet::new();
+        for group in &self.groups {
+            if !names.insert(&group.name) {
+                validation.errors.push(format!(
+                    "Several groups of {} are called {}, names have to be unique",
+                    name, group.name
+                ));
+            }
+        }
+        // The group each group is in.
+        let mut parents = HashMap::new();
+        for parent in &self.groups {
+            for member in parent.members.iter().filter_map(|member| find_group(member)) {
+                if member.members.iter().any(|member| find_group(member).is_some()) {
+                    validation.errors.push(format!(
+                        "Group {} of {} contains group {}, which contains groups itself, \
+                         groups can only be nested one level deep",
+                        parent.name, name, member.name
+                    ));
+                } else if parents.insert(&member.name, parent).is_some() {
+                    validation.errors.push(format!(
+                        "Group {} of {} is in several groups",
+                        member.name, name
+                    ));
+                }
+            }
+        }
+        let mut frames = HashMap::new();
+        for group in &self.groups {
+            let full = match parents.get(&group.name) {
+                Some(parent) => frame(parent).then(&frame(group)),
+                None => frame(group),
+            };
+            for member in group.members.iter().filter(|member| find_group(member).is_none()) {
+                let pieces = self.pieces_named(member);
+                if pieces.is_empty() {
+                    validation.errors.push(format!(
+                        "Group {} of {} contains {}, which is neither a piece nor a group",
+                        group.name, name, member
+                    ));
+                }
+                for piece in pieces {
+                    if frames.insert(piece, full.clone()).is_some() {
+                        validation.errors.push(format!(
+                            "Piece {} of {} is in several groups",
+                            self.pieces[piece].specifier, name
+                        ));
+                    }
+                }
+            }
+        }
+        frames
+    }
+
+    /// The index of the piece called `name`, or with `name` as its specifier.
+    pub(crate) fn piece_index(&self, name: &str) -> Option<usize> {
+        self.pieces_named(name).first().copied()
+    }
+
+    /// This spec with the entries of the specs it includes merged in, the
+    /// loaded specs in `included` along with their names and the paths they
+    /// were loaded from, in the order of `include`. The entries of the spec
+    /// itself override the included ones.
+    pub fn with_includes(mut self, name: &str, included: Vec<(String, String, PlaceSpec)>) -> Self {
+        let mut replace = Vec::<([u8; 3], BlockSpec)>::new();
+        let mut provenance = Provenance::default();
+        let mut merge = |color: [u8; 3], block: BlockSpec, source: Option<String>| {
+            if let Some(i) = replace.iter().position(|(other, _)| *other == color) {
+                let (_, old) = replace.remove(i);
+                let old_source =
+                    provenance.colors.remove(&color).unwrap_or_else(|| name.to_string());
+                // Blocks don't compare, but their specs print the same when
+                // they are the same.
+                if format!("{:?}", old) != format!("{:?}", block) {
+                    let source = source.clone().unwrap_or_else(|| name.to_string());
+                    provenance.overridden.push((color, old_source, source));
+                }
+            }
+            if let Some(source) = source {
+                provenance.colors.insert(color, source);
+            }
+            replace.push((color, block));
+        };
+        let mut replace_indices = Vec::new();
+        // Later ranges win, so the ranges of the spec itself come last.
+        let mut replace_ranges = Vec::new();
+        let mut markers = Vec::<(String, [u8; 3])>::new();
+        let mut pieces = Vec::new();
+        let mut nested = Vec::new();
+        let (mut default_block, mut glowy_block, mut shiny_block) = (None, None, None);
+        for (include, path, spec) in included {
+            for (color, block) in spec.replace {
+                let source = spec.provenance.colors.get(&color).unwrap_or(&include).clone();
+                merge(color, block, Some(source));
+            }
+            // Overrides within an included spec are reported too.
+            nested.extend(spec.provenance.overridden);
+            for (index, block) in spec.replace_indices {
+                replace_indices.retain(|(other, _)| *other != index);
+                replace_indices.push((index, block));
+            }
+            replace_ranges.extend(spec.replace_ranges);
+            for (marker, color) in spec.markers {
+                markers.retain(|(other, _)| *other != marker);
+                markers.push((marker, color));
+            }
+            default_block = spec.default_block.or(default_block);
+            glowy_block = spec.glowy_block.or(glowy_block);
+            shiny_block = spec.shiny_block.or(shiny_block);
+            if self.include_pieces {
+                // Model files of included pieces are relative to their own spec.
+                let mut dir = spec_dir(&path);
+                if dir.is_relative() {
+                    dir = std::env::current_dir().unwrap_or_default().join(dir);
+                }
+                for mut piece in spec.pieces {
+                    resolve_model_path(&dir, &mut piece);
+                    pieces.push(piece);
+                }
+            }
+        }
+        for (color, block) in std::mem::take(&mut self.replace) {
+            merge(color, block, None);
+        }
+        for (index, block) in std::mem::take(&mut self.replace_indices) {
+            replace_indices.retain(|(other, _)| *other != index);
+            replace_indices.push((index, block));
+        }
+        replace_ranges.append(&mut self.replace_ranges);
+        for (marker, color) in std::mem::take(&mut self.markers) {
+            markers.retain(|(other, _)| *other != marker);
+            markers.push((marker, color));
+        }
+        pieces.append(&mut self.pieces);
+        provenance.overridden.splice(0..0, nested);
+        Self {
+            pieces,
+            replace,
+            replace_indices,
+            replace_ranges,
+            markers,
+            default_block: self.default_block.or(default_block),
+            glowy_block: self.glowy_block.or(glowy_block),
+            shiny_block: self.shiny_block.or(shiny_block),
+            provenance,
+            ..self
+        }
+    }
+
+    /// The replace map of each piece, including the colors of its model that
+    /// are only replaced within the color tolerance.
+    pub fn replace_maps(&self) -> Vec<ReplaceMap> {
+        self.pieces
+            .iter()
+            .map(|piece| {
+                let mut colors = self.exact_replace_map(piece);
+                for (color, replaced) in self.tolerant_matches(piece, &colors) {
+                    let block = colors[&replaced].clone();
+                    colors.insert(color, block);
+                }
+                let indices = self
+                    .replace_indices
+                    .iter()
+                    .chain(&piece.options.replace_indices)
+                    .cloned()
+                    .collect();
+                let ranges = self
+                    .replace_ranges
+                    .iter()
+                    .chain(&piece.options.replace_ranges)
+                    .cloned()
+                    .collect();
+                let materials = self.material_map(piece);
+                ReplaceMap {
+                    colors,
+                    indices,
+                    ranges,
+                    materials,
+                    lookup: self.lookup_transform(),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether any block of this spec depends on neighbouring cells.
+    pub fn uses_neighbors(&self) -> bool {
+        let maps = self.replace_maps();
+        maps.iter()
+            .flat_map(|map| {
+                map.colors
+                    .values()
+                    .chain(map.indices.values())
+                    .chain(map.ranges.iter().map(|(_, block)| block))
+                    .chain(map.materials.values())
+            })
+            .chain(&self.fill_block)
+            .chain(&self.default_block)
+            .chain(&self.glowy_block)
+            .chain(&self.shiny_block)
+            .chain(self.pieces.iter().flat_map(|spec| &spec.options.cutout_block))
+            .chain(
+                self.pieces
+                    .iter()
+                    .flat_map(|spec| spec.options.skirt.iter().flat_map(Skirt::blocks)),
+            )
+            .chain(self.overlay.iter().map(|overlay| &overlay.block))
+            .any(BlockSpec::uses_neighbors)
+    }
+
+    /// The blocks for the palette indices of `piece` picked from their
+    /// materials, if `use_materials` is set.
+    pub(crate) fn material_map(&self, piece: &VoxSpec) -> HashMap<u8, BlockSpec> {
+        if !self.use_materials {
+            return HashMap::new();
+        }
+        let Ok(PieceModel::Vox(vox)) = load_piece_model(&piece.specifier) else {
+            return HashMap::new();
+        };
+        let vox = vox.read();
+        vox.0
+            .materials
+            .iter()
+            .filter_map(|material| {
+                // Material ids are one more than the palette index they belong to.
+                let index = usize::try_from(material.id).ok()?.checked_sub(1)?;
+                let index = u8::try_from(index).ok()?;
+                let kind = material.properties.get("_type")?.trim_start_matches('_');
+                let block = self
+                    .materials
+                    .iter()
+                    .find(|(name, _)| name == kind)
+                    .map(|(_, block)| block.clone())
+                    .or_else(|| default_material_block(kind))?;
+                Some((index, block))
+            })
+            .collect()
+    }
+
+    /// The transform applied to colors before looking up their replacement.
+    pub(crate) fn lookup_transform(&self) -> ColorTransform {
+        if self.transform_before_lookup {
+            self.color_transform
+        } else {
+            ColorTransform::None
+        }
+    }
+
+    /// Up to `count` colors used by the models of the pieces, spread over
+    /// all of them, along with those colors transformed by `color_transform`.
+    pub fn color_samples(&self, count: usize) -> Vec<(Rgb<u8>, Rgb<u8>)> {
+        let mut colors = self
+            .pieces
+            .iter()
+            .filter_map(|piece| match load_piece_model(&piece.specifier) {
+                Ok(PieceModel::Vox(vox)) => Some(used_colors(&vox.read().0)),
+                Ok(PieceModel::Template(_)) | Err(_) => None,
+            })
+            .flatten()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        colors.sort_by_key(|color| color.into_array());
+        let count = count.max(1);
+        let step = ((colors.len() + count - 1) / count).max(1);
+        colors
+            .into_iter()
+            .step_by(step)
+            .map(|color| (color, self.color_transform.apply(color)))
+            .collect()
+    }
+
+    /// The colors the spec and `piece` itself replace.
+    pub fn exact_replace_map(&self, piece: &VoxSpec) -> HashMap<Rgb<u8>, BlockSpec> {
+        self.replace
+            .iter()
+            .chain(&piece.options.replace)
+            .map(|(color, block)| (Rgb::from(*color), block.clone()))
+            .collect()
+    }
+
+    /// The colors used by the model of `piece` that aren't in `replace_map`,
+    /// along with the closest color in `replace_map` within the color
+    /// tolerance.
+    pub fn tolerant_matches(
+        &self,
+        piece: &VoxSpec,
+        replace_map: &HashMap<Rgb<u8>, BlockSpec>,
+    ) -> Vec<(Rgb<u8>, Rgb<u8>)> {
+        if self.color_tolerance == 0 {
+            return Vec::new();
+        }
+        let Ok(PieceModel::Vox(vox)) = load_piece_model(&piece.specifier) else {
+            return Vec::new();
+        };
+        let max_distance = u32::from(self.color_tolerance).pow(2);
+        let lookup = self.lookup_transform();
+        let mut matches = used_colors(&vox.read().0)
+            .into_iter()
+            .map(|color| lookup.apply(color))
+            .filter(|color| !replace_map.contains_key(color))
+            .filter_map(|color| {
+                let closest = replace_map
+                    .keys()
+                    .copied()
+                    .min_by_key(|replaced| {
+                        (color_distance(color, *replaced), replaced.into_array())
+                    })?;
+                (color_distance(color, closest) <= max_distance).then_some((color, closest))
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by_key(|(color, _)| color.into_array());
+        matches
+    }
+
+    /// Moves the pieces and groups aligned to the context by the position in
+    /// the context they align to. Only pieces outside of groups and
+    /// outermost groups can align to it.
+    fn align_to_context(&mut self, name: &str, dir: &Path, validation: &mut Validation) {
+        let aligned = self.pieces.iter().any(|piece| piece.options.align_to_context.is_some())
+            || self.groups.iter().any(|group| group.align_to_context.is_some());
+        if !aligned {
+            return;
+        }
+        let Some(path) = &self.context else {
+            validation.errors.push(format!(
+                "Pieces or groups of {} align to the context, but it has no context",
+                name
+            ));
+            return;
+        };
+        let context = match Context::load(&dir.join(path)) {
+            Ok(context) => context,
+            Err(e) => {
+                validation.errors.push(format!("{}, the context of {}", e, name));
+                return;
+            },
+        };
+        let grouped = self
+            .groups
+            .iter()
+            .flat_map(|group| group.members.iter().map(move |member| (group, member)))
+            .collect::<Vec<_>>();
+        for i in 0..self.pieces.len() {
+            let Some(align) = &self.pieces[i].options.align_to_context else {
+                continue;
+            };
+            let piece = &self.pieces[i];
+            if let Some((group, _)) =
+                grouped.iter().find(|(_, member)| self.pieces_named(member).contains(&i))
+            {
+                validation.errors.push(format!(
+                    "Piece {} of {} aligns to the context, but is in group {}, align the group \
+                     instead",
+                    piece.name(),
+                    name,
+                    group.name
+                ));
+                continue;
+            }
+            match align.position(&context) {
+                Ok(position) => {
+                    let offset = (position + Vec3::from(piece.offset)).into_array();
+                    self.pieces[i].offset = offset;
+                },
+                Err(e) => validation.errors.push(format!(
+                    "Piece {} of {} can't align to the context, {}",
+                    piece.name(),
+                    name,
+                    e
+                )),
+            }
+        }
+        let nested = grouped
+            .iter()
+            .map(|(_, member)| member.to_string())
+            .collect::<HashSet<_>>();
+        for group in &mut self.groups {
+            let Some(align) = &group.align_to_context else {
+                continue;
+            };
+            if nested.contains(&group.name) {
+                validation.errors.push(format!(
+                    "Group {} of {} aligns to the context, but is in another group",
+                    group.name, name
+                ));
+                continue;
+            }
+            match align.position(&context) {
+                Ok(position) => {
+                    group.offset = (position + Vec3::from(group.offset)).into_array();
+                },
+                Err(e) => validation.errors.push(format!(
+                    "Group {} of {} can't align to the context, {}",
+                    group.name, name, e
+                )),
+            }
+        }
+    }
+
+    /// This spec with the instances of every scatter area added to its pieces,
+    /// picked with `seed` or a thread local RNG without one.
+    pub fn expanded(
+        &self,
+        name: &str,
+        seed: Option<u64>,
+        validation: &mut Validation,
+    ) -> Self {
+        let mut spec = self.clone();
+        let dir = spec_dir(name);
+        spec.align_to_context(name, &dir, validation);
+        let mut names = HashSet::new();
+        for piece_name in self.pieces.iter().filter_map(|piece| piece.options.name.as_ref()) {
+            if !names.insert(piece_name) {
+                validation.errors.push(format!(
+                    "Several pieces of {} are called {}, names have to be unique",
+                    name, piece_name
+                ));
+            }
+        }
+        let frames = spec.group_frames(name, validation);
+        for (i, mut piece) in std::mem::take(&mut spec.pieces).into_iter().enumerate() {
+            resolve_model_path(&dir, &mut piece);
+            // The instances of pieces in groups are laid out within the group,
+            // then moved with it.
+            let first = spec.pieces.len();
+            if !piece.options.path.is_empty() {
+                let instances = self.path_instances(&piece, name, validation);
+                spec.pieces.extend(instances);
+            } else {
+                let count = piece.options.repeat_count(piece.offset);
+                if count == 0 {
+                    validation.warnings.push(format!(
+                        "Piece {} of {} doesn't fit between its offset and the end of \
+                         repeat_between even once, it is left out",
+                        piece.specifier, name
+                    ));
+                }
+                let stride =
+                    Vec3::from(piece.options.repeat.as_ref().map_or([0; 3], |r| r.stride));
+                for i in 0..count {
+                    spec.pieces.push(VoxSpec {
+                        offset: (Vec3::from(piece.offset) + stride * i as i32).into_array(),
+                        ..piece.clone()
+                    });
+                }
+            }
+            if let Some(frame) = frames.get(&i) {
+                for instance in &mut spec.pieces[first..] {
+                    frame.apply(instance);
+                }
+            }
+        }
+        for (i, scatter) in std::mem::take(&mut spec.scatter).iter().enumerate() {
+            let seed = seed.unwrap_or_else(|| thread_rng().gen());
+            let mut rng = PosRng(splitmix64(seed ^ i as u64));
+            let (mut instances, rejected) = scatter.instances(&mut rng);
+            for instance in &mut instances {
+                resolve_model_path(&dir, instance);
+            }
+            info!(
+                "Scattered {} instances in area {} of {}, rejected {} positions",
+                instances.len(),
+                i,
+                name,
+                rejected
+            );
+            spec.pieces.extend(instances);
+        }
+        if let Some(missing_piece) = &self.missing_piece {
+            let substitute = if is_model_path(missing_piece) {
+                dir.join(missing_piece).to_string_lossy().into_owned()
+            } else {
+                missing_piece.clone()
+            };
+            if let Err(e) = load_piece_model(&substitute) {
+                validation.errors.push(format!(
+                    "Could not load the missing piece {} of {}: {}",
+                    substitute, name, e
+                ));
+                return spec;
+            }
+            for piece in &mut spec.pieces {
+                if load_piece_model(&piece.specifier).is_ok() {
+                    continue;
+                }
+                if !spec.substituted.iter().any(|(intended, _)| *intended == piece.specifier) {
+                    validation.warnings.push(format!(
+                        "Could not load vox file {} of {}, {} is placed instead",
+                        piece.specifier, name, substitute
+                    ));
+                    spec.substituted.push((piece.specifier.clone(), substitute.clone()));
+                }
+                // The piece can still be aligned to and picked by its model.
+                piece.options.name.get_or_insert_with(|| piece.specifier.clone());
+                piece.specifier = substitute.clone();
+            }
+        }
+        spec
+    }
+
+    /// Checks that the models of every piece load, and that the replaced
+    /// colors and blocks make sense. Missing models are only warned about
+    /// with `allow_missing`, they are left out. Rotations
+    /// that aren't quarter turns are only warned about with
+    /// `approximate_rotations`.
+    pub fn validate(
+        &self,
+        name: &str,
+        allow_missing: bool,
+        approximate_rotations: bool,
+        validation: &mut Validation,
+    ) {
+        let mut colors = Vec::new();
+        for (i, spec) in self.pieces.iter().enumerate() {
+            match (&spec.options.repeat, &spec.options.repeat_between) {
+                (Some(repeat), _) if repeat.stride == [0; 3] => validation.errors.push(format!(
+                    "Piece {} of {} repeats with a stride of zero",
+                    spec.specifier, name
+                )),
+                (None, Some(_)) => validation.errors.push(format!(
+                    "Piece {} of {} has repeat_between without the stride of a repeat",
+                    spec.specifier, name
+                )),
+                _ => {},
+            }
+            if !(0.0..=1.0).contains(&spec.options.decay) {
+                validation.errors.push(format!(
+                    "Piece {} of {} has a decay of {}, which has to be between 0 and 1",
+                    spec.specifier, name, spec.options.decay
+                ));
+            }
+            for cutout in &spec.options.cutouts {
+                if cutout.min.map2(cutout.max, |min, max| min > max).reduce_or() {
+                    validation.errors.push(format!(
+                        "Piece {} of {} has a cutout from {} to {}, which is empty",
+                        spec.specifier, name, cutout.min, cutout.max
+                    ));
+                }
+            }
+            if let Some(skirt) = &spec.options.skirt {
+                let slope = skirt.berm.as_ref().map_or(1.0, |berm| berm.slope);
+                if !(slope.is_finite() && slope >= 0.0) {
+                    validation.errors.push(format!(
+                        "Piece {} of {} has a berm with a slope of {}, which can't be negative",
+                        spec.specifier, name, slope
+                    ));
+                }
+                if skirt.width == 0 && skirt.berm.is_none() {
+                    validation.warnings.push(format!(
+                        "Piece {} of {} has a skirt with a width of zero, which places nothing",
+                        spec.specifier, name
+                    ));
+                }
+            }
+            if let Some(align) = &spec.options.align {
+                match self.piece_index(&align.piece) {
+                    Some(target) if target < i => {},
+                    Some(_) => validation.errors.push(format!(
+                        "Piece {} of {} aligns to {}, which has to come before it",
+                        spec.specifier, name, align.piece
+                    )),
+                    None => validation.errors.push(format!(
+                        "Piece {} of {} aligns to {}, which isn't a piece of the spec",
+                        spec.specifier, name, align.piece
+                    )),
+                }
+            }
+            if let FrameSpec::Random(start, end) = spec.options.frame {
+                if start >= end {
+                    validation.errors.push(format!(
+                        "The frame range {}..{} of piece {} of {} is empty",
+                        start, end, spec.specifier, name
+                    ));
+                }
+            }
+            match load_piece_model(&spec.specifier) {
+                Ok(PieceModel::Vox(vox)) => {
+                    let vox = vox.read();
+                    let layers = (0..).zip(&vox.0.layers);
+                    for layer_ref in spec
+                        .options
+                        .include_layers
+                        .iter()
+                        .flatten()
+                        .chain(&spec.options.exclude_layers)
+                    {
+                        if !layers.clone().any(|(i, layer)| layer_ref.matches(i, layer)) {
+                            validation.warnings.push(format!(
+                                "Piece {} of {} has no layer {:?}",
+                                spec.specifier, name, layer_ref
+                            ));
+                        }
+                    }
+                    if spec.options.root_node(&vox.0).is_none() {
+                        validation.errors.push(format!(
+                            "Piece {} of {} has no node named {:?}, the named nodes are {:?}",
+                            spec.specifier,
+                            name,
+                            spec.options.node.as_deref().unwrap_or_default(),
+                            named_nodes(&vox.0).map(|(_, name)| name).collect::<Vec<_>>()
+                        ));
+                    }
+                    check_transforms(
+                        &vox.0,
+                        &format!("piece {} of {}", spec.specifier, name),
+                        approximate_rotations,
+                        validation,
+                    );
+                    colors.push(used_colors(&vox.0));
+                },
+                Ok(PieceModel::Template(_)) => {
+                    if spec.options.snap_to_surface || spec.options.conform {
+                        validation.warnings.push(format!(
+                            "Piece {} of {} is a template, templates can't be moved to the \
+                             surface",
+                            spec.specifier, name
+                        ));
+                    }
+                    colors.push(HashSet::new());
+                },
+                Err(e) => {
+                    let problem = format!(
+                        "Could not load vox file {} of {}: {}",
+                        spec.specifier, name, e
+                    );
+                    // Files are always expected to exist.
+                    if allow_missing && !is_model_path(&spec.specifier) {
+                        validation.warnings.push(format!("{}, it is left out", problem));
+                    } else {
+                        validation.errors.push(format!(
+                            "{}, set missing_piece to place another model instead",
+                            problem
+                        ));
+                    }
+                    colors.push(HashSet::new());
+                },
+            }
+        }
+        let all_colors = colors.iter().flatten().copied().collect::<HashSet<_>>();
+        for (color, first, overriding) in &self.provenance.overridden {
+            validation.warnings.push(format!(
+                "{}: the color {:?} replaced in {} is replaced with a different block in {}, \
+                 which takes precedence",
+                name, color, first, overriding
+            ));
+        }
+        // The replacements from included specs are reported with the spec
+        // they come from.
+        let check_colors = |validation: &mut Validation,
+                            replace: &[([u8; 3], BlockSpec)],
+                            colors: &HashSet<Rgb<u8>>,
+                            owner: &str,
+                            sources: &HashMap<[u8; 3], String>| {
+            for (color, block) in replace {
+                block.check(validation);
+                let max_distance = u32::from(self.color_tolerance).pow(2);
+                let lookup = self.lookup_transform();
+                if !colors.iter().any(|used| {
+                    color_distance(lookup.apply(*used), Rgb::from(*color)) <= max_distance
+                }) {
+                    let mut present = colors
+                        .iter()
+                        .map(|c| lookup.apply(*c).into_array())
+                        .collect::<Vec<_>>();
+                    present.sort();
+                    let owner = match sources.get(color) {
+                        Some(source) => format!("{}, included by {}", source, owner),
+                        None => owner.to_string(),
+                    };
+                    validation.warnings.push(format!(
+                        "The color {:?} replaced in {} isn't used by its models, the colors \
+                         used are {:?}",
+                        color, owner, present
+                    ));
+                }
+            }
+        };
+        check_colors(validation, &self.replace, &all_colors, name, &self.provenance.colors);
+        if let ColorTransform::Gamma(gamma) = self.color_transform {
+            if !(gamma.is_finite() && gamma > 0.0) {
+                validation.errors.push(format!(
+                    "{}: the gamma of color_transform is {}, which has to be above 0",
+                    name, gamma
+                ));
+            }
+        }
+        for (kind, _) in &self.materials {
+            if !MATERIAL_TYPES.contains(&kind.as_str()) {
+                validation.warnings.push(format!(
+                    "{}: unknown material type {:?}, expected one of {:?}",
+                    name, kind, MATERIAL_TYPES
+                ));
+            } else if !self.use_materials {
+                validation.warnings.push(format!(
+                    "{}: material {:?} is ignored without use_materials: true",
+                    name, kind
+                ));
+            }
+        }
+        for (i, (marker, color)) in self.markers.iter().enumerate() {
+            if let Some((other, _)) = self.markers[..i].iter().find(|(other, other_color)| {
+                other == marker || other_color == color
+            }) {
+                validation.errors.push(format!(
+                    "{}: markers {:?} and {:?} have the same name or color",
+                    name, other, marker
+                ));
+            }
+            if self.anchor_colors.contains(color) {
+                validation.errors.push(format!(
+                    "{}: the color {:?} of marker {:?} is also an anchor color",
+                    name, color, marker
+                ));
+            }
+        }
+        for block in [&self.default_block, &self.glowy_block, &self.shiny_block, &self.fill_block]
+            .into_iter()
+            .flatten()
+            .chain(self.replace_indices.iter().map(|(_, block)| block))
+            .chain(self.replace_ranges.iter().map(|(_, block)| block))
+            .chain(self.materials.iter().map(|(_, block)| block))
+            .chain(
+                self.pieces
+                    .iter()
+                    .flat_map(|spec| &spec.options.replace_indices)
+                    .map(|(_, block)| block),
+            )
+            .chain(
+                self.pieces
+                    .iter()
+                    .flat_map(|spec| &spec.options.replace_ranges)
+                    .map(|(_, block)| block),
+            )
+            .chain(self.pieces.iter().flat_map(|spec| &spec.options.cutout_block))
+            .chain(
+                self.pieces
+                    .iter()
+                    .flat_map(|spec| spec.options.skirt.iter().flat_map(Skirt::blocks)),
+            )
+            .chain(self.overlay.iter().map(|overlay| &overlay.block))
+        {
+            block.check(validation);
+        }
+        let footprint = |spec: &VoxSpec| {
+            spec.options.fill_mode.unwrap_or(self.fill_mode) == FillMode::Footprint
+        };
+        if self.fill_margin > 0 && !self.pieces.iter().any(footprint) {
+            validation.warnings.push(format!(
+                "{} has a fill_margin, which only applies to pieces with a fill_mode of Footprint",
+                name
+            ));
+        }
+        if self.strip_hidden_glow && self.hidden_glow_radius == 0 {
+            validation.errors.push(format!(
+                "{} strips hidden glow with a hidden_glow_radius of zero, which would strip all \
+                 of it",
+                name
+            ));
+        }
+        if let Some(overlay) = &self.overlay {
+            if !(0.0..=1.0).contains(&overlay.coverage) {
+                validation.errors.push(format!(
+                    "The overlay of {} has a coverage of {}, it has to be between 0 and 1",
+                    name, overlay.coverage
+                ));
+            }
+            if overlay.min_exposure == 0 {
+                validation.errors.push(format!(
+                    "The overlay of {} has a min_exposure of zero, it needs at least one empty \
+                     block to go in",
+                    name
+                ));
+            }
+        }
+        for (spec, colors) in self.pieces.iter().zip(&colors) {
+            check_colors(
+                validation,
+                &spec.options.replace,
+                colors,
+                &format!("piece {} of {}", spec.specifier, name),
+                &HashMap::new(),
+            );
+        }
+        let check_ranges = |validation: &mut Validation,
+                            ranges: &[(ColorRange, BlockSpec)],
+                            colors: &HashSet<Rgb<u8>>,
+                            owner: &str| {
+            let lookup = self.lookup_transform();
+            for (range, _) in ranges {
+                if (0..3).any(|i| range.min[i] > range.max[i]) {
+                    validation.errors.push(format!(
+                        "The color range {:?} to {:?} of {} is empty, min can't be above max",
+                        range.min, range.max, owner
+                    ));
+                } else if !colors.iter().any(|color| range.contains(lookup.apply(*color))) {
+                    validation.warnings.push(format!(
+                        "The color range {:?} to {:?} of {} doesn't contain any color used by \
+                         its models",
+                        range.min, range.max, owner
+                    ));
+                }
+            }
+        };
+        check_ranges(validation, &self.replace_ranges, &all_colors, name);
+        for (spec, colors) in self.pieces.iter().zip(&colors) {
+            check_ranges(
+                validation,
+                &spec.options.replace_ranges,
+                colors,
+                &format!("piece {} of {}", spec.specifier, name),
+            );
+        }
+    }
+
+    /// Builds the scene of every piece, rotated by `rot` around and translated
+    /// by `offset`. Pieces snapping or conforming to the surface look it up in
+    /// `world`, pieces without a surface are left out. Random frames are
+    /// picked with `seed`, or a thread local RNG without one.
+    pub fn build_pieces(
+        &self,
+        rot: Mat3<i32>,
+        offset: Vec3<i32>,
+        world: &mut World,
+        seed: Option<u64>,
+        validation: &mut Validation,
+    ) -> Vec<BuiltPiece> {
+        let mut pieces = Vec::new();
+        self.build_pieces_each(rot, offset, world, seed, validation, |piece, _| pieces.push(piece));
+        pieces
+    }
+
+    /// Builds the pieces like `build_pieces`, handing every piece to `each` as
+    /// soon as it is built along with `world`, so only one scene has to be
+    /// kept at a time.
+    pub fn build_pieces_each(
+        &self,
+        rot: Mat3<i32>,
+        offset: Vec3<i32>,
+        world: &mut World,
+        seed: Option<u64>,
+        validation: &mut Validation,
+        mut each: impl FnMut(BuiltPiece, &mut World),
+    ) {
+        let anchor_colors = self.anchor_colors.iter().copied().map(Rgb::from).collect::<Vec<_>>();
+        let markers = self
+            .markers
+            .iter()
+            .map(|(name, color)| (name.clone(), Rgb::from(*color)))
+            .collect::<Vec<_>>();
+        // The models of the pieces are loaded and their scenes built where
+        // the spec puts them in parallel, a batch of pieces at a time so only
+        // that many scenes are kept at once.
+        let first_build = |piece: usize| {
+            let spec = &self.pieces[piece];
+            let Ok(PieceModel::Vox(vox)) = load_piece_model(&spec.specifier) else {
+                return None;
+            };
+            let piece = u16::try_from(piece).expect("Too many pieces");
+            let frame = spec.options.frame_of(piece, seed);
+            let built = self.build_scene(
+                &vox.read().0,
+                spec,
+                piece,
+                frame,
+                rot * spec.options.transform(),
+                offset + rot * Vec3::<i32>::from(spec.offset),
+                &anchor_colors,
+                &markers,
+            );
+            Some((frame, built))
+        };
+        let batch = rayon::current_num_threads().max(1);
+        let mut first_builds = HashMap::new();
+        // Pieces are moved one by one after being built, since they may align
+        // to the pieces before them. Only the bounds of built pieces are kept,
+        // for aligning to them.
+        let mut built = Vec::<(u16, Option<PieceBounds>)>::new();
+        'pieces: for (piece, spec) in self.pieces.iter().enumerate() {
+            if piece % batch == 0 {
+                let end = (piece + batch).min(self.pieces.len());
+                first_builds = (piece..end)
+                    .into_par_iter()
+                    .filter_map(|piece| Some((piece, first_build(piece)?)))
+                    .collect::<HashMap<_, _>>();
+            }
+            let first = first_builds.remove(&piece);
+            let piece = u16::try_from(piece).expect("Too many pieces");
+            let piece_rot = rot * spec.options.transform();
+            let placed_offset = offset + rot * Vec3::<i32>::from(spec.offset);
+            let vox = match load_piece_model(&spec.specifier) {
+                Ok(PieceModel::Vox(vox)) => vox,
+                // Missing models are reported during validation, and only get
+                // here when they are left out.
+                Err(_) => continue,
+                // Templates are placed exactly where they are.
+                Ok(PieceModel::Template(template)) => {
+                    let template = template.read();
+                    let (mut scene, aabbs) =
+                        SparseScene::from_template(&template, piece, piece_rot, placed_offset);
+                    let cutouts = aabbs.first().map_or_else(Vec::new, |aabb| {
+                        scene.cut_out(&spec.options.cutouts, piece_rot, *aabb)
+                    });
+                    let local_aabb = aabbs
+                        .first()
+                        .map(|aabb| spec.options.local_bounds(*aabb, rot, offset));
+                    let bounds = aabbs.first().map(|aabb| PieceBounds {
+                        name: spec.specifier.clone(),
+                        aabb: *aabb,
+                        voxels: template.blocks.len(),
+                        centroid: scene.centroid(),
+                        anchors: Vec::new(),
+                        markers: HashMap::new(),
+                    });
+                    built.push((piece, bounds.clone()));
+                    let fill_footprint = self.fill_footprint(spec, &scene);
+                    each(
+                        BuiltPiece {
+                            index: piece,
+                            scene,
+                            aabbs,
+                            bounds,
+                            local_aabb,
+                            merge: spec.options.merge,
+                            priority: spec.options.priority,
+                            rot: piece_rot,
+                            offset: placed_offset,
+                            decayed: 0,
+                            fill_empty: spec.options.fill_empty.unwrap_or(self.fill_empty),
+                            fill_footprint,
+                            cutouts,
+                            skirt: Vec::new(),
+                        },
+                        world,
+                    );
+                    continue;
+                },
+            };
+            let mut piece_offset = placed_offset;
+            // Models are only turned into scenes here again once they move.
+            let (frame, first) = match first {
+                Some((frame, first)) => (frame, first),
+                None => {
+                    let frame = spec.options.frame_of(piece, seed);
+                    let first = self.build_scene(
+                        &vox.read().0,
+                        spec,
+                        piece,
+                        frame,
+                        piece_rot,
+                        piece_offset,
+                        &anchor_colors,
+                        &markers,
+                    );
+                    (frame, first)
+                },
+            };
+            let build = |piece_offset: Vec3<i32>| {
+                self.build_scene(
+                    &vox.read().0,
+                    spec,
+                    piece,
+                    frame,
+                    piece_rot,
+                    piece_offset,
+                    &anchor_colors,
+                    &markers,
+                )
+            };
+            let (mut piece_scene, mut piece_aabbs, mut piece_bounds) = match first {
+                Ok(built) => built,
+                Err(e) => {
+                    validation.errors.push(e.to_string());
+                    continue;
+                },
+            };
+            if let Some(align) = &spec.options.align {
+                // Missing pieces are reported during validation.
+                let target = self
+                    .piece_index(&align.piece)
+                    .and_then(|index| built.iter().find(|(p, _)| usize::from(*p) == index))
+                    .and_then(|(_, bounds)| bounds.as_ref());
+                let anchor = target.and_then(|target| target.anchors.get(align.anchor));
+                let own = piece_bounds.first().map_or(&[][..], |b| &b.anchors[..]);
+                match (anchor, own.get(align.to_anchor)) {
+                    (Some(anchor), Some(own)) => {
+                        piece_offset += *anchor - *own;
+                        (piece_scene, piece_aabbs, piece_bounds) =
+                            build(piece_offset).expect("Moving a scene doesn't break it");
+                    },
+                    (None, _) => {
+                        validation.errors.push(format!(
+                            "Piece {} aligns to anchor {} of {}, which has {} anchors",
+                            spec.specifier,
+                            align.anchor,
+                            align.piece,
+                            target.map_or(0, |target| target.anchors.len())
+                        ));
+                        continue;
+                    },
+                    (_, None) => {
+                        validation.errors.push(format!(
+                            "Piece {} aligns its anchor {}, but only has {} anchors",
+                            spec.specifier,
+                            align.to_anchor,
+                            own.len()
+                        ));
+                        continue;
+                    },
+                }
+            }
+            let mut retry_offsets = spec.options.retry_offsets.iter();
+            loop {
+                if spec.options.snap_to_surface {
+                    // The lowest voxel of the piece rests on the surface.
+                    let Some(lowest) = piece_scene.lowest_cell() else {
+                        continue 'pieces;
+                    };
+                    match world.surface_height(piece_offset.xy(), spec.options.surface_range) {
+                        Some(height) => {
+                            piece_offset.z += height + 1 - lowest;
+                            (piece_scene, piece_aabbs, piece_bounds) =
+                                build(piece_offset).expect("Moving a scene doesn't break it");
+                        },
+                        None => {
+                            warn!(
+                                "Found no surface below piece {} at {}, skipping it",
+                                spec.specifier,
+                                piece_offset.xy()
+                            );
+                            continue 'pieces;
+                        },
+                    }
+                }
+                let (Some(require_flat), Some(bounds)) = (
+                    spec.options.require_flat,
+                    piece_aabbs.iter().copied().reduce(|a, b| a.union(b)),
+                ) else {
+                    break;
+                };
+                let footprint = Aabr {
+                    min: bounds.min.xy(),
+                    max: bounds.max.xy(),
+                };
+                let range = spec.options.surface_range;
+                let flat = require_flat.check(world, footprint, range, self.water_level);
+                let Err(reason) = flat else {
+                    break;
+                };
+                // Scattered instances try their other positions first.
+                let Some(retry) = retry_offsets.next() else {
+                    warn!(
+                        "The terrain below piece {} at {} isn't flat, {}, skipping it",
+                        spec.specifier,
+                        piece_offset.xy(),
+                        reason
+                    );
+                    continue 'pieces;
+                };
+                debug!(
+                    "The terrain below piece {} at {} isn't flat, {}, trying {:?} instead",
+                    spec.specifier,
+                    piece_offset.xy(),
+                    reason,
+                    retry
+                );
+                piece_offset = offset + rot * Vec3::<i32>::from(*retry);
+                (piece_scene, piece_aabbs, piece_bounds) =
+                    build(piece_offset).expect("Moving a scene doesn't break it");
+            }
+            // Cutouts are made before conforming, so they move with the
+            // columns they cut.
+            let cutouts = match piece_aabbs.iter().copied().reduce(|a, b| a.union(b)) {
+                Some(bounds) => piece_scene.cut_out(&spec.options.cutouts, piece_rot, bounds),
+                None => Vec::new(),
+            };
+            if spec.options.conform {
+                let (conformed, bounds) = piece_scene.conform(
+                    world,
+                    spec.options.surface_range,
+                    spec.options.max_slope,
+                );
+                piece_scene = conformed;
+                piece_aabbs = bounds.into_iter().collect();
+                if let Some(extent) = piece_bounds.first_mut() {
+                    extent.aabb = piece_aabbs.first().copied().unwrap_or(extent.aabb);
+                }
+            }
+            let mut decayed = 0;
+            if spec.options.decay > 0.0 {
+                let seed = seed.unwrap_or_else(|| thread_rng().gen());
+                let seed = splitmix64(seed ^ u64::from(piece));
+                decayed = piece_scene.decay(spec.options.decay, seed);
+                if let Some(extent) = piece_bounds.first_mut() {
+                    extent.voxels -= decayed;
+                }
+            }
+            if spec.options.conform || decayed > 0 {
+                if let Some(extent) = piece_bounds.first_mut() {
+                    extent.centroid = piece_scene.centroid();
+                }
+            }
+            let skirt = spec
+                .options
+                .skirt
+                .as_ref()
+                .map_or_else(Vec::new, |skirt| skirt.positions(&piece_scene, world));
+            let local_aabb = piece_aabbs
+                .iter()
+                .copied()
+                .reduce(|a, b| a.union(b))
+                .map(|aabb| spec.options.local_bounds(aabb, rot, offset));
+            let bounds = piece_bounds.pop();
+            built.push((piece, bounds.clone()));
+            let fill_footprint = self.fill_footprint(spec, &piece_scene);
+            each(
+                BuiltPiece {
+                    index: piece,
+                    scene: piece_scene,
+                    aabbs: piece_aabbs,
+                    bounds,
+                    local_aabb,
+                    merge: spec.options.merge,
+                    priority: spec.options.priority,
+                    rot: piece_rot,
+                    offset: placed_offset,
+                    decayed,
+                    fill_empty: spec.options.fill_empty.unwrap_or(self.fill_empty),
+                    fill_footprint,
+                    cutouts,
+                    skirt,
+                },
+                world,
+            );
+        }
+    }
+
+    /// The columns the piece `spec` with the scene `scene` fills, if it fills
+    /// its footprint.
+    fn fill_footprint(
+        &self,
+        spec: &VoxSpec,
+        scene: &SparseScene,
+    ) -> Option<HashMap<Vec2<i32>, (i32, i32)>> {
+        let fills = spec.options.fill_empty.unwrap_or(self.fill_empty);
+        let mode = spec.options.fill_mode.unwrap_or(self.fill_mode);
+        (fills && mode == FillMode::Footprint).then(|| scene.column_extents(self.fill_margin))
+    }
+
+    /// Builds the scene of the piece `spec`, the model `vox` turned by `rot`
+    /// and moved to `offset`.
+    #[allow(clippy::too_many_arguments)]
+    fn build_scene(
+        &self,
+        vox: &DotVoxData,
+        spec: &VoxSpec,
+        piece: u16,
+        frame: u32,
+        rot: Mat3<i32>,
+        offset: Vec3<i32>,
+        anchor_colors: &[Rgb<u8>],
+        markers: &[(String, Rgb<u8>)],
+    ) -> Result<(SparseScene, Vec<Aabb<i32>>, Vec<PieceBounds>), SceneError> {
+        SparseScene::new_from(
+            std::iter::once(SceneSource {
+                piece,
+                name: &spec.specifier,
+                vox,
+                frame,
+                skipped_layers: spec.options.skipped_layers(vox),
+                // Missing nodes are reported during validation.
+                root: spec.options.root_node(vox).unwrap_or(0),
+                pivot: spec.options.pivot,
+                rot,
+                offset,
+            }),
+            &self.glow_indices,
+            &self.hollow_indices,
+            anchor_colors,
+            markers,
+            self.water_alpha_threshold,
+        )
+    }
+
+    /// A hash of the model of a piece, along with everything else in this spec
+    /// that changes how it is placed.
+    pub fn piece_hash(&self, piece: u16) -> u64 {
+        use std::fmt::Write;
+        let spec = &self.pieces[piece as usize];
+        let mut hasher = Fnv::default();
+        match load_piece_model(&spec.specifier) {
+            Ok(PieceModel::Vox(vox)) => write!(hasher, "{:?}", vox.read().0),
+            Ok(PieceModel::Template(template)) => write!(hasher, "{:?}", template.read().blocks),
+            Err(e) => write!(hasher, "{}", e),
+        }
+        .unwrap();
+        write!(
+            hasher,
+            "{:?}{:?}{:?}{:?}{:?}{:?}{:?}",
+            spec.options,
+            self.replace,
+            self.replace_indices,
+            self.fill_empty,
+            self.fill_block,
+            self.glow_indices,
+            self.hollow_indices
+        )
+        .unwrap();
+        // Only hashed when set, so the hashes of existing manifests still match.
+        if !self.replace_ranges.is_empty() {
+            write!(hasher, "{:?}", self.replace_ranges).unwrap();
+        }
+        if self.fill_mode != FillMode::Aabb {
+            write!(hasher, "{:?}{:?}", self.fill_mode, self.fill_margin).unwrap();
+        }
+        if self.color_transform != ColorTransform::None {
+            write!(
+                hasher,
+                "{:?}{:?}",
+                self.color_transform, self.transform_before_lookup
+            )
+            .unwrap();
+        }
+        hasher.0
+    }
+}
+
+/// A 64 bit FNV-1a hasher, which unlike the hasher of the standard library is
+/// stable between builds.
+#[derive(Clone, Debug)]
+pub struct Fnv(pub u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl std::fmt::Write for Fnv {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        for byte in s.bytes() {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+        Ok(())
+    }
+}
+
+impl Asset for PlaceSpec {
+    type Loader = RonLoader;
+
+    const EXTENSION: &'static str = "ron";
+}
+
+/// A rotation around the z axis in steps of 90 degrees.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(try_from = "u32")]
+pub struct Rotation(pub(crate) u8);
+
+impl TryFrom<u32> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u32) -> Result<Self, Self::Error> {
+        match degrees {
+            0 | 90 | 180 | 270 => Ok(Rotation((degrees / 90) as u8)),
+            _ => Err(format!(
+                "invalid rotation {}, expected 0, 90, 180 or 270",
+                degrees
+            )),
+        }
+    }
+}
+
+impl Rotation {
+    /// The rotation as a matrix.
+    pub fn to_mat(self) -> Mat3<i32> {
+        // A counter clockwise quarter turn.
+        let quarter = Mat3::from_col_arrays([[0, 1, 0], [-1, 0, 0], [0, 0, 1]]);
+        (0..self.0).fold(Mat3::identity(), |rot, _| quarter * rot)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Placement {
+    pub spec: String,
+    pub offset: [i32; 3],
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+/// A list of specs to place, each at its own offset.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PlacementSet {
+    pub placements: Vec<Placement>,
+}
+
+impl Asset for PlacementSet {
+    type Loader = RonLoader;
+
+    const EXTENSION: &'static str = "ron";
+}
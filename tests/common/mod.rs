@@ -0,0 +1,91 @@
+use std::collections::{HashMap, HashSet};
+
+use dot_vox::{Color, DotVoxData, Frame, Model, SceneNode, ShapeModel, Size, Voxel};
+use map_placer::{
+    scene::{PieceBounds, SceneSource, SparseScene},
+    spec::Pivot,
+};
+use vek::*;
+
+/// A vox file with a single model of `size`, made of `voxels` with a position
+/// and a palette index each.
+pub fn vox_model(size: [u32; 3], voxels: &[([u8; 3], u8)], palette: &[Rgb<u8>]) -> DotVoxData {
+    DotVoxData {
+        version: 150,
+        models: vec![Model {
+            size: Size {
+                x: size[0],
+                y: size[1],
+                z: size[2],
+            },
+            voxels: voxels
+                .iter()
+                .map(|([x, y, z], i)| Voxel {
+                    x: *x,
+                    y: *y,
+                    z: *z,
+                    i: *i,
+                })
+                .collect(),
+        }],
+        palette: palette
+            .iter()
+            .map(|c| Color {
+                r: c.r,
+                g: c.g,
+                b: c.b,
+                a: 255,
+            })
+            .collect(),
+        materials: Vec::new(),
+        scenes: vec![
+            SceneNode::Transform {
+                attributes: HashMap::new(),
+                frames: vec![Frame {
+                    attributes: HashMap::new(),
+                }],
+                child: 1,
+                layer_id: 0,
+            },
+            SceneNode::Shape {
+                attributes: HashMap::new(),
+                models: vec![ShapeModel {
+                    model_id: 0,
+                    attributes: HashMap::new(),
+                }],
+            },
+        ],
+        layers: Vec::new(),
+    }
+}
+
+/// Builds `vox` as the piece `piece`, with its minimum corner at `offset`
+/// before rotating it by `rot`.
+pub fn build_piece(
+    vox: &DotVoxData,
+    piece: u16,
+    rot: Mat3<i32>,
+    offset: Vec3<i32>,
+    markers: &[(String, Rgb<u8>)],
+) -> (SparseScene, Vec<Aabb<i32>>, PieceBounds) {
+    let (scene, aabbs, mut bounds) = SparseScene::new_from(
+        std::iter::once(SceneSource {
+            piece,
+            name: "test",
+            vox,
+            frame: 0,
+            skipped_layers: HashSet::new(),
+            root: 0,
+            pivot: Pivot::MinCorner,
+            rot,
+            offset,
+        }),
+        &[],
+        &[],
+        &[],
+        markers,
+        None,
+    )
+    .unwrap();
+    (scene, aabbs, bounds.pop().unwrap())
+}
@@ -0,0 +1,117 @@
+//! Writes blocks to terrain persistence in temporary directories and reads
+//! the chunk files back.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use map_placer::{
+    persistence::{terrain_chunk_key, PersistedChunk, Writer},
+    sink::BlockSink,
+};
+use vek::*;
+use veloren_common::terrain::{Block, BlockKind};
+
+/// An empty directory `name` in the temporary directory.
+fn empty_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A block persisted at the origin before anything is written.
+fn persisted_before(dir: &Path) -> Block {
+    let block = Block::new(BlockKind::Wood, Rgb::new(100, 70, 40));
+    let chunk = PersistedChunk {
+        blocks: HashMap::from([(Vec3::zero(), block)]),
+    };
+    chunk.save(dir, Vec2::zero()).unwrap();
+    block
+}
+
+#[test]
+fn batched_writes_match_writing_every_block() {
+    // A row of blocks through three terrain chunks, starting at the origin.
+    let blocks = (0..40)
+        .map(|i| {
+            let block = Block::new(BlockKind::Rock, Rgb::new(i as u8, 0, 0));
+            (Vec3::new(i * 2, i % 3, i / 4), block)
+        })
+        .collect::<Vec<_>>();
+    let write = |name: &str, batched: bool| {
+        let dir = empty_dir(name);
+        let before = persisted_before(&dir);
+        let mut writer = Writer::new(dir.clone(), true);
+        if batched {
+            let mut chunks = HashMap::<_, Vec<_>>::new();
+            for (wpos, block) in &blocks {
+                chunks.entry(terrain_chunk_key(*wpos)).or_default().push((*wpos, *block));
+            }
+            for (key, blocks) in &chunks {
+                writer.set_blocks_in_chunk(*key, blocks);
+            }
+        } else {
+            for (wpos, block) in &blocks {
+                writer.set_block(*wpos, *block);
+            }
+        }
+        writer.flush();
+        let mut prior = writer.into_undo().prior;
+        prior.sort_by_key(|(wpos, _)| (wpos.z, wpos.y, wpos.x));
+        assert_eq!(prior[0], (Vec3::zero(), Some(before)));
+        let chunks = (0..3)
+            .map(|x| PersistedChunk::load(&dir, Vec2::new(x, 0)).unwrap().unwrap().blocks)
+            .collect::<Vec<_>>();
+        (chunks, prior)
+    };
+    let batched = write("map_placer_test_batched", true);
+    assert_eq!(batched, write("map_placer_test_unbatched", false));
+    assert_eq!(batched.0.iter().map(HashMap::len).sum::<usize>(), blocks.len());
+}
+
+#[test]
+fn standalone_chunks_round_trip() {
+    let dir = empty_dir("map_placer_test_standalone");
+    let before = persisted_before(&dir);
+    let rock = Block::new(BlockKind::Rock, Rgb::new(90, 90, 90));
+    let mut writer = Writer::new(dir.clone(), true);
+    // Chunks with negative keys are relative to their own corner too.
+    writer.set_block(Vec3::new(-3, 40, -7), rock);
+    writer.set_block(Vec3::new(1, 2, 3), rock);
+    writer.flush();
+    assert!(PersistedChunk::path(&dir, Vec2::new(-1, 1)).ends_with("chunk_-1_1.dat"));
+    let chunk = PersistedChunk::load(&dir, Vec2::new(-1, 1)).unwrap().unwrap();
+    assert_eq!(chunk.blocks, HashMap::from([(Vec3::new(29, 8, -7), rock)]));
+    // The blocks persisted before are kept.
+    let chunk = PersistedChunk::load(&dir, Vec2::zero()).unwrap().unwrap();
+    let expected = HashMap::from([(Vec3::zero(), before), (Vec3::new(1, 2, 3), rock)]);
+    assert_eq!(chunk.blocks, expected);
+    assert!(PersistedChunk::load(&dir, Vec2::new(5, 5)).unwrap().is_none());
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn the_server_reads_standalone_chunks() {
+    let dir = empty_dir("map_placer_test_standalone_server");
+    // The directory of `TerrainPersistence` can only be set this way.
+    std::env::set_var("VELOREN_TERRAIN", &dir);
+    let before = persisted_before(&dir);
+    let rock = Block::new(BlockKind::Rock, Rgb::new(90, 90, 90));
+    let mut standalone = Writer::new(dir.clone(), true);
+    standalone.set_block(Vec3::new(1, 2, 3), rock);
+    standalone.flush();
+    // The server loads the chunk written without it before writing it again.
+    let mut server = Writer::new(dir.clone(), false);
+    server.set_block(Vec3::new(4, 5, 6), rock);
+    server.flush();
+    assert_eq!(server.into_undo().prior, vec![(Vec3::new(4, 5, 6), None)]);
+    let chunk = PersistedChunk::load(&dir, Vec2::zero()).unwrap().unwrap();
+    let expected = HashMap::from([
+        (Vec3::zero(), before),
+        (Vec3::new(1, 2, 3), rock),
+        (Vec3::new(4, 5, 6), rock),
+    ]);
+    assert_eq!(chunk.blocks, expected);
+}
@@ -0,0 +1,1133 @@
+mod common;
+
+use std::collections::{BTreeMap, HashMap};
+
+use map_placer::{
+    model::load_model_bytes,
+    persistence::{parse_allowed_chunks, Undo, World},
+    place::{
+        merge_pieces, place_into, points_of_interest, BuiltPiece, Context, ContextEntry,
+        MergedScene, PlaceOptions, Verbosity,
+    },
+    sink::ChunkFilter,
+    spec::{Merge, PlaceSpec, Validation},
+};
+use vek::*;
+use veloren_common::terrain::{Block, BlockKind};
+
+const STONE: Rgb<u8> = Rgb {
+    r: 128,
+    g: 128,
+    b: 128,
+};
+
+/// The path of `tests/fixtures/<name>`, for the pieces of specs.
+fn fixture(name: &str) -> String {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    path.to_string_lossy().into_owned()
+}
+
+/// A piece of a 4 by 4 by 4 model with a single voxel at its minimum corner,
+/// at `offset`.
+fn corner_piece(index: u16, offset: Vec3<i32>, fill_empty: bool) -> BuiltPiece {
+    let vox = common::vox_model([4, 4, 4], &[([0, 0, 0], 0)], &[STONE]);
+    let (scene, aabbs, bounds) = common::build_piece(&vox, index, Mat3::identity(), offset, &[]);
+    BuiltPiece {
+        index,
+        scene,
+        aabbs,
+        bounds: Some(bounds),
+        local_aabb: None,
+        merge: Merge::Overwrite,
+        priority: 0,
+        rot: Mat3::identity(),
+        offset,
+        decayed: 0,
+        fill_empty,
+        fill_footprint: None,
+        cutouts: Vec::new(),
+        skirt: Vec::new(),
+    }
+}
+
+/// A piece of a 4 by 4 by 4 model full of stone.
+fn full_piece() -> BuiltPiece {
+    let voxels = (0..64).map(|i| ([i % 4, i / 4 % 4, i / 16], 0)).collect::<Vec<_>>();
+    let vox = common::vox_model([4, 4, 4], &voxels, &[STONE]);
+    let (scene, aabbs, bounds) = common::build_piece(&vox, 0, Mat3::identity(), Vec3::zero(), &[]);
+    BuiltPiece {
+        index: 0,
+        scene,
+        aabbs,
+        bounds: Some(bounds),
+        local_aabb: None,
+        merge: Merge::Overwrite,
+        priority: 0,
+        rot: Mat3::identity(),
+        offset: Vec3::zero(),
+        decayed: 0,
+        fill_empty: false,
+        fill_footprint: None,
+        cutouts: Vec::new(),
+        skirt: Vec::new(),
+    }
+}
+
+fn place(spec: &str, pieces: &[BuiltPiece]) -> HashMap<Vec3<i32>, Block> {
+    let place_spec: PlaceSpec = ron::from_str(spec).unwrap();
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    let mut blocks = HashMap::new();
+    let options = PlaceOptions {
+        verbosity: Verbosity::Quiet,
+        ..PlaceOptions::default()
+    };
+    place_into(&place_spec, &merge_pieces(pieces.iter()), &mut world, &mut blocks, options);
+    blocks
+}
+
+/// Places two overlapping corner pieces, the first at the origin and the
+/// second two blocks further along x.
+fn place_overlapping(spec: &str, fill: [bool; 2]) -> HashMap<Vec3<i32>, Block> {
+    place(
+        spec,
+        &[
+            corner_piece(0, Vec3::zero(), fill[0]),
+            corner_piece(1, Vec3::new(2, 0, 0), fill[1]),
+        ],
+    )
+}
+
+const TWO_PIECES: &str = r#"(
+    pieces: [("a.vox", (0, 0, 0)), ("b.vox", (2, 0, 0))],
+    replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+)"#;
+
+/// Builds the pieces of `spec` on the terrain of `world`.
+fn build_pieces(spec: &str, world: &mut World) -> Vec<BuiltPiece> {
+    let place_spec: PlaceSpec = ron::from_str(spec).unwrap();
+    let mut validation = Validation::default();
+    let pieces =
+        place_spec.build_pieces(Mat3::identity(), Vec3::zero(), world, Some(0), &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    pieces
+}
+
+/// Builds the pieces of `spec` without any terrain and places them.
+fn build_and_place(spec: &str) -> HashMap<Vec3<i32>, Block> {
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    place(spec, &build_pieces(spec, &mut world))
+}
+
+/// A world without persisted terrain, with a rock surface in every column
+/// of `area` at the height `height` gives it, covered by water where it says
+/// so.
+fn heightfield(area: Aabr<i32>, height: impl Fn(Vec2<i32>) -> (i32, bool)) -> World {
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    let rock = Block::new(BlockKind::Rock, STONE);
+    let water = Block::new(BlockKind::Water, Rgb::zero());
+    for x in area.min.x..=area.max.x {
+        for y in area.min.y..=area.max.y {
+            let pos = Vec2::new(x, y);
+            let (z, flooded) = height(pos);
+            world.written.insert(pos.with_z(z), rock);
+            if flooded {
+                world.written.insert(pos.with_z(z + 1), water);
+            }
+        }
+    }
+    world
+}
+
+/// Flat at a height of 0 up to x 20, rising a block with every column after
+/// it, and flooded from y 25.
+fn hillside(pos: Vec2<i32>) -> (i32, bool) { ((pos.x - 20).max(0), pos.y >= 25) }
+
+const HILLSIDE_AREA: Aabr<i32> = Aabr {
+    min: Vec2 { x: -10, y: -10 },
+    max: Vec2 { x: 60, y: 40 },
+};
+
+/// Loads a 3 by 2 by 1 model as `flat.vox`.
+fn load_flat_model() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/asymmetric.vox");
+    let bytes = std::fs::read(path).unwrap();
+    load_model_bytes("flat.vox", &bytes).unwrap();
+}
+
+/// Builds the single piece of `spec` on the hillside.
+fn build_on_hillside(spec: &str) -> BuiltPiece {
+    let mut world = heightfield(HILLSIDE_AREA, hillside);
+    let mut pieces = build_pieces(spec, &mut world);
+    assert_eq!(pieces.len(), 1);
+    pieces.pop().unwrap()
+}
+
+/// The positions of the cells of `piece`, from the bottom up.
+fn cell_positions(piece: &BuiltPiece) -> Vec<Vec3<i32>> {
+    let mut cells = piece.scene.cells().map(|(wpos, _, _)| wpos).collect::<Vec<_>>();
+    cells.sort_by_key(|wpos| (wpos.z, wpos.y, wpos.x));
+    cells
+}
+
+#[test]
+fn only_pieces_that_fill_are_filled() {
+    let blocks = place_overlapping(TWO_PIECES, [true, false]);
+    // Every position of the first piece is written, including the ones
+    // shared with the second piece, but none of the second piece alone.
+    assert_eq!(blocks.len(), 4 * 4 * 4);
+    assert!(blocks.keys().all(|wpos| wpos.x < 4));
+    assert_eq!(blocks[&Vec3::zero()].kind(), BlockKind::Rock);
+    assert_eq!(blocks[&Vec3::new(2, 0, 0)].kind(), BlockKind::Rock);
+    assert_eq!(blocks[&Vec3::new(3, 3, 3)].kind(), BlockKind::Air);
+}
+
+#[test]
+fn pieces_can_opt_out_of_the_fill_of_the_spec() {
+    // The flags of the built pieces already have the overrides applied.
+    let blocks = place_overlapping(TWO_PIECES, [false, true]);
+    assert_eq!(blocks.len(), 4 * 4 * 4 + 1);
+    assert!(blocks.keys().all(|wpos| wpos.x >= 2 || *wpos == Vec3::zero()));
+    assert_eq!(blocks[&Vec3::new(5, 3, 3)].kind(), BlockKind::Air);
+}
+
+#[test]
+fn writes_belong_to_the_last_piece_covering_them() {
+    let place_spec: PlaceSpec = ron::from_str(TWO_PIECES).unwrap();
+    let pieces = [
+        corner_piece(0, Vec3::zero(), true),
+        corner_piece(1, Vec3::new(2, 0, 0), true),
+    ];
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    let mut blocks = HashMap::new();
+    let options = PlaceOptions {
+        verbosity: Verbosity::Quiet,
+        ..PlaceOptions::default()
+    };
+    let report =
+        place_into(&place_spec, &merge_pieces(pieces.iter()), &mut world, &mut blocks, options);
+    let counts = report
+        .writes
+        .iter()
+        .map(|(piece, positions)| (piece.as_str(), positions.len()))
+        .collect::<Vec<_>>();
+    // The voxel of the first piece is its own, the filled overlap belongs to
+    // the second piece.
+    assert_eq!(counts, vec![("a.vox", 2 * 4 * 4), ("b.vox", 4 * 4 * 4)]);
+    assert!(report.writes[0].1.contains(&Vec3::zero()));
+    let mut undo = Undo {
+        prior: blocks.keys().map(|wpos| (*wpos, None)).collect(),
+        ..Undo::default()
+    };
+    undo.attribute(&report.writes);
+    assert_eq!(undo.pieces.len(), 2);
+    assert!(undo.pieces.iter().all(|piece| piece.overwritten.is_empty()));
+}
+
+#[test]
+fn fill_block_replaces_air() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0)), ("b.vox", (2, 0, 0))],
+        fill_block: Some(Water),
+    )"#;
+    let blocks = place_overlapping(spec, [true, false]);
+    assert_eq!(blocks[&Vec3::new(3, 3, 3)].kind(), BlockKind::Water);
+    assert_eq!(blocks[&Vec3::zero()].kind(), BlockKind::Misc);
+}
+
+#[test]
+fn bounds_without_cells_are_filled() {
+    // A long model with a single voxel only needs the chunk of that voxel,
+    // the rest of its bounds is filled all the same.
+    let vox = common::vox_model([100, 1, 1], &[([0, 0, 0], 0)], &[STONE]);
+    let (scene, aabbs, bounds) = common::build_piece(&vox, 0, Mat3::identity(), Vec3::zero(), &[]);
+    assert_eq!(scene.iter().count(), 1);
+    let piece = BuiltPiece {
+        index: 0,
+        scene,
+        aabbs,
+        bounds: Some(bounds),
+        local_aabb: None,
+        merge: Merge::Overwrite,
+        priority: 0,
+        rot: Mat3::identity(),
+        offset: Vec3::zero(),
+        decayed: 0,
+        fill_empty: true,
+        fill_footprint: None,
+        cutouts: Vec::new(),
+        skirt: Vec::new(),
+    };
+    let blocks = place(r#"(pieces: [("a.vox", (0, 0, 0))])"#, &[piece]);
+    assert_eq!(blocks.len(), 100);
+    assert_eq!(blocks[&Vec3::new(99, 0, 0)].kind(), BlockKind::Air);
+}
+
+#[test]
+fn pieces_are_mirrored_before_they_are_turned() {
+    // Three voxels of different colors, two of them in a row.
+    let path = fixture("asymmetric.vox");
+    let cells = |options: &str| {
+        let spec = format!(r#"(pieces: [({:?}, (0, 0, 0), {})])"#, path, options);
+        let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+        let pieces = build_pieces(&spec, &mut world);
+        let mut cells = pieces[0]
+            .scene
+            .cells()
+            .map(|(wpos, cell, _)| (wpos, cell.get_color().unwrap()))
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|(wpos, _)| (wpos.z, wpos.y, wpos.x));
+        (cells, pieces[0].aabbs.clone())
+    };
+    let (turned, turned_aabbs) = cells("(rotation: 90, pivot: MinCorner)");
+    let (mirrored, aabbs) =
+        cells("(rotation: 90, mirror: (true, false, false), pivot: MinCorner)");
+    // Flipping x before a quarter turn flips y after it, within the same
+    // bounds.
+    assert_eq!(aabbs, turned_aabbs);
+    let mut reflected = turned
+        .iter()
+        .map(|(wpos, color)| (Vec3::new(wpos.x, 2 - wpos.y, wpos.z), *color))
+        .collect::<Vec<_>>();
+    reflected.sort_by_key(|(wpos, _)| (wpos.z, wpos.y, wpos.x));
+    assert_eq!(mirrored, reflected);
+    assert_eq!(mirrored[0], (Vec3::zero(), Rgb::new(0, 0, 255)));
+}
+
+#[test]
+fn fill_covers_bounds_across_scene_chunks() {
+    // The bounds span x 31 to 33 and y 31 to 32, across four scene chunks.
+    let spec = format!(
+        r#"(pieces: [({:?}, (32, 32, 32))], fill_empty: true)"#,
+        fixture("asymmetric.vox")
+    );
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    let pieces = build_pieces(&spec, &mut world);
+    let bounds = Aabb {
+        min: Vec3::new(31, 31, 32),
+        max: Vec3::new(33, 32, 32),
+    };
+    assert_eq!(pieces[0].aabbs, vec![bounds]);
+    // Nothing is persisted there, so the world is all air.
+    let blocks = place(&spec, &pieces);
+    // Every cell within the bounds is written, the empty ones as air.
+    assert_eq!(blocks.len(), 3 * 2);
+    assert!(blocks.keys().all(|wpos| bounds.contains_point(*wpos)));
+    let filled = blocks.values().filter(|block| **block == Block::empty()).count();
+    assert_eq!(filled, 3);
+}
+
+#[test]
+fn the_lowest_voxel_of_a_model_rests_on_the_surface() {
+    // A staircase of single voxels, every column starting a block higher.
+    let path = fixture("steps.vox");
+    let spec = |options: &str| format!(r#"(pieces: [({:?}, (0, 0, 20), {})])"#, path, options);
+    // The whole model moves, so only its lowest column reaches the ground.
+    let snapped = build_on_hillside(&spec("(snap_to_surface: true, pivot: MinCorner)"));
+    let steps = (0..3).map(|i| Vec3::new(i, 0, i + 1)).collect::<Vec<_>>();
+    assert_eq!(cell_positions(&snapped), steps);
+    assert_eq!(snapped.aabbs[0].min.z, 1);
+    // Conforming moves every column down to the ground on its own.
+    let conformed = build_on_hillside(&spec("(conform: true, pivot: MinCorner)"));
+    let flat = (0..3).map(|i| Vec3::new(i, 0, 1)).collect::<Vec<_>>();
+    assert_eq!(cell_positions(&conformed), flat);
+}
+
+#[test]
+fn conformed_columns_follow_the_slope() {
+    // The model spans x 20 to 22, where the hillside starts to rise.
+    let path = fixture("asymmetric.vox");
+    let spec = |options: &str| format!(r#"(pieces: [({:?}, (20, 0, 20), {})])"#, path, options);
+    let piece = build_on_hillside(&spec("(conform: true, pivot: MinCorner)"));
+    let columns = vec![Vec3::new(20, 0, 1), Vec3::new(21, 0, 2), Vec3::new(22, 1, 3)];
+    assert_eq!(cell_positions(&piece), columns);
+    let union = Aabb {
+        min: Vec3::new(20, 0, 1),
+        max: Vec3::new(22, 1, 3),
+    };
+    assert_eq!(piece.aabbs, vec![union]);
+    assert_eq!(piece.bounds.as_ref().unwrap().aabb, union);
+    // The first two columns shift a block apart, the last one has no
+    // neighbouring columns.
+    let piece = build_on_hillside(&spec("(conform: true, max_slope: Some(0), pivot: MinCorner)"));
+    assert_eq!(cell_positions(&piece), vec![Vec3::new(22, 1, 3)]);
+    let column = Aabb {
+        min: Vec3::new(22, 1, 3),
+        max: Vec3::new(22, 1, 3),
+    };
+    assert_eq!(piece.aabbs, vec![column]);
+    assert_eq!(piece.bounds.as_ref().unwrap().aabb, column);
+}
+
+#[test]
+fn the_interior_of_a_hollow_cube_is_carved() {
+    // A shell of stone around a 3 by 3 by 3 hollow.
+    let path = fixture("hollow_cube.vox");
+    let place_cube = |carve: &str| {
+        let spec = format!(
+            r#"(
+                pieces: [({:?}, (0, 0, 0), (pivot: MinCorner))],
+                replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+                {}
+            )"#,
+            path, carve
+        );
+        build_and_place(&spec)
+    };
+    let hollow = |wpos: &Vec3<i32>| wpos.map(|e| (1..=3).contains(&e)).reduce_and();
+    // Without carving, the hollow is left alone like any empty cell.
+    let blocks = place_cube("");
+    assert_eq!(blocks.len(), 98);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Rock));
+    let blocks = place_cube("carve_interior: true");
+    assert_eq!(blocks.len(), 5 * 5 * 5);
+    for (wpos, block) in &blocks {
+        let expected = if hollow(wpos) {
+            BlockKind::Air
+        } else {
+            BlockKind::Rock
+        };
+        assert_eq!(block.kind(), expected, "at {}", wpos);
+    }
+    // The shell is a single cell thick.
+    let blocks = place_cube("carve_interior: true, carve_depth: Some(0)");
+    assert_eq!(blocks.len(), 5 * 5 * 5);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Air));
+}
+
+#[test]
+fn translations_beyond_the_world_fail_validation() {
+    // Two nested translations of 2000000000 along x, the inner one named far.
+    let spec = format!(r#"(pieces: [({:?}, (0, 0, 0))])"#, fixture("odd_transform.vox"));
+    let spec: PlaceSpec = ron::from_str(&spec).unwrap();
+    // Approximating rotations doesn't let them through.
+    for approximate_rotations in [false, true] {
+        let mut validation = Validation::default();
+        spec.validate("test.ron", false, approximate_rotations, &mut validation);
+        assert_eq!(validation.errors.len(), 1, "{:?}", validation.errors);
+        let error = &validation.errors[0];
+        assert!(error.contains("node \"far\"") && error.contains("more than fits"), "{}", error);
+    }
+}
+
+#[test]
+fn overlapping_cubes_merge_by_the_strategy_of_the_later_one() {
+    // A full cube, then a cube with only a voxel at its minimum corner, which
+    // overlaps the last two layers of the first one along x.
+    let merged = |merge: Merge| {
+        let later = BuiltPiece {
+            merge,
+            ..corner_piece(1, Vec3::new(2, 0, 0), false)
+        };
+        let merged = merge_pieces([full_piece(), later].iter());
+        let mut counts = BTreeMap::<u16, usize>::new();
+        for (_, _, origin) in merged.scene.cells() {
+            *counts.entry(origin.piece).or_default() += 1;
+        }
+        (counts, merged)
+    };
+    let piece_at = |merged: &MergedScene, wpos| {
+        merged.scene.cells().find(|(cell_pos, _, _)| *cell_pos == wpos).map(|(_, _, o)| o.piece)
+    };
+    let corner = Vec3::new(2, 0, 0);
+    let (counts, overwritten) = merged(Merge::Overwrite);
+    assert_eq!(counts, BTreeMap::from([(0, 63), (1, 1)]));
+    assert_eq!(piece_at(&overwritten, corner), Some(1));
+    let (counts, underwritten) = merged(Merge::Underwrite);
+    assert_eq!(counts, BTreeMap::from([(0, 64)]));
+    assert_eq!(piece_at(&underwritten, corner), Some(0));
+    // The bounds of the later cube are emptied first, whether it has voxels
+    // there or not.
+    let (counts, cut_out) = merged(Merge::CutOut);
+    assert_eq!(counts, BTreeMap::from([(0, 2 * 4 * 4), (1, 1)]));
+    assert_eq!(piece_at(&cut_out, corner), Some(1));
+    assert_eq!(piece_at(&cut_out, Vec3::new(3, 3, 3)), None);
+    assert_eq!(piece_at(&cut_out, Vec3::new(1, 3, 3)), Some(0));
+}
+
+#[test]
+fn translucent_colors_are_placed_as_water() {
+    // A basin of stone around a translucent voxel, under a transparent one.
+    let spec = format!(
+        r#"(
+            pieces: [({:?}, (0, 0, 0), (pivot: MinCorner))],
+            replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+            water_alpha_threshold: Some(200),
+        )"#,
+        fixture("pond.vox")
+    );
+    let pond = Vec3::new(1, 1, 1);
+    let blocks = build_and_place(&spec);
+    // Transparent voxels aren't placed at all.
+    assert_eq!(blocks.len(), 18);
+    assert!(!blocks.contains_key(&Vec3::new(1, 1, 2)));
+    assert_eq!(blocks[&pond].kind(), BlockKind::Water);
+    let rock = blocks.values().filter(|block| block.kind() == BlockKind::Rock);
+    assert_eq!(rock.count(), 17);
+    // Colors with more alpha than the threshold are placed like any other.
+    let blocks = build_and_place(&spec.replace("Some(200)", "Some(100)"));
+    assert_eq!(blocks[&pond].kind(), BlockKind::Misc);
+    assert_eq!(blocks[&pond].get_color(), Some(Rgb::new(40, 90, 200)));
+    // Replacements take precedence over the alpha.
+    let replaced = spec.replace(
+        "replace: [",
+        "replace: [((40, 90, 200), Block(kind: Wood, color: (40, 90, 200))), ",
+    );
+    let blocks = build_and_place(&replaced);
+    assert_eq!(blocks[&pond].kind(), BlockKind::Wood);
+    assert!(!blocks.contains_key(&Vec3::new(1, 1, 2)));
+}
+
+#[test]
+fn materials_pick_the_blocks_of_their_voxels() {
+    // A stone voxel, one of a glass material and one of an emissive material.
+    let spec = format!(
+        r#"(
+            pieces: [({:?}, (0, 0, 0), (pivot: MinCorner))],
+            use_materials: true,
+        )"#,
+        fixture("materials.vox")
+    );
+    let kinds = |spec: &str| {
+        let blocks = build_and_place(spec);
+        (0..3).map(|x| blocks[&Vec3::new(x, 0, 0)].kind()).collect::<Vec<_>>()
+    };
+    let blocks = build_and_place(&spec);
+    assert_eq!(kinds(&spec), vec![BlockKind::Misc, BlockKind::Water, BlockKind::GlowingRock]);
+    assert_eq!(blocks[&Vec3::new(2, 0, 0)].get_color(), Some(Rgb::new(255, 200, 0)));
+    // Without materials only emissive voxels stand out.
+    let ignored = spec.replace("use_materials: true", "use_materials: false");
+    assert_eq!(kinds(&ignored), vec![BlockKind::Misc, BlockKind::Misc, BlockKind::GlowingRock]);
+    let overridden = spec.replace(
+        "use_materials: true,",
+        r#"use_materials: true,
+            materials: [("glass", Block(kind: Wood, color: (100, 70, 40))), ("emit", Hollow)],"#,
+    );
+    assert_eq!(kinds(&overridden), vec![BlockKind::Misc, BlockKind::Wood, BlockKind::Air]);
+}
+
+#[test]
+fn sprites_are_placed_by_chance() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0))],
+        replace: [((128, 128, 128), Sprite(kind: ShortGrass, chance: 0.5))],
+        seed: Some(3),
+    )"#;
+    // The other voxels keep the world as it is.
+    let blocks = place(spec, &[full_piece()]);
+    assert!(!blocks.is_empty() && blocks.len() < 64);
+    assert!(blocks.values().all(|block| block.get_sprite().is_some()));
+    assert_eq!(place(spec, &[full_piece()]), blocks);
+
+    let spec = spec.replace("chance: 0.5", "chance: 0.5, fallback: Some(Water)");
+    let with_fallback = place(&spec, &[full_piece()]);
+    assert_eq!(with_fallback.len(), 64);
+    let water = with_fallback.values().filter(|block| block.kind() == BlockKind::Water);
+    assert_eq!(water.count(), 64 - blocks.len());
+}
+
+/// A wall of stone four blocks wide, one deep and three high, with its bottom
+/// at `z`.
+fn wall_piece(z: i32) -> BuiltPiece {
+    let voxels = (0..12).map(|i| ([i % 4, 0, i / 4], 0)).collect::<Vec<_>>();
+    let vox = common::vox_model([4, 1, 3], &voxels, &[STONE]);
+    let offset = Vec3::new(0, 0, z);
+    let (scene, aabbs, bounds) = common::build_piece(&vox, 0, Mat3::identity(), offset, &[]);
+    BuiltPiece {
+        index: 0,
+        scene,
+        aabbs,
+        bounds: Some(bounds),
+        local_aabb: None,
+        merge: Merge::Overwrite,
+        priority: 0,
+        rot: Mat3::identity(),
+        offset,
+        decayed: 0,
+        fill_empty: false,
+        fill_footprint: None,
+        cutouts: Vec::new(),
+        skirt: Vec::new(),
+    }
+}
+
+const CAPPED_WALL: &str = r#"(
+    pieces: [("wall.vox", (0, 0, 0))],
+    replace: [((128, 128, 128), Neighbor(
+        dir: Up,
+        is: Empty,
+        then: Block(kind: Wood, color: (100, 70, 40)),
+        else: Block(kind: Rock, color: (128, 128, 128)),
+    ))],
+)"#;
+
+#[test]
+fn only_the_top_of_a_wall_is_exposed_upwards() {
+    let blocks = place(CAPPED_WALL, &[wall_piece(0)]);
+    assert_eq!(blocks.len(), 12);
+    for (wpos, block) in &blocks {
+        let expected = if wpos.z == 2 {
+            BlockKind::Wood
+        } else {
+            BlockKind::Rock
+        };
+        assert_eq!(block.kind(), expected, "at {}", wpos);
+    }
+}
+
+#[test]
+fn neighbors_in_missing_chunks_are_empty() {
+    // The top row is at the top of its scene chunk, the chunk above it has no
+    // cells.
+    let blocks = place(CAPPED_WALL, &[wall_piece(29)]);
+    let wood = blocks.iter().filter(|(_, block)| block.kind() == BlockKind::Wood);
+    assert!(wood.map(|(wpos, _)| wpos.z).all(|z| z == 31));
+    assert_eq!(blocks.values().filter(|block| block.kind() == BlockKind::Wood).count(), 4);
+}
+
+#[test]
+fn neighbor_conditions_nest() {
+    // Wood on top, glowing rock at the ends of the middle row below it.
+    let spec = r#"(
+        pieces: [("wall.vox", (0, 0, 0))],
+        replace: [((128, 128, 128), Neighbor(
+            dir: Up,
+            is: Empty,
+            then: Block(kind: Wood, color: (100, 70, 40)),
+            else: Neighbor(
+                dir: Down,
+                is: Solid,
+                then: Neighbor(
+                    dir: East,
+                    is: Color((128, 128, 128)),
+                    then: Neighbor(
+                        dir: West,
+                        is: Solid,
+                        then: Block(kind: Rock, color: (128, 128, 128)),
+                        else: Glowy,
+                    ),
+                    else: Glowy,
+                ),
+                else: Block(kind: Rock, color: (128, 128, 128)),
+            ),
+        ))],
+    )"#;
+    let blocks = place(spec, &[wall_piece(0)]);
+    let glowing = |wpos: Vec3<i32>| blocks[&wpos].kind() == BlockKind::GlowingRock;
+    assert!(glowing(Vec3::new(0, 0, 1)) && glowing(Vec3::new(3, 0, 1)));
+    assert!(!glowing(Vec3::new(1, 0, 1)) && !glowing(Vec3::new(2, 0, 1)));
+    assert!((0..4).all(|x| blocks[&Vec3::new(x, 0, 2)].kind() == BlockKind::Wood));
+    assert!((0..4).all(|x| blocks[&Vec3::new(x, 0, 0)].kind() == BlockKind::Rock));
+}
+
+#[test]
+fn cutouts_are_placed_as_their_block() {
+    // A doorway reaching past the top of the wall, which is clipped.
+    let mut wall = wall_piece(0);
+    let doorway = Aabb {
+        min: Vec3::new(1, 0, 0),
+        max: Vec3::new(2, 0, 5),
+    };
+    let bounds = wall.bounds.as_ref().unwrap().aabb;
+    wall.cutouts = wall.scene.cut_out(&[doorway], wall.rot, bounds);
+    assert_eq!(wall.cutouts[0].max, Vec3::new(2, 0, 2));
+    let spec = r#"(
+        pieces: [("wall.vox", (0, 0, 0), (cutout_block: Some(Hollow)))],
+        replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+    )"#;
+    let blocks = place(spec, &[wall.clone()]);
+    assert_eq!(blocks.len(), 12);
+    for (wpos, block) in &blocks {
+        let expected = if (1..=2).contains(&wpos.x) {
+            BlockKind::Air
+        } else {
+            BlockKind::Rock
+        };
+        assert_eq!(block.kind(), expected, "at {}", wpos);
+    }
+    // Without a block, cutouts are empty cells, which aren't filled here.
+    let spec = spec.replace("cutout_block: Some(Hollow)", "fill_empty: Some(false)");
+    let blocks = place(&spec, &[wall]);
+    assert_eq!(blocks.len(), 6);
+}
+
+#[test]
+fn color_transforms_change_placed_colors() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0))],
+        color_transform: Gamma(2.0),
+    )"#;
+    let dark = Rgb::new(64, 64, 64);
+    let blocks = place(spec, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.get_color() == Some(dark)));
+    // Replacements are looked up by the painted color, unless told otherwise.
+    let replaced = spec.replace(
+        "color_transform",
+        "replace: [((128, 128, 128), Block(kind: Rock, keep_color: true))], color_transform",
+    );
+    let blocks = place(&replaced, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Rock));
+    assert!(blocks.values().all(|block| block.get_color() == Some(dark)));
+    let replaced = replaced.replace("((128, 128, 128)", "((64, 64, 64)");
+    let blocks = place(&replaced, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Misc));
+    let before_lookup = replaced.replace("Gamma(2.0)", "Gamma(2.0), transform_before_lookup: true");
+    let blocks = place(&before_lookup, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Rock));
+}
+
+#[test]
+fn color_ranges_keep_or_adjust_the_color_of_voxels() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0))],
+        replace_ranges: [
+            ((min: (100, 100, 100), max: (150, 150, 150)), Block(kind: Wood, color: Inherit)),
+        ],
+    )"#;
+    let blocks = place(spec, &[full_piece()]);
+    assert_eq!(blocks.len(), 64);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Wood));
+    assert!(blocks.values().all(|block| block.get_color() == Some(STONE)));
+    // Channels are clamped once tinted.
+    let tinted = spec.replace("Inherit", "Tint(add: (127, 0, -100))");
+    let blocks = place(&tinted, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.get_color() == Some(Rgb::new(255, 128, 28))));
+    // Replaced colors take precedence over ranges.
+    let replaced = spec.replace(
+        "replace_ranges",
+        "replace: [((128, 128, 128), Block(kind: Rock, color: Multiply(rgb: (0.5, 2.5, 1.0))))],
+        replace_ranges",
+    );
+    let blocks = place(&replaced, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Rock));
+    assert!(blocks.values().all(|block| block.get_color() == Some(Rgb::new(64, 255, 128))));
+}
+
+const GROUPS: &str = r#"(
+    pieces: [("a.vox", (2, 0, 0), (name: Some("a"))), ("b.vox", (0, 3, 0))],
+    groups: [
+        (name: "inner", offset: (1, 0, 0), rotation: 90, members: ["a"]),
+        (name: "outer", offset: (10, 0, 0), rotation: 90, members: ["inner", "b.vox"]),
+    ],
+)"#;
+
+#[test]
+fn groups_move_and_turn_their_members() {
+    let spec: PlaceSpec = ron::from_str(GROUPS).unwrap();
+    let mut validation = Validation::default();
+    let spec = spec.expanded("test.ron", Some(0), &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    // Turning the outer group also turns the offset of the inner one.
+    assert_eq!(spec.pieces[0].offset, [8, 1, 0]);
+    assert_eq!(spec.pieces[1].offset, [7, 0, 0]);
+    let group = |i: usize| spec.pieces[i].options.group.as_ref().unwrap().name.clone();
+    assert_eq!((group(0), group(1)), ("inner".to_string(), "outer".to_string()));
+}
+
+#[test]
+fn groups_are_nested_one_level_at_most() {
+    let spec = GROUPS.replace(
+        "    ],\n)",
+        "        (name: \"outermost\", members: [\"outer\"]),\n    ],\n)",
+    );
+    let spec: PlaceSpec = ron::from_str(&spec).unwrap();
+    let mut validation = Validation::default();
+    spec.expanded("test.ron", Some(0), &mut validation);
+    assert_eq!(validation.errors.len(), 1);
+    assert!(validation.errors[0].contains("one level deep"));
+}
+
+#[test]
+fn included_replacements_are_overridden_by_later_ones() {
+    let spec = |ron: &str| ron::from_str::<PlaceSpec>(ron).unwrap();
+    let base = spec(
+        r#"(
+            pieces: [],
+            replace: [((1, 1, 1), Hollow), ((2, 2, 2), Hollow)],
+            markers: [("door", (9, 9, 9))],
+        )"#,
+    );
+    let colors = spec(
+        r#"(pieces: [], replace: [((2, 2, 2), Water)], default_block: Some(Hollow))"#,
+    );
+    let own = spec(r#"(pieces: [("a.vox", (0, 0, 0))], replace: [((1, 1, 1), Hollow)])"#);
+    let merged = own.with_includes(
+        "own.ron",
+        vec![
+            ("base.ron".to_string(), "base.ron".to_string(), base),
+            ("colors.ron".to_string(), "colors.ron".to_string(), colors),
+        ],
+    );
+    let mut replace = merged.replace.iter().map(|(color, _)| *color).collect::<Vec<_>>();
+    replace.sort();
+    assert_eq!(replace, vec![[1, 1, 1], [2, 2, 2]]);
+    assert_eq!(merged.markers.len(), 1);
+    assert!(merged.default_block.is_some());
+    // Pieces of included specs are left out without include_pieces.
+    assert_eq!(merged.pieces.len(), 1);
+    // Only the color replaced with a different block is reported.
+    assert_eq!(
+        merged.provenance.overridden,
+        vec![([2, 2, 2], "base.ron".to_string(), "colors.ron".to_string())]
+    );
+    assert_eq!(merged.provenance.colors[&[2, 2, 2]], "colors.ron");
+    assert!(!merged.provenance.colors.contains_key(&[1, 1, 1]));
+}
+
+#[test]
+fn offsets_and_repeats_are_evaluated_with_the_vars() {
+    let spec = r#"(
+        vars: {"grid": 24, "base_z": 5},
+        pieces: [
+            ("a.vox", ["$grid * 2", 0, "$base_z - 1"], (
+                repeat: Some((count: "$grid - 7", stride: ["-($grid + 1)", 0, 0])),
+            )),
+            ("b.vox", (1, 2, 3)),
+        ],
+    )"#;
+    let mut place_spec = ron::from_str::<PlaceSpec>(spec).unwrap();
+    // Overridden like with --set.
+    place_spec.evaluate_vars(&HashMap::from([("grid".to_string(), 10)])).unwrap();
+    assert_eq!(place_spec.pieces[0].offset, [20, 0, 4]);
+    let repeat = place_spec.pieces[0].options.repeat.as_ref().unwrap();
+    assert_eq!((repeat.count, repeat.stride), (3, [-11, 0, 0]));
+    assert_eq!(place_spec.pieces[1].offset, [1, 2, 3]);
+
+    let mut place_spec =
+        ron::from_str::<PlaceSpec>(r#"(pieces: [("a.vox", ("$street", 0, 0))])"#).unwrap();
+    let error = place_spec.evaluate_vars(&HashMap::new()).unwrap_err();
+    assert!(error.contains("offset of piece a.vox") && error.contains("$street"), "{}", error);
+}
+
+#[test]
+fn missing_pieces_are_substituted() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/asymmetric.vox");
+    let bytes = std::fs::read(path).unwrap();
+    load_model_bytes("stand_in.vox", &bytes).unwrap();
+    let spec: PlaceSpec = ron::from_str(
+        r#"(
+            pieces: [("gone.vox", (0, 0, 0)), ("gone.vox", (4, 0, 0))],
+            missing_piece: Some("stand_in.vox"),
+        )"#,
+    )
+    .unwrap();
+    let mut validation = Validation::default();
+    let expanded = spec.expanded("test.ron", Some(0), &mut validation);
+    expanded.validate("test.ron", false, false, &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    assert_eq!(expanded.substituted, vec![("gone.vox".to_string(), "stand_in.vox".to_string())]);
+    let warnings = validation.warnings.iter().filter(|w| w.contains("placed instead"));
+    assert_eq!(warnings.count(), 1);
+    // The substituted pieces keep the name of their model.
+    assert_eq!(expanded.pieces[1].specifier, "stand_in.vox");
+    assert_eq!(expanded.pieces[1].options.name.as_deref(), Some("gone.vox"));
+
+    // Without a missing piece, missing models fail validation.
+    let spec = PlaceSpec {
+        missing_piece: None,
+        ..spec
+    };
+    let mut validation = Validation::default();
+    let expanded = spec.expanded("test.ron", Some(0), &mut validation);
+    expanded.validate("test.ron", false, false, &mut validation);
+    assert_eq!(validation.errors.len(), 2);
+    assert!(validation.errors[0].contains("missing_piece"));
+}
+
+#[test]
+fn overlays_cover_exposed_faces_within_the_pieces() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0))],
+        replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+        overlay: Some((block: Block(kind: Snow, color: (255, 255, 255)))),
+    )"#;
+    let blocks = place(spec, &[corner_piece(0, Vec3::zero(), false)]);
+    assert_eq!(blocks[&Vec3::new(0, 0, 1)].kind(), BlockKind::Snow);
+    assert_eq!(blocks.len(), 2);
+    // The top of a full piece is at the top of its bounds.
+    let blocks = place(spec, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::Rock));
+}
+
+#[test]
+fn pieces_on_slopes_or_under_water_are_left_out() {
+    load_flat_model();
+    let options = "(snap_to_surface: true, require_flat: Some((max_height_diff: 1, \
+                   footprint_margin: 1)))";
+    let spec = format!(
+        r#"(pieces: [
+            ("flat.vox", (0, 0, 20), {options}),
+            ("flat.vox", (40, 0, 20), {options}),
+            ("flat.vox", (0, 30, 20), {options}),
+            ("flat.vox", (40, 0, 20)),
+        ])"#,
+        options = options
+    );
+    let place_spec: PlaceSpec = ron::from_str(&spec).unwrap();
+    let mut world = heightfield(HILLSIDE_AREA, hillside);
+    let mut validation = Validation::default();
+    let pieces = place_spec.build_pieces(
+        Mat3::identity(),
+        Vec3::zero(),
+        &mut world,
+        Some(0),
+        &mut validation,
+    );
+    // The pieces on the slope and in the lake are skipped, the one without
+    // require_flat is placed anyway.
+    assert_eq!(pieces.iter().map(|piece| piece.index).collect::<Vec<_>>(), vec![0, 3]);
+    assert_eq!(pieces[0].aabbs[0].min.z, 1);
+
+    // Flat ground below the water level of the spec is under water too.
+    let spec = r#"(
+        pieces: [("flat.vox", (0, 0, 20), (require_flat: Some((max_height_diff: 0))))],
+        water_level: Some(4),
+    )"#;
+    let place_spec: PlaceSpec = ron::from_str(spec).unwrap();
+    let pieces = place_spec.build_pieces(
+        Mat3::identity(),
+        Vec3::zero(),
+        &mut world,
+        Some(0),
+        &mut validation,
+    );
+    assert!(pieces.is_empty());
+}
+
+#[test]
+fn scattered_instances_on_slopes_move_to_flat_ground() {
+    load_flat_model();
+    let spec: PlaceSpec = ron::from_str(
+        r#"(
+            pieces: [],
+            scatter: [(
+                area: ((0, 0), (39, 9)),
+                z: 20,
+                density: Count(4),
+                variants: [(1.0, ("flat.vox", (0, 0, 0), (snap_to_surface: true)))],
+                min_spacing: 2.0,
+                require_flat: Some((max_height_diff: 0)),
+                flat_retries: 10,
+            )],
+        )"#,
+    )
+    .unwrap();
+    let mut validation = Validation::default();
+    let spec = spec.expanded("test.ron", Some(0), &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    let mut world = heightfield(HILLSIDE_AREA, hillside);
+    let pieces =
+        spec.build_pieces(Mat3::identity(), Vec3::zero(), &mut world, Some(0), &mut validation);
+    assert_eq!(pieces.len(), 4);
+    for piece in &pieces {
+        assert!(piece.aabbs[0].max.x <= 20, "{:?}", piece.aabbs);
+        assert_eq!(piece.aabbs[0].min.z, 1);
+    }
+}
+
+#[test]
+fn blocks_outside_of_the_allowed_chunks_are_counted() {
+    let place_spec: PlaceSpec = ron::from_str(
+        r#"(
+            pieces: [("a.vox", (0, 0, 0))],
+            replace: [((128, 128, 128), Block(kind: Rock, color: (128, 128, 128)))],
+        )"#,
+    )
+    .unwrap();
+    let place = |allowed: &str| {
+        let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+        let mut sink = ChunkFilter {
+            allowed: Some(parse_allowed_chunks(allowed, false).unwrap()),
+            disallowed: BTreeMap::new(),
+            sink: HashMap::new(),
+        };
+        let options = PlaceOptions {
+            verbosity: Verbosity::Quiet,
+            ..PlaceOptions::default()
+        };
+        let pieces = [full_piece()];
+        place_into(&place_spec, &merge_pieces(pieces.iter()), &mut world, &mut sink, options);
+        (sink.sink.len(), sink.disallowed)
+    };
+    // The piece is in the terrain chunk at the origin, x -1 is in the one
+    // before it.
+    let (placed, disallowed) = place("-1,0");
+    assert_eq!(placed, 0);
+    assert_eq!(disallowed.into_iter().collect::<Vec<_>>(), vec![([0, 0], 64)]);
+    let (placed, disallowed) = place("0,0,0,0");
+    assert_eq!(placed, 64);
+    assert!(disallowed.is_empty());
+}
+
+#[test]
+fn glow_buried_in_solid_blocks_is_stripped() {
+    let spec = r#"(
+        pieces: [("a.vox", (0, 0, 0))],
+        replace: [((128, 128, 128), Block(kind: GlowingRock, color: (128, 128, 128)))],
+        strip_hidden_glow: true,
+    )"#;
+    // Only the 2 by 2 by 2 core of the full piece is surrounded by solid
+    // blocks, the surface borders positions the spec doesn't place.
+    let blocks = place(spec, &[full_piece()]);
+    let unlit = blocks.iter().filter(|(_, block)| block.kind() == BlockKind::Rock);
+    let unlit = unlit.map(|(wpos, _)| *wpos).collect::<Vec<_>>();
+    assert_eq!(unlit.len(), 8);
+    assert!(unlit.iter().all(|wpos| wpos.map(|e| (1..=2).contains(&e)).reduce_and()));
+    // Two steps from every block of the core reach past the surface.
+    let spec = spec.replace("true,", "true, hidden_glow_radius: 2,");
+    let blocks = place(&spec, &[full_piece()]);
+    assert!(blocks.values().all(|block| block.kind() == BlockKind::GlowingRock));
+}
+
+#[test]
+fn points_of_interest_are_centered_on_their_voxels() {
+    let spec: PlaceSpec = ron::from_str(
+        r#"(
+            pieces: [
+                ("a.vox", (0, 0, 0), (name: Some("hut"), poi: Some("house"))),
+                ("b.vox", (2, 0, 0), (name: Some("shed"))),
+            ],
+            groups: [(name: "farm", members: ["hut", "shed"], poi: Some("town"))],
+        )"#,
+    )
+    .unwrap();
+    let mut validation = Validation::default();
+    let spec = spec.expanded("test.ron", Some(0), &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    let pieces = [corner_piece(0, Vec3::zero(), false), corner_piece(1, Vec3::new(2, 0, 0), false)];
+    let pois = points_of_interest(&spec, &pieces);
+    assert_eq!(pois.len(), 2);
+    // The only voxel of the corner piece is at its minimum corner.
+    assert_eq!((pois[0].name.as_str(), pois[0].kind.as_str()), ("hut", "house"));
+    assert_eq!(pois[0].center, [0.0; 3]);
+    assert_eq!((pois[0].min, pois[0].max), ([0; 3], [3; 3]));
+    assert_eq!(pois[1].name, "farm");
+    assert_eq!(pois[1].center, [1.0, 0.0, 0.0]);
+    assert_eq!(pois[1].max, [5, 3, 3]);
+}
+
+#[test]
+fn footprints_leave_the_notch_of_an_l_shape_alone() {
+    // A floor and a roof over an L shape, leaving out the corner at x and y 2
+    // to 3.
+    let voxels = (0..16)
+        .map(|i| [i % 4, i / 4])
+        .filter(|[x, y]| *x < 2 || *y < 2)
+        .flat_map(|[x, y]| [([x, y, 0], 0), ([x, y, 2], 0)])
+        .collect::<Vec<_>>();
+    let vox = common::vox_model([4, 4, 3], &voxels, &[STONE]);
+    let l_piece = |margin: u32| {
+        let (scene, aabbs, bounds) =
+            common::build_piece(&vox, 0, Mat3::identity(), Vec3::zero(), &[]);
+        BuiltPiece {
+            index: 0,
+            aabbs,
+            bounds: Some(bounds),
+            local_aabb: None,
+            merge: Merge::Overwrite,
+            priority: 0,
+            rot: Mat3::identity(),
+            offset: Vec3::zero(),
+            decayed: 0,
+            fill_empty: true,
+            fill_footprint: Some(scene.column_extents(margin)),
+            scene,
+            cutouts: Vec::new(),
+            skirt: Vec::new(),
+        }
+    };
+    let spec = r#"(pieces: [("a.vox", (0, 0, 0))])"#;
+    let blocks = place(spec, &[l_piece(0)]);
+    assert_eq!(blocks.len(), 12 * 3);
+    assert_eq!(blocks[&Vec3::new(0, 3, 1)].kind(), BlockKind::Air);
+    assert!(blocks.keys().all(|wpos| wpos.x < 2 || wpos.y < 2));
+    // The margin reaches below the floor.
+    let blocks = place(spec, &[l_piece(1)]);
+    assert_eq!(blocks.len(), 12 * 4);
+    assert_eq!(blocks[&Vec3::new(3, 0, -1)].kind(), BlockKind::Air);
+    assert!(!blocks.contains_key(&Vec3::new(3, 3, -1)));
+    // Filling the bounds fills the notch too.
+    let piece = BuiltPiece {
+        fill_footprint: None,
+        ..l_piece(0)
+    };
+    assert_eq!(place(spec, &[piece]).len(), 4 * 4 * 3);
+}
+
+#[test]
+fn higher_priorities_keep_their_cells() {
+    let first = BuiltPiece {
+        priority: 1,
+        ..corner_piece(0, Vec3::zero(), false)
+    };
+    let second = corner_piece(1, Vec3::zero(), false);
+    let merged = merge_pieces([first.clone(), second.clone()].iter());
+    let origins = merged.scene.cells().map(|(_, _, origin)| origin.piece).collect::<Vec<_>>();
+    assert_eq!(origins, vec![0]);
+    assert_eq!(merged.suppressed, BTreeMap::from([((0, 1), 1)]));
+    // Equal priorities keep the later piece.
+    let first = BuiltPiece { priority: 0, ..first };
+    let merged = merge_pieces([first, second].iter());
+    let origins = merged.scene.cells().map(|(_, _, origin)| origin.piece).collect::<Vec<_>>();
+    assert_eq!(origins, vec![1]);
+    assert!(merged.suppressed.is_empty());
+}
+
+#[test]
+fn pieces_and_groups_align_to_the_context() {
+    let dir = std::env::temp_dir().join("map_placer_test_context");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut context = Context::default();
+    context.entries.insert(
+        "platform".to_string(),
+        ContextEntry {
+            min: [0, 0, 0],
+            max: [31, 31, 4],
+            anchors: vec![[5, 6, 7]],
+            markers: BTreeMap::from([("stairs".to_string(), vec![[12, 0, 5]])]),
+        },
+    );
+    context.save(&dir.join("platform_run.ron")).unwrap();
+    let spec: PlaceSpec = ron::from_str(
+        r#"(
+            context: Some("platform_run.ron"),
+            pieces: [
+                ("a.vox", (1, 0, 0), (
+                    align_to_context: Some((piece: "platform", corner: MaxZMinXMinY)),
+                )),
+                ("b.vox", (0, 0, 1), (
+                    align_to_context: Some((piece: "platform", marker: Some("stairs"))),
+                )),
+                ("c.vox", (2, 0, 0), (name: Some("hut"))),
+            ],
+            groups: [(
+                name: "yard",
+                offset: (0, 1, 0),
+                members: ["hut"],
+                align_to_context: Some((piece: "platform", anchor: Some(0))),
+            )],
+        )"#,
+    )
+    .unwrap();
+    let name = dir.join("stage.ron").to_str().unwrap().to_string();
+    let mut validation = Validation::default();
+    let expanded = spec.expanded(&name, Some(0), &mut validation);
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    assert_eq!(expanded.pieces[0].offset, [1, 0, 4]);
+    assert_eq!(expanded.pieces[1].offset, [12, 0, 6]);
+    let frame = expanded.pieces[2].options.group.as_ref().unwrap();
+    assert_eq!(frame.offset, Vec3::new(5, 7, 7));
+
+    // Names that aren't in the context fail with the names that are.
+    let spec: PlaceSpec = ron::from_str(
+        r#"(
+            context: Some("platform_run.ron"),
+            pieces: [("a.vox", (0, 0, 0), (align_to_context: Some((piece: "tower"))))],
+        )"#,
+    )
+    .unwrap();
+    let mut validation = Validation::default();
+    spec.expanded(&name, Some(0), &mut validation);
+    assert_eq!(validation.errors.len(), 1);
+    let error = &validation.errors[0];
+    assert!(error.contains("no tower") && error.contains("platform"), "{}", error);
+}
@@ -0,0 +1,328 @@
+mod common;
+
+use std::{collections::HashSet, path::Path};
+
+use dot_vox::DotVoxData;
+use map_placer::{
+    scene::{local_to_world, SceneProblem, SceneSource, SparseScene},
+    spec::Pivot,
+};
+use vek::*;
+
+const STONE: Rgb<u8> = Rgb {
+    r: 128,
+    g: 128,
+    b: 128,
+};
+const DOOR: Rgb<u8> = Rgb { r: 255, g: 0, b: 0 };
+const SPAWN: Rgb<u8> = Rgb { r: 0, g: 0, b: 255 };
+
+/// A 4 by 2 by 1 model with a stone voxel at the origin, two door markers
+/// next to it and a spawn marker in the far corner.
+fn marked_model() -> DotVoxData {
+    common::vox_model(
+        [4, 2, 1],
+        &[([0, 0, 0], 0), ([1, 0, 0], 1), ([2, 0, 0], 1), ([3, 1, 0], 2)],
+        &[STONE, DOOR, SPAWN],
+    )
+}
+
+fn markers() -> Vec<(String, Rgb<u8>)> {
+    vec![("door".to_string(), DOOR), ("spawn".to_string(), SPAWN)]
+}
+
+/// Loads `tests/fixtures/<name>`.
+fn fixture(name: &str) -> DotVoxData {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    dot_vox::load_bytes(&std::fs::read(path).unwrap()).unwrap()
+}
+
+/// The whole of `vox` as the first piece, centered on the origin.
+fn source(vox: &DotVoxData) -> SceneSource<'_> {
+    SceneSource {
+        piece: 0,
+        name: "test",
+        vox,
+        frame: 0,
+        skipped_layers: HashSet::new(),
+        root: 0,
+        pivot: Pivot::Center,
+        rot: Mat3::identity(),
+        offset: Vec3::zero(),
+    }
+}
+
+#[test]
+fn markers_are_taken_out_of_the_scene() {
+    let vox = marked_model();
+    let (scene, _, bounds) =
+        common::build_piece(&vox, 0, Mat3::identity(), Vec3::zero(), &markers());
+    let cells = scene.cells().map(|(wpos, cell, _)| (wpos, cell.get_color())).collect::<Vec<_>>();
+    assert_eq!(cells, vec![(Vec3::zero(), Some(STONE))]);
+    assert_eq!(bounds.voxels, 1);
+    assert_eq!(bounds.markers["door"], vec![Vec3::new(1, 0, 0), Vec3::new(2, 0, 0)]);
+    assert_eq!(bounds.markers["spawn"], vec![Vec3::new(3, 1, 0)]);
+}
+
+#[test]
+fn markers_follow_rotation_and_offset() {
+    let vox = marked_model();
+    // A counter clockwise quarter turn, the model then covers x 0 to 1 and y
+    // 0 to 3 relative to the offset.
+    let rot = Mat3::from_col_arrays([[0, 1, 0], [-1, 0, 0], [0, 0, 1]]);
+    let offset = Vec3::new(10, 20, 30);
+    let (scene, _, bounds) = common::build_piece(&vox, 0, rot, offset, &markers());
+    let cells = scene.cells().map(|(wpos, _, _)| wpos).collect::<Vec<_>>();
+    assert_eq!(cells, vec![Vec3::new(11, 20, 30)]);
+    assert_eq!(bounds.markers["door"], vec![Vec3::new(11, 21, 30), Vec3::new(11, 22, 30)]);
+    assert_eq!(bounds.markers["spawn"], vec![Vec3::new(10, 23, 30)]);
+    assert_eq!(bounds.markers.len(), 2);
+}
+
+#[test]
+fn mirrored_models_are_reflected_within_their_bounds() {
+    // Three voxels of different colors, two of them in a row.
+    let vox = fixture("asymmetric.vox");
+    let mirror = Mat3::with_diagonal(Vec3::new(-1, 1, 1));
+    let (plain, plain_aabbs, _) = common::build_piece(&vox, 0, Mat3::identity(), Vec3::zero(), &[]);
+    let (mirrored, aabbs, _) = common::build_piece(&vox, 0, mirror, Vec3::zero(), &[]);
+    assert_eq!(aabbs, plain_aabbs);
+    // Every voxel is reflected within the bounds.
+    let max_x = aabbs[0].max.x;
+    let mut reflected = plain
+        .cells()
+        .map(|(wpos, cell, _)| ([max_x - wpos.x, wpos.y, wpos.z], cell.get_color()))
+        .collect::<Vec<_>>();
+    reflected.sort_by_key(|(wpos, _)| *wpos);
+    let mut cells = mirrored
+        .cells()
+        .map(|(wpos, cell, _)| (wpos.into_array(), cell.get_color()))
+        .collect::<Vec<_>>();
+    cells.sort_by_key(|(wpos, _)| *wpos);
+    assert_eq!(cells, reflected);
+    assert_eq!(cells.len(), 3);
+}
+
+#[test]
+fn emissive_materials_and_glow_indices_are_glowy() {
+    // A stone voxel next to one of an emissive material.
+    let vox = fixture("emissive.vox");
+    let glowy = |glow_indices: &[u8]| {
+        let (scene, _, _) = SparseScene::new_from(
+            std::iter::once(source(&vox)),
+            glow_indices,
+            &[],
+            &[],
+            &[],
+            None,
+        )
+        .unwrap();
+        let mut cells = scene
+            .cells()
+            .map(|(wpos, cell, _)| (wpos.x, cell.is_glowy()))
+            .collect::<Vec<_>>();
+        cells.sort();
+        cells
+    };
+    assert_eq!(glowy(&[]), vec![(-1, false), (0, true)]);
+    assert_eq!(glowy(&[16]), vec![(-1, false), (0, true)]);
+    // Palette indices count from 0, the stone is the first color.
+    assert_eq!(glowy(&[0]), vec![(-1, true), (0, true)]);
+}
+
+#[test]
+fn frames_have_their_own_transforms_and_models() {
+    // A flat model at the origin in the first frame, an upright one moved
+    // along x in the second.
+    let vox = fixture("frames.vox");
+    let aabbs = |frame: u32| {
+        let source = SceneSource {
+            frame,
+            ..source(&vox)
+        };
+        SparseScene::new_from(std::iter::once(source), &[], &[], &[], &[], None)
+            .unwrap()
+            .1
+    };
+    let first = Aabb {
+        min: Vec3::new(-1, 0, 0),
+        max: Vec3::zero(),
+    };
+    let second = Aabb {
+        min: Vec3::new(10, 0, -1),
+        max: Vec3::new(10, 0, 1),
+    };
+    assert_eq!(aabbs(0), vec![first]);
+    assert_eq!(aabbs(1), vec![second]);
+    // Nodes without the frame use their first one.
+    assert_eq!(aabbs(2), vec![first]);
+}
+
+#[test]
+fn models_turn_around_the_voxel_at_half_their_size() {
+    // A stone, a red and a blue voxel in a row, and a stone and a red one,
+    // turned by 0, 90, 180 and 270 degrees 10 apart along x, the shorter
+    // ones 10 further along y.
+    let vox = fixture("pivots.vox");
+    let build = |pivot: Pivot| {
+        let source = SceneSource {
+            pivot,
+            ..source(&vox)
+        };
+        SparseScene::new_from(std::iter::once(source), &[], &[], &[], &[], None).unwrap()
+    };
+    let (scene, _, _) = build(Pivot::Center);
+    let mut cells = scene
+        .cells()
+        .map(|(wpos, cell, _)| (wpos.into_array(), cell.get_color().unwrap()))
+        .collect::<Vec<_>>();
+    cells.sort_by_key(|(wpos, _)| *wpos);
+    let (red, blue) = (Rgb::new(255, 0, 0), Rgb::new(0, 0, 255));
+    let mut expected = vec![
+        ([-1, 0, 0], STONE),
+        ([0, 0, 0], red),
+        ([1, 0, 0], blue),
+        ([10, -1, 0], STONE),
+        ([10, 0, 0], red),
+        ([10, 1, 0], blue),
+        ([21, 0, 0], STONE),
+        ([20, 0, 0], red),
+        ([19, 0, 0], blue),
+        ([30, 1, 0], STONE),
+        ([30, 0, 0], red),
+        ([30, -1, 0], blue),
+        // The voxel at half the size of the even model is its second one.
+        ([-1, 10, 0], STONE),
+        ([0, 10, 0], red),
+        ([10, 9, 0], STONE),
+        ([10, 10, 0], red),
+        ([21, 10, 0], STONE),
+        ([20, 10, 0], red),
+        ([30, 11, 0], STONE),
+        ([30, 10, 0], red),
+    ];
+    expected.sort_by_key(|(wpos, _)| *wpos);
+    assert_eq!(cells, expected);
+    // Each model has its minimum corner at its translation instead.
+    let (_, aabbs, _) = build(Pivot::MinCorner);
+    let mut corners = aabbs.iter().map(|aabb| aabb.min.into_array()).collect::<Vec<_>>();
+    corners.sort();
+    let mut translations = (0..4)
+        .flat_map(|i| [[i * 10, 0, 0], [i * 10, 10, 0]])
+        .collect::<Vec<_>>();
+    translations.sort();
+    assert_eq!(corners, translations);
+}
+
+#[test]
+fn broken_scene_graphs_are_errors_naming_the_node() {
+    // A group with a child that exists next to one that doesn't.
+    let vox = fixture("dangling_child.vox");
+    let build = |vox: &DotVoxData| {
+        SparseScene::new_from(std::iter::once(source(vox)), &[], &[], &[], &[], None)
+    };
+    let error = build(&vox).unwrap_err();
+    assert_eq!((error.node, error.problem), (1, SceneProblem::MissingNode(9)));
+    assert_eq!(
+        error.to_string(),
+        "The scene graph of test is broken, node 1 refers to the missing node 9"
+    );
+    // A transform that is its own child.
+    let mut vox = common::vox_model([1, 1, 1], &[([0, 0, 0], 0)], &[STONE]);
+    if let dot_vox::SceneNode::Transform { child, .. } = &mut vox.scenes[0] {
+        *child = 0;
+    }
+    let error = build(&vox).unwrap_err();
+    assert_eq!((error.node, error.problem), (0, SceneProblem::Cycle));
+}
+
+#[test]
+fn cutouts_turn_with_their_piece() {
+    let vox = marked_model();
+    let quarter = Mat3::from_col_arrays([[0, 1, 0], [-1, 0, 0], [0, 0, 1]]);
+    let mirror = Mat3::from_col_arrays([[-1, 0, 0], [0, 1, 0], [0, 0, 1]]);
+    let offset = Vec3::new(10, 20, 30);
+    for rot in [Mat3::identity(), quarter, quarter * quarter, quarter * mirror] {
+        let (_, aabbs, bounds) = common::build_piece(&vox, 0, rot, offset, &markers());
+        // A cutout of a single voxel lands on the voxel.
+        let point = |pos: Vec3<i32>| local_to_world(Aabb { min: pos, max: pos }, rot, aabbs[0]);
+        assert_eq!(point(Vec3::new(3, 1, 0)).min, bounds.markers["spawn"][0]);
+        assert!(bounds.markers["door"].contains(&point(Vec3::new(2, 0, 0)).max));
+        // The whole model covers the bounds of the piece.
+        let whole = local_to_world(
+            Aabb {
+                min: Vec3::zero(),
+                max: Vec3::new(3, 1, 0),
+            },
+            rot,
+            aabbs[0],
+        );
+        assert_eq!(whole, aabbs[0]);
+    }
+}
+
+#[test]
+fn cutouts_are_clipped_to_the_piece() {
+    let vox = marked_model();
+    let rot = Mat3::from_col_arrays([[0, 1, 0], [-1, 0, 0], [0, 0, 1]]);
+    let (mut scene, aabbs, _) = common::build_piece(&vox, 0, rot, Vec3::zero(), &markers());
+    let cutouts = scene.cut_out(
+        &[
+            Aabb {
+                min: Vec3::new(-5, -5, -5),
+                max: Vec3::new(0, 0, 0),
+            },
+            Aabb {
+                min: Vec3::new(10, 0, 0),
+                max: Vec3::new(12, 0, 0),
+            },
+        ],
+        rot,
+        aabbs[0],
+    );
+    // The second cutout is outside of the piece altogether.
+    assert_eq!(cutouts.len(), 1);
+    assert!(aabbs[0].contains_aabb(cutouts[0]));
+    assert_eq!(scene.cells().count(), 0);
+}
+
+#[test]
+fn skirts_follow_the_edge_of_the_bottom_layer() {
+    // A solid 3 by 3 by 2 block, offset so its bottom layer is at z 5.
+    let voxels = (0..2)
+        .flat_map(|z| (0..3).flat_map(move |y| (0..3).map(move |x| ([x, y, z], 0))))
+        .collect::<Vec<_>>();
+    let vox = common::vox_model([3, 3, 2], &voxels, &[STONE]);
+    let (scene, _, _) = common::build_piece(&vox, 0, Mat3::identity(), Vec3::new(0, 0, 5), &[]);
+    let (bottom, columns) = scene.skirt_columns(1).unwrap();
+    assert_eq!(bottom, 5);
+    // Every column but the middle one is at the edge.
+    assert_eq!(columns.len(), 8);
+    assert!(!columns.contains(&Vec2::new(1, 1)));
+    assert_eq!(scene.skirt_columns(2).unwrap().1.len(), 9);
+    let berm = scene.berm_columns(2);
+    assert_eq!(berm.len(), 7 * 7 - 9);
+    assert_eq!(berm.iter().filter(|(_, distance)| *distance == 1).count(), 16);
+    assert!(berm.contains(&(Vec2::new(-2, -2), 2)));
+}
+
+#[test]
+fn thin_models_turned_across_chunks_keep_every_voxel() {
+    // A column of 5 voxels toppled onto its side, so it lies along y across
+    // the border of the scene chunks at y 0.
+    let voxels = (0..5).map(|z| ([0, 0, z], 0)).collect::<Vec<_>>();
+    let vox = common::vox_model([1, 1, 5], &voxels, &[STONE]);
+    let rot = Mat3::from_col_arrays([[1, 0, 0], [0, 0, 1], [0, -1, 0]]);
+    let (scene, aabbs, bounds) = common::build_piece(&vox, 0, rot, Vec3::new(0, -2, 0), &[]);
+    let mut cells = scene.cells().map(|(wpos, _, _)| wpos).collect::<Vec<_>>();
+    cells.sort_by_key(|wpos| wpos.y);
+    assert_eq!(cells, (-2..=2).map(|y| Vec3::new(0, y, 0)).collect::<Vec<_>>());
+    assert_eq!(
+        aabbs,
+        vec![Aabb {
+            min: Vec3::new(0, -2, 0),
+            max: Vec3::new(0, 2, 0),
+        }]
+    );
+    assert_eq!(bounds.voxels, 5);
+}
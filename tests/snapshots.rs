@@ -0,0 +1,170 @@
+//! Places the vox files in `tests/fixtures` and compares the placed blocks
+//! with the snapshots in `tests/snapshots`.
+//!
+//! Snapshots that are missing or differ fail the test that checks them. Run
+//! the tests with `BLESS=1` to write them, after making sure the new blocks
+//! are right.
+
+use std::{collections::HashMap, path::Path};
+
+use map_placer::{
+    model::load_model_bytes,
+    persistence::World,
+    place::{merge_pieces, place_into, PlaceOptions, Verbosity},
+    spec::{PlaceSpec, Validation},
+};
+use serde::{Deserialize, Serialize};
+use vek::*;
+use veloren_common::terrain::Block;
+
+/// Loads the fixture `name` from its bytes, under the name specs refer to it
+/// by, `fixtures/<name>`.
+fn load_fixture(name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let bytes = std::fs::read(&path).unwrap();
+    load_model_bytes(&format!("fixtures/{}", name), &bytes).unwrap();
+}
+
+/// Builds the pieces of `spec` and resolves their blocks, without any terrain.
+fn place(spec: &str) -> HashMap<Vec3<i32>, Block> {
+    for name in ["asymmetric.vox", "scene_graph.vox", "emissive.vox"] {
+        load_fixture(name);
+    }
+    let place_spec: PlaceSpec = ron::from_str(spec).unwrap();
+    let mut world = World::new(std::env::temp_dir().join("map_placer_test_no_terrain"));
+    let mut validation = Validation::default();
+    let pieces = place_spec.build_pieces(
+        Mat3::identity(),
+        Vec3::zero(),
+        &mut world,
+        Some(0),
+        &mut validation,
+    );
+    assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+    let mut blocks = HashMap::new();
+    let options = PlaceOptions {
+        verbosity: Verbosity::Quiet,
+        seed: Some(0),
+        ..PlaceOptions::default()
+    };
+    place_into(&place_spec, &merge_pieces(pieces.iter()), &mut world, &mut blocks, options);
+    blocks
+}
+
+/// A block as snapshots keep it, by its kind, color and sprite, so snapshots
+/// don't depend on how the server crate serializes blocks.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SnapshotBlock {
+    kind: String,
+    color: Option<(u8, u8, u8)>,
+    sprite: Option<String>,
+}
+
+impl From<Block> for SnapshotBlock {
+    fn from(block: Block) -> Self {
+        Self {
+            kind: format!("{:?}", block.kind()),
+            color: block.get_color().map(|color| (color.r, color.g, color.b)),
+            sprite: block.get_sprite().map(|sprite| format!("{:?}", sprite)),
+        }
+    }
+}
+
+/// Compares `blocks` with the snapshot `name`, sorted by position.
+fn assert_snapshot(name: &str, blocks: &HashMap<Vec3<i32>, Block>) {
+    let mut blocks = blocks
+        .iter()
+        .map(|(wpos, block)| (wpos.into_array(), SnapshotBlock::from(*block)))
+        .collect::<Vec<_>>();
+    blocks.sort_unstable_by_key(|(wpos, _)| (wpos[2], wpos[1], wpos[0]));
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+    let path = dir.join(format!("{}.ron", name));
+    let golden = std::fs::read_to_string(&path).ok();
+    // Snapshots are compared by what they hold, not how they are formatted.
+    let parsed = golden
+        .as_deref()
+        .and_then(|golden| ron::from_str::<Vec<([i32; 3], SnapshotBlock)>>(golden).ok());
+    if parsed.as_ref() == Some(&blocks) {
+        return;
+    }
+    let snapshot = ron::ser::to_string_pretty(&blocks, ron::ser::PrettyConfig::new()).unwrap();
+    if std::env::var_os("BLESS").is_none() {
+        let problem = if golden.is_some() { "differ from" } else { "have no snapshot at" };
+        panic!(
+            "The blocks placed for {} {} {}, run with BLESS=1 to write it:\n{}",
+            name,
+            problem,
+            path.display(),
+            snapshot
+        );
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&path, snapshot + "\n").unwrap();
+}
+
+const REPLACE: &str = r#"replace: [
+    ((128, 128, 128), Block(kind: Rock, color: (128, 128, 128))),
+    ((255, 0, 0), Block(kind: Wood, color: (255, 0, 0))),
+    ((0, 0, 255), Block(kind: GlowingRock, color: (0, 0, 255))),
+]"#;
+
+#[test]
+fn asymmetric_model_in_every_rotation() {
+    for rotation in [0, 90, 180, 270] {
+        for mirror in [false, true] {
+            let spec = format!(
+                r#"(
+                    pieces: [("fixtures/asymmetric.vox", (0, 0, 0), (
+                        rotation: {},
+                        mirror: ({}, false, false),
+                    ))],
+                    {},
+                )"#,
+                rotation, mirror, REPLACE
+            );
+            let blocks = place(&spec);
+            assert_eq!(blocks.len(), 3);
+            let name = format!("asymmetric_{}{}", rotation, if mirror { "_mirrored" } else { "" });
+            assert_snapshot(&name, &blocks);
+        }
+    }
+}
+
+#[test]
+fn asymmetric_model_with_min_corner_pivot() {
+    let spec = format!(
+        r#"(
+            pieces: [("fixtures/asymmetric.vox", (10, 20, 30), (
+                rotation: 90,
+                pivot: MinCorner,
+            ))],
+            {},
+        )"#,
+        REPLACE
+    );
+    let blocks = place(&spec);
+    // The minimum corner of the turned model is at the offset.
+    let min = blocks.keys().copied().reduce(Vec3::partial_min).unwrap();
+    assert_eq!(min, Vec3::new(10, 20, 30));
+    assert_snapshot("asymmetric_min_corner", &blocks);
+}
+
+#[test]
+fn nested_groups_and_rotations() {
+    let spec = format!(r#"(pieces: [("fixtures/scene_graph.vox", (0, 0, 0))], {})"#, REPLACE);
+    let blocks = place(&spec);
+    assert_eq!(blocks.len(), 4);
+    assert_snapshot("scene_graph", &blocks);
+}
+
+#[test]
+fn emissive_materials_glow() {
+    let spec = r#"(
+        pieces: [("fixtures/emissive.vox", (0, 0, 0))],
+        use_materials: true,
+    )"#;
+    let blocks = place(spec);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks.values().filter(|block| block.get_glow().is_some()).count(), 1);
+    assert_snapshot("emissive", &blocks);
+}